@@ -59,7 +59,7 @@
 //! [1600] 105.987ms / 118.933ms / 96.213ms
 //! ```
 
-use std::{collections::HashMap, env};
+use std::{collections::HashSet, env};
 
 use color_eyre::Result;
 use fake::{faker::name::en::Name as FakeName, Fake, Faker};
@@ -70,7 +70,7 @@ use rand::{
     thread_rng,
     Rng,
 };
-use sg_core::models::{Entity, EventFilter, Meta, Name, User};
+use sg_core::models::{Entity, EventFilter, User};
 use tokio::time::Instant;
 
 const KINDS: &[&str] = &[
@@ -96,22 +96,18 @@ fn gen_user(event_filter: EventFilter) -> User {
         avatar: "https://placekitten.com/114/514".parse().ok(),
         im: ["tg", "qq"].choose(&mut rng).unwrap().to_owned().to_owned(),
         im_payload: Faker.fake(),
+        muted_until: None,
+        preferred_language: isolanguage_1::LanguageCode::En,
     }
 }
 
 fn gen_entity() -> Entity {
-    let en = isolanguage_1::LanguageCode::En;
     let id: uuid::Uuid = Faker.fake();
-    let name = Name {
-        name: HashMap::from_iter([(en, FakeName().fake())]),
-        default_language: en,
-    };
-    let meta = Meta { name, group: None };
-    Entity {
-        id: id.into(),
-        meta,
-        tasks: vec![],
-    }
+    Entity::builder()
+        .id(id.into())
+        .name(isolanguage_1::LanguageCode::En, FakeName().fake::<String>())
+        .build()
+        .expect("Generated entity must be valid")
 }
 
 fn gen_ef(rng: &mut ThreadRng, entities: &[uuid::Uuid]) -> EventFilter {
@@ -125,7 +121,11 @@ fn gen_ef(rng: &mut ThreadRng, entities: &[uuid::Uuid]) -> EventFilter {
         .choose_multiple(rng, entities_len)
         .map(|x| (*x).into())
         .collect();
-    EventFilter { kinds, entities }
+    EventFilter {
+        entities,
+        groups: HashSet::default(),
+        kinds,
+    }
 }
 
 #[tokio::main]
@@ -1,6 +1,8 @@
 use http::StatusCode;
 use thiserror::Error;
 
+use crate::rpc::ErrorCode;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Reqwest error: {0}")]
@@ -40,6 +42,12 @@ impl Error {
             .map_or(false, |api_error| api_error.matches_status(status))
     }
 
+    #[must_use]
+    pub fn matches_api_code(&self, code: ErrorCode) -> bool {
+        self.as_api()
+            .map_or(false, |api_error| api_error.code() == code)
+    }
+
     // Allow b/c destructor cannot be evaluated at compile time
     #[must_use]
     #[allow(clippy::missing_const_for_fn)]
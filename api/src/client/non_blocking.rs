@@ -1,9 +1,11 @@
+use std::time::{Duration, SystemTime};
+
 use reqwest::{IntoUrl, Url};
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
     client::{Result, Shim},
-    rpc::{ApiResult, Request, ResponseObject},
+    rpc::{ApiError, ApiResult, Request, ResponseObject},
 };
 
 /// Non-blocking version of the client to invoke API methods.
@@ -12,6 +14,8 @@ pub struct Client {
     client: reqwest::Client,
     url: Url,
     token: Option<String>,
+    /// Expiry of `token`, if known.
+    valid_until: Option<SystemTime>,
 }
 
 impl Client {
@@ -36,6 +40,7 @@ impl Client {
     pub fn with_client(client: reqwest::Client, url: impl IntoUrl) -> Result<Self> {
         Ok(Self {
             token: None,
+            valid_until: None,
             client,
             url: url.into_url()?,
         })
@@ -72,7 +77,10 @@ impl Client {
         Ok(resp?)
     }
 
+    /// Store a token for future use. Its expiry is unknown, so it's never refreshed by
+    /// [`refresh_if_expiring`](Self::refresh_if_expiring).
     pub fn set_token(&mut self, token: impl Into<String>) -> Option<String> {
+        self.valid_until = None;
         self.token.replace(token.into())
     }
 
@@ -81,6 +89,12 @@ impl Client {
         self.token.as_deref()
     }
 
+    /// Expiry of the stored token, if it's obtained by this client.
+    #[must_use]
+    pub const fn valid_until(&self) -> Option<SystemTime> {
+        self.valid_until
+    }
+
     /// Login and store the credential for future use.
     /// Returns `Some(Token)` if there's already one stored.
     ///
@@ -93,6 +107,37 @@ impl Client {
         password: impl Into<String> + Send,
     ) -> Result<Option<String>> {
         let token = self.login(username.into(), password.into()).await?;
+        self.valid_until = Some(token.valid_until);
         Ok(self.token.replace(token.token))
     }
+
+    /// Exchange the stored token for a new one and store it.
+    /// Returns the replaced token.
+    ///
+    /// # Errors
+    /// Fails if no token is stored, or on invalid `RefreshToken` method, bad request body, network
+    /// issue or bad response.
+    pub async fn refresh_and_store(&mut self) -> Result<Option<String>> {
+        let token = self.token.clone().ok_or_else(ApiError::missing_token)?;
+        let token = self.refresh_token(token).await?;
+        self.valid_until = Some(token.valid_until);
+        Ok(self.token.replace(token.token))
+    }
+
+    /// Refresh the stored token if it expires within `margin`. Returns whether it's refreshed.
+    ///
+    /// Tokens of unknown expiry, i.e. those given by [`set_token`](Self::set_token), are left
+    /// as is.
+    ///
+    /// # Errors
+    /// Fails if the refresh fails. See [`refresh_and_store`](Self::refresh_and_store).
+    pub async fn refresh_if_expiring(&mut self, margin: Duration) -> Result<bool> {
+        match self.valid_until {
+            Some(valid_until) if valid_until <= SystemTime::now() + margin => {
+                self.refresh_and_store().await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
 }
@@ -19,7 +19,7 @@ Represents an API Error.
 ## Format into JSON
 ```rust
 # use api::{rpc::{ApiError,Response}, server::ResponseExt}; fn main() {
-let resp = r#"{"data":{"error":["Not Found","Cannot find user with ID `26721d57-37f5-458c-afea-2b18baf34925`"]},"success":false,"time":"2022-01-01T00:00:00.000000000Z"}"#;
+let resp = r#"{"data":{"error":["Not Found","Cannot find user with ID `26721d57-37f5-458c-afea-2b18baf34925`"],"code":"UserNotFound","status":404},"success":false,"time":"2022-01-01T00:00:00.000000000Z"}"#;
 let mut resp_obj = ApiError::user_not_found_with_id(
     &mongodb::bson::uuid::Uuid::parse_str("26721d57-37f5-458c-afea-2b18baf34925").unwrap(),
 ).into_packed();
@@ -30,9 +30,11 @@ assert_eq!(resp, resp_obj.to_json());
 "##
 )]
 #[must_use]
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ApiError {
     error: Vec<String>,
+    #[serde(default)]
+    code: ErrorCode,
     #[serde(with = "http_serde::status_code")]
     status: StatusCode,
 }
@@ -48,14 +50,63 @@ impl Display for ApiError {
 
 impl StdError for ApiError {}
 
+/// Machine-readable kind of an [`ApiError`], for clients to branch on without parsing messages.
+#[must_use]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ErrorCode {
+    BadToken,
+    MissingToken,
+    Unauthorized,
+    UserNotFound,
+    UserAlreadyExists,
+    EntityNotFound,
+    EntityAlreadyExists,
+    TaskNotFound,
+    GroupNotFound,
+    RequestInProgress,
+    BadRequest,
+    TooManyRequests,
+    PayloadTooLarge,
+    DatabaseUnavailable,
+    Internal,
+    /// A code unknown to this version, e.g. sent by a newer server.
+    #[serde(other)]
+    #[default]
+    Unknown,
+}
+
+impl From<StatusCode> for ErrorCode {
+    /// The most generic code for `status`.
+    fn from(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::BAD_REQUEST => Self::BadRequest,
+            StatusCode::TOO_MANY_REQUESTS => Self::TooManyRequests,
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge,
+            StatusCode::INTERNAL_SERVER_ERROR => Self::Internal,
+            _ => Self::Unknown,
+        }
+    }
+}
+
 impl ApiError {
+    /// Create an error with the most generic code for `status`. See [`ErrorCode::from`].
     #[inline]
     pub fn new(status: StatusCode) -> Self {
+        Self::with_code(status, status.into())
+    }
+
+    #[inline]
+    pub fn with_code(status: StatusCode, code: ErrorCode) -> Self {
         let error = match status.canonical_reason() {
             Some(reason) => vec![reason.to_owned()],
             None => vec![],
         };
-        Self { error, status }
+        Self {
+            error,
+            code,
+            status,
+        }
     }
 
     #[must_use]
@@ -86,6 +137,11 @@ impl ApiError {
         self.status
     }
 
+    #[inline]
+    pub const fn code(&self) -> ErrorCode {
+        self.code
+    }
+
     /// Match the text with the error reasons.
     ///
     /// Returns `true` if the text is a substring of any of the errors.
@@ -124,12 +180,14 @@ impl ApiError {
 
     #[inline]
     pub fn bad_token() -> Self {
-        Self::new(StatusCode::UNAUTHORIZED).explain("Token is either expired or in bad shape")
+        Self::with_code(StatusCode::UNAUTHORIZED, ErrorCode::BadToken)
+            .explain("Token is either expired or in bad shape")
     }
 
     #[inline]
     pub fn missing_token() -> Self {
-        Self::new(StatusCode::UNAUTHORIZED).explain("Token is missing")
+        Self::with_code(StatusCode::UNAUTHORIZED, ErrorCode::MissingToken)
+            .explain("Token is missing")
     }
 
     #[inline]
@@ -139,12 +197,13 @@ impl ApiError {
 
     #[inline]
     pub fn user_not_found_with_id(user_id: &Uuid) -> Self {
-        Self::new(StatusCode::NOT_FOUND).explain(format!("Cannot find user with ID `{}`", user_id))
+        Self::with_code(StatusCode::NOT_FOUND, ErrorCode::UserNotFound)
+            .explain(format!("Cannot find user with ID `{user_id}`"))
     }
 
     #[inline]
     pub fn user_not_found_with_im(im: impl AsRef<str>, im_payload: impl AsRef<str>) -> Self {
-        Self::new(StatusCode::NOT_FOUND).explain(format!(
+        Self::with_code(StatusCode::NOT_FOUND, ErrorCode::UserNotFound).explain(format!(
             "Cannot find user with im `{}` and im_payload `{}`",
             im.as_ref(),
             im_payload.as_ref()
@@ -160,9 +219,14 @@ impl ApiError {
     }
 
     #[inline]
-    pub fn user_already_exists(im: impl AsRef<str>, im_payload: impl AsRef<str>) -> Self {
-        Self::new(StatusCode::CONFLICT).explain(format!(
-            "User already exists im `{}` and im_payload `{}`",
+    pub fn user_already_exists(
+        im: impl AsRef<str>,
+        im_payload: impl AsRef<str>,
+        user_id: &Uuid,
+    ) -> Self {
+        Self::with_code(StatusCode::CONFLICT, ErrorCode::UserAlreadyExists).explain(format!(
+            "User `{}` already exists with im `{}` and im_payload `{}`",
+            user_id,
             im.as_ref(),
             im_payload.as_ref()
         ))
@@ -170,13 +234,37 @@ impl ApiError {
 
     #[inline]
     pub fn entity_not_found(entity_id: &Uuid) -> Self {
-        Self::new(StatusCode::NOT_FOUND)
-            .explain(format!("Cannot find entity with ID `{}`", entity_id))
+        Self::with_code(StatusCode::NOT_FOUND, ErrorCode::EntityNotFound)
+            .explain(format!("Cannot find entity with ID `{entity_id}`"))
+    }
+
+    #[inline]
+    pub fn entity_already_exists(external_id: impl AsRef<str>, entity_id: &Uuid) -> Self {
+        Self::with_code(StatusCode::CONFLICT, ErrorCode::EntityAlreadyExists).explain(format!(
+            "Entity `{}` already exists with external_id `{}`",
+            entity_id,
+            external_id.as_ref()
+        ))
     }
 
     #[inline]
     pub fn task_not_found(task_id: &Uuid) -> Self {
-        Self::new(StatusCode::NOT_FOUND).explain(format!("Cannot find task with ID `{}`", task_id))
+        Self::with_code(StatusCode::NOT_FOUND, ErrorCode::TaskNotFound)
+            .explain(format!("Cannot find task with ID `{task_id}`"))
+    }
+
+    #[inline]
+    pub fn group_not_found(group_id: &Uuid) -> Self {
+        Self::with_code(StatusCode::NOT_FOUND, ErrorCode::GroupNotFound)
+            .explain(format!("Cannot find group with ID `{group_id}`"))
+    }
+
+    #[inline]
+    pub fn request_in_progress(key: impl AsRef<str>) -> Self {
+        Self::with_code(StatusCode::CONFLICT, ErrorCode::RequestInProgress).explain(format!(
+            "Request with idempotency key `{}` is still in progress",
+            key.as_ref()
+        ))
     }
 
     #[inline]
@@ -188,6 +276,23 @@ impl ApiError {
     pub fn internal() -> Self {
         Self::new(StatusCode::INTERNAL_SERVER_ERROR)
     }
+
+    #[inline]
+    pub fn too_many_requests() -> Self {
+        Self::new(StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    #[inline]
+    pub fn payload_too_large(limit: usize) -> Self {
+        Self::new(StatusCode::PAYLOAD_TOO_LARGE)
+            .explain(format!("Request body exceeds the limit of {limit} bytes"))
+    }
+
+    #[inline]
+    pub fn database_unavailable() -> Self {
+        Self::with_code(StatusCode::SERVICE_UNAVAILABLE, ErrorCode::DatabaseUnavailable)
+            .explain("Database is unreachable")
+    }
 }
 
 impl Response for ApiError {
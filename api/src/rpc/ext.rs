@@ -5,8 +5,16 @@ use crate::rpc::model::AddTask;
 impl From<AddTask> for Task {
     fn from(new_task: AddTask) -> Self {
         let AddTask {
-            entity_id, param, ..
+            entity_id,
+            param,
+            schedule,
+            required_tags,
+            ..
         } = new_task;
-        param.into_task_with(entity_id)
+        Self {
+            schedule,
+            required_tags,
+            ..param.into_task_with(entity_id)
+        }
     }
 }
@@ -42,10 +42,11 @@
 //! - Implement [`Request`] for that request struct.
 //! - If response object has fields, define it and implement [`Response`] for
 //!   it.
+//! - Define `method_schemas` listing all methods as [`MethodSchema`]s.
 //! - If `client` feature is enabled, generate methods for
 //!   [`Client`](crate::client::Client) to invoke RPC methods.
 
-mod_use::mod_use![wrapper, traits, error, ext];
+mod_use::mod_use![wrapper, traits, error, ext, schema];
 
 pub mod model;
 
@@ -112,7 +113,7 @@ macro_rules! methods {
 
             impl $req {
                 #[inline]
-                #[allow(clippy::new_without_default)]
+                #[allow(clippy::new_without_default, clippy::too_many_arguments)]
                 #[must_use]
                 pub const fn new($( $req_field_name : $req_field_type, )*) -> Self {
                     Self {
@@ -139,7 +140,7 @@ macro_rules! methods {
 
                 impl $resp {
                     #[inline]
-                    #[allow(clippy::new_without_default)]
+                    #[allow(clippy::new_without_default, clippy::too_many_arguments)]
                     #[must_use]
                     pub const fn new($( $resp_field_name : $resp_field_type, )*) -> Self {
                         Self {
@@ -152,6 +153,35 @@ macro_rules! methods {
             )?
         )*
 
+        /// Description of all RPC methods, in definition order.
+        #[must_use]
+        pub fn method_schemas() -> Vec<$crate::rpc::MethodSchema> {
+            use $crate::rpc::{FieldSchema, MethodSchema};
+
+            vec![
+                $(
+                    MethodSchema {
+                        method: stringify!($method).to_owned(),
+                        request: stringify!($req).to_owned(),
+                        params: vec![
+                            $( FieldSchema::new(stringify!($req_field_name), stringify!($req_field_type)), )*
+                        ],
+                        response: stringify!($resp).to_owned(),
+                        // Only present if the response type is defined here
+                        response_fields: [
+                            $(
+                                vec![
+                                    $( FieldSchema::new(stringify!($resp_field_name), stringify!($resp_field_type)), )*
+                                ],
+                            )?
+                        ]
+                        .into_iter()
+                        .next(),
+                    },
+                )*
+            ]
+        }
+
         #[test]
         fn test_requests_size() {
             use ::std::mem::size_of;
@@ -171,7 +201,7 @@ macro_rules! methods {
         use $crate::{client::Result as ClientResult};
 
         #[cfg(feature = "client")]
-        #[allow(clippy::missing_errors_doc)]
+        #[allow(clippy::missing_errors_doc, clippy::too_many_arguments)]
         impl $crate::client::Client {
             $(
                 $( #[ $method_meta ] )*
@@ -194,7 +224,7 @@ macro_rules! methods {
         }
 
         #[cfg(feature = "client_blocking")]
-        #[allow(clippy::missing_errors_doc)]
+        #[allow(clippy::missing_errors_doc, clippy::too_many_arguments)]
         impl $crate::client::blocking::Client {
             $(
                 $( #[ $method_meta ] )*
@@ -254,7 +284,7 @@ mod test_macro {
     use mongodb::bson::Uuid;
 
     use crate::{
-        rpc::{ApiError, Request, Response},
+        rpc::{ApiError, ErrorCode, FieldSchema, MethodSchema, Request, Response},
         timestamp,
     };
 
@@ -273,6 +303,23 @@ mod test_macro {
         assert_eq!(GetUser::METHOD, "get_user");
     }
 
+    #[test]
+    fn test_method_schemas() {
+        assert_eq!(
+            method_schemas(),
+            [MethodSchema {
+                method: String::from("get_user"),
+                request: String::from("GetUser"),
+                params: vec![FieldSchema::new("user_id", "String")],
+                response: String::from("DummyUser"),
+                response_fields: Some(vec![
+                    FieldSchema::new("user_id", "String"),
+                    FieldSchema::new("user_info", "String"),
+                ]),
+            }]
+        );
+    }
+
     #[test]
     fn test_serialize_success() {
         let now = timestamp();
@@ -295,7 +342,7 @@ mod test_macro {
         let now = timestamp();
         let id = "26721d57-37f5-458c-afea-2b18baf34925";
         let resp = format!(
-            r#"{{"data":{{"error":["Not Found","Cannot find user with ID `{id}`"],"status":404}},"success":false,"time":"{now}"}}"#,
+            r#"{{"data":{{"error":["Not Found","Cannot find user with ID `{id}`"],"code":"UserNotFound","status":404}},"success":false,"time":"{now}"}}"#,
         );
 
         let mut resp_obj =
@@ -304,4 +351,20 @@ mod test_macro {
 
         assert_eq!(resp, resp_obj.to_json());
     }
+
+    #[test]
+    fn test_deserialize_error_code() {
+        let err: ApiError =
+            serde_json::from_str(r#"{"error":[],"code":"TaskNotFound","status":404}"#).unwrap();
+        assert_eq!(err.code(), ErrorCode::TaskNotFound);
+
+        // From a newer server
+        let err: ApiError =
+            serde_json::from_str(r#"{"error":[],"code":"Whatever","status":418}"#).unwrap();
+        assert_eq!(err.code(), ErrorCode::Unknown);
+
+        // From an older server
+        let err: ApiError = serde_json::from_str(r#"{"error":[],"status":500}"#).unwrap();
+        assert_eq!(err.code(), ErrorCode::Unknown);
+    }
 }
@@ -0,0 +1,33 @@
+use mongodb::bson::{DateTime, Uuid};
+use sg_core::utils::uuid_v5;
+
+/// Namespace of ids of principals logging in. See [`login_principal`].
+pub const LOGIN_NAMESPACE: Uuid = Uuid::from_bytes([
+    0x2c, 0x8e, 0x71, 0xd4, 0x06, 0xb9, 0x4f, 0x3a, 0xa5, 0x5d, 0x13, 0xe2, 0x97, 0x4b, 0x60, 0xfc,
+]);
+
+/// Id of the principal logging in with `username`, which is the subject of its tokens.
+///
+/// It's a version 5 UUID of `username` in [`LOGIN_NAMESPACE`], so it's the same on every login.
+#[must_use]
+pub fn login_principal(username: &str) -> Uuid {
+    uuid_v5(LOGIN_NAMESPACE, username)
+}
+
+/// Record of a successful admin mutation.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AuditEntry {
+    /// UUID of the entry
+    pub id: Uuid,
+    /// Subject of the token that did the mutation. See [`login_principal`] for tokens issued by
+    /// `login`.
+    pub actor: Uuid,
+    /// Id (`jti` claim) of the token that did the mutation
+    pub token: Uuid,
+    /// Name of the RPC method, e.g. `add_entity`
+    pub action: String,
+    /// UUID of the mutated object, if any
+    pub target: Option<Uuid>,
+    /// Time of the mutation
+    pub at: DateTime,
+}
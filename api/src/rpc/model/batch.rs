@@ -0,0 +1,13 @@
+use crate::ApiError;
+
+/// Maximum number of tokens created by a single `new_tokens` request.
+pub const MAX_NEW_TOKENS: usize = 1000;
+
+/// Failure of a single item in a batch request, which doesn't fail the other items.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BatchError {
+    /// Index of the failed item in the request
+    pub index: usize,
+    /// Why the item failed
+    pub error: ApiError,
+}
@@ -1,9 +1,15 @@
 use mongodb::bson::Uuid;
 
+use crate::model::Privilege;
+
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Bot {
     /// UUID of the bot
-    id: Uuid,
+    pub id: Uuid,
+    /// Name of the bot
+    pub name: String,
+    /// Privilege of tokens issued to the bot
+    pub privilege: Privilege,
     /// UUID of the admin who created the bot
-    created_by: Uuid,
+    pub created_by: Uuid,
 }
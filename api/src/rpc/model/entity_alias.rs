@@ -0,0 +1,12 @@
+use mongodb::bson::{DateTime, Uuid};
+
+/// Redirect from an entity merged into another, see `merge_entities`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EntityAlias {
+    /// ID of the merged entity
+    pub from: Uuid,
+    /// ID of the entity it's merged into
+    pub into: Uuid,
+    /// Time the entities are merged
+    pub at: DateTime,
+}
@@ -0,0 +1,11 @@
+use mongodb::bson::DateTime;
+use sg_core::models::Event;
+
+/// An event as persisted in the event log.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EventRecord {
+    /// The event, including its kind and fields
+    pub event: Event,
+    /// Time the event is received by the API
+    pub at: DateTime,
+}
@@ -0,0 +1,6 @@
+/// Response of importing entities from NDJSON.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Imported {
+    /// Number of entities inserted or replaced
+    pub count: u64,
+}
@@ -0,0 +1,17 @@
+/// A public key tokens can be verified with, in JWK format.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Jwk {
+    /// Key type, `OKP` for Ed25519 keys
+    pub kty: String,
+    /// Curve of the key, e.g. `Ed25519`
+    pub crv: String,
+    /// The public key, in url-safe base64 without padding
+    pub x: String,
+    /// Id of the key, as in the `kid` header of tokens signed by it
+    pub kid: String,
+    /// Algorithm of the key, e.g. `EdDSA`
+    pub alg: String,
+    /// Intended use of the key, always `sig`
+    #[serde(rename = "use")]
+    pub use_: String,
+}
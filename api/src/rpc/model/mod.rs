@@ -1,31 +1,76 @@
 //! Contains all model definition and trait implementations.
 
-use std::time::SystemTime;
+// `Option<Option<T>>` is used to tell absent fields from `null` ones in patch requests.
+#![allow(clippy::option_option)]
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::SystemTime,
+};
 
 // Core models
-use mongodb::bson::Uuid;
-use sg_core::models::{Entity, EventFilter, Group, Meta, Task, User};
+use isolanguage_1::LanguageCode;
+use mongodb::bson::{DateTime, Uuid};
+use sg_core::{
+    models::{DeadLetter, Entity, EventFilter, Group, Meta, Name, Task, User},
+    schedule::Schedule,
+};
 use url::Url;
 
-use crate::successful_response;
+use crate::{rpc::MethodSchema, successful_response};
 
-mod_use::mod_use![bot, null, admin, add_task, user_query];
+mod_use::mod_use![
+    bot, null, admin, add_task, user_query, patch, privilege, audit, jwk, export, batch,
+    event_record, entity_alias
+];
 
-successful_response![Entity, Task, User, Group];
+successful_response![Entity, Task, User, Group, Imported];
 
 crate::methods! {
     // ---------------------- //
     // Does not require Token //
     // ---------------------- //
-    /// Health check
+    /// Health check, fails if the database is unreachable
     health := Health {} -> Null,
 
+    /// Readiness check, reporting whether this node can do useful work. `health` is enough to
+    /// tell whether the process is alive.
+    ///
+    /// Also served as `GET /v1/ready`, answering 503 unless ready, for readiness probes.
+    detailed_health := DetailedHealth {} -> HealthReport {
+        /// Whether the database is reachable, the message queue is connected if events are
+        /// consumed, and the coordinator has live workers if `ready_requires_workers` is set
+        ready: bool,
+        /// Whether the database is reachable
+        database: bool,
+        /// Number of workers the coordinator heard from within `worker_heartbeat_timeout`
+        workers: u64,
+        /// Whether this node is the active coordinator. Always `false`, since the coordinator runs
+        /// as its own process and API nodes only read its state
+        coordinator: bool,
+        /// Whether event consumers are connected to the message queue. Absent if no event is
+        /// consumed by this node.
+        message_queue: Option<bool>
+    },
+
+    /// Get public keys tokens can be verified with, in JWKS format
+    ///
+    /// Empty if tokens are signed with a shared secret.
+    get_jwks := GetJwks {} -> Jwks {
+        keys: Vec<Jwk>
+    },
+
+    /// Describe all RPC methods, with fields of their requests and responses
+    get_schema := GetSchema {} -> Schema {
+        methods: Vec<MethodSchema>
+    },
+
     /// Login with Username and Password
     ///
     /// This method checks for login information stored in DB,
     /// returns a token if matched and has sufficient permission.
     ///
-    /// The token is composed with a nil user id (UUID with all 0),
+    /// The token is composed with an id derived from the username (see `login_principal`),
     /// which cannot be used to request some methods that require user information
     /// like `update_setting` or `auth_user`
     login := Login {
@@ -37,6 +82,13 @@ crate::methods! {
         valid_until: SystemTime
     },
 
+    /// Exchange a valid user token for a new one with the same subject and a reset expiry. The
+    /// old token is revoked
+    refresh_token := RefreshToken {
+        /// The token to refresh. Must be a user token, not expired or revoked.
+        token: String,
+    } -> Token,
+
     // ----------- //
     // User method //
     // ----------  //
@@ -46,11 +98,92 @@ crate::methods! {
         event_filter: EventFilter
     } -> User,
 
+    /// Add entities to the user's event filter, return the updated `User`
+    ///
+    /// Unlike `update_setting`, concurrent changes to other entries are kept.
+    subscribe_entities := SubscribeEntities {
+        entity_ids: Vec<Uuid>
+    } -> User,
+
+    /// Remove entities from the user's event filter, return the updated `User`
+    unsubscribe_entities := UnsubscribeEntities {
+        entity_ids: Vec<Uuid>
+    } -> User,
+
+    /// Add event kinds to the user's event filter, return the updated `User`
+    subscribe_kinds := SubscribeKinds {
+        kinds: Vec<String>
+    } -> User,
+
+    /// Remove event kinds from the user's event filter, return the updated `User`
+    unsubscribe_kinds := UnsubscribeKinds {
+        kinds: Vec<String>
+    } -> User,
+
+    /// Mute all events until some time regardless of the event filter, return the updated `User`
+    set_mute := SetMute {
+        /// Time until which no event is delivered. Absent to unmute.
+        #[serde(default, with = "humantime_serde")]
+        until: Option<SystemTime>,
+    } -> User,
+
+    /// Set the language events are delivered in, return the updated `User`
+    set_language := SetLanguage {
+        /// Language in ISO 639-1. Entity names missing in it fall back to their default language.
+        lang: LanguageCode,
+    } -> User,
+
     /// Get all entities, include vtbs and groups
+    ///
+    /// Vtbs are sorted by id and can be paged through with `limit` and `after`.
+    /// Omit both to get everything at once. Tasks of vtbs are referred to by id, and only returned
+    /// in full if asked for.
     get_entities := GetEntities {
+        /// Maximum number of vtbs to return.
+        limit: Option<i64>,
+        /// Only return vtbs whose id is greater than this one, i.e. `next_cursor` of the previous page.
+        after: Option<Uuid>,
+        /// Whether to include soft-deleted vtbs.
+        #[serde(default)]
+        include_deleted: bool,
+        /// Whether to return the tasks of the vtbs in `tasks`.
+        #[serde(default)]
+        include_tasks: bool,
     } -> Entities {
         vtbs: Vec<Entity>,
-        groups: Vec<Group>
+        groups: Vec<Group>,
+        /// Tasks of the returned vtbs if `include_tasks` is set, sorted by id.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tasks: Vec<Task>,
+        /// Id of the last vtb if a full page is returned. Pass it as `after` to get the next page.
+        next_cursor: Option<Uuid>
+    },
+
+    /// Search vtbs by a case-insensitive substring of their names, sorted by id.
+    /// Soft-deleted vtbs are excluded.
+    search_entities := SearchEntities {
+        /// Substring to look for.
+        query: String,
+        /// Only match names in this language. Names in any language are matched if absent.
+        lang: Option<LanguageCode>,
+        /// Maximum number of vtbs to return, capped at 50.
+        limit: Option<i64>,
+    } -> EntityMatches {
+        vtbs: Vec<Entity>
+    },
+
+    /// Count vtbs without fetching them. Soft-deleted vtbs are excluded.
+    count_entities := CountEntities {
+        /// Only count vtbs in this group.
+        group: Option<Uuid>,
+        /// Whether to count vtbs in each group as well.
+        #[serde(default)]
+        per_group: bool,
+    } -> EntityCount {
+        count: u64,
+        /// Number of vtbs keyed by group id, if `per_group` is set. Vtbs without a group are only
+        /// counted in `count`.
+        groups: HashMap<String, u64>
     },
 
     /// Authorize user
@@ -67,7 +200,7 @@ crate::methods! {
     // ---------- //
 
     /// Create a new token for an user,
-    /// which has `User` privilege and carries a non-nil `user_id`
+    /// which has `User` privilege and carries the `user_id`
     new_token := NewToken {
         /// Either (`user id`) or combination of (`im` and `im_payload`)
         /// that can be used to look up user
@@ -75,6 +208,20 @@ crate::methods! {
         query: UserQuery,
     } -> Token,
 
+    /// Create a token for each user like `new_token`, in a single request. At most
+    /// [`MAX_NEW_TOKENS`] users can be queried at once.
+    ///
+    /// Users that can't be found are reported in `errors` instead of failing the whole batch.
+    new_tokens := NewTokens {
+        /// Queries of users to create tokens for
+        queries: Vec<UserQuery>,
+    } -> NewTokensResult {
+        /// Id of each found user along with its token, in the order of `queries`
+        tokens: Vec<(Uuid, Token)>,
+        /// Queries that failed, by their index in `queries`
+        errors: Vec<BatchError>
+    },
+
     /// Create a new user.
     add_user := AddUser {
         /// The IM that the user is in.
@@ -84,7 +231,28 @@ crate::methods! {
         /// Avatar of the user.
         avatar: Option<Url>,
         /// Name of the user.
-        name: String
+        name: String,
+        /// Return the existing user with the same `im` and `im_payload` instead of failing with
+        /// `UserAlreadyExists`. The existing user is left as is.
+        #[serde(default)]
+        return_existing: bool,
+    } -> User,
+
+    /// Register a webhook, i.e. a user whose events are sent with `POST` to `callback_url` as
+    /// JSON, or update an existing one. Subscriptions of the webhook are managed as any other
+    /// user. Return the webhook.
+    set_webhook := SetWebhook {
+        /// ID of the webhook to update. Absent to register a new one.
+        #[serde(default)]
+        user_id: Option<Uuid>,
+        /// Name of the webhook.
+        name: String,
+        /// Endpoint events are sent to with `POST`.
+        callback_url: Url,
+        /// Secret to sign events with, sent in the `X-Stargazer-Signature` header. Absent to not
+        /// sign them.
+        #[serde(default)]
+        secret: Option<String>,
     } -> User,
 
     /// Delete an existing user.
@@ -94,8 +262,24 @@ crate::methods! {
         query: UserQuery,
     } -> User,
 
+    /// Delete all users matching the query, return how many are deleted.
+    ///
+    /// Fails without a query, i.e. deleting everyone, unless `confirm_all` is set.
+    del_users := DelUsers {
+        /// Either `user id`, `im` and `im_payload` of a user, or `im` alone for all users in it
+        #[serde(flatten)]
+        query: Option<BulkUserQuery>,
+        /// Allow deleting everyone without a query.
+        #[serde(default)]
+        confirm_all: bool,
+    } -> Deleted {
+        count: u64
+    },
+
     /// Query users that subscribed to specific events. This
     /// is filtered by the user's event filter and im.
+    ///
+    /// Users subscribed to the group of the entity are included as well.
     get_interest := GetInterest {
         entity_id: Uuid,
         kind: String,
@@ -108,24 +292,175 @@ crate::methods! {
     // ------------ //
     // Admin method //
     // ------------ //
+    /// Get all registered bots, sorted by name
+    get_bots := GetBots {} -> Bots {
+        bots: Vec<Bot>
+    },
+
+    /// Get audit entries of admin mutations, newest first
+    ///
+    /// Entries can be paged through with `limit` and `before`.
+    get_audit_log := GetAuditLog {
+        /// Only return entries of this actor.
+        actor: Option<Uuid>,
+        /// Only return entries at or after this time.
+        #[serde(default, with = "humantime_serde")]
+        since: Option<SystemTime>,
+        /// Only return entries before this time.
+        #[serde(default, with = "humantime_serde")]
+        until: Option<SystemTime>,
+        /// Maximum number of entries to return.
+        limit: Option<i64>,
+        /// Only return entries older than this one, i.e. `next_cursor` of the previous page.
+        before: Option<Uuid>,
+    } -> AuditLog {
+        entries: Vec<AuditEntry>,
+        /// Id of the last entry if a full page is returned. Pass it as `before` to get the next page.
+        next_cursor: Option<Uuid>
+    },
+
+    /// Get events of an entity recorded in the event log, newest first
+    ///
+    /// Events can be paged through with `limit` and `before`. Only events received since the log
+    /// is enabled and within `event_ttl` are kept.
+    get_entity_events := GetEntityEvents {
+        entity_id: Uuid,
+        /// Only return events older than this one, i.e. `next_cursor` of the previous page.
+        before: Option<Uuid>,
+        /// Maximum number of events to return.
+        limit: Option<i64>,
+    } -> EntityEvents {
+        events: Vec<EventRecord>,
+        /// Id of the last event if a full page is returned. Pass it as `before` for more.
+        next_cursor: Option<Uuid>
+    },
+
+    /// Get events that couldn't be delivered after all retries, sorted by id
+    ///
+    /// Dead letters can be paged through with `limit` and `after`.
+    get_dead_letters := GetDeadLetters {
+        /// Maximum number of dead letters to return.
+        limit: Option<i64>,
+        /// Only return dead letters after this one, i.e. `next_cursor` of the previous page.
+        after: Option<Uuid>,
+    } -> DeadLetters {
+        letters: Vec<DeadLetter>,
+        /// Id of the last dead letter if a full page is returned. Pass it as `after` for more.
+        next_cursor: Option<Uuid>
+    },
+
+    /// Mark dead letters to be delivered again, return the number of dead letters found
+    ///
+    /// They're delivered within `dead_letter_replay_interval` if delivery is enabled, then removed
+    /// once delivered, or unmarked with the new error otherwise.
+    replay_dead_letters := ReplayDeadLetters {
+        ids: Vec<Uuid>
+    } -> Replayed {
+        count: u64
+    },
+
+    /// Get tasks left unassigned by the coordinator because no worker has the tags they require,
+    /// sorted by id
+    get_unschedulable_tasks := GetUnschedulableTasks {} -> Tasks,
+
+    /// Decode a token to see what it encodes, for debugging. The token may be expired, revoked or
+    /// signed by an unknown key.
+    introspect_token := IntrospectToken {
+        /// The token to inspect
+        token: String,
+    } -> TokenInfo {
+        /// Id of the subject, derived from the username for tokens issued by `login`
+        user_id: Uuid,
+        /// Privilege of the token
+        privilege: Privilege,
+        /// Absent for tokens issued before issue times were recorded
+        #[serde(default, with = "humantime_serde")]
+        issued_at: Option<SystemTime>,
+        #[serde(with = "humantime_serde")]
+        valid_until: SystemTime,
+        /// Id of the token, used to revoke it
+        jti: Uuid,
+        /// Whether the token is signed by a key currently accepted
+        verified: bool,
+        /// Whether the token is past `valid_until`
+        expired: bool,
+        /// Whether the token is revoked
+        revoked: bool
+    },
+
+    /// Create a new bot, along with a long-lived token with `Bot` privilege
+    new_bot := NewBot {
+        /// Name of the bot
+        name: String
+    } -> BotInfo {
+        /// The created bot
+        bot: Bot,
+        /// Token for the bot to access the API
+        token: String,
+        #[serde(with = "humantime_serde")]
+        valid_until: SystemTime
+    },
+
     add_task := AddTask {
         #[serde(flatten)]
         /// Task parameter
         param: AddTaskParam,
         /// The ID of this entity which this task belongs to.
         entity_id: Uuid,
+        /// Key to dedupe retried requests. A seen key returns the previous response.
+        #[serde(default)]
+        idempotency_key: Option<String>,
+        /// When the task runs, e.g. `{"interval": "5m"}` or `{"cron": "0 * * * *"}`.
+        #[serde(default)]
+        schedule: Option<Schedule>,
+        /// Tags a worker must have to run the task, e.g. `["jp"]`.
+        #[serde(default)]
+        required_tags: HashSet<String>,
     } -> Task,
 
+    /// Add multiple tasks to an entity at once. Return the created tasks.
+    add_tasks := AddTasks {
+        /// The ID of this entity which these tasks belong to.
+        entity_id: Uuid,
+        /// Parameters of the tasks
+        tasks: Vec<AddTaskParam>,
+    } -> Tasks {
+        tasks: Vec<Task>
+    },
+
     del_task := DelTask {
         /// The ID of the task going to be deleted.
         task_id: Uuid
     } -> Task,
 
+    /// Resume a disabled task. Return the task.
+    enable_task := EnableTask {
+        /// The ID of the task
+        task_id: Uuid
+    } -> Task,
+
+    /// Pause a task without deleting it. Its worker stops running it. Return the task.
+    disable_task := DisableTask {
+        /// The ID of the task
+        task_id: Uuid
+    } -> Task,
+
     add_entity := AddEntity {
         /// Meta of the entity
         meta: Meta,
         /// List of tasks that this entity has.
-        tasks: Vec<AddTaskParam>
+        tasks: Vec<AddTaskParam>,
+        /// Key to dedupe retried requests. A seen key returns the previous response.
+        #[serde(default)]
+        idempotency_key: Option<String>,
+        /// Id of the vtuber in the source it's imported from. The entity id is derived from it,
+        /// so importing the same vtuber again doesn't duplicate it.
+        #[serde(default)]
+        external_id: Option<String>,
+        /// Replace the meta of the entity with the same `external_id` instead of failing with
+        /// `EntityAlreadyExists`. Its tasks are left as is, and `tasks` is ignored.
+        #[serde(default)]
+        upsert: bool,
     } -> Entity,
 
     /// Update the entity's meta. Return the new entity.
@@ -136,9 +471,103 @@ crate::methods! {
         meta: Meta,
     } -> Entity,
 
-    /// Update an entity. Return the deleted entity.
+    /// Partially update the entity's meta, touching only supplied fields. Return the new entity.
+    patch_entity_meta := PatchEntityMeta {
+        /// The ID of the entity
+        entity_id: Uuid,
+        /// Names to add or overwrite, keyed by language
+        #[serde(default)]
+        name_additions: HashMap<LanguageCode, String>,
+        /// Languages whose name should be removed
+        #[serde(default)]
+        name_removals: Vec<LanguageCode>,
+        /// New preferred language of the name
+        #[serde(default)]
+        default_language: Option<LanguageCode>,
+        /// New group of the entity. Absent to keep it unchanged, `null` to clear it.
+        #[serde(default, deserialize_with = "double_option", skip_serializing_if = "Option::is_none")]
+        group: Option<Option<Uuid>>,
+        /// New debut date of the entity. Absent to keep it unchanged, `null` to clear it.
+        #[serde(default, deserialize_with = "double_option", skip_serializing_if = "Option::is_none")]
+        debut: Option<Option<DateTime>>,
+        /// Links to add or overwrite, keyed by platform
+        #[serde(default)]
+        link_additions: HashMap<String, String>,
+        /// Platforms whose link should be removed
+        #[serde(default)]
+        link_removals: Vec<String>,
+    } -> Entity,
+
+    /// Soft-delete an entity along with its tasks, so that it can be restored later.
+    /// Return the deleted entity.
     del_entity := DelEntity {
+        /// The ID of the entity
+        entity_id: Uuid,
+        /// Remove the entity and its tasks permanently instead.
+        #[serde(default)]
+        purge: bool,
+    } -> Entity,
+
+    /// Restore a soft-deleted entity along with its tasks. Return the restored entity.
+    /// Entities merged into another can't be restored.
+    restore_entity := RestoreEntity {
         /// The ID of the entity
         entity_id: Uuid
     } -> Entity,
+
+    /// Merge an entity into another, e.g. after a rebrand. Tasks and subscribers of `from` are
+    /// moved to `into`, `from` is soft-deleted, and its ID is redirected to `into` from then on.
+    /// Return the merged entity.
+    merge_entities := MergeEntities {
+        /// The ID of the entity to merge
+        from: Uuid,
+        /// The ID of the entity to merge into
+        into: Uuid
+    } -> Entity,
+
+    /// Move tasks of an entity off their current worker, e.g. if they misbehave there. Tasks stay
+    /// on their new worker until it leaves.
+    ///
+    /// The request is recorded as `reassignment` of the entity, and applied by the coordinator
+    /// shortly after. Return the entity with the pending request.
+    reassign_entity := ReassignEntity {
+        /// The ID of the entity
+        entity_id: Uuid,
+        /// Worker to move the tasks to. The next-best worker other than the current one is
+        /// picked if absent.
+        target_worker: Option<Uuid>,
+    } -> Entity,
+
+    /// Add a group vtbs can be put in. Return the new group.
+    add_group := AddGroup {
+        /// Name of the group
+        name: Name
+    } -> Group,
+
+    /// Rename the group. Return the new group.
+    update_group := UpdateGroup {
+        /// The ID of the group
+        group_id: Uuid,
+        /// Name of the group
+        name: Name,
+    } -> Group,
+
+    /// Delete the group. Vtbs in it are left without a group. Return the deleted group.
+    del_group := DelGroup {
+        /// The ID of the group
+        group_id: Uuid
+    } -> Group,
+
+    /// Revoke a token before it expires. Either `token` or `target_jti` is required.
+    revoke_token := RevokeToken {
+        /// The token to revoke
+        #[serde(default)]
+        token: Option<String>,
+        /// Id (`jti` claim) of the token to revoke
+        #[serde(default)]
+        target_jti: Option<Uuid>,
+    } -> Revoked {
+        /// Id of the revoked token
+        jti: Uuid
+    },
 }
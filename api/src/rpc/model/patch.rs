@@ -0,0 +1,266 @@
+use mongodb::bson::{Bson, Document};
+use serde::{Deserialize, Deserializer};
+use sg_core::models::Meta;
+
+use crate::{model::PatchEntityMeta, ApiError};
+
+/// Deserialize a present field into `Some`, so that an explicit `null` becomes `Some(None)`.
+///
+/// Use together with `#[serde(default)]` to tell an absent field from a `null` one.
+///
+/// # Errors
+/// Fails if the inner value fails to deserialize.
+pub fn double_option<'de, T, D>(de: D) -> Result<Option<Option<T>>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+{
+    Option::deserialize(de).map(Some)
+}
+
+impl PatchEntityMeta {
+    /// Build the update document touching only the supplied paths,
+    /// or `None` if there's nothing to update.
+    ///
+    /// # Errors
+    /// Fails if a language or platform is both added and removed.
+    pub fn as_update(&self) -> Result<Option<Document>, ApiError> {
+        if let Some(lang) = self
+            .name_removals
+            .iter()
+            .find(|lang| self.name_additions.contains_key(lang))
+        {
+            return Err(ApiError::bad_request(format!(
+                "Language `{lang}` cannot be added and removed at the same time"
+            )));
+        }
+        if let Some(platform) = self
+            .link_removals
+            .iter()
+            .find(|platform| self.link_additions.contains_key(*platform))
+        {
+            return Err(ApiError::bad_request(format!(
+                "Link on `{platform}` cannot be added and removed at the same time"
+            )));
+        }
+
+        let mut set = Document::new();
+        let mut unset = Document::new();
+
+        for (lang, name) in &self.name_additions {
+            set.insert(format!("meta.name.name.{}", lang.code()), name);
+        }
+        for lang in &self.name_removals {
+            unset.insert(format!("meta.name.name.{}", lang.code()), "");
+        }
+        if let Some(lang) = self.default_language {
+            set.insert("meta.name.default_language", lang.code());
+        }
+        if let Some(group) = self.group {
+            set.insert("meta.group", group.map_or(Bson::Null, Bson::from));
+        }
+        match self.debut {
+            Some(Some(debut)) => {
+                set.insert("meta.debut", debut);
+            }
+            Some(None) => {
+                unset.insert("meta.debut", "");
+            }
+            None => {}
+        }
+        for (platform, url) in &self.link_additions {
+            set.insert(format!("meta.links.{platform}"), url);
+        }
+        for platform in &self.link_removals {
+            unset.insert(format!("meta.links.{platform}"), "");
+        }
+
+        let mut update = Document::new();
+        if !set.is_empty() {
+            update.insert("$set", set);
+        }
+        if !unset.is_empty() {
+            update.insert("$unset", unset);
+        }
+
+        Ok((!update.is_empty()).then_some(update))
+    }
+
+    /// Apply the patch to `meta` in place, the same way [`as_update`](Self::as_update) does in database.
+    pub fn apply(&self, meta: &mut Meta) {
+        meta.name.name.extend(self.name_additions.clone());
+        for lang in &self.name_removals {
+            meta.name.name.remove(lang);
+        }
+        if let Some(lang) = self.default_language {
+            meta.name.default_language = lang;
+        }
+        if let Some(group) = self.group {
+            meta.group = group;
+        }
+        if let Some(debut) = self.debut {
+            meta.debut = debut;
+        }
+        meta.links.extend(self.link_additions.clone());
+        for platform in &self.link_removals {
+            meta.links.remove(platform);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use isolanguage_1::LanguageCode;
+    use mongodb::bson::{doc, DateTime, Uuid};
+    use sg_core::models::{Meta, Name};
+
+    use crate::model::PatchEntityMeta;
+
+    #[test]
+    fn test_group_absent_or_null() {
+        let id = "5e9f8f8f-f8f8-f8f8-f8f8-f8f8f8f8f8f8";
+
+        let absent: PatchEntityMeta = serde_json::from_value(serde_json::json!({
+            "entity_id": id,
+        }))
+            .unwrap();
+        assert_eq!(absent.group, None);
+
+        let null: PatchEntityMeta = serde_json::from_value(serde_json::json!({
+            "entity_id": id,
+            "group": null,
+        }))
+            .unwrap();
+        assert_eq!(null.group, Some(None));
+
+        let set: PatchEntityMeta = serde_json::from_value(serde_json::json!({
+            "entity_id": id,
+            "group": id,
+        }))
+            .unwrap();
+        assert_eq!(set.group, Some(Some(Uuid::parse_str(id).unwrap())));
+    }
+
+    #[test]
+    fn test_as_update() {
+        let group = Uuid::new();
+        let debut = DateTime::from_millis(1_521_072_000_000);
+        let patch = PatchEntityMeta::new(
+            Uuid::new(),
+            HashMap::from([(LanguageCode::En, "Suisei".to_string())]),
+            vec![LanguageCode::Ja],
+            Some(LanguageCode::En),
+            Some(Some(group)),
+            Some(Some(debut)),
+            HashMap::from([("youtube".to_string(), "https://youtube.com/@suisei".to_string())]),
+            vec!["twitter".to_string()],
+        );
+        assert_eq!(
+            patch.as_update().unwrap(),
+            Some(doc! {
+                "$set": {
+                    "meta.name.name.en": "Suisei",
+                    "meta.name.default_language": "en",
+                    "meta.group": group,
+                    "meta.debut": debut,
+                    "meta.links.youtube": "https://youtube.com/@suisei",
+                },
+                "$unset": { "meta.name.name.ja": "", "meta.links.twitter": "" },
+            })
+        );
+
+        let clear = PatchEntityMeta::new(
+            Uuid::new(),
+            HashMap::new(),
+            vec![],
+            None,
+            Some(None),
+            Some(None),
+            HashMap::new(),
+            vec![],
+        );
+        assert_eq!(
+            clear.as_update().unwrap(),
+            Some(doc! { "$set": { "meta.group": null }, "$unset": { "meta.debut": "" } })
+        );
+
+        let empty = PatchEntityMeta::new(
+            Uuid::new(),
+            HashMap::new(),
+            vec![],
+            None,
+            None,
+            None,
+            HashMap::new(),
+            vec![],
+        );
+        assert_eq!(empty.as_update().unwrap(), None);
+
+        let conflict = PatchEntityMeta::new(
+            Uuid::new(),
+            HashMap::from([(LanguageCode::En, "Suisei".to_string())]),
+            vec![LanguageCode::En],
+            None,
+            None,
+            None,
+            HashMap::new(),
+            vec![],
+        );
+        assert!(conflict.as_update().is_err());
+
+        let conflict = PatchEntityMeta::new(
+            Uuid::new(),
+            HashMap::new(),
+            vec![],
+            None,
+            None,
+            None,
+            HashMap::from([("youtube".to_string(), "https://youtube.com/@suisei".to_string())]),
+            vec!["youtube".to_string()],
+        );
+        assert!(conflict.as_update().is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut meta = Meta {
+            name: Name {
+                name: HashMap::from([(LanguageCode::Ja, "星街すいせい".to_string())]),
+                default_language: LanguageCode::Ja,
+            },
+            group: Some(Uuid::new()),
+            debut: Some(DateTime::from_millis(1_521_072_000_000)),
+            links: HashMap::from([("twitter".to_string(), "https://twitter.com/suisei".to_string())]),
+        };
+
+        let patch = PatchEntityMeta::new(
+            Uuid::new(),
+            HashMap::from([(LanguageCode::En, "Suisei".to_string())]),
+            vec![LanguageCode::Ja],
+            None,
+            Some(None),
+            Some(None),
+            HashMap::from([("youtube".to_string(), "https://youtube.com/@suisei".to_string())]),
+            vec!["twitter".to_string()],
+        );
+        patch.apply(&mut meta);
+
+        assert_eq!(
+            meta.name.name,
+            HashMap::from([(LanguageCode::En, "Suisei".to_string())])
+        );
+        assert_eq!(meta.group, None);
+        assert_eq!(meta.debut, None);
+        assert_eq!(
+            meta.links,
+            HashMap::from([("youtube".to_string(), "https://youtube.com/@suisei".to_string())])
+        );
+        // Default language is removed, so this is no longer valid
+        assert!(meta.validate().is_err());
+
+        meta.name.default_language = LanguageCode::En;
+        assert!(meta.validate().is_ok());
+    }
+}
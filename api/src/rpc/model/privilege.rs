@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+
+/// Privilege of a token. Three levels: User, Bot, Admin.
+///
+/// - **User** can only access some API, mostly related to themselves.
+/// - **Bot** can access more API, include creating session for users.
+/// - **Admin** can access all API.
+#[must_use]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Privilege {
+    #[serde(alias = "user")]
+    User,
+    #[serde(alias = "bot")]
+    Bot,
+    #[serde(alias = "admin")]
+    Admin,
+}
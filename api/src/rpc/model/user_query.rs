@@ -55,11 +55,39 @@ impl From<UserQuery> for ApiError {
     }
 }
 
+/// Query of users to delete at once:
+///
+/// - A single user, by [`UserQuery`].
+/// - By IM only: all users of the IM. This is usually used to clean up a deprecated IM.
+#[must_use]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum BulkUserQuery {
+    One(UserQuery),
+    InIm { im: String },
+}
+
+impl BulkUserQuery {
+    #[must_use]
+    pub fn as_document(&self) -> Document {
+        match self {
+            Self::One(query) => query.as_document(),
+            Self::InIm { im } => doc! { "im": im },
+        }
+    }
+}
+
+impl From<UserQuery> for BulkUserQuery {
+    fn from(user_query: UserQuery) -> Self {
+        Self::One(user_query)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use mongodb::bson::Uuid;
 
-    use crate::model::UserQuery;
+    use crate::model::{BulkUserQuery, UserQuery};
 
     #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
     struct Test {
@@ -67,6 +95,14 @@ mod test {
         query: UserQuery,
     }
 
+    #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    struct BulkTest {
+        #[serde(flatten)]
+        query: Option<BulkUserQuery>,
+        #[serde(default)]
+        confirm_all: bool,
+    }
+
     #[test]
     fn test_user_query() {
         let obj = serde_json::json!({
@@ -80,5 +116,49 @@ mod test {
                 user_id: Uuid::parse_str("5e9f8f8f-f8f8-f8f8-f8f8-f8f8f8f8f8f8").unwrap()
             }
         );
+
+        let obj = serde_json::json!({
+            "im": "tg",
+            "im_payload": "114514",
+        });
+
+        let test: Test = serde_json::from_value(obj).unwrap();
+        assert_eq!(
+            test.query,
+            UserQuery::ByIm {
+                im: "tg".to_owned(),
+                im_payload: "114514".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_bulk_user_query() {
+        let obj = serde_json::json!({
+            "im": "tg",
+            "im_payload": "114514",
+        });
+        let test: BulkTest = serde_json::from_value(obj).unwrap();
+        assert_eq!(
+            test.query,
+            Some(BulkUserQuery::One(UserQuery::ByIm {
+                im: "tg".to_owned(),
+                im_payload: "114514".to_owned()
+            }))
+        );
+
+        let obj = serde_json::json!({ "im": "tg" });
+        let test: BulkTest = serde_json::from_value(obj).unwrap();
+        assert_eq!(
+            test.query,
+            Some(BulkUserQuery::InIm {
+                im: "tg".to_owned()
+            })
+        );
+
+        let obj = serde_json::json!({ "confirm_all": true });
+        let test: BulkTest = serde_json::from_value(obj).unwrap();
+        assert_eq!(test.query, None);
+        assert!(test.confirm_all);
     }
 }
@@ -0,0 +1,39 @@
+//! Description of RPC methods, for clients that can't use the types in this crate.
+
+use serde::{Deserialize, Serialize};
+
+/// A field of a request or response, with its type as written in Rust.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldSchema {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+}
+
+impl FieldSchema {
+    #[must_use]
+    pub fn new(name: &str, ty: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            ty: ty.to_owned(),
+        }
+    }
+}
+
+/// An RPC method, generated by [`methods!`](crate::methods).
+///
+/// Field names are those of the Rust structs, so fields with serde attributes, e.g. flattened
+/// ones, may differ on the wire.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MethodSchema {
+    /// Name of the method, i.e. the path to post to.
+    pub method: String,
+    /// Name of the request type.
+    pub request: String,
+    /// Fields of the request.
+    pub params: Vec<FieldSchema>,
+    /// Name of the response type.
+    pub response: String,
+    /// Fields of the response, if it's defined along with the method.
+    pub response_fields: Option<Vec<FieldSchema>>,
+}
@@ -14,6 +14,10 @@ pub struct ResponseObject<T> {
     pub data: T,
     pub success: bool,
     pub time: String,
+    /// Correlation id of the request, given in the `X-Request-Id` header or generated by the
+    /// server. Absent if the request is rejected before reaching the method.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 impl<T> ResponseObject<T> {
@@ -28,8 +32,16 @@ impl<T> ResponseObject<T> {
             data,
             success,
             time,
+            request_id: None,
         }
     }
+
+    /// Echo back the correlation id of the request.
+    #[inline]
+    pub fn with_request_id(mut self, request_id: Option<String>) -> Self {
+        self.request_id = request_id;
+        self
+    }
 }
 
 impl<T: Response> ResponseObject<T> {
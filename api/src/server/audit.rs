@@ -0,0 +1,65 @@
+//! Targets of audited admin mutations.
+
+use mongodb::bson::Uuid;
+use sg_core::models::{Entity, Group, Task, User};
+
+use crate::model::{BotInfo, Replayed, Revoked, Tasks};
+
+/// Response of a mutation that can be audited. See [`RouterExt::mount_audited`].
+///
+/// [`RouterExt::mount_audited`]: crate::server::RouterExt::mount_audited
+pub trait Audited {
+    /// UUID of the mutated object, if any.
+    fn audit_target(&self) -> Option<Uuid>;
+}
+
+impl Audited for Entity {
+    fn audit_target(&self) -> Option<Uuid> {
+        Some(self.id)
+    }
+}
+
+impl Audited for Group {
+    fn audit_target(&self) -> Option<Uuid> {
+        Some(self.id)
+    }
+}
+
+impl Audited for Task {
+    fn audit_target(&self) -> Option<Uuid> {
+        Some(self.id)
+    }
+}
+
+impl Audited for Tasks {
+    /// The entity tasks are added to.
+    fn audit_target(&self) -> Option<Uuid> {
+        self.tasks.first().map(|task| task.entity)
+    }
+}
+
+impl Audited for User {
+    fn audit_target(&self) -> Option<Uuid> {
+        Some(self.id)
+    }
+}
+
+impl Audited for BotInfo {
+    fn audit_target(&self) -> Option<Uuid> {
+        Some(self.bot.id)
+    }
+}
+
+impl Audited for Revoked {
+    /// Id of the revoked token.
+    fn audit_target(&self) -> Option<Uuid> {
+        Some(self.jti)
+    }
+}
+
+impl Audited for Replayed {
+    /// Multiple dead letters may be replayed at once.
+    fn audit_target(&self) -> Option<Uuid> {
+        None
+    }
+}
@@ -1,26 +1,59 @@
 //! Context of the server. Contains the configuration and database handle.
-use std::collections::HashSet;
-use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, SystemTime};
 
 use color_eyre::Result;
-use futures::future::try_join;
+use futures::future::join;
+use isolanguage_1::LanguageCode;
 use futures::TryStreamExt;
 use mongodb::{
-    bson::{doc, to_document, Uuid},
-    options::{FindOneAndUpdateOptions, ReturnDocument},
-    Client, Collection, Database,
+    bson::{Bson, DateTime, doc, Document, from_bson, to_bson, to_document, Uuid},
+    Client,
+    ClientSession,
+    Collection,
+    Cursor,
+    Database, IndexModel, error::{Error as MongoError, ErrorKind, WriteFailure}, options::{
+        FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument, UpdateOptions,
+    },
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use url::Url;
 
 use sg_auth::AuthClient;
-use sg_core::models::{Entity, EventFilter, Group, Meta, Task, User};
+use sg_core::{
+    models::{
+        kind_prefixes, DeadLetter, Entity, Event, EventFilter, Group, Meta, Name, Reassignment,
+        Task, User,
+    },
+    task_kind::TaskKind,
+    utils::connect_mongo,
+    webhook::{self, WebhookTarget},
+};
 
 use crate::{
-    model::{AddTaskParam, Bot, UserQuery},
+    model::{AddTaskParam, Bot, BulkUserQuery, PatchEntityMeta, UserQuery},
     rpc::{ApiError, ApiResult},
-    server::{Claims, config::Config, JWTContext, Privilege},
+    server::{
+        Claims, config::Config, JWTContext, Metrics, Privilege, RequestId, retry_transient,
+    },
 };
-use crate::model::Entities;
+use crate::model::{
+    AuditEntry, AuditLog, BatchError, BotInfo, DeadLetters, Entities, EntityAlias, EntityCount,
+    EntityEvents, EventRecord, HealthReport, Jwk, NewTokensResult, Token, TokenInfo,
+    MAX_NEW_TOKENS,
+};
+
+/// Maximum number of entities returned by a search.
+const SEARCH_LIMIT: i64 = 50;
+
+/// Maximum number of characters in a name of an entity. Its search keys grow quadratically with
+/// the length, see `search_keys`.
+const MAX_NAME_LEN: usize = 64;
 
 /// Context being shared between handlers. This will be cloned every time a handler is called.
 /// So all underlying data should be wrapped in Arc or similar shared reference thingy.
@@ -33,23 +66,55 @@ pub struct Context {
     config: Arc<Config>,
     /// JWT
     jwt: Arc<JWTContext>,
+    /// DB client, used to start sessions. Cloning is cheap.
+    client: Client,
     /// DB instance. Since DB is composed of [`Collection`](mongodb::Collection)s, cloning is cheap.
     db: Database,
     /// Auth context.
     auth: AuthClient,
+    /// Metrics, if enabled in config.
+    metrics: Option<Arc<Metrics>>,
+    /// Known task kinds, used to validate tasks before insert.
+    task_kinds: Arc<TaskKind>,
+    /// Whether event consumers are connected to the message queue.
+    mq_connected: Arc<AtomicBool>,
     /// Claims that are extracted from the JWT token header by auth middleware, optionally.
     claims: Option<Claims>,
+    /// Correlation id of the request being handled. See [`RequestId`].
+    request_id: Option<RequestId>,
+}
+
+/// Response of a request carrying an idempotency key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    /// Method of the request.
+    pub method: String,
+    /// Idempotency key given by the client.
+    pub key: String,
+    /// The response produced for the first request, or `None` while it's in progress.
+    #[serde(default)]
+    pub response: Option<Bson>,
+    /// Time until which the request in progress holds the key. Absent once it's done.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_until: Option<DateTime>,
+    /// Time after which the record is removed by TTL index.
+    pub exp: DateTime,
 }
 
 /// Context of the server. Contains the configuration and database handle.
 impl Context {
+    /// Connect to the database given in config.
+    ///
     /// # Errors
-    /// Fail on invalid database url.
-    pub async fn new(jwt: Arc<JWTContext>, config: Arc<Config>) -> Result<Self> {
-        let client = Client::with_uri_str(&config.mongo_uri).await?;
-        let db = client.database(&config.mongo_db);
-
-        Ok(Self::new_with_db(db, jwt, config))
+    /// Fail on invalid database url or options.
+    pub async fn connect(config: &Config) -> Result<Client> {
+        Ok(connect_mongo(
+            &config.mongo_uri,
+            config.max_pool_size,
+            config.write_concern.clone(),
+            config.read_preference,
+        )
+        .await?)
     }
 
     #[inline]
@@ -58,19 +123,34 @@ impl Context {
         &self.config
     }
 
-    /// Construct self with pre-connected database.
+    /// Construct self with pre-connected client.
     #[inline]
-    pub fn new_with_db(db: Database, jwt: Arc<JWTContext>, config: Arc<Config>) -> Self {
+    pub fn new_with_client(client: Client, jwt: Arc<JWTContext>, config: Arc<Config>) -> Self {
+        let db = client.database(&config.mongo_db);
         let auth = AuthClient::new(db.collection(&config.auth_collection));
+        let metrics = config
+            .metrics_enabled
+            .then(|| Arc::new(Metrics::new().expect("Metrics should be well-formed")));
         Self {
+            client,
             db,
             jwt,
             auth,
+            metrics,
+            task_kinds: Arc::new(TaskKind::default()),
+            mq_connected: Arc::new(AtomicBool::new(false)),
             config,
             claims: None,
+            request_id: None,
         }
     }
 
+    #[inline]
+    #[must_use]
+    pub const fn metrics(&self) -> Option<&Arc<Metrics>> {
+        self.metrics.as_ref()
+    }
+
     /// Get the claims from the JWT token header and assert its validity as an user. Admin and bots are not allowed.
     /// Only use this if trying to get user information from the token.
     ///
@@ -81,10 +161,10 @@ impl Context {
             .as_ref()
             .ok_or_else(ApiError::unauthorized)
             .and_then(|c| {
-                if c.as_bytes() == &[0; 16] {
-                    Err(ApiError::unauthorized())
-                } else {
+                if c.privilege() == Privilege::User {
                     Ok(c)
+                } else {
+                    Err(ApiError::unauthorized())
                 }
             })
     }
@@ -102,6 +182,19 @@ impl Context {
         self.claims.replace(claims)
     }
 
+    /// Get the correlation id of the request being handled.
+    #[inline]
+    #[must_use]
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_ref().map(|id| id.0.as_str())
+    }
+
+    /// Set the correlation id of the request being handled, if there's already one, return it
+    #[inline]
+    pub fn set_request_id(&mut self, request_id: RequestId) -> Option<RequestId> {
+        self.request_id.replace(request_id)
+    }
+
     /// Encode the user id and corresponding privilege into a JWT token.
     ///
     /// # Errors
@@ -114,6 +207,73 @@ impl Context {
         })
     }
 
+    /// Encode the bot id into a long-lived JWT token with `Bot` privilege.
+    ///
+    /// # Errors
+    /// Fails when encoding failed. This is unlikely to happen, but if it does, it's a bug.
+    #[inline]
+    pub fn encode_bot(&self, bot_id: &Uuid) -> ApiResult<(String, Claims)> {
+        self.jwt.encode_bot(bot_id).map_err(|detail| {
+            tracing::error!(?detail, "Failed to encode JWT token");
+            ApiError::internal()
+        })
+    }
+
+    /// Decode and validate the token. Revocation is not checked.
+    ///
+    /// # Errors
+    /// Fails if the token is expired or in bad shape.
+    #[inline]
+    pub fn decode(&self, token: impl AsRef<str>) -> ApiResult<Claims> {
+        self.jwt.validate(token).map_err(Into::into)
+    }
+
+    /// Decode and validate the token, including whether it's revoked.
+    ///
+    /// # Errors
+    /// Fails if the token is expired, in bad shape or revoked, or on database error.
+    pub async fn validate_token(&self, token: impl AsRef<str> + Send) -> ApiResult<Claims> {
+        self.jwt.validate_token(token).await
+    }
+
+    /// Public keys tokens can be verified with.
+    #[inline]
+    #[must_use]
+    pub fn jwks(&self) -> Vec<Jwk> {
+        self.jwt.jwks()
+    }
+
+    /// Inspect the token, which may be expired, revoked or not signed by any accepted key.
+    ///
+    /// # Errors
+    /// Fail if the token is malformed, or on database error
+    pub async fn introspect_token(&self, token: &str) -> ApiResult<TokenInfo> {
+        let (claims, verified) = self
+            .jwt
+            .inspect(token)
+            .map_err(|e| ApiError::bad_request(format!("Malformed token: {e}")))?;
+
+        Ok(TokenInfo {
+            user_id: claims.id(),
+            privilege: claims.privilege(),
+            issued_at: claims.issued_at(),
+            valid_until: claims.valid_until(),
+            jti: claims.jti(),
+            verified,
+            expired: claims.valid_until() <= SystemTime::now(),
+            revoked: self.jwt.is_revoked(claims.jti()).await?,
+        })
+    }
+
+    /// Revoke the token with given `jti`, so it can no longer pass guards. Pass `valid_until` of
+    /// the token if known, so the revocation isn't kept longer than needed.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn revoke(&self, jti: Uuid, valid_until: Option<SystemTime>) -> ApiResult<()> {
+        self.jwt.revoke(jti, valid_until).await.map_err(Into::into)
+    }
+
     #[inline]
     #[must_use]
     pub fn users(&self) -> Collection<User> {
@@ -138,6 +298,48 @@ impl Context {
         self.db.collection(&self.config.groups_collection)
     }
 
+    #[inline]
+    #[must_use]
+    pub fn bots(&self) -> Collection<Bot> {
+        self.db.collection(&self.config.bots_collection)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn idempotency(&self) -> Collection<IdempotencyRecord> {
+        self.db.collection(&self.config.idempotency_collection)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn entity_aliases(&self) -> Collection<EntityAlias> {
+        self.db.collection(&self.config.entity_aliases_collection)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn audit_log(&self) -> Collection<AuditEntry> {
+        self.db.collection(&self.config.audit_collection)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn event_log(&self) -> Collection<EventRecord> {
+        self.db.collection(&self.config.events_collection)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn dead_letters(&self) -> Collection<DeadLetter> {
+        self.db.collection(&self.config.dead_letters_collection)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn migrations(&self) -> Collection<Document> {
+        self.db.collection(&self.config.migrations_collection)
+    }
+
     #[inline]
     #[must_use]
     pub fn auth_db(&self) -> Collection<Bot> {
@@ -150,183 +352,1758 @@ impl Context {
         &self.auth
     }
 
+    /// Create indexes needed by the context. Existing indexes are left intact, so this is safe to
+    /// call on every startup.
+    ///
     /// # Errors
-    /// Fail on database error or user not found
-    pub async fn find_user(&self, query: &UserQuery) -> ApiResult<Option<User>> {
-        self.users()
-            .find_one(query.as_document(), None)
-            .await
-            .map_err(Into::into)
+    /// Fail on database error
+    pub async fn init(&self) -> ApiResult<()> {
+        let unique = || IndexOptions::builder().unique(true).build();
+
+        ensure_indexes(
+            &self.entities(),
+            vec![
+                index("id_1", doc! { "id": 1 }, unique()),
+                index(
+                    "external_id_1",
+                    doc! { "external_id": 1 },
+                    IndexOptions::builder()
+                        .unique(true)
+                        .partial_filter_expression(doc! { "external_id": { "$exists": true } })
+                        .build(),
+                ),
+                index("created_at_-1", doc! { "created_at": -1 }, IndexOptions::default()),
+                // See `search_keys`
+                index("search.any_1", doc! { "search.any": 1 }, IndexOptions::default()),
+                index("search.lang_1", doc! { "search.lang": 1 }, IndexOptions::default()),
+            ],
+        )
+            .await?;
+        self.migrate_once("fill_search_keys", self.fill_search_keys()).await?;
+        ensure_indexes(
+            &self.tasks(),
+            vec![
+                index("id_1", doc! { "id": 1 }, unique()),
+                index("entity_1", doc! { "entity": 1 }, IndexOptions::default()),
+                index("created_at_-1", doc! { "created_at": -1 }, IndexOptions::default()),
+            ],
+        )
+            .await?;
+        ensure_indexes(
+            &self.users(),
+            vec![
+                index("id_1", doc! { "id": 1 }, unique()),
+                index("im_1_im_payload_1", doc! { "im": 1, "im_payload": 1 }, unique()),
+            ],
+        )
+            .await?;
+        ensure_indexes(&self.groups(), vec![index("id_1", doc! { "id": 1 }, unique())]).await?;
+        ensure_indexes(
+            &self.entity_aliases(),
+            vec![
+                index("from_1", doc! { "from": 1 }, unique()),
+                index("into_1", doc! { "into": 1 }, IndexOptions::default()),
+            ],
+        )
+            .await?;
+        ensure_indexes(
+            &self.audit_log(),
+            vec![
+                index("id_1", doc! { "id": 1 }, unique()),
+                index("at_-1_id_-1", doc! { "at": -1, "id": -1 }, IndexOptions::default()),
+                index("actor_1", doc! { "actor": 1 }, IndexOptions::default()),
+            ],
+        )
+            .await?;
+        ensure_indexes(
+            &self.event_log(),
+            vec![
+                index(
+                    "event.entity_1_at_-1_event.id_-1",
+                    doc! { "event.entity": 1, "at": -1, "event.id": -1 },
+                    IndexOptions::default(),
+                ),
+                index("event.id_1", doc! { "event.id": 1 }, IndexOptions::default()),
+                index(
+                    "at_1",
+                    doc! { "at": 1 },
+                    IndexOptions::builder().expire_after(self.config.event_ttl).build(),
+                ),
+            ],
+        )
+            .await?;
+        ensure_indexes(
+            &self.dead_letters(),
+            vec![
+                index("id_1", doc! { "id": 1 }, unique()),
+                index("replay_1", doc! { "replay": 1 }, IndexOptions::default()),
+            ],
+        )
+            .await?;
+        ensure_indexes(
+            &self.idempotency(),
+            vec![
+                index(
+                    "exp_1",
+                    doc! { "exp": 1 },
+                    IndexOptions::builder().expire_after(Duration::ZERO).build(),
+                ),
+                index("method_1_key_1", doc! { "method": 1, "key": 1 }, unique()),
+            ],
+        )
+            .await?;
+
+        Ok(())
     }
 
-    pub async fn add_user(
+    /// Run `migration` unless it has been run under `name`, and record it once done.
+    ///
+    /// A migration interrupted halfway is run again on the next startup, so it must be safe to
+    /// resume.
+    async fn migrate_once(
         &self,
-        im: String,
-        im_payload: String,
-        avatar: Option<Url>,
-        name: String,
-    ) -> ApiResult<User> {
-        if self
-            .find_user(&UserQuery::ByIm {
-                im: im.clone(),
-                im_payload: im_payload.clone(),
-            })
-            .await?
-            .is_some()
-        {
-            return Err(ApiError::user_already_exists(&im, &im_payload));
-        };
-
-        let user = User {
-            im,
-            im_payload,
-            avatar,
-            name,
-            event_filter: EventFilter {
-                entities: HashSet::default(),
-                kinds: HashSet::default(),
-            },
-            id: Uuid::default(),
-        };
+        name: &str,
+        migration: impl Future<Output=ApiResult<()>> + Send,
+    ) -> ApiResult<()> {
+        if self.migrations().find_one(doc! { "_id": name }, None).await?.is_some() {
+            return Ok(());
+        }
 
-        self.users().insert_one(&user, None).await?;
-        Ok(user)
+        tracing::info!(migration = name, "Running migration");
+        migration.await?;
+        self.migrations()
+            .update_one(
+                doc! { "_id": name },
+                doc! { "$setOnInsert": { "at": DateTime::now() } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
     }
 
-    /// # Errors
-    /// Fail on database error or user not found
-    pub async fn del_user(&self, query: &UserQuery) -> ApiResult<User> {
-        self.users()
-            .find_one_and_delete(query.as_document(), None)
-            .await?
-            .ok_or_else(|| query.as_error())
+    /// Fill in search keys of entities written before they were introduced.
+    async fn fill_search_keys(&self) -> ApiResult<()> {
+        let missing = doc! { "search": { "$exists": false } };
+        let mut entities = self.entities().find(missing.clone(), None).await?;
+        while let Some(entity) = entities.try_next().await? {
+            // Skip entities written since found, which have their keys set already
+            let mut filter = missing.clone();
+            filter.insert("id", entity.id);
+            self.entities()
+                .update_one(filter, doc! { "$set": { "search": search_keys(&entity.meta) } }, None)
+                .await?;
+        }
+        Ok(())
     }
 
+    /// Run `f` at most once per idempotency `key` of `method`.
+    ///
+    /// The key is reserved before running `f`, so that a concurrent request with the same key is
+    /// rejected instead of running `f` again. Once `f` is done, the stored response is returned for
+    /// the key without running `f`. Only successful responses are stored, so failed requests can
+    /// be retried with the same key.
+    ///
+    /// The reservation is a lease of `idempotency_lease`. If `f` isn't done by then, e.g. the node
+    /// running it crashed, the key is free for a request with it to run `f` again.
+    ///
     /// # Errors
-    /// Fail on database error or user not found
-    pub async fn update_setting(&self, id: &Uuid, event_filter: &EventFilter) -> ApiResult<User> {
-        let serialized = to_document(&event_filter)?;
+    /// Fail on database error, a request with the same key in progress, or whatever `f` fails with
+    pub async fn idempotent<T, F>(&self, method: &str, key: Option<String>, f: F) -> ApiResult<T>
+        where
+            T: Serialize + DeserializeOwned + Send,
+            F: Future<Output=ApiResult<T>> + Send,
+    {
+        let key = match key {
+            Some(key) => key,
+            None => return f.await,
+        };
+        let filter = doc! { "method": method, "key": &key };
 
-        self.users()
-            .find_one_and_update(
-                doc! { "id": id },
-                doc! { "$set": { "event_filter": serialized } },
-                FindOneAndUpdateOptions::builder()
-                    .return_document(ReturnDocument::After)
-                    .build(),
-            )
-            .await?
-            .ok_or_else(|| ApiError::user_not_found_with_id(id))
+        // Reserve the key, unless it's taken by a previous request
+        let now = SystemTime::now();
+        let pending_until = DateTime::from_system_time(now + self.config.idempotency_lease);
+        let exp = DateTime::from_system_time(now + self.config.idempotency_ttl);
+        let pending = IdempotencyRecord {
+            method: method.to_owned(),
+            key: key.clone(),
+            response: None,
+            pending_until: Some(pending_until),
+            exp,
+        };
+        match self.idempotency().insert_one(&pending, None).await {
+            Ok(_) => {}
+            Err(e) if is_duplicate_key(&e) => {
+                // Take over the key if its lease is over. Records without a lease are taken too.
+                let expired = doc! {
+                    "method": method,
+                    "key": &key,
+                    "response": null,
+                    "pending_until": { "$not": { "$gte": DateTime::from_system_time(now) } },
+                };
+                let renew = doc! { "$set": { "pending_until": pending_until, "exp": exp } };
+                let taken = self.idempotency().update_one(expired, renew, None).await?;
+                if taken.matched_count > 0 {
+                    return self.run_idempotent(filter, f).await;
+                }
+
+                return match self.idempotency().find_one(filter, None).await? {
+                    Some(IdempotencyRecord {
+                        response: Some(response),
+                        ..
+                    }) => from_bson(response).map_err(|detail| {
+                        tracing::error!(?detail, "Bson deserialize error");
+                        ApiError::internal()
+                    }),
+                    // Still running, or just failed and released for retries
+                    _ => Err(ApiError::request_in_progress(key)),
+                };
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        self.run_idempotent(filter, f).await
     }
 
-    pub async fn add_entity(&self, meta: Meta, tasks: Vec<AddTaskParam>) -> ApiResult<Entity> {
-        let mut ent = Entity {
-            id: Uuid::new(),
-            meta,
-            tasks: vec![],
+    /// Run `f` holding the idempotency key matching `filter`, and store its response.
+    async fn run_idempotent<T, F>(&self, filter: Document, f: F) -> ApiResult<T>
+        where
+            T: Serialize + Send,
+            F: Future<Output=ApiResult<T>> + Send,
+    {
+        let res = match f.await {
+            Ok(res) => res,
+            Err(e) => {
+                // Release the key, so that the request can be retried
+                self.idempotency().delete_one(filter, None).await?;
+                return Err(e);
+            }
         };
 
-        self.entities().insert_one(&ent, None).await?;
+        self.idempotency()
+            .update_one(
+                filter,
+                doc! {
+                    "$set": { "response": to_bson(&res)? },
+                    "$unset": { "pending_until": "" },
+                },
+                None,
+            )
+            .await?;
 
-        ent.tasks = self
-            .add_tasks(&ent.id, tasks.into_iter())
-            .await?
-            .into_iter()
-            .map(|x| x.id)
-            .collect();
+        Ok(res)
+    }
 
-        Ok(ent)
+    /// Start a session with a transaction in progress.
+    ///
+    /// Pass the session to `*_with_session` operations and call
+    /// [`ClientSession::commit_transaction`] when done. The transaction is aborted if the session is
+    /// dropped before committing, e.g. when returning early on error.
+    ///
+    /// # Errors
+    /// Fail on database error, or the deployment doesn't support transactions
+    pub async fn start_transaction(&self) -> mongodb::error::Result<ClientSession> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        Ok(session)
     }
 
+    /// Run database operations in `f`, retrying them with backoff on transient errors as
+    /// configured. See [`retry_transient`].
+    ///
     /// # Errors
-    /// Fail on database error or entity not found
-    pub async fn find_entity(&self, id: &Uuid) -> ApiResult<Entity> {
-        self.entities()
-            .find_one(doc! { "id": id }, None)
-            .await?
-            .ok_or_else(|| ApiError::entity_not_found(id))
+    /// Fail on non-transient database error, or transient ones persisting after all retries
+    pub async fn retry<T, F, Fut>(&self, f: F) -> ApiResult<T>
+    where
+        T: Send,
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = mongodb::error::Result<T>> + Send,
+    {
+        Ok(retry_transient(self.config.db_retries, self.config.db_retry_backoff, f).await?)
     }
 
+    /// Ping the database, give up if it doesn't respond in 2 seconds.
+    ///
     /// # Errors
-    /// Fail on database error, entity not found or failed to serialize meta
-    pub async fn update_entity(&self, id: &Uuid, meta: &Meta) -> ApiResult<Entity> {
-        self.entities()
-            .find_one_and_update(
-                doc! { "id": id },
-                doc! { "meta": to_document(meta)? },
-                FindOneAndUpdateOptions::builder()
-                    .return_document(ReturnDocument::After)
-                    .build(),
-            )
-            .await?
-            .ok_or_else(|| ApiError::entity_not_found(id))
+    /// Fail if the database is unreachable or timed out
+    pub async fn ping(&self) -> ApiResult<()> {
+        match tokio::time::timeout(
+            Duration::from_secs(2),
+            self.db.run_command(doc! { "ping": 1 }, None),
+        )
+            .await
+        {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(detail)) => {
+                tracing::error!(?detail, "Failed to ping database");
+                Err(ApiError::database_unavailable())
+            }
+            Err(_) => {
+                tracing::error!("Timed out pinging database");
+                Err(ApiError::database_unavailable())
+            }
+        }
     }
 
-    pub async fn del_entity(&self, id: &Uuid) -> ApiResult<Entity> {
-        // Get the entity, make sure it exists and get all related tasks
-        let entity = self
-            .entities()
-            .find_one_and_delete(doc! { "id": id }, None)
-            .await?
-            .ok_or_else(|| ApiError::entity_not_found(&id))?;
+    /// Record whether event consumers are connected to the message queue, see
+    /// [`detailed_health`](Self::detailed_health).
+    pub fn set_mq_connected(&self, connected: bool) {
+        self.mq_connected.store(connected, Ordering::SeqCst);
+    }
 
-        // Delete all related tasks
-        self.tasks()
-            .delete_many(doc! { "id": { "$in": &entity.tasks } }, None)
-            .await?;
+    /// Check whether this node can do useful work.
+    pub async fn detailed_health(&self) -> HealthReport {
+        let database = self.ping().await.is_ok();
+        let workers = if database {
+            // Logged by the conversion into `ApiError`
+            self.live_workers().await.unwrap_or_default()
+        } else {
+            0
+        };
+        let message_queue = (self.config.events_enabled
+            || self.config.event_log_enabled
+            || self.config.delivery_enabled)
+            .then(|| self.mq_connected.load(Ordering::SeqCst));
+        // A node can serve requests without workers, which come and go with the coordinator
+        let workers_ready = !self.config.ready_requires_workers || workers > 0;
 
-        Ok(entity)
+        HealthReport {
+            ready: database && workers_ready && message_queue != Some(false),
+            database,
+            workers,
+            coordinator: false,
+            message_queue,
+        }
     }
 
-    pub async fn get_entities(&self) -> ApiResult<Entities> {
-        let (vtbs, groups) = try_join(
-            async { self.entities().find(None, None).await?.try_collect().await },
-            async { self.groups().find(None, None).await?.try_collect().await },
-        )
+    /// Count workers the coordinator heard from within `worker_heartbeat_timeout`, according to
+    /// the state it persists.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn live_workers(&self) -> ApiResult<u64> {
+        /// Part of the state of a worker group, written by the coordinator.
+        #[derive(Deserialize)]
+        struct GroupState {
+            workers: Vec<WorkerState>,
+        }
+        #[derive(Deserialize)]
+        struct WorkerState {
+            last_heartbeat: DateTime,
+        }
+
+        let since =
+            DateTime::from_system_time(SystemTime::now() - self.config.worker_heartbeat_timeout);
+        let options = FindOptions::builder()
+            .projection(doc! { "workers.last_heartbeat": 1 })
+            .build();
+        let states: Vec<GroupState> = self
+            .db
+            .collection::<GroupState>(&self.config.coordinator_state_collection)
+            .find(None, options)
+            .await?
+            .try_collect()
             .await?;
 
-        Ok(Entities { vtbs, groups })
+        Ok(states
+            .iter()
+            .flat_map(|state| &state.workers)
+            .filter(|worker| worker.last_heartbeat >= since)
+            .count() as u64)
     }
 
+    /// Get all bots sorted by name.
+    ///
     /// # Errors
-    /// Fail on database error or task not found
-    pub async fn add_task(&self, entity_id: &Uuid, task: Task) -> ApiResult<Task> {
-        if self
-            .entities()
-            .update_one(
-                doc! { "id": entity_id },
-                doc! { "$push": { "tasks": task.id } },
-                None,
-            )
+    /// Fail on database error
+    pub async fn get_bots(&self) -> ApiResult<Vec<Bot>> {
+        Ok(self
+            .bots()
+            .find(None, FindOptions::builder().sort(doc! { "name": 1 }).build())
             .await?
-            .modified_count
-            == 0
-        {
-            Err(ApiError::entity_not_found(entity_id))
-        } else {
-            self.tasks().insert_one(&task, None).await?;
-            Ok(task)
-        }
+            .try_collect()
+            .await?)
     }
 
+    /// Create a new bot and issue a bot token for it.
+    ///
     /// # Errors
-    /// Fail on database error
-    pub async fn add_tasks(
-        &self,
-        entity_id: &Uuid,
-        tasks: impl Iterator<Item=AddTaskParam> + Send,
-    ) -> ApiResult<Vec<Task>> {
-        let tasks = tasks
-            .map(|x| x.into_task_with(*entity_id))
-            .collect::<Vec<_>>();
+    /// Fail on database error or failed to encode the token
+    pub async fn new_bot(&self, name: String, created_by: Uuid) -> ApiResult<BotInfo> {
+        let bot = Bot {
+            id: Uuid::new(),
+            name,
+            privilege: Privilege::Bot,
+            created_by,
+        };
+        let (token, claims) = self.encode_bot(&bot.id)?;
 
-        self.tasks().insert_many(&tasks, None).await?;
-        Ok(tasks)
+        self.bots().insert_one(&bot, None).await?;
+
+        Ok(BotInfo {
+            bot,
+            token,
+            valid_until: claims.valid_until(),
+        })
     }
 
     /// # Errors
-    /// Fail on database error or task not found
-    pub async fn del_task(&self, task_id: &Uuid) -> ApiResult<Task> {
+    /// Fail on database error or user not found
+    pub async fn find_user(&self, query: &UserQuery) -> ApiResult<Option<User>> {
+        self.users()
+            .find_one(query.as_document(), None)
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Create a token with `User` privilege for the user of each query. Users are looked up all
+    /// at once, and those not found are reported in `errors` by the index of their query.
+    ///
+    /// # Errors
+    /// Fail on database error, or if there are more than [`MAX_NEW_TOKENS`] queries
+    pub async fn new_tokens(&self, queries: &[UserQuery]) -> ApiResult<NewTokensResult> {
+        if queries.len() > MAX_NEW_TOKENS {
+            return Err(ApiError::bad_request(format!(
+                "At most {MAX_NEW_TOKENS} tokens can be created at once, got {}",
+                queries.len()
+            )));
+        }
+
+        // `$or` must not be empty
+        let users: Vec<User> = if queries.is_empty() {
+            vec![]
+        } else {
+            let filter: Vec<_> = queries.iter().map(UserQuery::as_document).collect();
+            self.users()
+                .find(doc! { "$or": filter }, None)
+                .await?
+                .try_collect()
+                .await?
+        };
+        let by_id: HashMap<_, _> = users.iter().map(|user| (user.id, user)).collect();
+        let by_im: HashMap<_, _> = users
+            .iter()
+            .map(|user| ((user.im.as_str(), user.im_payload.as_str()), user))
+            .collect();
+
+        let mut tokens = Vec::with_capacity(users.len());
+        let mut errors = vec![];
+        for (index, query) in queries.iter().enumerate() {
+            let user = match query {
+                UserQuery::ById { user_id } => by_id.get(user_id),
+                UserQuery::ByIm { im, im_payload } => {
+                    by_im.get(&(im.as_str(), im_payload.as_str()))
+                }
+            };
+            let minted = user
+                .ok_or_else(|| query.as_error())
+                .and_then(|user| Ok((user.id, self.encode(&user.id, Privilege::User)?)));
+            match minted {
+                Ok((id, (token, claims))) => tokens.push((
+                    id,
+                    Token {
+                        token,
+                        valid_until: claims.valid_until(),
+                    },
+                )),
+                Err(error) => errors.push(BatchError { index, error }),
+            }
+        }
+
+        Ok(NewTokensResult { tokens, errors })
+    }
+
+    /// Add a user, or return the existing one with the same `im` and `im_payload` if
+    /// `return_existing` is set.
+    ///
+    /// # Errors
+    /// Fail on database error, or if the user exists and `return_existing` is not set
+    pub async fn add_user(
+        &self,
+        im: String,
+        im_payload: String,
+        avatar: Option<Url>,
+        name: String,
+        return_existing: bool,
+    ) -> ApiResult<User> {
+        let existing = |user: User| {
+            if return_existing {
+                Ok(user)
+            } else {
+                Err(ApiError::user_already_exists(&user.im, &user.im_payload, &user.id))
+            }
+        };
+
+        let query = UserQuery::ByIm {
+            im: im.clone(),
+            im_payload: im_payload.clone(),
+        };
+        if let Some(user) = self.find_user(&query).await? {
+            return existing(user);
+        };
+
+        let user = User {
+            im,
+            im_payload,
+            avatar,
+            name,
+            event_filter: EventFilter {
+                entities: HashSet::default(),
+                groups: HashSet::default(),
+                kinds: HashSet::default(),
+            },
+            id: Uuid::default(),
+            muted_until: None,
+            preferred_language: LanguageCode::En,
+        };
+
+        match self.users().insert_one(&user, None).await {
+            Ok(_) => Ok(user),
+            // Added concurrently since checked
+            Err(e) if is_duplicate_key(&e) => existing(self.conflicting_user(e, &query).await?),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Register a webhook delivered to `target`, or update the one of `id`.
+    ///
+    /// # Errors
+    /// Fail on database error, invalid target, or webhook not found
+    pub async fn set_webhook(
+        &self,
+        id: Option<Uuid>,
+        name: String,
+        target: &WebhookTarget,
+    ) -> ApiResult<User> {
+        target.validate().map_err(invalid_webhook)?;
+
+        // Another webhook with the same target is a conflict, as any other user.
+        let query = UserQuery::ByIm {
+            im: webhook::PLATFORM.to_owned(),
+            im_payload: target.to_payload(),
+        };
+        let conflict = |user: User| {
+            ApiError::user_already_exists(&user.im, &user.im_payload, &user.id)
+        };
+
+        if let Some(id) = id {
+            return match self
+                .users()
+                .find_one_and_update(
+                    doc! { "id": id, "im": webhook::PLATFORM },
+                    doc! { "$set": { "name": name, "im_payload": target.to_payload() } },
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                )
+                .await
+            {
+                Ok(user) => user.ok_or_else(|| ApiError::user_not_found_with_id(&id)),
+                Err(e) if is_duplicate_key(&e) => {
+                    Err(conflict(self.conflicting_user(e, &query).await?))
+                }
+                Err(e) => Err(e.into()),
+            };
+        }
+
+        let user = User {
+            id: Uuid::new(),
+            im: webhook::PLATFORM.to_owned(),
+            im_payload: target.to_payload(),
+            name,
+            avatar: None,
+            event_filter: EventFilter {
+                entities: HashSet::default(),
+                groups: HashSet::default(),
+                kinds: HashSet::default(),
+            },
+            muted_until: None,
+            preferred_language: LanguageCode::En,
+        };
+        match self.users().insert_one(&user, None).await {
+            Ok(_) => Ok(user),
+            Err(e) if is_duplicate_key(&e) => {
+                Err(conflict(self.conflicting_user(e, &query).await?))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The user matching `query`, after `error` tells that it violates the unique index of `im` and
+    /// `im_payload`.
+    ///
+    /// # Errors
+    /// Fail with `error` if the user is deleted in between, or on database error
+    async fn conflicting_user(&self, error: MongoError, query: &UserQuery) -> ApiResult<User> {
+        self.find_user(query).await?.ok_or_else(|| error.into())
+    }
+
+    /// # Errors
+    /// Fail on database error or user not found
+    pub async fn del_user(&self, query: &UserQuery) -> ApiResult<User> {
+        self.users()
+            .find_one_and_delete(query.as_document(), None)
+            .await?
+            .ok_or_else(|| query.as_error())
+    }
+
+    /// Delete all users matching `query`, or everyone if `query` is `None` and `confirm_all` is set.
+    ///
+    /// # Errors
+    /// Fail on database error or no query without `confirm_all`
+    pub async fn del_users(
+        &self,
+        query: Option<&BulkUserQuery>,
+        confirm_all: bool,
+    ) -> ApiResult<u64> {
+        let filter = match query {
+            Some(query) => query.as_document(),
+            None if confirm_all => doc! {},
+            None => {
+                return Err(ApiError::bad_request(
+                    "Refuse to delete all users without `confirm_all`",
+                ))
+            }
+        };
+
+        Ok(self.users().delete_many(filter, None).await?.deleted_count)
+    }
+
+    /// # Errors
+    /// Fail on database error or user not found
+    pub async fn update_setting(&self, id: &Uuid, event_filter: &EventFilter) -> ApiResult<User> {
+        let serialized = &to_document(&event_filter)?;
+
+        self.retry(move || async move {
+            self.users()
+                .find_one_and_update(
+                    doc! { "id": id },
+                    doc! { "$set": { "event_filter": serialized } },
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                )
+                .await
+        })
+        .await?
+        .ok_or_else(|| ApiError::user_not_found_with_id(id))
+    }
+
+    /// Mute events to the user until `until`, or unmute if `None`.
+    ///
+    /// # Errors
+    /// Fail on database error or user not found
+    pub async fn set_mute(&self, id: &Uuid, until: Option<SystemTime>) -> ApiResult<User> {
+        let update = match until {
+            Some(until) => doc! { "$set": { "muted_until": DateTime::from_system_time(until) } },
+            None => doc! { "$unset": { "muted_until": "" } },
+        };
+
+        self.users()
+            .find_one_and_update(
+                doc! { "id": id },
+                update,
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(|| ApiError::user_not_found_with_id(id))
+    }
+
+    /// Set the language events are delivered to the user in.
+    ///
+    /// # Errors
+    /// Fail on database error or user not found
+    pub async fn set_language(&self, id: &Uuid, lang: LanguageCode) -> ApiResult<User> {
+        self.users()
+            .find_one_and_update(
+                doc! { "id": id },
+                doc! { "$set": { "preferred_language": to_bson(&lang)? } },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(|| ApiError::user_not_found_with_id(id))
+    }
+
+    /// Add `values` to the set `field` of the user's event filter, or remove them if `subscribe` is
+    /// `false`. Other entries are left intact, so concurrent changes don't clobber each other.
+    ///
+    /// # Errors
+    /// Fail on database error or user not found
+    pub async fn update_subscription(
+        &self,
+        id: &Uuid,
+        field: &str,
+        values: impl Into<Bson> + Send,
+        subscribe: bool,
+    ) -> ApiResult<User> {
+        let path = format!("event_filter.{field}");
+        let update = if subscribe {
+            doc! { "$addToSet": { path: { "$each": values.into() } } }
+        } else {
+            doc! { "$pull": { path: { "$in": values.into() } } }
+        };
+
+        self.users()
+            .find_one_and_update(
+                doc! { "id": id },
+                update,
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(|| ApiError::user_not_found_with_id(id))
+    }
+
+    /// Add an entity along with its tasks.
+    ///
+    /// With `external_id`, the entity id is derived from it. If there's already an entity with the
+    /// same `external_id`, its meta is replaced if `upsert` is set, and `tasks` are ignored.
+    ///
+    /// # Errors
+    /// Fail on database error or invalid meta, or if an entity with `external_id` exists and
+    /// `upsert` is not set
+    pub async fn add_entity(
+        &self,
+        meta: Meta,
+        tasks: Vec<AddTaskParam>,
+        external_id: Option<String>,
+        upsert: bool,
+    ) -> ApiResult<Entity> {
+        let now = DateTime::now();
+        self.check_group(&meta).await?;
+        let mut builder = Entity::builder().meta(meta.clone());
+        if let Some(external_id) = &external_id {
+            builder = builder.external_id(external_id);
+        }
+        let mut ent = builder.build().map_err(invalid_meta)?;
+
+        if let Some(external_id) = &external_id {
+            if self.entities().find_one(doc! { "id": ent.id }, None).await?.is_some() {
+                return self.add_existing_entity(&ent.id, external_id, &meta, upsert).await;
+            }
+        }
+
+        let tasks = tasks
+            .into_iter()
+            .map(|x| created(x.into_task_with(ent.id), now))
+            .collect::<Vec<_>>();
+        self.validate_tasks(&tasks)?;
+        ent.tasks = tasks.iter().map(|x| x.id).collect();
+        ent.created_at = Some(now);
+        ent.updated_at = Some(now);
+
+        let mut ent_doc = to_document(&ent)?;
+        ent_doc.insert("search", search_keys(&ent.meta));
+
+        // Insert the entity and its tasks atomically
+        let (ent_doc, tasks) = (&ent_doc, &tasks);
+        let inserted = retry_transient(self.config.db_retries, self.config.db_retry_backoff, move || {
+            async move {
+                let mut session = self.start_transaction().await?;
+                self.entities()
+                    .clone_with_type::<Document>()
+                    .insert_one_with_session(ent_doc, None, &mut session)
+                    .await?;
+                if !tasks.is_empty() {
+                    self.tasks()
+                        .insert_many_with_session(tasks, None, &mut session)
+                        .await?;
+                }
+                session.commit_transaction().await
+            }
+        })
+        .await;
+
+        match (inserted, &external_id) {
+            (Ok(()), _) => Ok(ent),
+            // Imported concurrently since checked
+            (Err(e), Some(external_id)) if is_duplicate_key(&e) => {
+                self.add_existing_entity(&ent.id, external_id, &meta, upsert).await
+            }
+            (Err(e), _) => Err(e.into()),
+        }
+    }
+
+    /// Replace the meta of entity `id` added before with `external_id` if `upsert` is set, or fail.
+    async fn add_existing_entity(
+        &self,
+        id: &Uuid,
+        external_id: &str,
+        meta: &Meta,
+        upsert: bool,
+    ) -> ApiResult<Entity> {
+        if upsert {
+            self.update_entity(id, meta).await
+        } else {
+            Err(ApiError::entity_already_exists(external_id, id))
+        }
+    }
+
+    /// Soft-deleted entities are not found.
+    ///
+    /// # Errors
+    /// Fail on database error or entity not found
+    pub async fn find_entity(&self, id: &Uuid) -> ApiResult<Entity> {
+        self.entities()
+            .find_one(doc! { "id": id, "deleted_at": null }, None)
+            .await?
+            .ok_or_else(|| ApiError::entity_not_found(id))
+    }
+
+    /// Soft-deleted entities are not found.
+    ///
+    /// # Errors
+    /// Fail on database error, entity not found, invalid meta or failed to serialize meta
+    pub async fn update_entity(&self, id: &Uuid, meta: &Meta) -> ApiResult<Entity> {
+        validate_meta(meta)?;
+        self.check_group(meta).await?;
+
+        self.entities()
+            .find_one_and_update(
+                doc! { "id": id, "deleted_at": null },
+                doc! {
+                    "$set": {
+                        "meta": to_document(meta)?,
+                        "search": search_keys(meta),
+                        "updated_at": DateTime::now(),
+                    }
+                },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(|| ApiError::entity_not_found(id))
+    }
+
+    /// Update only the parts of the entity's meta given in `patch`. Soft-deleted entities are not
+    /// found.
+    ///
+    /// # Errors
+    /// Fail on database error, entity not found, conflicting patch or the patched meta is invalid
+    pub async fn patch_entity_meta(&self, patch: &PatchEntityMeta) -> ApiResult<Entity> {
+        let id = &patch.entity_id;
+        let mut update = match patch.as_update()? {
+            Some(update) => update,
+            None => return self.find_entity(id).await,
+        };
+        touch(&mut update, DateTime::now());
+
+        // Make sure the result is still valid before touching the database
+        let mut meta = self.find_entity(id).await?.meta;
+        patch.apply(&mut meta);
+        validate_meta(&meta)?;
+        self.check_group(&meta).await?;
+        update
+            .get_document_mut("$set")
+            .expect("`$set` must be a document")
+            .insert("search", search_keys(&meta));
+
+        self.entities()
+            .find_one_and_update(
+                doc! { "id": id, "deleted_at": null },
+                update,
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(|| ApiError::entity_not_found(id))
+    }
+
+    /// Soft-delete the entity and its tasks by setting `deleted_at`, so that they can be restored.
+    ///
+    /// # Errors
+    /// Fail on database error, or entity not found or already deleted
+    pub async fn del_entity(&self, id: &Uuid) -> ApiResult<Entity> {
+        let now = DateTime::now();
+
+        self.retry(move || async move {
+            let mut session = self.start_transaction().await?;
+
+            let entity = self
+                .entities()
+                .find_one_and_update_with_session(
+                    doc! { "id": id, "deleted_at": null },
+                    doc! { "$set": { "deleted_at": now } },
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                    &mut session,
+                )
+                .await?;
+            let Some(entity) = entity else { return Ok(None) };
+
+            // Tombstone tasks as well so that they are no longer scheduled
+            self.tasks()
+                .update_many_with_session(
+                    doc! { "id": { "$in": &entity.tasks } },
+                    doc! { "$set": { "deleted_at": now } },
+                    None,
+                    &mut session,
+                )
+                .await?;
+
+            session.commit_transaction().await?;
+            Ok(Some(entity))
+        })
+        .await?
+        .ok_or_else(|| ApiError::entity_not_found(id))
+    }
+
+    /// Restore a soft-deleted entity and its tasks.
+    ///
+    /// # Errors
+    /// Fail on database error, entity not found or not deleted, or merged into another entity
+    pub async fn restore_entity(&self, id: &Uuid) -> ApiResult<Entity> {
+        self.retry(move || async move {
+            let mut session = self.start_transaction().await?;
+
+            // Its tasks and subscribers are moved to the entity it's merged into
+            let alias = self
+                .entity_aliases()
+                .find_one_with_session(doc! { "from": id }, None, &mut session)
+                .await?;
+            if let Some(alias) = alias {
+                return Ok(Err(ApiError::bad_request(format!(
+                    "Entity {id} is merged into {}, and can't be restored",
+                    alias.into
+                ))));
+            }
+
+            let entity = self
+                .entities()
+                .find_one_and_update_with_session(
+                    doc! { "id": id, "deleted_at": { "$ne": null } },
+                    doc! { "$unset": { "deleted_at": "" } },
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                    &mut session,
+                )
+                .await?;
+            let Some(entity) = entity else { return Ok(Err(ApiError::entity_not_found(id))) };
+
+            self.tasks()
+                .update_many_with_session(
+                    doc! { "id": { "$in": &entity.tasks } },
+                    doc! { "$unset": { "deleted_at": "" } },
+                    None,
+                    &mut session,
+                )
+                .await?;
+
+            session.commit_transaction().await?;
+            Ok(Ok(entity))
+        })
+        .await?
+    }
+
+    /// Merge entity `from` into `into`, both alive.
+    ///
+    /// Tasks of `from` are moved to `into`, subscribers of `from` are subscribed to `into` instead,
+    /// and `from` is soft-deleted with an alias redirecting it to `into`. Aliases to `from` are
+    /// redirected to `into` as well, so that they never chain.
+    ///
+    /// # Errors
+    /// Fail on database error, either entity not found or deleted, or merging an entity into itself
+    pub async fn merge_entities(&self, from: &Uuid, into: &Uuid) -> ApiResult<Entity> {
+        if from == into {
+            return Err(ApiError::bad_request("Can't merge an entity into itself"));
+        }
+        let now = DateTime::now();
+
+        self.retry(move || async move {
+            let mut session = self.start_transaction().await?;
+
+            let alive = |id: &Uuid| doc! { "id": id, "deleted_at": null };
+            if self
+                .entities()
+                .find_one_with_session(alive(into), None, &mut session)
+                .await?
+                .is_none()
+            {
+                return Ok(Err(ApiError::entity_not_found(into)));
+            }
+            let merged = self
+                .entities()
+                .find_one_and_update_with_session(
+                    alive(from),
+                    doc! { "$set": { "tasks": [], "deleted_at": now, "updated_at": now } },
+                    None,
+                    &mut session,
+                )
+                .await?;
+            let Some(merged) = merged else { return Ok(Err(ApiError::entity_not_found(from))) };
+
+            self.tasks()
+                .update_many_with_session(
+                    doc! { "id": { "$in": &merged.tasks } },
+                    doc! { "$set": { "entity": into, "updated_at": now } },
+                    None,
+                    &mut session,
+                )
+                .await?;
+            let entity = self
+                .entities()
+                .find_one_and_update_with_session(
+                    doc! { "id": into },
+                    doc! {
+                        "$addToSet": { "tasks": { "$each": &merged.tasks } },
+                        "$set": { "updated_at": now },
+                    },
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                    &mut session,
+                )
+                .await?;
+            // Checked in the transaction already
+            let Some(entity) = entity else { return Ok(Err(ApiError::entity_not_found(into))) };
+
+            self.entity_aliases()
+                .update_many_with_session(
+                    doc! { "into": from },
+                    doc! { "$set": { "into": into } },
+                    None,
+                    &mut session,
+                )
+                .await?;
+            let alias = EntityAlias {
+                from: *from,
+                into: *into,
+                at: now,
+            };
+            self.entity_aliases()
+                .insert_one_with_session(&alias, None, &mut session)
+                .await?;
+
+            // Can't add and remove from the same array in one update
+            self.users()
+                .update_many_with_session(
+                    doc! { "event_filter.entities": from },
+                    doc! { "$addToSet": { "event_filter.entities": into } },
+                    None,
+                    &mut session,
+                )
+                .await?;
+            self.users()
+                .update_many_with_session(
+                    doc! { "event_filter.entities": from },
+                    doc! { "$pull": { "event_filter.entities": from } },
+                    None,
+                    &mut session,
+                )
+                .await?;
+
+            session.commit_transaction().await?;
+            Ok(Ok(entity))
+        })
+        .await?
+    }
+
+    /// Request the coordinator to move tasks of entity `id` to `target`, or to the next-best
+    /// worker other than the current one if `None`. A pending request is replaced.
+    ///
+    /// # Errors
+    /// Fail on database error, or entity not found or deleted
+    pub async fn reassign_entity(&self, id: &Uuid, target: Option<Uuid>) -> ApiResult<Entity> {
+        let reassignment = Reassignment {
+            target,
+            at: DateTime::now(),
+        };
+        self.entities()
+            .find_one_and_update(
+                doc! { "id": id, "deleted_at": null },
+                doc! { "$set": { "reassignment": to_document(&reassignment)? } },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(|| ApiError::entity_not_found(id))
+    }
+
+    /// ID of the entity `id` is merged into, or `id` itself if it's not merged.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn resolve_entity(&self, id: Uuid) -> ApiResult<Uuid> {
+        Ok(self
+            .entity_aliases()
+            .find_one(doc! { "from": id }, None)
+            .await?
+            .map_or(id, |alias| alias.into))
+    }
+
+    /// Remove the entity and its tasks permanently, whether soft-deleted or not.
+    ///
+    /// # Errors
+    /// Fail on database error or entity not found
+    pub async fn purge_entity(&self, id: &Uuid) -> ApiResult<Entity> {
+        // Both deletions are committed atomically, or none of them if anything fails
+        self.retry(move || async move {
+            let mut session = self.start_transaction().await?;
+
+            // Get the entity, make sure it exists and get all related tasks
+            let entity = self
+                .entities()
+                .find_one_and_delete_with_session(doc! { "id": id }, None, &mut session)
+                .await?;
+            let Some(entity) = entity else { return Ok(None) };
+
+            // Delete all related tasks
+            self.tasks()
+                .delete_many_with_session(
+                    doc! { "id": { "$in": &entity.tasks } },
+                    None,
+                    &mut session,
+                )
+                .await?;
+
+            session.commit_transaction().await?;
+            Ok(Some(entity))
+        })
+        .await?
+        .ok_or_else(|| ApiError::entity_not_found(id))
+    }
+
+    /// # Errors
+    /// Fail on database error or invalid name
+    pub async fn add_group(&self, name: Name) -> ApiResult<Group> {
+        name.validate().map_err(invalid_name)?;
+        let group = Group {
+            id: Uuid::new(),
+            name,
+        };
+        self.groups().insert_one(&group, None).await?;
+        Ok(group)
+    }
+
+    /// # Errors
+    /// Fail on database error, group not found or invalid name
+    pub async fn update_group(&self, id: &Uuid, name: &Name) -> ApiResult<Group> {
+        name.validate().map_err(invalid_name)?;
+
+        self.groups()
+            .find_one_and_update(
+                doc! { "id": id },
+                doc! { "$set": { "name": to_document(name)? } },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(|| ApiError::group_not_found(id))
+    }
+
+    /// Remove the group, and detach entities in it, soft-deleted ones included.
+    ///
+    /// # Errors
+    /// Fail on database error or group not found
+    pub async fn del_group(&self, id: &Uuid) -> ApiResult<Group> {
+        self.retry(move || async move {
+            let mut session = self.start_transaction().await?;
+
+            let group = self
+                .groups()
+                .find_one_and_delete_with_session(doc! { "id": id }, None, &mut session)
+                .await?;
+            let Some(group) = group else { return Ok(None) };
+            self.entities()
+                .update_many_with_session(
+                    doc! { "meta.group": id },
+                    doc! { "$set": { "meta.group": null, "updated_at": DateTime::now() } },
+                    None,
+                    &mut session,
+                )
+                .await?;
+
+            session.commit_transaction().await?;
+            Ok(Some(group))
+        })
+        .await?
+        .ok_or_else(|| ApiError::group_not_found(id))
+    }
+
+    /// Reject meta referencing a group that doesn't exist.
+    async fn check_group(&self, meta: &Meta) -> ApiResult<()> {
+        if let Some(id) = meta.group {
+            if self.groups().find_one(doc! { "id": id }, None).await?.is_none() {
+                return Err(ApiError::group_not_found(&id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Cursor over all entities sorted by id, soft-deleted ones included.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn entities_cursor(&self) -> ApiResult<Cursor<Entity>> {
+        let options = FindOptions::builder().sort(doc! { "id": 1 }).build();
+        Ok(self.entities().find(None, options).await?)
+    }
+
+    /// Insert the entities, or replace those with the same ids, all in one write.
+    ///
+    /// Entities before one with invalid meta are still written, as if imported one by one.
+    ///
+    /// # Errors
+    /// Fail on database error or invalid meta
+    pub async fn import_entities(&self, entities: &[Entity]) -> ApiResult<()> {
+        let mut invalid = Ok(());
+        let mut updates = Vec::with_capacity(entities.len());
+        for entity in entities {
+            if let Err(e) = validate_meta(&entity.meta) {
+                invalid = Err(e);
+                break;
+            }
+            let mut replacement = to_document(entity)?;
+            replacement.insert("search", search_keys(&entity.meta));
+            updates.push(doc! { "q": { "id": entity.id }, "u": replacement, "upsert": true });
+        }
+        if updates.is_empty() {
+            return invalid;
+        }
+
+        // The driver can't replace many documents at once, so the command is run directly
+        let command = doc! { "update": self.entities().name(), "updates": updates };
+        let reply = self.db.run_command(command, None).await?;
+        if let Ok(errors) = reply.get_array("writeErrors") {
+            tracing::error!(?errors, "Failed to import entities");
+            return Err(ApiError::internal());
+        }
+        invalid
+    }
+
+    /// Get entities sorted by id, optionally paged by `limit` and `after`.
+    /// Soft-deleted entities are skipped unless `include_deleted` is set.
+    ///
+    /// Since pages are cut by id instead of offset, entities inserted between
+    /// two requests won't cause the rest of pages to shift.
+    ///
+    /// Groups are left empty if they can't be fetched.
+    ///
+    /// # Errors
+    /// Fail on database error or non-positive `limit`
+    pub async fn get_entities(
+        &self,
+        limit: Option<i64>,
+        after: Option<Uuid>,
+        include_deleted: bool,
+        include_tasks: bool,
+    ) -> ApiResult<Entities> {
+        if matches!(limit, Some(limit) if limit <= 0) {
+            return Err(ApiError::bad_request("`limit` must be positive"));
+        }
+
+        let mut filter = Document::new();
+        if let Some(after) = after {
+            filter.insert("id", doc! { "$gt": after });
+        }
+        if !include_deleted {
+            filter.insert("deleted_at", Bson::Null);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "id": 1 })
+            .limit(limit)
+            .build();
+
+        let (vtbs, groups) = join(
+            async {
+                self.entities()
+                    .find(filter, options)
+                    .await?
+                    .try_collect::<Vec<Entity>>()
+                    .await
+            },
+            async { self.groups().find(None, None).await?.try_collect().await },
+        )
+            .await;
+        let vtbs = vtbs?;
+        // Groups are supplementary, so entities are still returned without them
+        let groups = groups.unwrap_or_else(|detail| {
+            tracing::warn!(?detail, "Failed to get groups, returning entities without them");
+            vec![]
+        });
+
+        let next_cursor = limit
+            .filter(|limit| i64::try_from(vtbs.len()) == Ok(*limit))
+            .and_then(|_| vtbs.last().map(|x| x.id));
+
+        let tasks = if include_tasks {
+            let ids: Vec<_> = vtbs.iter().flat_map(|x| &x.tasks).collect();
+            let options = FindOptions::builder().sort(doc! { "id": 1 }).build();
+            self.tasks()
+                .find(doc! { "id": { "$in": ids } }, options)
+                .await?
+                .try_collect()
+                .await?
+        } else {
+            vec![]
+        };
+
+        Ok(Entities {
+            vtbs,
+            groups,
+            tasks,
+            next_cursor,
+        })
+    }
+
+    /// Get dead letters sorted by id, at most `limit` of them after `after`.
+    ///
+    /// # Errors
+    /// Fail on database error or non-positive `limit`
+    pub async fn get_dead_letters(
+        &self,
+        limit: Option<i64>,
+        after: Option<Uuid>,
+    ) -> ApiResult<DeadLetters> {
+        if matches!(limit, Some(limit) if limit <= 0) {
+            return Err(ApiError::bad_request("`limit` must be positive"));
+        }
+
+        let filter = after.map(|after| doc! { "id": { "$gt": after } });
+        let options = FindOptions::builder()
+            .sort(doc! { "id": 1 })
+            .limit(limit)
+            .build();
+        let letters: Vec<DeadLetter> = self
+            .dead_letters()
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await?;
+
+        let next_cursor = limit
+            .filter(|limit| i64::try_from(letters.len()) == Ok(*limit))
+            .and_then(|_| letters.last().map(|x| x.id));
+
+        Ok(DeadLetters {
+            letters,
+            next_cursor,
+        })
+    }
+
+    /// Get tasks the coordinator leaves unassigned because no worker has the tags they require,
+    /// sorted by id.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn get_unschedulable_tasks(&self) -> ApiResult<Vec<Task>> {
+        /// Part of the state of a worker group, written by the coordinator.
+        #[derive(Deserialize)]
+        struct GroupState {
+            #[serde(default)]
+            unschedulable: Vec<Uuid>,
+        }
+
+        let options = FindOptions::builder()
+            .projection(doc! { "unschedulable": 1 })
+            .build();
+        let ids: Vec<_> = self
+            .db
+            .collection::<GroupState>(&self.config.coordinator_state_collection)
+            .find(None, options)
+            .await?
+            .map_ok(|state| state.unschedulable)
+            .try_concat()
+            .await?;
+
+        let options = FindOptions::builder().sort(doc! { "id": 1 }).build();
+        Ok(self
+            .tasks()
+            .find(doc! { "id": { "$in": ids } }, options)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// Mark dead letters with given ids for replay, and return how many are found.
+    ///
+    /// Marked letters are delivered by the next replay round of
+    /// [`spawn_delivery`](crate::server::spawn_delivery).
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn replay_dead_letters(&self, ids: &[Uuid]) -> ApiResult<u64> {
+        let res = self
+            .dead_letters()
+            .update_many(
+                doc! { "id": { "$in": ids } },
+                doc! { "$set": { "replay": true } },
+                None,
+            )
+            .await?;
+        Ok(res.matched_count)
+    }
+
+    /// Record a successful mutation by the token of this request.
+    ///
+    /// Failures are logged instead of returned, since the mutation is already done.
+    pub async fn audit(&self, action: &str, target: Option<Uuid>) {
+        let claims = match self.claims() {
+            Some(claims) => claims,
+            None => return tracing::error!(action, "Audited method called without token"),
+        };
+        let entry = AuditEntry {
+            id: Uuid::new(),
+            actor: claims.id(),
+            token: claims.jti(),
+            action: action.to_owned(),
+            target,
+            at: DateTime::now(),
+        };
+
+        if let Err(detail) = self.audit_log().insert_one(&entry, None).await {
+            tracing::error!(?detail, ?entry, "Failed to write audit entry");
+        }
+    }
+
+    /// Get audit entries newest first, optionally of `actor` and in `since..until`.
+    ///
+    /// # Errors
+    /// Fail on database error, non-positive limit or unknown `before`
+    pub async fn get_audit_log(
+        &self,
+        actor: Option<Uuid>,
+        since: Option<SystemTime>,
+        until: Option<SystemTime>,
+        limit: Option<i64>,
+        before: Option<Uuid>,
+    ) -> ApiResult<AuditLog> {
+        if matches!(limit, Some(limit) if limit <= 0) {
+            return Err(ApiError::bad_request("`limit` must be positive"));
+        }
+
+        let mut at = Document::new();
+        if let Some(since) = since {
+            at.insert("$gte", DateTime::from_system_time(since));
+        }
+        if let Some(until) = until {
+            at.insert("$lt", DateTime::from_system_time(until));
+        }
+
+        let mut filter = Document::new();
+        if let Some(actor) = actor {
+            filter.insert("actor", actor);
+        }
+        if !at.is_empty() {
+            filter.insert("at", at);
+        }
+        if let Some(before) = before {
+            let cursor = self
+                .audit_log()
+                .find_one(doc! { "id": before }, None)
+                .await?
+                .ok_or_else(|| ApiError::bad_request("Unknown `before`"))?;
+            // Entries are ordered by time, then id for those at the same time
+            filter.insert(
+                "$or",
+                vec![
+                    doc! { "at": { "$lt": cursor.at } },
+                    doc! { "at": cursor.at, "id": { "$lt": cursor.id } },
+                ],
+            );
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "at": -1, "id": -1 })
+            .limit(limit)
+            .build();
+
+        let entries: Vec<AuditEntry> = self
+            .audit_log()
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await?;
+
+        let next_cursor = limit
+            .filter(|limit| i64::try_from(entries.len()) == Ok(*limit))
+            .and_then(|_| entries.last().map(|x| x.id));
+
+        Ok(AuditLog {
+            entries,
+            next_cursor,
+        })
+    }
+
+    /// Record an event in the event log, removed after `event_ttl`.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn log_event(&self, event: Event) -> ApiResult<()> {
+        let record = EventRecord {
+            event,
+            at: DateTime::now(),
+        };
+        self.event_log().insert_one(&record, None).await?;
+        Ok(())
+    }
+
+    /// Get logged events of the entity newest first, optionally only those older than the event
+    /// `before`.
+    ///
+    /// # Errors
+    /// Fail on database error, non-positive limit or unknown `before`
+    pub async fn get_entity_events(
+        &self,
+        entity_id: Uuid,
+        before: Option<Uuid>,
+        limit: Option<i64>,
+    ) -> ApiResult<EntityEvents> {
+        if matches!(limit, Some(limit) if limit <= 0) {
+            return Err(ApiError::bad_request("`limit` must be positive"));
+        }
+
+        // Events of merged entities are listed along with those of the entity they're merged into
+        let entity_id = self.resolve_entity(entity_id).await?;
+        let mut ids = vec![entity_id];
+        let mut aliases = self
+            .entity_aliases()
+            .find(doc! { "into": entity_id }, None)
+            .await?;
+        while let Some(alias) = aliases.try_next().await? {
+            ids.push(alias.from);
+        }
+
+        let mut filter = doc! { "event.entity": { "$in": ids } };
+        if let Some(before) = before {
+            let cursor = self
+                .event_log()
+                .find_one(doc! { "event.id": before }, None)
+                .await?
+                .ok_or_else(|| ApiError::bad_request("Unknown `before`"))?;
+            // Events are ordered by time, then id for those at the same time
+            filter.insert(
+                "$or",
+                vec![
+                    doc! { "at": { "$lt": cursor.at } },
+                    doc! { "at": cursor.at, "event.id": { "$lt": cursor.event.id } },
+                ],
+            );
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "at": -1, "event.id": -1 })
+            .limit(limit)
+            .build();
+
+        let events: Vec<EventRecord> = self
+            .event_log()
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await?;
+
+        let next_cursor = limit
+            .filter(|limit| i64::try_from(events.len()) == Ok(*limit))
+            .and_then(|_| events.last().map(|x| x.event.id));
+
+        Ok(EntityEvents {
+            events,
+            next_cursor,
+        })
+    }
+
+    /// Search entities whose name in `lang`, or any language, contains `query` case-insensitively.
+    ///
+    /// A name contains `query` if one of its suffixes starts with it, so the search is a prefix
+    /// match on the indexed suffixes in `search_keys`.
+    ///
+    /// # Errors
+    /// Fail on database error, empty query or non-positive limit
+    pub async fn search_entities(
+        &self,
+        query: &str,
+        lang: Option<LanguageCode>,
+        limit: Option<i64>,
+    ) -> ApiResult<Vec<Entity>> {
+        if query.is_empty() {
+            return Err(ApiError::bad_request("`query` must not be empty"));
+        }
+        if matches!(limit, Some(limit) if limit <= 0) {
+            return Err(ApiError::bad_request("`limit` must be positive"));
+        }
+
+        // Anchored and case-sensitive to be answered by the index
+        let query = escape_regex(&query.to_lowercase());
+        let mut filter = match lang {
+            Some(lang) => {
+                doc! { "search.lang": { "$regex": format!("^{}:{}", lang.code(), query) } }
+            }
+            None => doc! { "search.any": { "$regex": format!("^{}", query) } },
+        };
+        filter.insert("deleted_at", Bson::Null);
+        let options = FindOptions::builder()
+            .sort(doc! { "id": 1 })
+            .limit(limit.unwrap_or(SEARCH_LIMIT).min(SEARCH_LIMIT))
+            .build();
+
+        Ok(self.entities().find(filter, options).await?.try_collect().await?)
+    }
+
+    /// Count entities that aren't soft-deleted, optionally only in `group`, and per group if
+    /// `per_group` is set.
+    ///
+    /// # Errors
+    /// Fail on database error
+    pub async fn count_entities(
+        &self,
+        group: Option<Uuid>,
+        per_group: bool,
+    ) -> ApiResult<EntityCount> {
+        #[derive(Deserialize)]
+        struct GroupCount {
+            #[serde(rename = "_id")]
+            group: Uuid,
+            count: u64,
+        }
+
+        let mut filter = doc! { "deleted_at": null };
+        if let Some(group) = group {
+            filter.insert("meta.group", group);
+        }
+        let count = self.entities().count_documents(filter.clone(), None).await?;
+
+        let mut groups = HashMap::new();
+        if per_group {
+            if group.is_none() {
+                filter.insert("meta.group", doc! { "$ne": null });
+            }
+            let pipeline = [
+                doc! { "$match": filter },
+                doc! { "$group": { "_id": "$meta.group", "count": { "$sum": 1 } } },
+            ];
+            let mut cursor = self
+                .entities()
+                .aggregate(pipeline, None)
+                .await?
+                .with_type::<GroupCount>();
+            while let Some(GroupCount { group, count }) = cursor.try_next().await? {
+                groups.insert(group.to_string(), count);
+            }
+        }
+
+        Ok(EntityCount { count, groups })
+    }
+
+    /// Reject tasks of unknown kinds, with invalid params or with invalid schedules.
+    fn validate_tasks<'a>(&self, tasks: impl IntoIterator<Item = &'a Task>) -> ApiResult<()> {
+        tasks.into_iter().try_for_each(|task| {
+            self.task_kinds
+                .validate(task)
+                .map_err(|e| ApiError::bad_request(format!("Invalid task: {e:#}")))?;
+            task.schedule.as_ref().map_or(Ok(()), |schedule| {
+                schedule
+                    .validate()
+                    .map_err(|e| ApiError::bad_request(format!("Invalid schedule: {e:#}")))
+            })
+        })
+    }
+
+    /// The entity is linked first, so nothing is inserted if it doesn't exist. If inserting the
+    /// task fails, the link is removed again.
+    ///
+    /// # Errors
+    /// Fail on database error, invalid task, or entity not found or soft-deleted
+    pub async fn add_task(&self, entity_id: &Uuid, task: Task) -> ApiResult<Task> {
+        self.validate_tasks([&task])?;
+        let now = DateTime::now();
+        let task = created(task, now);
+
+        // A matched entity may be left unmodified, so existence is decided by the match
+        if self
+            .entities()
+            .update_one(
+                doc! { "id": entity_id, "deleted_at": null },
+                doc! { "$push": { "tasks": task.id }, "$set": { "updated_at": now } },
+                None,
+            )
+            .await?
+            .matched_count
+            == 0
+        {
+            return Err(ApiError::entity_not_found(entity_id));
+        }
+
+        if let Err(e) = self.tasks().insert_one(&task, None).await {
+            self.entities()
+                .update_one(
+                    doc! { "id": entity_id },
+                    doc! { "$pull": { "tasks": task.id } },
+                    None,
+                )
+                .await?;
+            return Err(e.into());
+        }
+        Ok(task)
+    }
+
+    /// Add multiple tasks to an entity at once. Nothing is added if the entity doesn't exist or
+    /// any task is invalid.
+    ///
+    /// # Errors
+    /// Fail on database error, invalid task, or entity not found or soft-deleted
+    pub async fn add_tasks(
+        &self,
+        entity_id: &Uuid,
+        tasks: Vec<AddTaskParam>,
+    ) -> ApiResult<Vec<Task>> {
+        let now = DateTime::now();
+        let tasks = tasks
+            .into_iter()
+            .map(|x| created(x.into_task_with(*entity_id), now))
+            .collect::<Vec<_>>();
+        self.validate_tasks(&tasks)?;
+        let ids = tasks.iter().map(|x| x.id).collect::<Vec<_>>();
+
+        let (ids, tasks_ref) = (&ids, &tasks);
+        let found = self
+            .retry(move || async move {
+                let mut session = self.start_transaction().await?;
+
+                let matched = self
+                    .entities()
+                    .update_one_with_session(
+                        doc! { "id": entity_id, "deleted_at": null },
+                        doc! {
+                            "$push": { "tasks": { "$each": ids } },
+                            "$set": { "updated_at": now },
+                        },
+                        None,
+                        &mut session,
+                    )
+                    .await?
+                    .matched_count;
+                if matched == 0 {
+                    return Ok(false);
+                }
+
+                if !tasks_ref.is_empty() {
+                    self.tasks()
+                        .insert_many_with_session(tasks_ref, None, &mut session)
+                        .await?;
+                }
+                session.commit_transaction().await?;
+                Ok(true)
+            })
+            .await?;
+        if !found {
+            return Err(ApiError::entity_not_found(entity_id));
+        }
+
+        Ok(tasks)
+    }
+
+    /// # Errors
+    /// Fail on database error or task not found
+    pub async fn del_task(&self, task_id: &Uuid) -> ApiResult<Task> {
         // Make sure this exists
         let task = self
             .tasks()
@@ -338,7 +2115,7 @@ impl Context {
         self.entities()
             .update_one(
                 doc! { "id": task.entity },
-                doc! { "tasks": { "$pull": task_id } },
+                doc! { "$pull": { "tasks": task_id }, "$set": { "updated_at": DateTime::now() } },
                 None,
             )
             .await?;
@@ -346,18 +2123,50 @@ impl Context {
         Ok(task)
     }
 
+    /// Enable or disable the task. Disabled tasks are not scheduled.
+    ///
+    /// # Errors
+    /// Fail on database error or task not found
+    pub async fn set_task_enabled(&self, task_id: &Uuid, enabled: bool) -> ApiResult<Task> {
+        self.tasks()
+            .find_one_and_update(
+                doc! { "id": task_id },
+                doc! { "$set": { "enabled": enabled, "updated_at": DateTime::now() } },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .ok_or_else(|| ApiError::task_not_found(task_id))
+    }
+
     pub async fn get_interest(
         &self,
         entity_id: Uuid,
         kind: &str,
         im: &str,
     ) -> ApiResult<Vec<User>> {
+        // Subscribers of a merged entity are moved to the entity it's merged into
+        let entity_id = self.resolve_entity(entity_id).await?;
+        let group = self
+            .entities()
+            .find_one(doc! { "id": entity_id }, None)
+            .await?
+            .and_then(|entity| entity.meta.group);
+
+        // Users subscribed to either the entity itself or its group
+        let mut subscribed = vec![doc! { "event_filter.entities": entity_id }];
+        if let Some(group) = group {
+            subscribed.push(doc! { "event_filter.groups": group });
+        }
+
         Ok(self
             .users()
             .find(
                 doc! {
-                  "event_filter.entities": entity_id,
-                  "event_filter.kinds": kind,
+                  "$or": subscribed,
+                  // Subscribed to the kind itself or any namespace of it
+                  "event_filter.kinds": { "$in": kind_prefixes(kind).collect::<Vec<_>>() },
                   "im": im,
                 },
                 None,
@@ -368,15 +2177,129 @@ impl Context {
     }
 
     /// # Errors
-    /// Fail on bad token, database error, the uuid is "nil" or user not exist.
+    /// Fail on bad token, database error, the token isn't issued to a subscriber or user not exist.
     ///
-    /// An uuid is "nil" if all bytes are set to 0, which at here represents that
-    /// the token is issued to an admin or bot, who does not represent a subscriber.
+    /// Tokens issued to admins and bots don't represent a subscriber.
     pub async fn find_and_assert_claim(&self) -> ApiResult<Option<User>> {
         let user_id = self.assert_user_claims()?.id();
-        if user_id.bytes() == [0; 16] {
-            return Err(ApiError::unauthorized());
-        }
         self.find_user(&UserQuery::ById { user_id }).await
     }
 }
+
+/// Reject inconsistent meta, e.g. `default_language` without corresponding name.
+fn validate_meta(meta: &Meta) -> ApiResult<()> {
+    meta.validate().map_err(invalid_meta)?;
+    if meta.name.name.values().any(|name| name.chars().count() > MAX_NAME_LEN) {
+        return Err(ApiError::bad_request(format!(
+            "Invalid meta: names are limited to {MAX_NAME_LEN} characters"
+        )));
+    }
+    Ok(())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn invalid_meta(e: color_eyre::Report) -> ApiError {
+    ApiError::bad_request(format!("Invalid meta: {e}"))
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn invalid_name(e: color_eyre::Report) -> ApiError {
+    ApiError::bad_request(format!("Invalid name: {e}"))
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn invalid_webhook(e: color_eyre::Report) -> ApiError {
+    ApiError::bad_request(format!("Invalid webhook: {e}"))
+}
+
+/// Escape characters special in regular expressions, so that `s` is matched literally.
+fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Keys `search_entities` matches names by: every suffix of each lowercased name, under `any` as
+/// is and under `lang` prefixed by the code of its language.
+fn search_keys(meta: &Meta) -> Document {
+    let (mut any, mut lang) = (BTreeSet::new(), BTreeSet::new());
+    for (code, name) in &meta.name.name {
+        let name = name.to_lowercase();
+        for (i, _) in name.char_indices() {
+            lang.insert(format!("{}:{}", code.code(), &name[i..]));
+            any.insert(name[i..].to_owned());
+        }
+    }
+    doc! {
+        "any": any.into_iter().collect::<Vec<_>>(),
+        "lang": lang.into_iter().collect::<Vec<_>>(),
+    }
+}
+
+/// Stamp a task about to be inserted.
+const fn created(mut task: Task, now: DateTime) -> Task {
+    task.created_at = Some(now);
+    task.updated_at = Some(now);
+    task
+}
+
+/// Bump `updated_at` in an update document.
+fn touch(update: &mut Document, now: DateTime) {
+    if !update.contains_key("$set") {
+        update.insert("$set", Document::new());
+    }
+    update
+        .get_document_mut("$set")
+        .expect("`$set` must be a document")
+        .insert("updated_at", now);
+}
+
+/// Whether `error` is caused by a unique index, e.g. on inserting a document twice.
+const fn is_duplicate_key(error: &MongoError) -> bool {
+    const DUPLICATE_KEY: i32 = 11000;
+
+    match &*error.kind {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        ErrorKind::Command(e) => e.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+/// Build a named index so it can be told apart from existing ones.
+fn index(name: &str, keys: Document, mut options: IndexOptions) -> IndexModel {
+    options.name = Some(name.to_owned());
+    IndexModel::builder().keys(keys).options(options).build()
+}
+
+/// Create `indexes` on `collection` unless they're already present.
+async fn ensure_indexes<T>(
+    collection: &Collection<T>,
+    indexes: Vec<IndexModel>,
+) -> ApiResult<()>
+    where
+        T: Send + Sync,
+{
+    // Listing fails if the collection doesn't exist yet, in which case there's no index either
+    let existing = collection.list_index_names().await.unwrap_or_default();
+
+    for index in indexes {
+        let name = index
+            .options
+            .as_ref()
+            .and_then(|x| x.name.clone())
+            .unwrap_or_default();
+        if existing.contains(&name) {
+            tracing::info!(collection = collection.name(), index = %name, "Index already present");
+        } else {
+            collection.create_index(index, None).await?;
+            tracing::info!(collection = collection.name(), index = %name, "Index created");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+//! Delivery of events from the message queue to subscribed users.
+
+use std::time::Duration;
+
+use color_eyre::Result;
+use futures::StreamExt;
+use sg_core::{
+    im::ImRegistry,
+    models::Event,
+    mq::{MessageQueue, RabbitMQ},
+    webhook::WebhookBackend,
+};
+
+use crate::server::Context;
+
+/// Start delivering events from the message queue given in config to their subscribers, if
+/// delivery is enabled.
+///
+/// Events are delivered by the backends of an [`ImRegistry`], to users interested in them as of
+/// [`Context::get_interest`]. Undeliverable events are kept as dead letters, and those marked for
+/// replay are delivered again every `dead_letter_replay_interval`.
+///
+/// # Errors
+/// Fail if the message queue is unreachable, or a backend can't be initialized.
+pub async fn spawn_delivery(ctx: &Context) -> Result<()> {
+    let config = ctx.config();
+    if !config.delivery_enabled {
+        return Ok(());
+    }
+
+    let registry = ImRegistry::new()
+        .register(WebhookBackend::new(config.webhook_timeout)?)
+        .with_retry(config.delivery_retry)
+        .with_limit(config.delivery_limit)
+        .with_dead_letters(ctx.dead_letters());
+
+    let mq = RabbitMQ::new(&config.amqp_url, &config.amqp_exchange).await?;
+    ctx.set_mq_connected(true);
+    tokio::spawn(replay(registry.clone(), config.dead_letter_replay_interval));
+    tokio::spawn(deliver(ctx.clone(), registry, mq));
+    Ok(())
+}
+
+/// Deliver dead letters marked for replay every `interval`.
+async fn replay(registry: ImRegistry, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(error) = registry.replay().await {
+            tracing::error!(?error, "Failed to replay dead letters");
+        }
+    }
+}
+
+/// Deliver events consumed from `mq` until it's exhausted.
+async fn deliver(ctx: Context, registry: ImRegistry, mq: impl MessageQueue) {
+    let mut events = mq.consume(None).await;
+    while let Some(event) = events.next().await {
+        match event {
+            Ok((_, event)) => dispatch(&ctx, &registry, &event).await,
+            Err(error) => tracing::error!(?error, "Failed to receive event"),
+        }
+    }
+    tracing::warn!("Event source closed");
+    ctx.set_mq_connected(false);
+}
+
+/// Deliver `event` to interested users of each platform with a backend.
+async fn dispatch(ctx: &Context, registry: &ImRegistry, event: &Event) {
+    for im in registry.platforms() {
+        // Logged by the conversion into `ApiError`
+        let users = match ctx.get_interest(event.entity, &event.kind, im).await {
+            Ok(users) => users,
+            Err(_) => {
+                tracing::warn!(id = %event.id, im, "Interested users not found, event skipped");
+                continue;
+            }
+        };
+        let dispatched = registry.dispatch(&users, event).await;
+        tracing::info!(
+            id = %event.id, im, delivered = dispatched.delivered, queued = dispatched.queued,
+            "Event dispatched"
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! Persisted history of events, to look into what happened to an entity.
+
+use color_eyre::Result;
+use futures::StreamExt;
+use sg_core::mq::{MessageQueue, RabbitMQ};
+
+use crate::server::Context;
+
+/// Start recording events from the message queue given in config, if the event log is enabled.
+/// See [`Context::log_event`].
+///
+/// # Errors
+/// Fail if the message queue is unreachable.
+pub async fn spawn_event_log(ctx: &Context) -> Result<()> {
+    let config = ctx.config();
+    if !config.event_log_enabled {
+        return Ok(());
+    }
+
+    let mq = RabbitMQ::new(&config.amqp_url, &config.amqp_exchange).await?;
+    ctx.set_mq_connected(true);
+    tokio::spawn(record(ctx.clone(), mq));
+    Ok(())
+}
+
+/// Record events consumed from `mq` until it's exhausted.
+async fn record(ctx: Context, mq: impl MessageQueue) {
+    let mut events = mq.consume(None).await;
+    while let Some(event) = events.next().await {
+        match event {
+            Ok((_, event)) => {
+                let id = event.id;
+                // Logged by the conversion into `ApiError`
+                if ctx.log_event(event).await.is_err() {
+                    tracing::warn!(%id, "Event not recorded");
+                }
+            }
+            Err(error) => tracing::error!(?error, "Failed to receive event"),
+        }
+    }
+    tracing::warn!("Event source closed");
+    ctx.set_mq_connected(false);
+}
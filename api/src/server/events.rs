@@ -0,0 +1,270 @@
+//! Real-time event stream for subscribers over websocket.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query,
+    },
+    response::Response,
+    routing::get,
+    Extension,
+    Router,
+};
+use color_eyre::Result;
+use futures::StreamExt;
+use serde::Deserialize;
+use sg_core::{
+    models::{Event, EventFilter},
+    mq::{MessageQueue, RabbitMQ},
+};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::sleep,
+};
+
+use crate::{
+    model::{Privilege, UserQuery},
+    rpc::ApiError,
+    server::{Context, ResponseExt},
+};
+
+/// Number of events buffered for each slow subscriber before it starts missing events.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Fan-out of events received from the message queue to connected subscribers.
+#[derive(Debug)]
+pub struct EventHub {
+    tx: broadcast::Sender<Arc<Event>>,
+    /// Most recent events, oldest first.
+    history: Mutex<VecDeque<Arc<Event>>>,
+    /// Maximum number of events in `history`.
+    capacity: usize,
+}
+
+impl EventHub {
+    /// Create a hub remembering the last `capacity` events for replay.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            tx,
+            history: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Add the `/events` route to `router` if event streaming is enabled, fed by the message queue
+    /// given in config of `ctx`.
+    ///
+    /// The route is authenticated by the token in query, since browsers can't set headers on
+    /// websocket requests.
+    ///
+    /// # Errors
+    /// Fail if the message queue is unreachable.
+    pub async fn mount(router: Router, ctx: &Context) -> Result<Router> {
+        let config = ctx.config();
+        if !config.events_enabled {
+            return Ok(router);
+        }
+
+        let mq = RabbitMQ::new(&config.amqp_url, &config.amqp_exchange).await?;
+        ctx.set_mq_connected(true);
+        let hub = Arc::new(Self::new(config.event_history));
+        tokio::spawn(hub.clone().feed(mq, ctx.clone()));
+
+        Ok(router
+            .route("/events", get(events))
+            .layer(Extension(hub)))
+    }
+
+    /// Publish events consumed from `mq` until it's exhausted.
+    async fn feed(self: Arc<Self>, mq: impl MessageQueue, ctx: Context) {
+        let mut events = mq.consume(None).await;
+        while let Some(event) = events.next().await {
+            match event {
+                Ok((_, event)) => self.publish(event),
+                Err(error) => tracing::error!(?error, "Failed to receive event"),
+            }
+        }
+        tracing::warn!("Event source closed");
+        ctx.set_mq_connected(false);
+    }
+
+    /// Send `event` to all subscribers and remember it for replay.
+    pub fn publish(&self, event: Event) {
+        let event = Arc::new(event);
+        let mut history = self.history.lock().expect("Event history lock poisoned");
+        if self.capacity > 0 {
+            if history.len() == self.capacity {
+                history.pop_front();
+            }
+            history.push_back(event.clone());
+        }
+        // No one is listening is fine
+        drop(self.tx.send(event));
+    }
+
+    /// Subscribe to new events, along with at most `replay` most recent ones.
+    ///
+    /// Nothing is missed or repeated between the replayed events and the subscription.
+    pub fn subscribe(&self, replay: usize) -> (Vec<Arc<Event>>, broadcast::Receiver<Arc<Event>>) {
+        let history = self.history.lock().expect("Event history lock poisoned");
+        let skip = history.len().saturating_sub(replay);
+        (history.iter().skip(skip).cloned().collect(), self.tx.subscribe())
+    }
+}
+
+/// Query of the event stream route.
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    /// Token of the subscriber.
+    token: String,
+    /// Number of recent events to send on connect.
+    #[serde(default)]
+    replay: usize,
+}
+
+/// Stream events the user is interested in over websocket, until the token expires.
+pub async fn events(
+    ws: WebSocketUpgrade,
+    Query(query): Query<EventsQuery>,
+    Extension(ctx): Extension<Context>,
+    Extension(hub): Extension<Arc<EventHub>>,
+) -> Response {
+    let claims = match ctx.validate_token(&query.token).await {
+        Ok(claims) if claims.privilege() == Privilege::User => claims,
+        Ok(_) => return ApiError::unauthorized().as_response(),
+        Err(e) => return e.as_response(),
+    };
+    let user = match ctx
+        .find_user(&UserQuery::ById {
+            user_id: claims.id(),
+        })
+        .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => return ApiError::user_not_found_with_id(&claims.id()).as_response(),
+        Err(e) => return e.as_response(),
+    };
+
+    ws.on_upgrade(move |socket| {
+        stream(
+            socket,
+            ctx,
+            hub,
+            user.event_filter,
+            claims.valid_until(),
+            query.replay,
+        )
+    })
+}
+
+async fn stream(
+    mut socket: WebSocket,
+    ctx: Context,
+    hub: Arc<EventHub>,
+    filter: EventFilter,
+    valid_until: SystemTime,
+    replay: usize,
+) {
+    let (history, mut rx) = hub.subscribe(replay);
+    let expiry = sleep(
+        valid_until
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+    );
+    tokio::pin!(expiry);
+
+    for event in history {
+        if !send(&mut socket, &ctx, &filter, &event).await {
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            () = &mut expiry => {
+                let frame = CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "Token expired".into(),
+                };
+                drop(socket.send(Message::Close(Some(frame))).await);
+                return;
+            }
+            event = rx.recv() => match event {
+                Ok(event) => {
+                    if !send(&mut socket, &ctx, &filter, &event).await {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(n)) => tracing::warn!(n, "Subscriber lagged, events skipped"),
+                Err(RecvError::Closed) => return,
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                // Pings are answered by the socket itself
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/// Send `event` if it passes `filter`. Return `false` if the socket is closed.
+async fn send(socket: &mut WebSocket, ctx: &Context, filter: &EventFilter, event: &Event) -> bool {
+    if !interested(ctx, filter, event).await {
+        return true;
+    }
+    let text = serde_json::to_string(event).expect("Event must be serializable");
+    socket.send(Message::Text(text)).await.is_ok()
+}
+
+async fn interested(ctx: &Context, filter: &EventFilter, event: &Event) -> bool {
+    if !filter.matches_kind(&event.kind) {
+        return false;
+    }
+    if filter.entities.contains(&event.entity) {
+        return true;
+    }
+    // Only look up the entity if it may match by group
+    if filter.groups.is_empty() {
+        return false;
+    }
+    ctx.find_entity(&event.entity)
+        .await
+        .map_or(false, |entity| filter.matches(event, &entity.meta))
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::Uuid;
+    use serde_json::json;
+    use sg_core::models::Event;
+
+    use crate::server::EventHub;
+
+    #[tokio::test]
+    async fn must_replay() {
+        let hub = EventHub::new(2);
+        let event = |kind| Event::from_serializable(kind, Uuid::new(), json!({})).unwrap();
+
+        hub.publish(event("a"));
+        hub.publish(event("b"));
+        hub.publish(event("c"));
+
+        let (history, mut rx) = hub.subscribe(5);
+        let kinds: Vec<_> = history.iter().map(|e| e.kind.as_str()).collect();
+        assert_eq!(kinds, ["b", "c"]);
+
+        let (history, _) = hub.subscribe(1);
+        assert_eq!(history[0].kind, "c");
+
+        hub.publish(event("d"));
+        assert_eq!(rx.recv().await.unwrap().kind, "d");
+    }
+}
@@ -0,0 +1,158 @@
+//! Bulk export and import of entities as newline-delimited JSON.
+
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::BodyStream,
+    http::header,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures::{StreamExt, TryStreamExt};
+use sg_core::models::Entity;
+
+use crate::{
+    model::Imported,
+    rpc::{ApiError, ApiResult},
+    server::{Context, ResponseExt},
+};
+
+/// Content type of NDJSON bodies.
+const NDJSON: &str = "application/x-ndjson";
+
+/// Stream all entities sorted by id, soft-deleted ones included, one JSON object per line.
+///
+/// Entities are sent as they're read from the database, so the dataset is never buffered.
+pub async fn export_entities(Extension(ctx): Extension<Context>) -> Response {
+    let cursor = match ctx.entities_cursor().await {
+        Ok(cursor) => cursor,
+        Err(e) => return e.as_response(),
+    };
+
+    let lines = cursor.map_ok(|entity| {
+        let mut line = serde_json::to_vec(&entity).expect("Entity must be serializable");
+        line.push(b'\n');
+        Bytes::from(line)
+    });
+    ([(header::CONTENT_TYPE, NDJSON)], StreamBody::new(lines)).into_response()
+}
+
+/// Insert or replace entities by id from an NDJSON body, as produced by [`export_entities`].
+///
+/// Entities are written in batches of [`BATCH_SIZE`] as their lines arrive. Import stops at the
+/// first invalid line, or one longer than `max_body_bytes`, leaving the entities before it written.
+pub async fn import_entities(Extension(ctx): Extension<Context>, body: BodyStream) -> Response {
+    match import(&ctx, body).await {
+        Ok(count) => {
+            ctx.audit("import_entities", None).await;
+            Imported { count }.as_response()
+        }
+        Err(e) => e.as_response(),
+    }
+}
+
+async fn import(ctx: &Context, mut body: BodyStream) -> ApiResult<u64> {
+    // The body as a whole isn't limited, but each line is, like other request bodies.
+    let max_line = ctx.config().max_body_bytes;
+    let mut batch = Batch::new(ctx);
+    let mut buf = Vec::new();
+    // Bytes of `buf` known to have no newline, so they're not searched again
+    let mut searched = 0;
+    let mut line_no = 0;
+
+    loop {
+        match body.next().await {
+            Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                batch.flush().await?;
+                return Err(ApiError::bad_request(format!("Failed to read body: {e}")));
+            }
+            // The last line may not end with a newline
+            None => {
+                batch.push_line(&buf, line_no + 1).await?;
+                batch.flush().await?;
+                return Ok(batch.count);
+            }
+        }
+
+        // Lines are deserialized in place, and the consumed ones are dropped at once. Draining
+        // each line would shift the rest of the chunk every time.
+        let mut start = 0;
+        while let Some(len) = buf[searched..].iter().position(|b| *b == b'\n') {
+            let end = searched + len;
+            line_no += 1;
+            if end - start > max_line {
+                batch.flush().await?;
+                return Err(line_too_long(line_no, max_line));
+            }
+            batch.push_line(&buf[start..end], line_no).await?;
+            start = end + 1;
+            searched = start;
+        }
+        buf.drain(..start);
+        searched = buf.len();
+
+        // Don't buffer an incomplete line past the limit
+        if buf.len() > max_line {
+            batch.flush().await?;
+            return Err(line_too_long(line_no + 1, max_line));
+        }
+    }
+}
+
+fn line_too_long(line_no: usize, limit: usize) -> ApiError {
+    ApiError::payload_too_large(limit)
+        .explain(format!("Line {line_no} exceeds the limit of {limit} bytes"))
+}
+
+/// Maximum number of entities written at once, keeping each write well below the size limit of
+/// MongoDB commands.
+const BATCH_SIZE: usize = 500;
+
+/// Entities parsed but not yet written by an import.
+struct Batch<'a> {
+    ctx: &'a Context,
+    entities: Vec<Entity>,
+    /// Number of entities written so far.
+    count: u64,
+}
+
+impl<'a> Batch<'a> {
+    fn new(ctx: &'a Context) -> Self {
+        Self {
+            ctx,
+            entities: Vec::with_capacity(BATCH_SIZE),
+            count: 0,
+        }
+    }
+
+    /// Add the entity on `line` unless it's blank, and write the batch once it's full. If the
+    /// line is invalid, entities before it are written before failing.
+    async fn push_line(&mut self, line: &[u8], line_no: usize) -> ApiResult<()> {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+
+        match serde_json::from_slice(line) {
+            Ok(entity) => self.entities.push(entity),
+            Err(e) => {
+                self.flush().await?;
+                return Err(ApiError::bad_request(format!("Invalid entity on line {line_no}: {e}")));
+            }
+        }
+        if self.entities.len() >= BATCH_SIZE {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Write the entities in the batch.
+    async fn flush(&mut self) -> ApiResult<()> {
+        if self.entities.is_empty() {
+            return Ok(());
+        }
+        self.ctx.import_entities(&self.entities).await?;
+        self.count += self.entities.len() as u64;
+        self.entities.clear();
+        Ok(())
+    }
+}
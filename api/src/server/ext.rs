@@ -1,16 +1,18 @@
+use std::time::Instant;
+
 use axum::{
     body::{self, Body, Full},
-    extract::{Extension, Json},
-    response::Response as AxumResponse,
+    extract::{rejection::JsonRejection, Extension, Json},
+    response::{IntoResponse, Response as AxumResponse},
     routing::{post, Router},
 };
 use futures::Future;
-use http::{header, HeaderValue};
+use http::{header, HeaderValue, StatusCode};
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
     rpc::{ApiError, ApiResult, Request, Response},
-    server::Context,
+    server::{Audited, Context, RequestId},
 };
 
 /// Marker trait to ensure handlers are in a good shape.
@@ -37,6 +39,15 @@ pub trait RouterExt {
             Fut: Future<Output=ApiResult<Req::Res>> + Send,
             Req: DeserializeOwned + Request + Send + 'static,
             Req::Res: Serialize;
+
+    /// Mount a mutating method, recording an audit entry on success. See [`Context::audit`].
+    #[must_use]
+    fn mount_audited<M, Req, Fut>(self, method: M) -> Self
+        where
+            M: Method<Req, Fut> + Send + Clone + 'static,
+            Fut: Future<Output=ApiResult<Req::Res>> + Send,
+            Req: DeserializeOwned + Request + Send + 'static,
+            Req::Res: Serialize + Audited + Send;
 }
 
 impl RouterExt for Router<Body> {
@@ -47,15 +58,59 @@ impl RouterExt for Router<Body> {
             R: DeserializeOwned + Request + Send + 'static,
             R::Res: Serialize,
     {
-        let handler = move |Json(req): Json<R>, Extension(ctx): Extension<Context>| async {
-            match method.invoke(ctx, req).await {
-                Ok(res) => res.as_response(),
-                Err(e) => e.as_response(),
+        let handler = move |Extension(mut ctx): Extension<Context>,
+                            request_id: Option<Extension<RequestId>>,
+                            body: Result<Json<R>, JsonRejection>| async {
+            let request_id = request_id.map(|Extension(id)| id);
+            let echoed = request_id.as_ref().map(|id| id.0.as_str());
+            let request = match body {
+                Ok(Json(request)) => request,
+                Err(rejection) => return reject(&ctx, rejection).as_response_with_id(echoed),
+            };
+            if let Some(request_id) = request_id.clone() {
+                ctx.set_request_id(request_id);
+            }
+            let metrics = ctx.metrics().cloned();
+            let start = Instant::now();
+
+            let res = method.invoke(ctx, request).await;
+
+            if let Some(metrics) = metrics {
+                metrics.observe(R::METHOD, start.elapsed(), res.as_ref().err());
+            }
+
+            match res {
+                Ok(res) => res.as_response_with_id(echoed),
+                Err(e) => e.as_response_with_id(echoed),
             }
         };
 
         self.route(&("/".to_owned() + R::METHOD), post(handler))
     }
+
+    fn mount_audited<M, R, F>(self, method: M) -> Self
+        where
+            M: Method<R, F> + Send + Clone + 'static,
+            F: Future<Output=ApiResult<R::Res>> + Send,
+            R: DeserializeOwned + Request + Send + 'static,
+            R::Res: Serialize + Audited + Send,
+    {
+        self.mount(move |req: R, ctx: Context| async move {
+            let res = method.invoke(ctx.clone(), req).await?;
+            let target = res.audit_target();
+            ctx.audit(R::METHOD, target).await;
+            Ok(res)
+        })
+    }
+}
+
+/// Turn a malformed request body into an [`ApiError`] with the same status.
+fn reject(ctx: &Context, rejection: JsonRejection) -> ApiError {
+    let explanation = rejection.to_string();
+    match rejection.into_response().status() {
+        StatusCode::PAYLOAD_TOO_LARGE => ApiError::payload_too_large(ctx.config().max_body_bytes),
+        status => ApiError::new(status).explain(explanation),
+    }
 }
 
 impl From<jsonwebtoken::errors::Error> for ApiError {
@@ -98,18 +153,25 @@ impl From<sg_auth::Error> for ApiError {
 }
 
 pub trait ResponseExt: Response + Serialize {
-    fn as_response(&self) -> AxumResponse;
+    fn as_response(&self) -> AxumResponse {
+        self.as_response_with_id(None)
+    }
+
+    /// Same as [`as_response`](Self::as_response), with the correlation id of the request echoed
+    /// back in the envelope.
+    fn as_response_with_id(&self, request_id: Option<&str>) -> AxumResponse;
 }
 
 impl<R: Response + Serialize> ResponseExt for R {
-    fn as_response(&self) -> AxumResponse {
+    fn as_response_with_id(&self, request_id: Option<&str>) -> AxumResponse {
+        let packed = self.packed().with_request_id(request_id.map(ToOwned::to_owned));
         AxumResponse::builder()
             .status(self.status())
             .header(
                 header::CONTENT_TYPE,
                 HeaderValue::from_static("application/json"),
             )
-            .body(body::boxed(Full::from(self.packed().to_json_bytes())))
+            .body(body::boxed(Full::from(packed.to_json_bytes())))
             .expect("Status and header should be statically known and not having any parsing issue")
     }
 }
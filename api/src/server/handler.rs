@@ -1,25 +1,49 @@
 #![allow(clippy::unused_async)]
 
-use std::sync::Arc;
+use std::{sync::Arc, time::SystemTime};
 
-use axum::{extract::Extension, Router};
-use color_eyre::Result;
-use http::Method;
-use mongodb::{bson::Uuid, Database};
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, Extension},
+    middleware,
+    Json,
+    Router,
+    routing::{get, post},
+};
+use color_eyre::{eyre::{bail, WrapErr}, Result};
+use http::{HeaderValue, Method, StatusCode};
+use mongodb::Client;
 use tower_http::{cors, trace};
 
 use sg_auth::{Permission, PermissionSet};
+use sg_core::{
+    models::{Entity, Task},
+    webhook::WebhookTarget,
+};
 
 use crate::{
-    model::{GetInterest, Health, Interest, Login, Null, UserQuery},
+    model::{
+        method_schemas, Bots, DetailedHealth, GetAuditLog, GetBots, GetDeadLetters,
+        GetEntityEvents, GetInterest, GetJwks, GetSchema, Health, HealthReport, Interest, Jwks,
+        Login, login_principal, NewBot, Null, RefreshToken, ReplayDeadLetters, Replayed, Schema, UserQuery,
+    },
     rpc::{
         ApiError,
-        ApiResult, model::{
-            AddEntity, AddTask, AddUser, Authorized, AuthUser, DelEntity, DelTask, DelUser,
-            GetEntities, NewToken, Token, UpdateEntity, UpdateSetting,
+        ApiResult, Request, model::{
+            AddEntity, AddGroup, AddTask, AddTasks, AddUser, Authorized, AuthUser, CountEntities,
+            DelEntity, DelGroup, DelTask, DelUser, DelUsers, Deleted, DisableTask, EnableTask,
+            EntityCount, EntityMatches, GetEntities, GetUnschedulableTasks, IntrospectToken,
+            MergeEntities, NewToken, NewTokens, PatchEntityMeta, ReassignEntity, RestoreEntity,
+            Revoked, RevokeToken, SearchEntities, SetLanguage, SetMute, SetWebhook,
+            SubscribeEntities, SubscribeKinds, Tasks, Token, UnsubscribeEntities, UnsubscribeKinds,
+            UpdateEntity, UpdateGroup, UpdateSetting,
         },
     },
-    server::{Config, Context, JWTContext, JWTGuard, Privilege, RouterExt},
+    server::{
+        Config, Context, EventHub, export_entities, import_entities, JWTContext, JWTGuard,
+        Privilege, propagate_request_id, RateLimiter, RequestId, RouterExt, spawn_delivery,
+        spawn_event_log,
+    },
 };
 
 /// Construct the router.
@@ -30,90 +54,273 @@ pub async fn make_app(config: Config) -> Result<Router> {
     make_app_with(config, None).await
 }
 
-/// Construct the router with given database.
+/// Construct the router with given database client.
 ///
 /// # Errors
 /// Fails on invalid db url
-pub async fn make_app_with(config: Config, db: Option<Database>) -> Result<Router> {
+pub async fn make_app_with(config: Config, client: Option<Client>) -> Result<Router> {
     let config = Arc::new(config);
 
-    let cors_layer = cors::CorsLayer::new()
+    let cors_layer = cors_layer(&config)?;
+    let trace_layer =
+        trace::TraceLayer::new_for_http().make_span_with(|request: &http::Request<Body>| {
+            let request_id = request.extensions().get::<RequestId>().map(|id| id.0.as_str());
+            tracing::info_span!(
+                "request",
+                method = %request.method(),
+                uri = %request.uri(),
+                request_id,
+            )
+        });
+
+    let client = match client {
+        Some(client) => client,
+        None => Context::connect(&config).await?,
+    };
+
+    let jwt = Arc::new(JWTContext::new(&config, &client.database(&config.mongo_db))?);
+    jwt.init().await?;
+
+    let methods = methods(&jwt);
+    let rate_limit = RateLimiter::new(jwt.clone(), config.requests_per_minute).into_layer();
+
+    let ctx = Context::new_with_client(client, jwt, config);
+    ctx.init().await?;
+    spawn_event_log(&ctx).await?;
+    spawn_delivery(&ctx).await?;
+    let metrics = ctx.metrics().cloned();
+    let body_limit = DefaultBodyLimit::max(ctx.config().max_body_bytes);
+
+    let api = EventHub::mount(methods, &ctx)
+        .await?
+        .route("/jwks.json", get(jwks))
+        .route("/ready", get(ready))
+        .layer(body_limit)
+        .layer(rate_limit)
+        .layer(Extension(ctx))
+        .layer(cors_layer)
+        .layer(trace_layer)
+        .layer(middleware::from_fn(propagate_request_id));
+
+    let router = Router::new().nest("/v1", api);
+
+    Ok(match metrics {
+        Some(metrics) => router.route("/metrics", get(move || async move { metrics.render() })),
+        None => router,
+    })
+}
+
+/// Serve public keys tokens can be verified with, in JWKS format.
+///
+/// Keys are read on each request, so rotated keys show up immediately.
+async fn jwks(Extension(ctx): Extension<Context>) -> Json<Jwks> {
+    Json(Jwks { keys: ctx.jwks() })
+}
+
+/// Readiness probe, answering `503 Service Unavailable` unless the node is ready.
+///
+/// Served by `GET` as well as `detailed_health`, since probes can't send `POST` requests.
+async fn ready(Extension(ctx): Extension<Context>) -> (StatusCode, Json<HealthReport>) {
+    let report = ctx.detailed_health().await;
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Build the CORS layer from allowed origins in config.
+fn cors_layer(config: &Config) -> Result<cors::CorsLayer> {
+    let origins = &config.allowed_origins;
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        // Rejected here instead of panicking when the layer is applied
+        if config.allow_credentials {
+            bail!("Credentials can't be allowed for any origin, set `allowed_origins` explicitly");
+        }
+        cors::AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| HeaderValue::from_str(origin))
+            .collect::<Result<Vec<_>, _>>()
+            .wrap_err("Invalid origin")?;
+        cors::AllowOrigin::list(origins)
+    };
+
+    Ok(cors::CorsLayer::new()
         .allow_methods(vec![Method::POST])
-        .allow_credentials(true)
-        .allow_origin(cors::Any);
-    let trace_layer = trace::TraceLayer::new_for_http();
+        .allow_credentials(config.allow_credentials)
+        .allow_origin(allow_origin))
+}
 
-    let jwt = Arc::new(JWTContext::new(&config));
+/// Mount all RPC methods, each behind the guard of the least privilege it requires.
+#[allow(clippy::too_many_lines)] // One mount per method
+fn methods(jwt: &Arc<JWTContext>) -> Router {
     let user_guard = JWTGuard::new(jwt.clone(), Privilege::User).into_layer();
     let bot_guard = JWTGuard::new(jwt.clone(), Privilege::Bot).into_layer();
     let admin_guard = JWTGuard::new(jwt.clone(), Privilege::Admin).into_layer();
 
-    let ctx = match db {
-        Some(db) => Context::new_with_db(db, jwt, config),
-        None => Context::new(jwt, config).await?,
-    };
-
-    let api = Router::new()
-        .mount(
+    Router::new()
+        .mount_audited(
             |AddUser {
                  im,
                  im_payload,
                  avatar,
                  name,
+                 return_existing,
              },
              ctx: Context| {
-                async move { ctx.add_user(im, im_payload, avatar, name).await }
+                async move { ctx.add_user(im, im_payload, avatar, name, return_existing).await }
             },
         )
-        .mount(|AddEntity { meta, tasks }, ctx: Context| async move {
-            ctx.add_entity(meta, tasks).await
+        .mount_audited(|req: SetWebhook, ctx: Context| async move {
+            let target = WebhookTarget {
+                callback_url: req.callback_url,
+                secret: req.secret,
+            };
+            ctx.set_webhook(req.user_id, req.name, &target).await
         })
-        .mount(|req: AddTask, ctx: Context| async move {
-            let id = req.entity_id;
-            ctx.add_task(&id, req.into()).await
+        .mount_audited(add_entity)
+        .mount_audited(add_task)
+        .mount_audited(|AddTasks { entity_id, tasks }, ctx: Context| async move {
+            ctx.add_tasks(&entity_id, tasks).await.map(|tasks| Tasks { tasks })
         })
-        .mount(
-            |DelEntity { entity_id }, ctx: Context| async move { ctx.del_entity(&entity_id).await },
-        )
-        .mount(|DelTask { task_id }, ctx: Context| async move { ctx.del_task(&task_id).await })
-        .mount(
+        .mount_audited(del_entity)
+        .mount_audited(|RestoreEntity { entity_id }, ctx: Context| async move {
+            ctx.restore_entity(&entity_id).await
+        })
+        .mount_audited(|MergeEntities { from, into }, ctx: Context| async move {
+            ctx.merge_entities(&from, &into).await
+        })
+        .mount_audited(|ReassignEntity { entity_id, target_worker }, ctx: Context| async move {
+            ctx.reassign_entity(&entity_id, target_worker).await
+        })
+        .mount_audited(|DelTask { task_id }, ctx: Context| async move { ctx.del_task(&task_id).await })
+        .mount_audited(|EnableTask { task_id }, ctx: Context| async move {
+            ctx.set_task_enabled(&task_id, true).await
+        })
+        .mount_audited(|DisableTask { task_id }, ctx: Context| async move {
+            ctx.set_task_enabled(&task_id, false).await
+        })
+        .mount_audited(
             |UpdateEntity { entity_id, meta }, ctx: Context| async move {
                 ctx.update_entity(&entity_id, &meta).await
             },
         )
+        .mount_audited(|req: PatchEntityMeta, ctx: Context| async move {
+            ctx.patch_entity_meta(&req).await
+        })
+        .mount_audited(|AddGroup { name }, ctx: Context| async move { ctx.add_group(name).await })
+        .mount_audited(|UpdateGroup { group_id, name }, ctx: Context| async move {
+            ctx.update_group(&group_id, &name).await
+        })
+        .mount_audited(|DelGroup { group_id }, ctx: Context| async move {
+            ctx.del_group(&group_id).await
+        })
+        .mount_audited(revoke_token)
+        .mount(|GetBots {}, ctx: Context| async move {
+            ctx.get_bots().await.map(|bots| Bots { bots })
+        })
+        .mount(|req: GetAuditLog, ctx: Context| async move {
+            ctx.get_audit_log(req.actor, req.since, req.until, req.limit, req.before)
+                .await
+        })
+        .mount(|GetEntityEvents { entity_id, before, limit }, ctx: Context| async move {
+            ctx.get_entity_events(entity_id, before, limit).await
+        })
+        .mount(|GetDeadLetters { limit, after }, ctx: Context| async move {
+            ctx.get_dead_letters(limit, after).await
+        })
+        .mount(|GetUnschedulableTasks {}, ctx: Context| async move {
+            ctx.get_unschedulable_tasks().await.map(|tasks| Tasks { tasks })
+        })
+        .mount(|IntrospectToken { token }, ctx: Context| async move {
+            ctx.introspect_token(&token).await
+        })
+        .mount_audited(|ReplayDeadLetters { ids }, ctx: Context| async move {
+            ctx.replay_dead_letters(&ids).await.map(|count| Replayed { count })
+        })
+        .mount_audited(|NewBot { name }, ctx: Context| async move {
+            let admin = ctx.claims().ok_or_else(ApiError::unauthorized)?.id();
+            ctx.new_bot(name, admin).await
+        })
+        .route("/export_entities", get(export_entities))
+        .route("/import_entities", post(import_entities))
         .layer(admin_guard)
-        .mount(
-            |GetInterest {
-                 entity_id,
-                 kind,
-                 im,
-             },
-             ctx: Context| async move {
-                ctx.get_interest(entity_id, &kind, &im)
-                    .await
-                    .map(|users| Interest { users })
-            },
-        )
-        .mount(|GetEntities {}, ctx: Context| async move { ctx.get_entities().await })
+        .mount(|req: GetInterest, ctx: Context| async move {
+            ctx.get_interest(req.entity_id, &req.kind, &req.im)
+                .await
+                .map(|users| Interest { users })
+        })
+        .mount(|req: GetEntities, ctx: Context| async move {
+            ctx.get_entities(req.limit, req.after, req.include_deleted, req.include_tasks)
+                .await
+        })
+        .mount(|req: SearchEntities, ctx: Context| async move {
+            let vtbs = ctx.search_entities(&req.query, req.lang, req.limit).await?;
+            Ok(EntityMatches { vtbs })
+        })
+        .mount(count_entities)
         .mount(new_token)
+        .mount(|NewTokens { queries }, ctx: Context| async move { ctx.new_tokens(&queries).await })
         .mount(|DelUser { query }, ctx: Context| async move { ctx.del_user(&query).await })
+        .mount(|DelUsers { query, confirm_all }, ctx: Context| async move {
+            let count = ctx.del_users(query.as_ref(), confirm_all).await?;
+            Ok(Deleted { count })
+        })
         .layer(bot_guard)
         .mount(|UpdateSetting { event_filter }, ctx: Context| async move {
             let id = ctx.assert_user_claims()?.id();
             ctx.update_setting(&id, &event_filter).await
         })
+        .mount(|SubscribeEntities { entity_ids }, ctx: Context| async move {
+            let id = ctx.assert_user_claims()?.id();
+            ctx.update_subscription(&id, "entities", entity_ids, true).await
+        })
+        .mount(|UnsubscribeEntities { entity_ids }, ctx: Context| async move {
+            let id = ctx.assert_user_claims()?.id();
+            ctx.update_subscription(&id, "entities", entity_ids, false).await
+        })
+        .mount(|SubscribeKinds { kinds }, ctx: Context| async move {
+            let id = ctx.assert_user_claims()?.id();
+            ctx.update_subscription(&id, "kinds", kinds, true).await
+        })
+        .mount(|UnsubscribeKinds { kinds }, ctx: Context| async move {
+            let id = ctx.assert_user_claims()?.id();
+            ctx.update_subscription(&id, "kinds", kinds, false).await
+        })
+        .mount(|SetMute { until }, ctx: Context| async move {
+            let id = ctx.assert_user_claims()?.id();
+            ctx.set_mute(&id, until).await
+        })
+        .mount(|SetLanguage { lang }, ctx: Context| async move {
+            let id = ctx.assert_user_claims()?.id();
+            ctx.set_language(&id, lang).await
+        })
         .mount(auth_user)
         .layer(user_guard)
-        .mount(|Health {}, _| async { Ok(Null) })
+        .mount(get_schema)
+        .mount(|GetJwks {}, ctx: Context| async move { Ok(Jwks { keys: ctx.jwks() }) })
+        .mount(health)
+        .mount(|DetailedHealth {}, ctx: Context| async move { Ok(ctx.detailed_health().await) })
         .mount(login)
-        .layer(Extension(ctx))
-        .layer(cors_layer)
-        .layer(trace_layer);
+        .mount(refresh_token)
+}
+
+async fn get_schema(_: GetSchema, _: Context) -> ApiResult<Schema> {
+    Ok(Schema {
+        methods: method_schemas(),
+    })
+}
 
-    Ok(Router::new().nest("/v1", api))
+async fn health(_: Health, ctx: Context) -> ApiResult<Null> {
+    ctx.ping().await?;
+    Ok(Null)
 }
 
 async fn login(req: Login, ctx: Context) -> ApiResult<Token> {
+    let principal = login_principal(&req.username);
     let prv = match ctx
         .auth()
         .look_up(req.username, req.password.as_bytes())
@@ -124,7 +331,30 @@ async fn login(req: Login, ctx: Context) -> ApiResult<Token> {
         _ => return Err(ApiError::unauthorized()),
     };
 
-    let (token, claims) = ctx.encode(&Uuid::from_bytes([0; 16]), prv)?;
+    let (token, claims) = ctx.encode(&principal, prv)?;
+
+    Ok(Token {
+        token,
+        valid_until: claims.valid_until(),
+    })
+}
+
+async fn refresh_token(req: RefreshToken, ctx: Context) -> ApiResult<Token> {
+    let claims = ctx.validate_token(&req.token).await?;
+
+    // Admins and bots log in or get a bot token again instead
+    if claims.privilege() != Privilege::User {
+        return Err(ApiError::unauthorized());
+    }
+    // Validation tolerates some leeway, but an expired token must not be refreshed
+    if claims.valid_until() <= SystemTime::now() {
+        return Err(ApiError::bad_token());
+    }
+
+    let (token, refreshed) = ctx.encode(&claims.id(), claims.privilege())?;
+    // So that a leaked token can't be refreshed over and over
+    ctx.revoke(claims.jti(), Some(claims.valid_until())).await?;
+    let claims = refreshed;
 
     Ok(Token {
         token,
@@ -162,3 +392,66 @@ async fn new_token(req: NewToken, ctx: Context) -> ApiResult<Token> {
         valid_until: claim.valid_until(),
     })
 }
+
+async fn add_entity(req: AddEntity, ctx: Context) -> ApiResult<Entity> {
+    let AddEntity {
+        meta,
+        tasks,
+        idempotency_key,
+        external_id,
+        upsert,
+    } = req;
+
+    ctx.idempotent(
+        AddEntity::METHOD,
+        idempotency_key,
+        ctx.add_entity(meta, tasks, external_id, upsert),
+    )
+    .await
+}
+
+async fn del_entity(req: DelEntity, ctx: Context) -> ApiResult<Entity> {
+    let DelEntity { entity_id, purge } = req;
+
+    if purge {
+        ctx.purge_entity(&entity_id).await
+    } else {
+        ctx.del_entity(&entity_id).await
+    }
+}
+
+async fn add_task(mut req: AddTask, ctx: Context) -> ApiResult<Task> {
+    let id = req.entity_id;
+    let key = req.idempotency_key.take();
+
+    ctx.idempotent(AddTask::METHOD, key, ctx.add_task(&id, req.into()))
+        .await
+}
+
+async fn count_entities(req: CountEntities, ctx: Context) -> ApiResult<EntityCount> {
+    ctx.count_entities(req.group, req.per_group).await
+}
+
+async fn revoke_token(req: RevokeToken, ctx: Context) -> ApiResult<Revoked> {
+    let (jti, valid_until) = match req {
+        RevokeToken {
+            target_jti: Some(jti),
+            ..
+        } => (jti, None),
+        RevokeToken {
+            token: Some(token), ..
+        } => {
+            let claims = ctx.decode(token)?;
+            (claims.jti(), Some(claims.valid_until()))
+        }
+        _ => {
+            return Err(ApiError::bad_request(
+                "Either `token` or `target_jti` is required",
+            ));
+        }
+    };
+
+    ctx.revoke(jti, valid_until).await?;
+
+    Ok(Revoked { jti })
+}
@@ -0,0 +1,104 @@
+//! Prometheus metrics of RPC methods.
+
+use std::time::Duration;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::rpc::ApiError;
+
+/// Metrics collected across all RPC methods, labeled by method name.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    errors: IntCounterVec,
+}
+
+impl Metrics {
+    /// Create and register all metrics into a new registry.
+    ///
+    /// # Errors
+    /// Fails if metrics are malformed. This is unlikely to happen, but if it does, it's a bug.
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("api_requests_total", "Total number of RPC requests"),
+            &["method"],
+        )?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "api_request_duration_seconds",
+                "Duration of handling RPC requests",
+            ),
+            &["method"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new("api_errors_total", "Total number of RPC requests failed with `ApiError`"),
+            &["method", "status"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(duration.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            duration,
+            errors,
+        })
+    }
+
+    /// Record a handled request of `method`.
+    pub fn observe(&self, method: &str, elapsed: Duration, error: Option<&ApiError>) {
+        self.requests.with_label_values(&[method]).inc();
+        self.duration
+            .with_label_values(&[method])
+            .observe(elapsed.as_secs_f64());
+        if let Some(error) = error {
+            self.errors
+                .with_label_values(&[method, error.status().as_str()])
+                .inc();
+        }
+    }
+
+    /// Render all metrics in Prometheus text format.
+    #[must_use]
+    pub fn render(&self) -> String {
+        let mut buf = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("Metrics should always be encodable");
+        String::from_utf8(buf).expect("Prometheus text format is always UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{rpc::ApiError, server::Metrics};
+
+    #[test]
+    fn must_render() {
+        let metrics = Metrics::new().unwrap();
+
+        metrics.observe("health", Duration::from_millis(1), None);
+        metrics.observe(
+            "del_task",
+            Duration::from_millis(2),
+            Some(&ApiError::bad_request("oops")),
+        );
+
+        let text = metrics.render();
+        assert!(text.contains(r#"api_requests_total{method="health"} 1"#));
+        assert!(text.contains(r#"api_requests_total{method="del_task"} 1"#));
+        assert!(text.contains(r#"api_request_duration_seconds_count{method="health"} 1"#));
+        assert!(text.contains(r#"api_errors_total{method="del_task",status="400"} 1"#));
+        assert!(!text.contains(r#"api_errors_total{method="health""#));
+    }
+}
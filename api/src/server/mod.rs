@@ -1,27 +1,83 @@
 //! Server implementation of the RPC API.
 
-use color_eyre::Result;
+use std::net::SocketAddr;
+
+use color_eyre::{eyre::bail, Result};
 use sg_core::utils::FigmentExt;
+use tokio::sync::oneshot;
 
-mod_use::mod_use![config, handler, jwt, context, ext];
+mod_use::mod_use![
+    config, handler, jwt, context, ext, metrics, rate_limit, events, audit, export, retry,
+    request_id, event_log, delivery
+];
 
 #[allow(clippy::missing_errors_doc)]
 pub async fn serve_with_config(config: Config) -> Result<()> {
     tracing::debug!(config = ?config);
 
+    if let Err(errors) = config.validate() {
+        for error in &errors {
+            tracing::error!("Invalid config {}", error);
+        }
+        bail!("{} invalid config field(s)", errors.len());
+    }
+
     let server = axum::Server::bind(&config.bind);
+    let shutdown_timeout = config.shutdown_timeout;
 
-    let app = make_app(config).await?.into_make_service();
+    // Peer address is needed to rate limit unauthenticated requests
+    let app = make_app(config)
+        .await?
+        .into_make_service_with_connect_info::<SocketAddr>();
 
     tracing::info!("Server starting");
 
-    server.serve(app).await?;
+    // Stop accepting connections once told to, and resolve when in-flight requests are done
+    let (stop, stopped) = oneshot::channel::<()>();
+    let serving = server.serve(app).with_graceful_shutdown(async {
+        drop(stopped.await);
+    });
+    tokio::pin!(serving);
+
+    tokio::select! {
+        r = &mut serving => r?,
+        r = shutdown_signal() => {
+            r?;
+            tracing::info!("Shutdown signal received, draining in-flight requests");
+            drop(stop.send(()));
+            match tokio::time::timeout(shutdown_timeout, serving).await {
+                Ok(r) => r?,
+                Err(_) => tracing::warn!(
+                    ?shutdown_timeout,
+                    "In-flight requests not finished in time, dropping them"
+                ),
+            }
+        }
+    }
 
     tracing::info!("Server stopped");
 
     Ok(())
 }
 
+/// Resolve on SIGINT, or SIGTERM on unix.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            r = tokio::signal::ctrl_c() => r?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
 #[allow(clippy::missing_errors_doc)]
 pub async fn serve() -> Result<()> {
     serve_with_config(Config::from_env("API_")?).await
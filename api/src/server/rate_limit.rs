@@ -0,0 +1,201 @@
+//! Per-client rate limiting with token buckets.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{body::BoxBody, extract::ConnectInfo, http::Request};
+use http::{header, HeaderValue};
+use mongodb::bson::Uuid;
+use tower_http::auth::{AuthorizeRequest, RequireAuthorizationLayer};
+
+use crate::{
+    rpc::ApiError,
+    server::{JWTContext, ResponseExt},
+};
+
+/// Number of shards buckets are spread across, to reduce lock contention.
+const SHARDS: usize = 16;
+
+/// Number of buckets in a shard above which full buckets are evicted.
+const EVICT_THRESHOLD: usize = 1024;
+
+/// Who a request is accounted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ClientKey {
+    /// Subject of a valid token.
+    Subject(Uuid),
+    /// Peer address of an unauthenticated request.
+    Peer(IpAddr),
+    /// Neither is known, e.g. the server is not serving with connect info.
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug)]
+struct Limiter {
+    /// Maximum number of tokens in a bucket, which is also the burst size.
+    capacity: f64,
+    /// Tokens refilled per second.
+    rate: f64,
+    shards: Vec<Mutex<HashMap<ClientKey, Bucket>>>,
+}
+
+impl Limiter {
+    fn new(requests_per_minute: u32) -> Self {
+        let capacity = f64::from(requests_per_minute);
+        Self {
+            capacity,
+            rate: capacity / 60.,
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    /// Take a token from the bucket of `key`, or return how long to wait until one is available.
+    fn acquire(&self, key: ClientKey, now: Instant) -> Result<(), Duration> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        #[allow(clippy::cast_possible_truncation)]
+        let shard = &self.shards[hasher.finish() as usize % SHARDS];
+        let mut buckets = shard.lock().expect("Rate limiter lock poisoned");
+
+        if buckets.len() > EVICT_THRESHOLD {
+            // Full buckets are indistinguishable from fresh ones and can be dropped
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.capacity);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.capacity,
+            last: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.last = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1. - bucket.tokens) / self.rate))
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        elapsed.mul_add(self.rate, bucket.tokens).min(self.capacity)
+    }
+}
+
+/// Rate limit requests by the subject of their token, falling back to the peer address.
+///
+/// Tokens are only decoded to tell clients apart here, the guards are still responsible for
+/// rejecting bad ones.
+#[derive(Clone)]
+pub struct RateLimiter {
+    jwt: Arc<JWTContext>,
+    /// `None` if rate limiting is disabled.
+    limiter: Option<Arc<Limiter>>,
+}
+
+impl RateLimiter {
+    /// Allow `requests_per_minute` requests per client. `0` disables rate limiting.
+    #[must_use]
+    pub fn new(jwt: Arc<JWTContext>, requests_per_minute: u32) -> Self {
+        Self {
+            jwt,
+            limiter: (requests_per_minute > 0).then(|| Arc::new(Limiter::new(requests_per_minute))),
+        }
+    }
+
+    #[must_use]
+    pub fn into_layer(self) -> RequireAuthorizationLayer<Self> {
+        RequireAuthorizationLayer::custom(self)
+    }
+
+    fn client_key<B>(&self, request: &Request<B>) -> ClientKey {
+        let subject = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.jwt.validate(token).ok())
+            .map(|claims| ClientKey::Subject(claims.id()));
+        let peer = || {
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map_or(ClientKey::Unknown, |ConnectInfo(addr)| {
+                    ClientKey::Peer(addr.ip())
+                })
+        };
+
+        subject.unwrap_or_else(peer)
+    }
+}
+
+impl<B> AuthorizeRequest<B> for RateLimiter {
+    type ResponseBody = BoxBody;
+
+    fn authorize(&mut self, request: &mut Request<B>) -> Result<(), http::Response<BoxBody>> {
+        let limiter = match &self.limiter {
+            Some(limiter) => limiter,
+            None => return Ok(()),
+        };
+
+        let key = self.client_key(request);
+        limiter.acquire(key, Instant::now()).map_err(|wait| {
+            tracing::debug!(?key, ?wait, "Rate limited");
+
+            let mut resp = ApiError::too_many_requests().as_response();
+            // Round up so that the client won't retry too early
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            resp
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
+
+    use mongodb::bson::Uuid;
+
+    use super::{ClientKey, Limiter};
+
+    #[test]
+    fn must_limit() {
+        let limiter = Limiter::new(60);
+        let now = Instant::now();
+        let bot = ClientKey::Subject(Uuid::new());
+        let peer = ClientKey::Peer(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        // Burst up to the capacity
+        for _ in 0..60 {
+            limiter.acquire(bot, now).unwrap();
+        }
+        let wait = limiter.acquire(bot, now).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+
+        // Other clients are not affected
+        limiter.acquire(peer, now).unwrap();
+
+        // Refilled at one token per second
+        let later = now + Duration::from_secs(1);
+        limiter.acquire(bot, later).unwrap();
+        assert!(limiter.acquire(bot, later).is_err());
+    }
+}
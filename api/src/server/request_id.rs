@@ -0,0 +1,36 @@
+//! Correlation ids of requests.
+
+use axum::{http::Request, middleware::Next, response::Response as AxumResponse};
+use http::HeaderValue;
+use mongodb::bson::Uuid;
+
+/// Header carrying the correlation id of a request, given by the client or generated.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest correlation id accepted from clients. Longer ones are replaced by a generated one.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Correlation id of a request, shared by all its log lines and echoed back in the response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// Take the correlation id from the `X-Request-Id` header, or generate one if absent. It's made
+/// available to inner layers and handlers as a [`RequestId`] extension, and set in the header of
+/// the response.
+pub async fn propagate_request_id<B>(mut request: Request<B>, next: Next<B>) -> AxumResponse {
+    let given = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN)
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned);
+    let id = given.unwrap_or_else(|| Uuid::new().to_string());
+    let value = HeaderValue::from_str(&id).expect("Request id should be visible ASCII");
+
+    request.headers_mut().insert(REQUEST_ID_HEADER, value.clone());
+    request.extensions_mut().insert(RequestId(id));
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
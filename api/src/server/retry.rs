@@ -0,0 +1,94 @@
+//! Retries of database operations failing transiently.
+
+use std::{fmt::Debug, future::Future, time::Duration};
+
+use mongodb::error::{Error, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR};
+
+/// Whether the driver labels `error` as safe to retry, e.g. on a network blip or an election.
+#[must_use]
+pub fn is_transient(error: &Error) -> bool {
+    error.contains_label(RETRYABLE_WRITE_ERROR) || error.contains_label(TRANSIENT_TRANSACTION_ERROR)
+}
+
+/// Run `f` until it succeeds, retrying transient errors up to `retries` times. The first retry
+/// waits `backoff`, doubling on each one after.
+///
+/// `f` is run from scratch on each attempt, so a transaction must be started inside it.
+///
+/// # Errors
+/// Fail with the first non-transient error, or the last error once retries are used up.
+pub async fn retry_transient<T, F, Fut>(retries: u32, backoff: Duration, f: F) -> Result<T, Error>
+where
+    T: Send,
+    F: FnMut() -> Fut + Send,
+    Fut: Future<Output = Result<T, Error>> + Send,
+{
+    retry_if(retries, backoff, is_transient, f).await
+}
+
+async fn retry_if<T, E, F, Fut>(
+    retries: u32,
+    backoff: Duration,
+    should_retry: fn(&E) -> bool,
+    mut f: F,
+) -> Result<T, E>
+where
+    T: Send,
+    E: Debug + Send,
+    F: FnMut() -> Fut + Send,
+    Fut: Future<Output = Result<T, E>> + Send,
+{
+    let mut delay = backoff;
+    for retry in 0.. {
+        match f().await {
+            Err(error) if retry < retries && should_retry(&error) => {
+                tracing::warn!(?error, retry, ?delay, "Transient database error, retrying");
+                tokio::time::sleep(delay).await;
+                delay = delay.saturating_mul(2);
+            }
+            res => return res,
+        }
+    }
+    unreachable!("Retries are bounded by `retries`")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        time::Duration,
+    };
+
+    use crate::server::retry::{retry_if, retry_transient};
+
+    #[tokio::test]
+    async fn must_retry_transient() {
+        let attempts = AtomicU32::new(0);
+        let fail_twice = || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err("transient"),
+                _ => Ok(()),
+            }
+        };
+        retry_if(2, Duration::ZERO, |e| *e == "transient", fail_twice)
+            .await
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // Out of retries
+        attempts.store(0, Ordering::SeqCst);
+        let res = retry_if(1, Duration::ZERO, |e| *e == "transient", fail_twice).await;
+        assert_eq!(res, Err("transient"));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // Errors without retryable labels propagate immediately
+        attempts.store(0, Ordering::SeqCst);
+        let res = retry_transient(3, Duration::ZERO, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(std::io::Error::from(std::io::ErrorKind::Other).into())
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -5,19 +5,35 @@
 //!
 //! Username: "test"
 //! Password: "test"
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime},
+};
 
-use mongodb::bson::Uuid;
+use isolanguage_1::LanguageCode;
+use mongodb::bson::{DateTime, Uuid};
 use once_cell::sync::Lazy;
 use prep::prep;
 use rand::Rng;
 use reqwest::Url;
-use sg_core::models::{EventFilter, User};
+use sg_core::{
+    models::{Entity, EventFilter, Meta, Name, User},
+    schedule::Schedule,
+    webhook::{self, WebhookTarget},
+};
 
-use crate::model::UserQuery;
+use crate::{
+    model::{
+        login_principal, AddTaskParam, AuditEntry, BulkUserQuery, Imported, Privilege, UserQuery,
+        MAX_NEW_TOKENS,
+    },
+    rpc::{ApiError, ErrorCode, ResponseObject},
+    server::{Config, IdempotencyRecord},
+};
 
 mod prep {
     use std::{
+        collections::HashMap,
         ops::{Deref, DerefMut},
         sync::atomic::{AtomicBool, AtomicU16, Ordering},
         time::Duration,
@@ -30,7 +46,7 @@ mod prep {
 
     use crate::{
         client::blocking::Client,
-        server::{make_app_with, Config},
+        server::{make_app_with, Config, Privilege},
     };
 
     static CURRENT: OnceCell<(Runtime, AuthClient)> = OnceCell::new();
@@ -98,10 +114,8 @@ mod prep {
 
             info!(%mongo_uri, "Connecting to mongodb");
 
-            let db = mongodb::Client::with_uri_str(&mongo_uri)
-                .await
-                .unwrap()
-                .database("stargazer-reborn");
+            let client = mongodb::Client::with_uri_str(&mongo_uri).await.unwrap();
+            let db = client.database("stargazer-reborn");
             let col = db.collection::<PermissionRecord>("auth");
 
             let auth = AuthClient::new(col);
@@ -118,10 +132,12 @@ mod prep {
             let app = make_app_with(
                 Config {
                     token_timeout: Duration::from_secs(0),
+                    // Long enough for user tokens to be refreshed
+                    token_ttl: HashMap::from([(Privilege::User, Duration::from_secs(60))]),
                     mongo_uri,
                     ..Config::default()
                 },
-                Some(db),
+                Some(client),
             )
             .await
             .unwrap()
@@ -181,6 +197,7 @@ fn test_new_user() {
             payload.clone(),
             URL.clone(),
             "Pop".to_owned(),
+            false,
         )
         .unwrap();
 
@@ -191,6 +208,8 @@ fn test_new_user() {
         name,
         avatar,
         event_filter,
+        muted_until,
+        preferred_language,
     } = &res1;
 
     assert_eq!(im, "tg");
@@ -204,22 +223,33 @@ fn test_new_user() {
         event_filter,
         &EventFilter {
             entities: HashSet::default(),
+            groups: HashSet::default(),
             kinds: HashSet::default(),
         }
     );
 
+    assert_eq!(muted_until, &None);
+    assert_eq!(preferred_language, &LanguageCode::En);
+
     tracing::info!(id = ?id, "New user added");
 
     // Make sure duplicate users are not allowed
     let err = c
-        .add_user("tg", payload, URL.clone(), "SomeOtherName")
+        .add_user("tg", payload.clone(), URL.clone(), "SomeOtherName", false)
         .unwrap_err();
     match err {
         crate::client::Error::Api(err) => {
             assert_eq!(err.error_reason(), Some("Conflict"));
+            assert_eq!(err.code(), ErrorCode::UserAlreadyExists);
+            assert!(err.errors()[1].contains(&id.to_string()));
         }
         _ => panic!("Unexpected error: {:?}", err),
     }
+    // unless the existing one is asked for
+    let existing = c
+        .add_user("tg", payload, URL.clone(), "SomeOtherName", true)
+        .unwrap();
+    assert_eq!(existing, res1);
 
     let token = c.new_token(UserQuery::ById { user_id: *id }).unwrap().token;
 
@@ -241,11 +271,1217 @@ fn test_new_user() {
     drop(c.auth_user().unwrap_err());
 }
 
+#[test]
+fn test_revoke_token() {
+    let mut c = prep();
+
+    let user_id = c
+        .add_user("tg", gen_payload(), URL.clone(), "Pop", false)
+        .unwrap()
+        .id;
+    let token = c.new_token(UserQuery::ById { user_id }).unwrap().token;
+
+    // Revoke by token
+    let jti = c.revoke_token(token.clone(), None).unwrap().jti;
+
+    let admin_token = c.set_token(token).unwrap();
+    let err = c.auth_user().unwrap_err();
+    assert!(err.matches_api_status(401));
+    assert!(err.matches_api_code(ErrorCode::BadToken));
+    c.set_token(admin_token).unwrap();
+
+    // Revoking again by id is fine
+    assert_eq!(c.revoke_token(None, jti).unwrap().jti, jti);
+
+    // Nothing to revoke
+    let err = c.revoke_token(None, None).unwrap_err();
+    assert!(err.matches_api_status(400));
+
+    c.del_user(UserQuery::ById { user_id }).unwrap();
+}
+
+#[test]
+fn test_new_tokens() {
+    let mut c = prep();
+
+    let payload = gen_payload();
+    let user_id = c
+        .add_user("tg", payload.clone(), URL.clone(), "Pop", false)
+        .unwrap()
+        .id;
+    let missing = Uuid::new();
+    let queries = vec![
+        UserQuery::ById { user_id },
+        UserQuery::ById { user_id: missing },
+        UserQuery::ByIm {
+            im: "tg".to_owned(),
+            im_payload: payload,
+        },
+    ];
+
+    // Missing users don't fail the others
+    let res = c.new_tokens(queries).unwrap();
+    assert_eq!(res.tokens.len(), 2);
+    assert!(res.tokens.iter().all(|(id, _)| *id == user_id));
+    assert_eq!(res.errors.len(), 1);
+    assert_eq!(res.errors[0].index, 1);
+    assert_eq!(res.errors[0].error.code(), ErrorCode::UserNotFound);
+
+    let admin_token = c.set_token(res.tokens[0].1.token.clone()).unwrap();
+    assert_eq!(c.auth_user().unwrap().user.id, user_id);
+    c.set_token(admin_token).unwrap();
+
+    let oversized = vec![UserQuery::ById { user_id }; MAX_NEW_TOKENS + 1];
+    let err = c.new_tokens(oversized).unwrap_err();
+    assert!(err.matches_api_status(400));
+
+    c.del_user(UserQuery::ById { user_id }).unwrap();
+}
+
+#[test]
+fn test_introspect_token() {
+    let c = prep();
+
+    let user_id = c
+        .add_user("tg", gen_payload(), URL.clone(), "Pop", false)
+        .unwrap()
+        .id;
+    let token = c.new_token(UserQuery::ById { user_id }).unwrap().token;
+
+    let info = c.introspect_token(token.clone()).unwrap();
+    assert_eq!(info.user_id, user_id);
+    assert_eq!(info.privilege, Privilege::User);
+    assert!(info.verified && !info.revoked);
+    assert!(info.issued_at.unwrap() <= info.valid_until);
+
+    // Revoked tokens can still be inspected
+    c.revoke_token(token.clone(), None).unwrap();
+    let info = c.introspect_token(token).unwrap();
+    assert!(info.revoked);
+
+    let err = c.introspect_token("not a token").unwrap_err();
+    assert!(err.matches_api_status(400));
+
+    c.del_user(UserQuery::ById { user_id }).unwrap();
+}
+
+#[test]
+fn test_refresh_token() {
+    let c = prep();
+
+    let user_id = c
+        .add_user("tg", gen_payload(), URL.clone(), "Pop", false)
+        .unwrap()
+        .id;
+    let token = c.new_token(UserQuery::ById { user_id }).unwrap().token;
+    let refreshed = c.refresh_token(token.clone()).unwrap();
+    assert_ne!(refreshed.token, token);
+
+    // The refreshed token is revoked, so it can't be refreshed again
+    assert!(c.introspect_token(token.clone()).unwrap().revoked);
+    let err = c.refresh_token(token).unwrap_err();
+    assert!(err.matches_api_status(401));
+    let _ = c.refresh_token(refreshed.token).unwrap();
+
+    // Bot tokens can't be refreshed
+    let token = c.new_bot("Refresh Bot").unwrap().token;
+    let err = c.refresh_token(token).unwrap_err();
+    assert!(err.matches_api_status(401));
+
+    // Neither can login tokens, which expire immediately in this suite
+    let err = c.refresh_token(c.token().unwrap().to_owned()).unwrap_err();
+    assert!(err.matches_api_status(401));
+
+    // Or garbage
+    let err = c.refresh_token("garbage").unwrap_err();
+    assert!(err.matches_api_status(401));
+
+    c.del_user(UserQuery::ById { user_id }).unwrap();
+}
+
+#[test]
+fn test_refresh_and_store() {
+    let mut c = prep();
+
+    // Login tokens in this suite are expiring right away
+    assert!(c.valid_until().is_some());
+    let err = c.refresh_if_expiring(Duration::ZERO).unwrap_err();
+    assert!(err.matches_api_status(401));
+
+    // Tokens of unknown expiry are left as is
+    let user_id = c
+        .add_user("tg", gen_payload(), URL.clone(), "Pop", false)
+        .unwrap()
+        .id;
+    let token = c.new_token(UserQuery::ById { user_id }).unwrap().token;
+    let admin_token = c.set_token(token.clone()).unwrap();
+    assert!(c.valid_until().is_none());
+    assert!(!c.refresh_if_expiring(Duration::from_secs(3600)).unwrap());
+
+    assert_eq!(c.refresh_and_store().unwrap(), Some(token));
+    assert!(c.valid_until().is_some());
+
+    c.set_token(admin_token).unwrap();
+    c.del_user(UserQuery::ById { user_id }).unwrap();
+
+    let mut anonymous = crate::client::blocking::Client::new("http://127.0.0.1:8080/v1/").unwrap();
+    let err = anonymous.refresh_and_store().unwrap_err();
+    assert!(err.matches_api_code(ErrorCode::MissingToken));
+}
+
+#[test]
+fn test_get_schema() {
+    let c = prep();
+
+    let methods = c.get_schema().unwrap().methods;
+    let login = methods.iter().find(|method| method.method == "login").unwrap();
+    let params: Vec<_> = login.params.iter().map(|param| param.name.as_str()).collect();
+    assert_eq!(params, ["username", "password"]);
+    assert_eq!(login.response, "Token");
+    assert!(methods.iter().any(|method| method.method == "get_schema"));
+}
+
+#[test]
+fn test_body_limit() {
+    let _c = prep();
+
+    let resp = reqwest::blocking::Client::new()
+        .post("http://127.0.0.1:8080/v1/health")
+        .header("content-type", "application/json")
+        .body(vec![b' '; 5 * 1024 * 1024])
+        .send()
+        .unwrap();
+    assert_eq!(resp.status(), 413);
+
+    let resp: ResponseObject<ApiError> = resp.json().unwrap();
+    assert_eq!(resp.code(), ErrorCode::PayloadTooLarge);
+}
+
+#[test]
+fn test_request_id() {
+    let _c = prep();
+    let http = reqwest::blocking::Client::new();
+
+    // Given by the client
+    let resp = http
+        .post("http://127.0.0.1:8080/v1/get_schema")
+        .header("x-request-id", "req-114514")
+        .json(&serde_json::json!({}))
+        .send()
+        .unwrap();
+    assert_eq!(resp.headers()["x-request-id"], "req-114514");
+    let resp: ResponseObject<serde_json::Value> = resp.json().unwrap();
+    assert_eq!(resp.request_id.as_deref(), Some("req-114514"));
+
+    // or generated
+    let resp = http
+        .post("http://127.0.0.1:8080/v1/get_schema")
+        .json(&serde_json::json!({}))
+        .send()
+        .unwrap();
+    let header = resp.headers()["x-request-id"].to_str().unwrap().to_owned();
+    let resp: ResponseObject<serde_json::Value> = resp.json().unwrap();
+    assert_eq!(resp.request_id, Some(header));
+}
+
+#[test]
+fn test_jwks_route() {
+    let _c = prep();
+
+    let resp = reqwest::blocking::get("http://127.0.0.1:8080/v1/jwks.json").unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // Tokens are signed with a shared secret in tests, so there's no public key
+    let jwks: serde_json::Value = resp.json().unwrap();
+    assert_eq!(jwks, serde_json::json!({ "keys": [] }));
+}
+
+#[test]
+fn test_detailed_health() {
+    let c = prep();
+
+    let report = c.detailed_health().unwrap();
+    assert!(report.database);
+    assert!(!report.coordinator);
+    // No event is consumed in tests
+    assert_eq!(report.message_queue, None);
+    // Workers are only reported, since `ready_requires_workers` isn't set
+    assert!(report.ready);
+
+    let resp = reqwest::blocking::get("http://127.0.0.1:8080/v1/ready").unwrap();
+    assert_eq!(resp.status(), if report.ready { 200 } else { 503 });
+    let probed: serde_json::Value = resp.json().unwrap();
+    assert_eq!(probed["database"], true);
+}
+
+#[test]
+fn test_export_import_entities() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta, vec![], None, None, false).unwrap();
+
+    let http = reqwest::blocking::Client::new();
+    let export = http
+        .get("http://127.0.0.1:8080/v1/export_entities")
+        .bearer_auth(c.token().unwrap())
+        .send()
+        .unwrap();
+    assert_eq!(export.headers()["content-type"], "application/x-ndjson");
+    let ndjson = export.text().unwrap();
+    let exported: Vec<Entity> = ndjson
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(exported.contains(&entity));
+
+    // Importing the export back replaces entities with themselves
+    c.del_entity(entity.id, true).unwrap();
+    let resp: ResponseObject<Imported> = http
+        .post("http://127.0.0.1:8080/v1/import_entities")
+        .bearer_auth(c.token().unwrap())
+        .body(ndjson)
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(resp.count, exported.len() as u64);
+    assert!(c
+        .get_entities(None, None, true, false)
+        .unwrap()
+        .vtbs
+        .contains(&entity));
+
+    // Invalid lines are rejected
+    let resp = http
+        .post("http://127.0.0.1:8080/v1/import_entities")
+        .bearer_auth(c.token().unwrap())
+        .body("\n{}\n")
+        .send()
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+
+    // Lines are limited to the size of a request body
+    let resp = http
+        .post("http://127.0.0.1:8080/v1/import_entities")
+        .bearer_auth(c.token().unwrap())
+        .body(" ".repeat(Config::default().max_body_bytes + 1))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status(), 413);
+
+    // Admin only
+    let resp = http
+        .get("http://127.0.0.1:8080/v1/export_entities")
+        .send()
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
 #[test]
 fn test_get_entities() {
     let c = prep();
 
-    c.get_entities().unwrap();
+    let all = c.get_entities(None, None, false, false).unwrap();
+    assert!(all.tasks.is_empty());
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let param = AddTaskParam::Twitter { id: gen_payload() };
+    let entity = c.add_entity(meta, vec![param], None, None, false).unwrap();
+
+    // Tasks are ids unless asked for in full
+    let all = c.get_entities(None, None, false, true).unwrap();
+    let tasks: Vec<_> = all.tasks.iter().filter(|x| x.entity == entity.id).collect();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(entity.tasks, [tasks[0].id]);
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_get_entities_paged() {
+    let c = prep();
+
+    let all = c.get_entities(None, None, false, false).unwrap();
+    assert_eq!(all.next_cursor, None);
+
+    let mut paged = vec![];
+    let mut after = None;
+    loop {
+        let page = c.get_entities(2, after, false, false).unwrap();
+        assert!(page.vtbs.len() <= 2);
+        paged.extend(page.vtbs);
+        match page.next_cursor {
+            Some(cursor) => after = Some(cursor),
+            None => break,
+        }
+    }
+
+    assert_eq!(all.vtbs, paged);
+
+    // Non-positive limit is rejected
+    let err = c.get_entities(0, None, false, false).unwrap_err();
+    assert!(err.matches_api_status(400));
+}
+
+#[test]
+fn test_count_entities() {
+    let c = prep();
+
+    let name = Name {
+        name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+        default_language: LanguageCode::En,
+    };
+    let group = c.add_group(name.clone()).unwrap().id;
+    let meta = Meta {
+        name,
+        group: Some(group),
+        debut: None,
+        links: HashMap::new(),
+    };
+    let first = c.add_entity(meta.clone(), vec![], None, None, false).unwrap();
+    let second = c.add_entity(meta, vec![], None, None, false).unwrap();
+
+    let total = c.count_entities(None, true).unwrap();
+    let all = c.get_entities(None, None, false, false).unwrap().vtbs;
+    assert_eq!(total.count, all.len() as u64);
+    assert_eq!(total.groups[&group.to_string()], 2);
+
+    let in_group = c.count_entities(group, false).unwrap();
+    assert_eq!(in_group.count, 2);
+    assert!(in_group.groups.is_empty());
+
+    c.del_entity(second.id, true).unwrap();
+    assert_eq!(c.count_entities(group, true).unwrap().count, 1);
+
+    c.del_entity(first.id, true).unwrap();
+    c.del_group(group).unwrap();
+}
+
+#[test]
+fn test_groups() {
+    let c = prep();
+
+    let name = |s: &str| Name {
+        name: HashMap::from([(LanguageCode::En, s.to_owned())]),
+        default_language: LanguageCode::En,
+    };
+    let group = c.add_group(name("Group")).unwrap();
+    let entity = c
+        .add_entity(
+            Meta {
+                name: name("Pop"),
+                group: Some(group.id),
+                debut: None,
+                links: HashMap::new(),
+            },
+            vec![],
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+
+    let renamed = c.update_group(group.id, name("Renamed")).unwrap();
+    assert_eq!(renamed.id, group.id);
+    assert_eq!(renamed.name, name("Renamed"));
+    let groups = c.get_entities(None, None, false, false).unwrap().groups;
+    assert!(groups.contains(&renamed));
+
+    // Entities can't be put in a group that doesn't exist
+    let err = c
+        .update_entity(
+            entity.id,
+            Meta {
+                name: name("Pop"),
+                group: Some(Uuid::new()),
+                debut: None,
+                links: HashMap::new(),
+            },
+        )
+        .unwrap_err();
+    assert!(err.matches_api_code(ErrorCode::GroupNotFound));
+
+    // Deleting the group detaches its entities
+    c.del_group(group.id).unwrap();
+    let entity = c
+        .get_entities(None, None, false, false)
+        .unwrap()
+        .vtbs
+        .into_iter()
+        .find(|x| x.id == entity.id)
+        .unwrap();
+    assert_eq!(entity.meta.group, None);
+    let err = c.del_group(group.id).unwrap_err();
+    assert!(err.matches_api_status(404));
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_get_bots() {
+    let c = prep();
+
+    let bots = c.get_bots().unwrap().bots;
+    assert!(bots.windows(2).all(|w| w[0].name <= w[1].name));
+}
+
+#[test]
+fn test_new_bot() {
+    let mut c = prep();
+
+    let info = c.new_bot("Test Bot").unwrap();
+    assert_eq!(info.bot.name, "Test Bot");
+    assert!(c.get_bots().unwrap().bots.contains(&info.bot));
+
+    // Act as the new bot
+    let admin_token = c.set_token(info.token).unwrap();
+
+    let user = c
+        .add_user("tg", gen_payload(), URL.clone(), "Pop", false)
+        .unwrap();
+    c.del_user(UserQuery::ById { user_id: user.id }).unwrap();
+
+    // But it's not an admin
+    let err = c.new_bot("Another Bot").unwrap_err();
+    assert!(err.matches_api_status(401));
+
+    c.set_token(admin_token).unwrap();
+}
+
+#[test]
+fn test_add_entity_idempotent() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let key = gen_payload();
+
+    let first = c.add_entity(meta.clone(), vec![], key.clone(), None, false).unwrap();
+    // Retried request with the same key gets the same entity instead of a new one
+    let second = c.add_entity(meta, vec![], key, None, false).unwrap();
+    assert_eq!(first, second);
+
+    c.del_entity(first.id, true).unwrap();
+}
+
+#[test]
+fn test_add_entity_idempotent_concurrently() {
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let key = gen_payload();
+
+    let results: Vec<_> = std::thread::scope(|s| {
+        let requests: Vec<_> = (0..4)
+            .map(|_| s.spawn(|| prep().add_entity(meta.clone(), vec![], key.clone(), None, false)))
+            .collect();
+        requests.into_iter().map(|r| r.join().unwrap()).collect()
+    });
+
+    // Only one request adds the entity, others get it or are told to retry later
+    let ids: HashSet<_> = results.iter().flatten().map(|entity| entity.id).collect();
+    assert_eq!(ids.len(), 1);
+    for err in results.iter().filter_map(|r| r.as_ref().err()) {
+        assert!(err.matches_api_code(ErrorCode::RequestInProgress));
+    }
+
+    let id = ids.into_iter().next().unwrap();
+    prep().del_entity(id, true).unwrap();
+}
+
+#[test]
+fn test_add_entity_idempotent_after_lease() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let key = gen_payload();
+
+    // The key is held by a request that never finished, e.g. its node crashed
+    let record = IdempotencyRecord {
+        method: "add_entity".to_owned(),
+        key: key.clone(),
+        response: None,
+        pending_until: Some(DateTime::now()),
+        exp: DateTime::from_system_time(SystemTime::now() + Duration::from_secs(60)),
+    };
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = Config::default();
+        let mongo_uri = std::env::var("MONGODB_URI").unwrap_or(config.mongo_uri);
+        mongodb::Client::with_uri_str(&mongo_uri)
+            .await
+            .unwrap()
+            .database(&config.mongo_db)
+            .collection(&config.idempotency_collection)
+            .insert_one(record, None)
+            .await
+            .unwrap();
+    });
+
+    // Its lease is over, so the key is taken over instead of being in progress forever
+    let entity = c.add_entity(meta, vec![], key, None, false).unwrap();
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_add_entity_by_external_id() {
+    let c = prep();
+
+    let meta = |name: &str| Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, name.to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let external_id = format!("test:{}", gen_payload());
+
+    let first = c
+        .add_entity(meta("Pop"), vec![], None, external_id.clone(), false)
+        .unwrap();
+    assert_eq!(first.id, Entity::id_of_external(&external_id));
+    assert_eq!(first.external_id.as_deref(), Some(external_id.as_str()));
+
+    // Importing again is a conflict
+    let err = c
+        .add_entity(meta("Pop"), vec![], None, external_id.clone(), false)
+        .unwrap_err();
+    assert!(err.matches_api_code(ErrorCode::EntityAlreadyExists));
+
+    // unless upserted
+    let upserted = c
+        .add_entity(meta("Renamed"), vec![], None, external_id, true)
+        .unwrap();
+    assert_eq!(upserted.id, first.id);
+    assert_eq!(upserted.meta, meta("Renamed"));
+
+    c.del_entity(first.id, true).unwrap();
+}
+
+#[test]
+fn test_add_task_to_missing_entity() {
+    let c = prep();
+
+    let err = c
+        .add_task(
+            AddTaskParam::Twitter { id: gen_payload() },
+            Uuid::new(),
+            None,
+            None,
+            HashSet::new(),
+        )
+        .unwrap_err();
+    assert!(err.matches_api_status(404));
+    assert!(err.matches_api_code(ErrorCode::EntityNotFound));
+}
+
+#[test]
+fn test_task_schedule() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta, vec![], None, None, false).unwrap();
+    let param = || AddTaskParam::Twitter { id: gen_payload() };
+
+    let schedule = Schedule::Interval(Duration::from_secs(300));
+    let task = c
+        .add_task(param(), entity.id, None, schedule.clone(), HashSet::new())
+        .unwrap();
+    assert_eq!(task.schedule, Some(schedule));
+
+    let schedule = Schedule::Cron(String::from("*/5 * * * *"));
+    let task = c
+        .add_task(param(), entity.id, None, schedule.clone(), HashSet::new())
+        .unwrap();
+    assert_eq!(task.schedule, Some(schedule));
+
+    let err = c
+        .add_task(
+            param(),
+            entity.id,
+            None,
+            Schedule::Cron(String::from("61 * * * *")),
+            HashSet::new(),
+        )
+        .unwrap_err();
+    assert!(err.matches_api_status(400));
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_task_tags() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta, vec![], None, None, false).unwrap();
+    let tags = HashSet::from([String::from("jp")]);
+    let task = c
+        .add_task(AddTaskParam::Twitter { id: gen_payload() }, entity.id, None, None, tags.clone())
+        .unwrap();
+    assert_eq!(task.required_tags, tags);
+
+    // Only tasks requiring tags can be unschedulable
+    let tasks = c.get_unschedulable_tasks().unwrap().tasks;
+    assert!(tasks.iter().all(|task| !task.required_tags.is_empty()));
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_disable_task() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta, vec![], None, None, false).unwrap();
+    let task = c
+        .add_task(AddTaskParam::Twitter { id: gen_payload() }, entity.id, None, None, HashSet::new())
+        .unwrap();
+    assert!(task.enabled);
+
+    let disabled = c.disable_task(task.id).unwrap();
+    assert!(!disabled.enabled);
+    assert_eq!(disabled.params, task.params);
+    assert!(c.enable_task(task.id).unwrap().enabled);
+
+    let err = c.disable_task(Uuid::new()).unwrap_err();
+    assert!(err.matches_api_code(ErrorCode::TaskNotFound));
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_del_task() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta, vec![], None, None, false).unwrap();
+    let task = c
+        .add_task(AddTaskParam::Twitter { id: gen_payload() }, entity.id, None, None, HashSet::new())
+        .unwrap();
+
+    c.del_task(task.id).unwrap();
+
+    // The task is no longer referenced by its entity
+    let entity = c
+        .get_entities(None, None, false, false)
+        .unwrap()
+        .vtbs
+        .into_iter()
+        .find(|x| x.id == entity.id)
+        .unwrap();
+    assert!(!entity.tasks.contains(&task.id));
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_add_tasks() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta, vec![], None, None, false).unwrap();
+
+    let params = vec![
+        AddTaskParam::Twitter { id: gen_payload() },
+        AddTaskParam::Bilibili { uid: gen_payload() },
+    ];
+    let tasks = c.add_tasks(entity.id, params).unwrap().tasks;
+    assert_eq!(tasks.len(), 2);
+    assert!(tasks.iter().all(|x| x.entity == entity.id));
+
+    let entity = c
+        .get_entities(None, None, false, false)
+        .unwrap()
+        .vtbs
+        .into_iter()
+        .find(|x| x.id == entity.id)
+        .unwrap();
+    assert_eq!(entity.tasks, tasks.iter().map(|x| x.id).collect::<Vec<_>>());
+
+    // Nonexistent entity
+    let err = c
+        .add_tasks(Uuid::new(), vec![AddTaskParam::Twitter { id: gen_payload() }])
+        .unwrap_err();
+    assert!(err.matches_api_status(404));
+    assert!(err.matches_api_code(ErrorCode::EntityNotFound));
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_timestamps() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta.clone(), vec![], None, None, false).unwrap();
+    let created_at = entity.created_at.unwrap();
+    assert_eq!(entity.updated_at, Some(created_at));
+
+    let task = c
+        .add_task(AddTaskParam::Twitter { id: gen_payload() }, entity.id, None, None, HashSet::new())
+        .unwrap();
+    assert!(task.created_at.unwrap() >= created_at);
+    assert_eq!(task.updated_at, task.created_at);
+
+    // Updates are stamped, creation time is kept
+    let entity = c.update_entity(entity.id, meta).unwrap();
+    assert_eq!(entity.created_at, Some(created_at));
+    assert!(entity.updated_at.unwrap() >= task.created_at.unwrap());
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_get_entity_events() {
+    let c = prep();
+
+    // Nothing is recorded unless the event log is enabled
+    let res = c.get_entity_events(Uuid::new(), None, 10).unwrap();
+    assert!(res.events.is_empty());
+    assert_eq!(res.next_cursor, None);
+
+    let err = c.get_entity_events(Uuid::new(), None, 0).unwrap_err();
+    assert!(err.matches_api_status(400));
+    let err = c.get_entity_events(Uuid::new(), Uuid::new(), None).unwrap_err();
+    assert!(err.matches_api_status(400));
+}
+
+#[test]
+fn test_audit_log() {
+    let c = prep();
+
+    let since = SystemTime::now() - Duration::from_secs(1);
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta.clone(), vec![], None, None, false).unwrap();
+    c.update_entity(entity.id, meta).unwrap();
+    // Failed mutations are not audited
+    c.del_entity(Uuid::new(), false).unwrap_err();
+
+    let actions = |entries: Vec<AuditEntry>| -> Vec<String> {
+        entries
+            .into_iter()
+            .filter(|entry| entry.target == Some(entity.id))
+            .map(|entry| entry.action)
+            .collect()
+    };
+    let log = c.get_audit_log(None, since, None, None, None).unwrap();
+    assert_eq!(actions(log.entries), ["update_entity", "add_entity"]);
+
+    // Mutations by login tokens are attributed to the username
+    let actor = login_principal("test");
+    let log = c.get_audit_log(actor, since, None, None, None).unwrap();
+    assert_eq!(actions(log.entries), ["update_entity", "add_entity"]);
+    let others = c.get_audit_log(login_principal("other"), since, None, None, None).unwrap();
+    assert!(others.entries.is_empty());
+
+    // Paged through newest first
+    let first = c.get_audit_log(None, since, None, 1, None).unwrap();
+    assert_eq!(first.entries.len(), 1);
+    let cursor = first.next_cursor.unwrap();
+    assert_eq!(cursor, first.entries[0].id);
+    let rest = c.get_audit_log(None, since, None, None, cursor).unwrap();
+    assert!(rest.entries.iter().all(|entry| entry.at <= first.entries[0].at));
+    assert!(rest.entries.iter().all(|entry| entry.id != cursor));
+
+    // Nothing in the future
+    let future = SystemTime::now() + Duration::from_secs(60);
+    assert!(c.get_audit_log(None, future, None, None, None).unwrap().entries.is_empty());
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_dead_letters() {
+    let c = prep();
+
+    let letters = c.get_dead_letters(None, None).unwrap().letters;
+    assert!(c.get_dead_letters(0, None).is_err());
+
+    // Unknown ids are ignored
+    let mut ids: Vec<_> = letters.iter().map(|letter| letter.id).collect();
+    ids.push(Uuid::new());
+    assert_eq!(c.replay_dead_letters(ids).unwrap().count, letters.len() as u64);
+}
+
+#[test]
+fn test_search_entities() {
+    let c = prep();
+
+    // Unique and full of regex metacharacters
+    let name = format!("Hoshi.*(machi)+ {}", gen_payload());
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([
+                (LanguageCode::En, name.clone()),
+                (LanguageCode::Ja, "星街すいせい".to_owned()),
+            ]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta.clone(), vec![], None, None, false).unwrap();
+
+    // Names are bounded, since every suffix of them is a search key
+    let mut long = meta;
+    long.name.name.insert(LanguageCode::En, "a".repeat(65));
+    assert!(c
+        .add_entity(long, vec![], None, None, false)
+        .unwrap_err()
+        .matches_api_status(400));
+
+    let found = |query: &str, lang: Option<LanguageCode>| {
+        c.search_entities(query, lang, None)
+            .unwrap()
+            .vtbs
+            .into_iter()
+            .any(|x| x.id == entity.id)
+    };
+
+    // Case-insensitive substring, matched literally
+    assert!(found(&name[1..].to_uppercase(), None));
+    assert!(found(&name, Some(LanguageCode::En)));
+    assert!(!found("Hoshimachi", None));
+    // In given language only
+    assert!(found("すいせい", None));
+    assert!(!found("すいせい", Some(LanguageCode::En)));
+
+    // Soft-deleted entities are excluded
+    c.del_entity(entity.id, false).unwrap();
+    assert!(!found(&name, None));
+
+    assert!(c.search_entities("", None, None).unwrap_err().matches_api_status(400));
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_soft_delete_entity() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta.clone(), vec![], None, None, false).unwrap();
+    let listed = |include_deleted| {
+        c.get_entities(None, None, include_deleted, false)
+            .unwrap()
+            .vtbs
+            .into_iter()
+            .any(|x| x.id == entity.id)
+    };
+
+    let deleted = c.del_entity(entity.id, false).unwrap();
+    assert!(deleted.deleted_at.is_some());
+    assert!(!listed(false));
+    assert!(listed(true));
+
+    // Deleted entities can't be deleted again or get new tasks
+    assert!(c.del_entity(entity.id, false).unwrap_err().matches_api_status(404));
+    assert!(c
+        .add_task(AddTaskParam::Twitter { id: gen_payload() }, entity.id, None, None, HashSet::new())
+        .unwrap_err()
+        .matches_api_status(404));
+    // or be updated
+    assert!(c.update_entity(entity.id, meta).unwrap_err().matches_api_status(404));
+    assert!(c
+        .patch_entity_meta(
+            entity.id,
+            HashMap::from([(LanguageCode::Ja, "ポップ".to_owned())]),
+            vec![],
+            None,
+            None::<Option<Uuid>>,
+            None::<Option<DateTime>>,
+            HashMap::new(),
+            vec![],
+        )
+        .unwrap_err()
+        .matches_api_status(404));
+
+    let restored = c.restore_entity(entity.id).unwrap();
+    assert!(restored.deleted_at.is_none());
+    assert!(listed(false));
+    assert!(c.restore_entity(entity.id).unwrap_err().matches_api_status(404));
+
+    c.del_entity(entity.id, true).unwrap();
+    assert!(!listed(true));
+}
+
+#[test]
+fn test_merge_entities() {
+    let mut c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let from = c.add_entity(meta.clone(), vec![], None, None, false).unwrap();
+    let into = c.add_entity(meta, vec![], None, None, false).unwrap();
+    let task = c
+        .add_task(AddTaskParam::Twitter { id: gen_payload() }, from.id, None, None, HashSet::new())
+        .unwrap();
+
+    let user_id = c
+        .add_user("tg", gen_payload(), URL.clone(), "Pop", false)
+        .unwrap()
+        .id;
+    let user_token = c.new_token(UserQuery::ById { user_id }).unwrap().token;
+    let admin_token = c.set_token(user_token.clone()).unwrap();
+    c.subscribe_entities(vec![from.id]).unwrap();
+    c.subscribe_kinds(vec!["twitter".to_owned()]).unwrap();
+    c.set_token(admin_token.clone()).unwrap();
+
+    assert!(c.merge_entities(from.id, from.id).unwrap_err().matches_api_status(400));
+    assert!(c.merge_entities(from.id, Uuid::new()).unwrap_err().matches_api_status(404));
+
+    let merged = c.merge_entities(from.id, into.id).unwrap();
+    assert_eq!(merged.tasks, [task.id]);
+    let from = c
+        .get_entities(None, None, true, false)
+        .unwrap()
+        .vtbs
+        .into_iter()
+        .find(|x| x.id == from.id)
+        .unwrap();
+    assert!(from.deleted_at.is_some());
+    assert!(from.tasks.is_empty());
+    // Merged entities can't be merged again
+    assert!(c.merge_entities(from.id, into.id).unwrap_err().matches_api_status(404));
+    // Nor restored
+    assert!(c.restore_entity(from.id).unwrap_err().matches_api_status(400));
+
+    // Subscribers are moved, and events of the old id reach them
+    c.set_token(user_token).unwrap();
+    let user = c.auth_user().unwrap().user;
+    assert_eq!(user.event_filter.entities, HashSet::from([into.id]));
+    c.set_token(admin_token).unwrap();
+    let interest = c.get_interest(from.id, "twitter", "tg").unwrap();
+    assert!(interest.users.iter().any(|user| user.id == user_id));
+
+    c.del_user(UserQuery::ById { user_id }).unwrap();
+    c.del_entity(into.id, true).unwrap();
+    c.del_entity(from.id, true).unwrap();
+}
+
+#[test]
+fn test_reassign_entity() {
+    let c = prep();
+
+    let meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::En, "Pop".to_owned())]),
+            default_language: LanguageCode::En,
+        },
+        group: None,
+        debut: None,
+        links: HashMap::new(),
+    };
+    let entity = c.add_entity(meta, vec![], None, None, false).unwrap();
+    assert_eq!(entity.reassignment, None);
+
+    // Left for the coordinator to apply
+    let worker = Uuid::new();
+    let reassigned = c.reassign_entity(entity.id, worker).unwrap();
+    assert_eq!(reassigned.reassignment.unwrap().target, Some(worker));
+    let reassigned = c.reassign_entity(entity.id, None).unwrap();
+    assert_eq!(reassigned.reassignment.unwrap().target, None);
+
+    assert!(c.reassign_entity(Uuid::new(), None).unwrap_err().matches_api_status(404));
+    c.del_entity(entity.id, false).unwrap();
+    assert!(c.reassign_entity(entity.id, None).unwrap_err().matches_api_status(404));
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_entity_links() {
+    let c = prep();
+
+    let debut = DateTime::from_millis(1_521_072_000_000);
+    let mut meta = Meta {
+        name: Name {
+            name: HashMap::from([(LanguageCode::Ja, "星街すいせい".to_owned())]),
+            default_language: LanguageCode::Ja,
+        },
+        group: None,
+        debut: Some(debut),
+        links: HashMap::from([("twitter".to_owned(), "https://twitter.com/suisei".to_owned())]),
+    };
+    let entity = c.add_entity(meta.clone(), vec![], None, None, false).unwrap();
+    assert_eq!(entity.meta, meta);
+
+    meta.links.insert("youtube".to_owned(), "not a url".to_owned());
+    assert!(c
+        .update_entity(entity.id, meta.clone())
+        .unwrap_err()
+        .matches_api_status(400));
+
+    let youtube = "https://www.youtube.com/@HoshimachiSuisei".to_owned();
+    let patched = c
+        .patch_entity_meta(
+            entity.id,
+            HashMap::new(),
+            vec![],
+            None,
+            None::<Option<Uuid>>,
+            Some(None),
+            HashMap::from([("youtube".to_owned(), youtube.clone())]),
+            vec!["twitter".to_owned()],
+        )
+        .unwrap();
+    assert_eq!(patched.meta.debut, None);
+    assert_eq!(patched.meta.links, HashMap::from([("youtube".to_owned(), youtube)]));
+
+    c.del_entity(entity.id, true).unwrap();
+}
+
+#[test]
+fn test_set_webhook() {
+    let c = prep();
+
+    let url: Url = "https://example.com/hook".parse().unwrap();
+    let hook = c
+        .set_webhook(None::<Uuid>, "integrator", url.clone(), Some("secret".to_owned()))
+        .unwrap();
+    assert_eq!(hook.im, webhook::PLATFORM);
+    let target = WebhookTarget::from_payload(&hook.im_payload).unwrap();
+    assert_eq!(target.callback_url, url);
+    assert_eq!(target.secret.as_deref(), Some("secret"));
+
+    // Rotate the secret away
+    let updated = c.set_webhook(hook.id, "integrator", url, None).unwrap();
+    assert_eq!(updated.id, hook.id);
+    assert_eq!(WebhookTarget::from_payload(&updated.im_payload).unwrap().secret, None);
+
+    let ftp: Url = "ftp://example.com".parse().unwrap();
+    assert!(c
+        .set_webhook(hook.id, "integrator", ftp, None)
+        .unwrap_err()
+        .matches_api_status(400));
+
+    // Only webhooks can be updated
+    let user = c.add_user("tg", gen_payload(), None::<Url>, "user", false).unwrap();
+    let url: Url = "https://example.com/hook".parse().unwrap();
+    assert!(c
+        .set_webhook(user.id, "integrator", url, None)
+        .unwrap_err()
+        .matches_api_status(404));
+
+    for id in [hook.id, user.id] {
+        c.del_user(UserQuery::ById { user_id: id }).unwrap();
+    }
 }
 
 #[test]
@@ -268,6 +1504,58 @@ fn test_delete_nonexistent_user() {
     }
 }
 
+#[test]
+fn test_del_users() {
+    let c = prep();
+
+    // Unique im so that other tests are not affected
+    let im = format!("deprecated-{}", gen_payload());
+    for _ in 0..3 {
+        c.add_user(im.clone(), gen_payload(), URL.clone(), "Pop", false)
+            .unwrap();
+    }
+
+    let query = BulkUserQuery::InIm { im };
+    assert_eq!(c.del_users(query.clone(), false).unwrap().count, 3);
+    assert_eq!(c.del_users(query, false).unwrap().count, 0);
+
+    // A single user can be deleted too
+    let user = c
+        .add_user("tg", gen_payload(), URL.clone(), "Pop", false)
+        .unwrap();
+    let query = UserQuery::ById { user_id: user.id };
+    assert_eq!(c.del_users(BulkUserQuery::from(query), false).unwrap().count, 1);
+
+    // Deleting everyone must be confirmed
+    let err = c.del_users(None, false).unwrap_err();
+    assert!(err.matches_api_code(ErrorCode::BadRequest));
+}
+
+#[test]
+fn test_user_query_by_im() {
+    let mut c = prep();
+
+    let payload = gen_payload();
+    let user = c
+        .add_user("tg", payload.clone(), URL.clone(), "Pop", false)
+        .unwrap();
+    let query = UserQuery::ByIm {
+        im: "tg".to_owned(),
+        im_payload: payload,
+    };
+
+    // Token can be issued without knowing the user id
+    let token = c.new_token(query.clone()).unwrap().token;
+    let admin_token = c.set_token(token).unwrap();
+    assert_eq!(c.auth_user().unwrap().user, user);
+
+    c.set_token(admin_token).unwrap();
+    assert_eq!(c.del_user(query.clone()).unwrap(), user);
+
+    let err = c.new_token(query).unwrap_err();
+    assert!(err.matches_api_status(404));
+}
+
 #[test]
 fn test_update_user_settings() {
     let mut c = prep();
@@ -279,6 +1567,7 @@ fn test_update_user_settings() {
             gen_payload(),
             URL.clone(),
             "Pop".to_owned(),
+            false,
         )
         .unwrap()
         .id;
@@ -294,6 +1583,9 @@ fn test_update_user_settings() {
         entities: HashSet::from_iter([
             Uuid::parse_str("a1e28c88-be24-48b0-b18a-81531e669905").unwrap()
         ]),
+        groups: HashSet::from_iter([
+            Uuid::parse_str("0d8e1a5c-3bc0-4e37-9a3c-5c5a2d1b7f10").unwrap()
+        ]),
         kinds: HashSet::from_iter(["twitter/new_tweet".to_owned()]),
     };
 
@@ -306,3 +1598,77 @@ fn test_update_user_settings() {
     // Assert they are the equal
     assert_eq!(user.event_filter, event_filter);
 }
+
+#[test]
+fn test_set_mute() {
+    let mut c = prep();
+
+    let user_id = c
+        .add_user("tg", gen_payload(), URL.clone(), "Pop", false)
+        .unwrap()
+        .id;
+    let token = c.new_token(UserQuery::ById { user_id }).unwrap().token;
+    c.set_token(token).unwrap();
+
+    // Whole seconds, so it's not truncated in the request
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    let until = SystemTime::UNIX_EPOCH + Duration::from_secs(now.as_secs() + 60 * 60);
+    let user = c.set_mute(until).unwrap();
+    assert_eq!(user.muted_until, Some(DateTime::from_system_time(until)));
+    assert!(user.is_muted_at(DateTime::now()));
+
+    let user = c.set_mute(None).unwrap();
+    assert_eq!(user.muted_until, None);
+}
+
+#[test]
+fn test_set_language() {
+    let mut c = prep();
+
+    let user_id = c
+        .add_user("tg", gen_payload(), URL.clone(), "Pop", false)
+        .unwrap()
+        .id;
+    let token = c.new_token(UserQuery::ById { user_id }).unwrap().token;
+    c.set_token(token).unwrap();
+
+    let user = c.set_language(LanguageCode::Ja).unwrap();
+    assert_eq!(user.preferred_language, LanguageCode::Ja);
+    assert_eq!(c.auth_user().unwrap().user.preferred_language, LanguageCode::Ja);
+}
+
+#[test]
+fn test_subscribe() {
+    let mut c = prep();
+
+    let user_id = c
+        .add_user("tg", gen_payload(), URL.clone(), "Pop", false)
+        .unwrap()
+        .id;
+    let token = c.new_token(UserQuery::ById { user_id }).unwrap().token;
+    c.set_token(token).unwrap();
+
+    let (a, b) = (Uuid::new(), Uuid::new());
+    c.subscribe_entities(vec![a]).unwrap();
+    // Subscribing twice is fine
+    let user = c.subscribe_entities(vec![a, b]).unwrap();
+    assert_eq!(user.event_filter.entities, HashSet::from([a, b]));
+
+    let user = c.unsubscribe_entities(vec![a]).unwrap();
+    assert_eq!(user.event_filter.entities, HashSet::from([b]));
+
+    c.subscribe_kinds(vec!["twitter/new_tweet".to_owned()])
+        .unwrap();
+    let user = c
+        .subscribe_kinds(vec!["bililive/live_start".to_owned()])
+        .unwrap();
+    assert_eq!(user.event_filter.kinds.len(), 2);
+    let user = c
+        .unsubscribe_kinds(vec!["twitter/new_tweet".to_owned()])
+        .unwrap();
+    assert_eq!(
+        user.event_filter.kinds,
+        HashSet::from(["bililive/live_start".to_owned()])
+    );
+    assert_eq!(user.event_filter.entities, HashSet::from([b]));
+}
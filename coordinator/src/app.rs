@@ -1,18 +1,23 @@
 //! Application state.
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap, HashSet},
     error::Error,
     ops::Deref,
     result::Result as StdResult,
     str::FromStr,
     sync::Arc,
+    time::SystemTime,
 };
 
 use eyre::Result;
-use sg_core::models::Task;
+use sg_core::{
+    message::{PROTOCOL_HEADER, PROTOCOL_VERSION},
+    models::Task,
+    protocol::{MAX_WEIGHT, VERSION_HEADER, WEIGHT_HEADER},
+};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{mpsc::UnboundedSender, Mutex},
 };
 use tokio_tungstenite::tungstenite::{
     handshake::server::{ErrorResponse, Request, Response},
@@ -23,6 +28,7 @@ use uuid::Uuid;
 
 use crate::{
     config::Config,
+    state::GroupState,
     worker::{Worker, WorkerGroup},
 };
 
@@ -37,6 +43,16 @@ impl App {
         Self(Arc::new(AppImpl::new(config)))
     }
 
+    /// Create a new application state, sending snapshots of worker groups to `snapshots` to be
+    /// persisted.
+    #[must_use]
+    pub fn with_snapshots(config: Config, snapshots: UnboundedSender<GroupState>) -> Self {
+        Self(Arc::new(AppImpl {
+            snapshots: Some(snapshots),
+            ..AppImpl::new(config)
+        }))
+    }
+
     /// Serve the application.
     ///
     /// # Errors
@@ -73,17 +89,41 @@ pub struct AppImpl {
     /// Worker groups.
     pub worker_groups: Mutex<HashMap<String, WorkerGroup>>,
     config: Config,
+    snapshots: Option<UnboundedSender<GroupState>>,
 }
 
 struct WorkerMeta {
     id: Uuid,
     kind: String,
+    tags: HashSet<String>,
+    /// Capacity relative to other workers of the kind.
+    weight: u32,
+    /// Last assignment version seen by a reconnecting worker.
+    version: Option<u64>,
 }
 
 impl TryFrom<&HeaderMap> for WorkerMeta {
     type Error = Box<dyn Error>;
 
     fn try_from(headers: &HeaderMap) -> StdResult<Self, Box<dyn Error>> {
+        // Workers predating the header speak the first version, which is no longer supported.
+        let protocol: u32 = headers
+            .get(PROTOCOL_HEADER)
+            .ok_or_else(|| {
+                format!(
+                    "missing header: {}, legacy workers must upgrade to protocol version {}",
+                    PROTOCOL_HEADER, PROTOCOL_VERSION
+                )
+            })?
+            .to_str()?
+            .parse()?;
+        if protocol != PROTOCOL_VERSION {
+            return Err(format!(
+                "unsupported protocol version: {}, expected {}",
+                protocol, PROTOCOL_VERSION
+            )
+            .into());
+        }
         let id = Uuid::from_str(
             headers
                 .get("Sg-Worker-ID")
@@ -95,7 +135,35 @@ impl TryFrom<&HeaderMap> for WorkerMeta {
             .ok_or("missing header: Sg-Worker-Kind")?
             .to_str()?
             .to_string();
-        Ok(Self { id, kind })
+        // Comma-separated, optional.
+        let tags = match headers.get("Sg-Worker-Tags") {
+            Some(tags) => tags
+                .to_str()?
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(ToString::to_string)
+                .collect(),
+            None => HashSet::new(),
+        };
+        let weight = match headers.get(WEIGHT_HEADER) {
+            Some(weight) => weight.to_str()?.parse()?,
+            None => 1,
+        };
+        if !(1..=MAX_WEIGHT).contains(&weight) {
+            return Err(format!("worker weight must be between 1 and {}", MAX_WEIGHT).into());
+        }
+        let version = match headers.get(VERSION_HEADER) {
+            Some(version) => Some(version.to_str()?.parse()?),
+            None => None,
+        };
+        Ok(Self {
+            id,
+            kind,
+            tags,
+            weight,
+            version,
+        })
     }
 }
 
@@ -106,20 +174,77 @@ impl AppImpl {
         Self {
             worker_groups: Default::default(),
             config,
+            snapshots: None,
+        }
+    }
+
+    /// Get the worker group of `kind`, creating it if it doesn't exist.
+    async fn group_of<'a>(
+        &self,
+        groups: &'a mut HashMap<String, WorkerGroup>,
+        kind: String,
+    ) -> &'a WorkerGroup {
+        match groups.entry(kind) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let group = WorkerGroup::with_vnodes(self.config.vnodes);
+                if let Some(tx) = &self.snapshots {
+                    let kind = entry.key().clone();
+                    group.with(|group| group.persist_to(kind, tx.clone())).await;
+                }
+                entry.insert(group)
+            }
+        }
+    }
+
+    /// Resume worker groups from states persisted before the last restart.
+    ///
+    /// Workers that haven't answered a ping in two ping intervals are considered gone. Assignments
+    /// to the others are honored for one ping interval, giving them time to reconnect.
+    pub async fn resume(&self, states: Vec<GroupState>) {
+        let now = SystemTime::now();
+        let grace = self.config.ping_interval;
+        let mut groups = self.worker_groups.lock().await;
+        for state in states {
+            let state = state.prune(now, grace * 2);
+            info!(
+                kind = %state.kind,
+                workers = state.workers.len(),
+                tasks = state.assignments.len(),
+                "Resuming worker group"
+            );
+            self.group_of(&mut groups, state.kind.clone())
+                .await
+                .with(|group| group.resume(state, grace))
+                .await;
         }
     }
 
     /// Add a task to worker group of its kind.
     pub async fn add_task(&self, task: Task) {
-        self.worker_groups
-            .lock()
+        let mut groups = self.worker_groups.lock().await;
+        self.group_of(&mut groups, task.kind.clone())
             .await
-            .entry(task.kind.clone())
-            .or_insert_with(WorkerGroup::new)
             .with(|group| group.add_task(task))
             .await;
     }
 
+    /// Pin tasks of an entity to `target` worker, or to the next-best worker other than the current
+    /// one if `None`. See [`WorkerGroupImpl::reassign_entity`](crate::worker::WorkerGroupImpl::reassign_entity).
+    ///
+    /// Return the new owner of each task of the entity.
+    pub async fn reassign_entity(&self, entity: Uuid, target: Option<Uuid>) -> HashMap<Uuid, Uuid> {
+        let mut owners = HashMap::new();
+        for group in self.worker_groups.lock().await.values() {
+            owners.extend(
+                group
+                    .with(|group| group.reassign_entity(entity, target))
+                    .await,
+            );
+        }
+        owners
+    }
+
     /// Remove a task from worker groups.
     pub async fn remove_task(&self, id: Uuid) {
         for group in self.worker_groups.lock().await.values_mut() {
@@ -158,13 +283,28 @@ impl AppImpl {
 
         // Spawn worker and add worker to a worker group.
         let mut worker_groups = self.worker_groups.lock().await;
-        let worker_group = worker_groups
-            .entry(worker_meta.kind)
-            .or_insert_with(WorkerGroup::new);
-        let worker = Worker::new(worker_meta.id, stream, worker_group.weak(), &self.config);
-        worker_group
-            .with(|worker_group| worker_group.add_worker(worker))
-            .await;
+        let worker_group = self.group_of(&mut worker_groups, worker_meta.kind).await;
+        let worker = Worker::new(
+            worker_meta.id,
+            worker_meta.tags,
+            worker_meta.weight,
+            stream,
+            worker_group.weak(),
+            &self.config,
+        );
+        match worker_meta.version {
+            Some(version) => {
+                // Don't block other groups while asking the worker for its tasks.
+                let worker_group = worker_group.weak().upgrade().expect("Group is alive");
+                drop(worker_groups);
+                worker_group.rejoin(worker, version).await;
+            }
+            None => {
+                worker_group
+                    .with(|worker_group| worker_group.add_worker(worker))
+                    .await;
+            }
+        }
 
         Ok(())
     }
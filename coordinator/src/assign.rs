@@ -0,0 +1,125 @@
+//! Assign entities to weighted workers with a consistent hash ring.
+
+use std::collections::HashMap;
+
+use consistent_hash_ring::RingBuilder;
+use uuid::Uuid;
+
+/// Default number of virtual nodes per unit of worker weight in the ring.
+pub const DEFAULT_VNODES: usize = 10;
+
+/// Assign `entities` to weighted `workers` with a consistent hash ring of [`DEFAULT_VNODES`].
+///
+/// See [`assign_with_vnodes`].
+#[must_use]
+pub fn assign(entities: &[Uuid], workers: &[(Uuid, u32)]) -> HashMap<Uuid, Vec<Uuid>> {
+    assign_with_vnodes(entities, workers, DEFAULT_VNODES)
+}
+
+/// Assign `entities` to weighted `workers` with a consistent hash ring of `vnodes` virtual nodes
+/// per unit of weight.
+///
+/// Every worker is present in the returned map, possibly with no entities. The map is empty if
+/// there's no worker.
+#[must_use]
+pub fn assign_with_vnodes(
+    entities: &[Uuid],
+    workers: &[(Uuid, u32)],
+    vnodes: usize,
+) -> HashMap<Uuid, Vec<Uuid>> {
+    let ring = RingBuilder::default()
+        .weighted_nodes_iter(workers.iter().filter_map(|(id, weight)| {
+            // Left out of the ring, i.e. with no entities, if it has too many virtual nodes.
+            Some((*id, weighted_vnodes(vnodes, *weight)?))
+        }))
+        .build();
+
+    let mut assignment: HashMap<_, _> = workers.iter().map(|(id, _)| (*id, vec![])).collect();
+    for entity in entities {
+        if let Some(worker) = ring.try_get(entity) {
+            assignment
+                .get_mut(worker)
+                .expect("ring nodes are the same as workers")
+                .push(*entity);
+        }
+    }
+    assignment
+}
+
+/// Number of virtual nodes a worker of `weight` gets on the ring, given `vnodes` per unit of
+/// weight.
+///
+/// A worker with weight 2 carries roughly twice as many entities as one with weight 1. Adding or
+/// removing one worker only moves roughly its share of the entities. More virtual nodes give a
+/// more even distribution at the cost of a larger ring.
+///
+/// Return `None` if the number overflows.
+#[must_use]
+pub const fn weighted_vnodes(vnodes: usize, weight: u32) -> Option<usize> {
+    vnodes.checked_mul(weight as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use uuid::Uuid;
+
+    use crate::assign::{assign, assign_with_vnodes, weighted_vnodes};
+
+    fn owners(assignment: &HashMap<Uuid, Vec<Uuid>>) -> HashMap<Uuid, Uuid> {
+        assignment
+            .iter()
+            .flat_map(|(worker, entities)| entities.iter().map(move |entity| (*entity, *worker)))
+            .collect()
+    }
+
+    #[test]
+    fn must_assign_all() {
+        let entities: Vec<_> = (0..100).map(|_| Uuid::new_v4()).collect();
+        let workers: Vec<_> = (0..3).map(|_| (Uuid::new_v4(), 1)).collect();
+
+        let assignment = assign(&entities, &workers);
+        assert_eq!(assignment.len(), 3);
+        assert_eq!(owners(&assignment).len(), 100);
+
+        assert!(assign(&entities, &[]).is_empty());
+    }
+
+    #[test]
+    fn must_bound_churn() {
+        let entities: Vec<_> = (0..1000).map(|_| Uuid::new_v4()).collect();
+        let workers: Vec<_> = (0..5).map(|_| (Uuid::new_v4(), 1)).collect();
+
+        let before = owners(&assign_with_vnodes(&entities, &workers, 100));
+        let after = owners(&assign_with_vnodes(&entities, &workers[1..], 100));
+
+        // Only entities of the removed worker are moved
+        let moved: Vec<_> = entities
+            .iter()
+            .filter(|entity| before[entity] != after[entity])
+            .collect();
+        assert!(moved.iter().all(|entity| before[entity] == workers[0].0));
+        // which is about 1/5 of all entities
+        assert!(moved.len() < 1000 * 2 / 5, "{} entities moved", moved.len());
+    }
+
+    #[test]
+    fn must_not_overflow() {
+        assert_eq!(weighted_vnodes(10, 3), Some(30));
+        assert_eq!(weighted_vnodes(usize::MAX, 2), None);
+    }
+
+    #[test]
+    fn must_follow_weights() {
+        let entities: Vec<_> = (0..10000).map(|_| Uuid::new_v4()).collect();
+        let light = Uuid::new_v4();
+        let heavy = Uuid::new_v4();
+
+        let assignment = assign_with_vnodes(&entities, &[(light, 1), (heavy, 2)], 100);
+
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = assignment[&heavy].len() as f64 / assignment[&light].len() as f64;
+        assert!((1.5..2.5).contains(&ratio), "ratio is {ratio}");
+    }
+}
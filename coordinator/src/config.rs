@@ -7,22 +7,50 @@ use figment::{
     providers::{Env, Serialized},
     Figment,
 };
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use sg_core::utils::{ReadMode, WriteAck};
 
 /// Coordinator config.
-#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Config {
     /// Bind address for coordinator.
     pub bind: SocketAddr,
     /// Determine how often coordinator sends ping to workers.
     #[serde(with = "humantime_serde")]
     pub ping_interval: Duration,
+    /// Fraction of `ping_interval` each ping is randomly shifted by, so that workers joined
+    /// together don't keep being pinged at once. Clamped to `0..=1`.
+    pub ping_jitter: f64,
     /// MongoDB connection string.
     pub mongo_uri: String,
     /// MongoDB database name.
     pub mongo_db: String,
+    /// Maximum number of connections to MongoDB. Driver default if unset.
+    pub max_pool_size: Option<u32>,
+    /// Write acknowledgment required from MongoDB, e.g. `majority`. Driver default if unset.
+    pub write_concern: Option<WriteAck>,
+    /// Replica set members reads are routed to, e.g. `secondaryPreferred`. Primary if unset.
+    pub read_preference: Option<ReadMode>,
     /// MongoDB collection name.
     pub mongo_collection: String,
+    /// MongoDB collection name for `Entities`.
+    pub entities_collection: String,
+    /// MongoDB collection name for coordinator state persisted across restarts.
+    pub state_collection: String,
+    /// Number of virtual nodes per unit of worker weight in the consistent hash ring.
+    pub vnodes: usize,
+    /// Number of requests to a worker that may be queued or in flight at once. Further requests
+    /// wait for room instead of being buffered.
+    pub send_buffer: usize,
+    /// Time a send to a worker may be blocked for before the worker is considered stuck and
+    /// removed, so that its tasks are reassigned.
+    #[serde(with = "humantime_serde")]
+    pub send_timeout: Duration,
+    /// Time tasks of a disconnected worker are kept for it to resume on reconnection, before being
+    /// reassigned. `0s` reassigns them immediately.
+    #[serde(with = "humantime_serde")]
+    pub resume_grace: Duration,
 }
 
 impl Config {
@@ -35,6 +63,17 @@ impl Config {
             .merge(Env::prefixed("COORDINATOR_"))
             .extract()?)
     }
+
+    /// Delay until the next ping, i.e. `ping_interval` shifted by at most `ping_jitter` of it.
+    #[must_use]
+    pub fn ping_delay(&self) -> Duration {
+        let jitter = self.ping_jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return self.ping_interval;
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+        self.ping_interval.mul_f64(factor)
+    }
 }
 
 impl Default for Config {
@@ -42,9 +81,19 @@ impl Default for Config {
         Self {
             bind: "127.0.0.1:7000".parse().unwrap(),
             ping_interval: Duration::from_secs(10),
+            ping_jitter: 0.1,
             mongo_uri: String::from("mongodb://localhost:27017"),
             mongo_db: String::from("stargazer-reborn"),
+            max_pool_size: None,
+            write_concern: None,
+            read_preference: None,
             mongo_collection: String::from("tasks"),
+            entities_collection: String::from("entities"),
+            state_collection: String::from("coordinator_state"),
+            vnodes: 10,
+            send_buffer: 100,
+            send_timeout: Duration::from_secs(30),
+            resume_grace: Duration::from_secs(10),
         }
     }
 }
@@ -54,6 +103,7 @@ mod tests {
     use std::time::Duration;
 
     use figment::Jail;
+    use sg_core::utils::{ReadMode, WriteAck};
 
     use crate::config::Config;
 
@@ -70,20 +120,59 @@ mod tests {
         Jail::expect_with(|jail| {
             jail.set_env("COORDINATOR_BIND", "0.0.0.0:8080");
             jail.set_env("COORDINATOR_PING_INTERVAL", "1s");
+            jail.set_env("COORDINATOR_PING_JITTER", "0.2");
             jail.set_env("COORDINATOR_MONGO_URI", "mongodb://suichan:27017");
             jail.set_env("COORDINATOR_MONGO_DB", "db");
+            jail.set_env("COORDINATOR_MAX_POOL_SIZE", "20");
+            jail.set_env("COORDINATOR_WRITE_CONCERN", "majority");
+            jail.set_env("COORDINATOR_READ_PREFERENCE", "primaryPreferred");
             jail.set_env("COORDINATOR_MONGO_COLLECTION", "coll");
+            jail.set_env("COORDINATOR_ENTITIES_COLLECTION", "ents");
+            jail.set_env("COORDINATOR_STATE_COLLECTION", "state");
+            jail.set_env("COORDINATOR_VNODES", "100");
+            jail.set_env("COORDINATOR_SEND_BUFFER", "10");
+            jail.set_env("COORDINATOR_SEND_TIMEOUT", "5s");
+            jail.set_env("COORDINATOR_RESUME_GRACE", "0s");
             assert_eq!(
                 Config::from_env().unwrap(),
                 Config {
                     bind: "0.0.0.0:8080".parse().unwrap(),
                     ping_interval: Duration::from_secs(1),
+                    ping_jitter: 0.2,
                     mongo_uri: String::from("mongodb://suichan:27017"),
                     mongo_db: String::from("db"),
+                    max_pool_size: Some(20),
+                    write_concern: Some(WriteAck::Named(String::from("majority"))),
+                    read_preference: Some(ReadMode::PrimaryPreferred),
                     mongo_collection: String::from("coll"),
+                    entities_collection: String::from("ents"),
+                    state_collection: String::from("state"),
+                    vnodes: 100,
+                    send_buffer: 10,
+                    send_timeout: Duration::from_secs(5),
+                    resume_grace: Duration::ZERO,
                 }
             );
             Ok(())
         });
     }
+
+    #[test]
+    fn must_jitter_ping() {
+        let config = Config {
+            ping_interval: Duration::from_secs(10),
+            ping_jitter: 0.1,
+            ..Config::default()
+        };
+        for _ in 0..100 {
+            let delay = config.ping_delay();
+            assert!((Duration::from_millis(8_999)..=Duration::from_millis(11_001)).contains(&delay));
+        }
+
+        let config = Config {
+            ping_jitter: 0.0,
+            ..config
+        };
+        assert_eq!(config.ping_delay(), Duration::from_secs(10));
+    }
 }
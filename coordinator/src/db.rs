@@ -1,44 +1,204 @@
 //! Database access.
 
-use std::collections::HashMap;
+use std::{collections::HashMap, future::Future};
 
 use eyre::Result;
-use futures_util::StreamExt;
+use futures_util::{StreamExt, TryStreamExt};
 use mongodb::{
     bson,
-    bson::oid::ObjectId,
-    change_stream::event::OperationType,
-    options::{ChangeStreamOptions, FullDocumentType},
-    Client,
+    bson::{doc, oid::ObjectId},
+    change_stream::{
+        event::{ChangeStreamEvent, OperationType, ResumeToken},
+        ChangeStream,
+    },
+    options::{ChangeStreamOptions, FullDocumentType, ReplaceOptions},
     Collection,
+    Database,
 };
-use sg_core::models::{InDB, Task};
-use tracing::{error, info};
+use serde::de::DeserializeOwned;
+use sg_core::{
+    models::{Entity, InDB, Task},
+    utils::connect_mongo,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-use crate::{App, Config};
+use crate::{state::GroupState, App, Config};
+
+/// A change of a document in a watched collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<T> {
+    /// A document is inserted.
+    Added(T),
+    /// A document is updated or replaced.
+    Updated(T),
+    /// A document with given id is deleted.
+    Removed(Uuid),
+}
+
+/// Models identified by an uuid.
+pub trait Identified {
+    /// The uuid of the model.
+    fn uuid(&self) -> Uuid;
+}
+
+impl Identified for Task {
+    fn uuid(&self) -> Uuid {
+        self.id.into()
+    }
+}
+
+impl Identified for Entity {
+    fn uuid(&self) -> Uuid {
+        self.id.into()
+    }
+}
+
+/// Change stream on a collection, yielding typed [`Change`]s.
+///
+/// The stream is opened before the collection is scanned by [`Watcher::snapshot`], so that no
+/// change is missed in between. This is also how a restarted coordinator catches up: it takes a
+/// fresh snapshot instead of resuming from where it stopped.
+struct Watcher<T>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    collection: Collection<InDB<T>>,
+    changes: ChangeStream<ChangeStreamEvent<InDB<T>>>,
+    /// Map from `ObjectId` to uuid, so that deleted documents can be identified.
+    oid_map: HashMap<ObjectId, Uuid>,
+}
+
+impl<T> Watcher<T>
+where
+    T: Identified + DeserializeOwned + Unpin + Send + Sync,
+{
+    async fn open(collection: Collection<InDB<T>>) -> Result<Self> {
+        let changes = Self::watch(&collection, None).await?;
+        Ok(Self {
+            collection,
+            changes,
+            oid_map: HashMap::new(),
+        })
+    }
+
+    async fn watch(
+        collection: &Collection<InDB<T>>,
+        resume_after: Option<ResumeToken>,
+    ) -> Result<ChangeStream<ChangeStreamEvent<InDB<T>>>> {
+        Ok(collection
+            .watch(
+                None,
+                ChangeStreamOptions::builder()
+                    .full_document(Some(FullDocumentType::UpdateLookup))
+                    .resume_after(resume_after)
+                    .build(),
+            )
+            .await?)
+    }
+
+    /// Get all documents in the collection.
+    async fn snapshot(&mut self) -> Result<Vec<T>> {
+        let mut docs = vec![];
+        let mut cursor = self.collection.find(None, None).await?;
+
+        while let Some(doc) = cursor.next().await {
+            let doc = doc?;
+
+            self.oid_map.insert(doc.id(), doc.uuid());
+            docs.push(doc.inner());
+        }
+
+        Ok(docs)
+    }
+
+    /// Wait for the next change.
+    ///
+    /// If the stream fails, it's reopened from the last seen event with its resume token.
+    async fn next(&mut self) -> Option<Result<Change<T>>> {
+        loop {
+            let event = match self.changes.next().await? {
+                Ok(event) => event,
+                Err(e) => {
+                    let token = self.changes.resume_token();
+                    warn!("Change stream failed, resuming: {}", e);
+                    match Self::watch(&self.collection, token).await {
+                        Ok(changes) => self.changes = changes,
+                        Err(e) => return Some(Err(e)),
+                    }
+                    continue;
+                }
+            };
+
+            match event.operation_type {
+                OperationType::Insert => {
+                    let doc = event
+                        .full_document
+                        .expect("Full document must be available");
+
+                    self.oid_map.insert(doc.id(), doc.uuid());
+                    return Some(Ok(Change::Added(doc.inner())));
+                }
+                OperationType::Update | OperationType::Replace => {
+                    // The document might be deleted before it's looked up.
+                    if let Some(doc) = event.full_document {
+                        return Some(Ok(Change::Updated(doc.inner())));
+                    }
+                }
+                OperationType::Delete => {
+                    let doc: InDB<()> = bson::from_document(
+                        event.document_key.expect("DocumentKey must be available"),
+                    )
+                    .expect("_id must be available");
+
+                    if let Some(id) = self.oid_map.remove(&doc.id()) {
+                        return Some(Ok(Change::Removed(id)));
+                    }
+                    error!("Document not found in oid map: {:?}.", doc.id());
+                }
+                OperationType::Invalidate => {
+                    error!("Change stream invalidated.");
+                }
+                ty => {
+                    error!("Unexpected event type: {:?}", ty);
+                }
+            }
+        }
+    }
+}
 
 /// Database instance.
 pub struct DB {
     app: App,
-    collection: Collection<InDB<Task>>,
-    oid_map: HashMap<ObjectId, Uuid>,
+    db: Database,
+    config: Config,
+    tasks: Watcher<Task>,
 }
 
 impl DB {
     /// Create a new DB instance.
     ///
+    /// Task changes are recorded from now on, and applied by [`DB::watch_tasks`].
+    ///
     /// # Errors
     /// Returns an error if the database connection fails.
     pub async fn new(app: App, config: Config) -> Result<Self> {
-        let client = Client::with_uri_str(config.mongo_uri).await?;
+        let client = connect_mongo(
+            &config.mongo_uri,
+            config.max_pool_size,
+            config.write_concern.clone(),
+            config.read_preference,
+        )
+        .await?;
         let db = client.database(&config.mongo_db);
-        let collection = db.collection(&config.mongo_collection);
+        let tasks = Watcher::open(db.collection(&config.mongo_collection)).await?;
 
         Ok(Self {
             app,
-            collection,
-            oid_map: HashMap::new(),
+            db,
+            config,
+            tasks,
         })
     }
 
@@ -47,15 +207,12 @@ impl DB {
     /// # Errors
     /// Returns an error if the database query fails.
     pub async fn init_tasks(&mut self) -> Result<()> {
+        let tasks = self.tasks.snapshot().await?;
         let mut count = 0;
-        let mut tasks = self.collection.find(None, None).await?;
-
-        while let Some(task) = tasks.next().await {
-            let task = task?;
-
-            self.oid_map.insert(task.id(), task.id.into());
-            self.app.add_task(task.inner()).await;
 
+        // Soft-deleted and disabled tasks are not scheduled.
+        for task in tasks.into_iter().filter(Task::is_active) {
+            self.app.add_task(task).await;
             count += 1;
         }
 
@@ -65,77 +222,154 @@ impl DB {
 
     /// Watch for changes in the database, and add/remove tasks as necessary.
     ///
+    /// Tasks being soft-deleted or disabled are removed, and added back once restored or enabled.
+    ///
     /// # Errors
     /// Returns an error if the database query fails.
     pub async fn watch_tasks(&mut self) -> Result<()> {
-        let mut changes = self
-            .collection
-            .watch(
-                None,
-                ChangeStreamOptions::builder()
-                    .full_document(Some(FullDocumentType::UpdateLookup))
-                    .build(),
-            )
-            .await?;
-
         info!("Watching database for task changes");
 
-        while let Some(event) = changes.next().await {
-            let event = event?;
-            match event.operation_type {
-                OperationType::Insert => {
-                    let task = event
-                        .full_document
-                        .expect("Full document must be available");
-
+        while let Some(change) = self.tasks.next().await {
+            match change? {
+                Change::Added(task) if task.is_active() => {
                     info!(task_id = %task.id, "Task added");
-
-                    self.oid_map.insert(task.id(), task.id.into());
-                    self.app.add_task(task.inner()).await;
+                    self.app.add_task(task).await;
                 }
-                OperationType::Update => {
-                    let task = event
-                        .full_document
-                        .expect("Full document must be available");
-
+                Change::Added(_) => {}
+                Change::Updated(task) => {
                     info!(task_id = %task.id, "Task updated");
-
                     self.app.remove_task(task.id.into()).await;
-                    self.app.add_task(task.inner()).await;
+                    if task.is_active() {
+                        self.app.add_task(task).await;
+                    }
                 }
-                OperationType::Replace => {
-                    let task = event
-                        .full_document
-                        .expect("Full document must be available");
+                Change::Removed(id) => {
+                    info!(task_id = %id, "Task removed");
+                    self.app.remove_task(id).await;
+                }
+            }
+        }
 
-                    info!(task_id = %task.id, "Task updated");
+        Ok(())
+    }
 
-                    self.app.remove_task(task.id.into()).await;
-                    self.app.add_task(task.inner()).await;
+    /// Watch for entity changes and send them to `tx`. Used by [`DB::reassign_entities`].
+    ///
+    /// Existing entities are sent as [`Change::Added`] first. The returned future doesn't borrow
+    /// `self`, so it can be spawned or run alongside [`DB::watch_tasks`]. It resolves when `tx` is
+    /// closed.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails.
+    pub fn watch_entities(
+        &self,
+        tx: UnboundedSender<Change<Entity>>,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        let collection = self.db.collection(&self.config.entities_collection);
+        async move {
+            let mut entities = Watcher::open(collection).await?;
+
+            for entity in entities.snapshot().await? {
+                if tx.send(Change::Added(entity)).is_err() {
+                    return Ok(());
                 }
-                OperationType::Delete => {
-                    let task: InDB<()> = bson::from_document(
-                        event.document_key.expect("DocumentKey must be available"),
-                    )
-                    .expect("_id must be available");
+            }
 
-                    if let Some(id) = self.oid_map.remove(&task.id()) {
-                        info!(task_id = %id, "Task removed");
+            info!("Watching database for entity changes");
 
-                        self.app.remove_task(id).await;
-                    } else {
-                        error!("Task not found in oid map: {:?}.", task.id());
-                    }
+            while let Some(change) = entities.next().await {
+                if tx.send(change?).is_err() {
+                    break;
                 }
-                OperationType::Invalidate => {
-                    error!("Change stream invalidated.");
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Apply reassignments requested on entities, see [`App::reassign_entity`].
+    ///
+    /// A request is cleared from its entity once applied, so that it's applied once, including
+    /// those requested while the coordinator is down. The returned future doesn't borrow `self`.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails.
+    pub fn reassign_entities(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let (tx, mut rx) = unbounded_channel();
+        let watch = self.watch_entities(tx);
+        let collection: Collection<Entity> = self.db.collection(&self.config.entities_collection);
+        let app = self.app.clone();
+        let apply = async move {
+            while let Some(change) = rx.recv().await {
+                let (Change::Added(entity) | Change::Updated(entity)) = change else { continue };
+                let Some(reassignment) = entity.reassignment else { continue };
+
+                let target = reassignment.target.map(Uuid::from);
+                let owners = app.reassign_entity(entity.id.into(), target).await;
+                if owners.is_empty() {
+                    warn!(entity_id = %entity.id, ?target, "No task of entity reassigned");
+                } else {
+                    info!(entity_id = %entity.id, ?owners, "Entity reassigned");
                 }
-                ty => {
-                    error!("Unexpected event type: {:?}", ty);
+
+                // Unless requested again since
+                let filter = doc! { "id": entity.id, "reassignment.at": reassignment.at };
+                let update = doc! { "$unset": { "reassignment": "" } };
+                if let Err(e) = collection.update_one(filter, update, None).await {
+                    error!(entity_id = %entity.id, "Failed to clear reassignment: {}", e);
                 }
             }
+            Ok::<_, eyre::Report>(())
+        };
+
+        async move {
+            tokio::try_join!(watch, apply)?;
+            Ok(())
         }
+    }
 
-        Ok(())
+    /// Load states of worker groups persisted before the last restart.
+    ///
+    /// # Errors
+    /// Returns an error if the database query fails.
+    pub async fn load_state(&self) -> Result<Vec<GroupState>> {
+        let collection: Collection<GroupState> = self.db.collection(&self.config.state_collection);
+        Ok(collection.find(None, None).await?.try_collect().await?)
+    }
+
+    /// Persist snapshots of worker groups received from `rx`, replacing the previous one of the
+    /// same group.
+    ///
+    /// Snapshots piled up while writing are coalesced, so that only the latest one of each group
+    /// is written. Failed writes are logged and skipped, since the next snapshot will follow soon.
+    /// The returned future doesn't borrow `self`, and resolves when `rx` is closed.
+    pub fn save_state(
+        &self,
+        mut rx: UnboundedReceiver<GroupState>,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        let collection: Collection<GroupState> = self.db.collection(&self.config.state_collection);
+        async move {
+            while let Some(state) = rx.recv().await {
+                let mut latest = HashMap::from([(state.kind.clone(), state)]);
+                while let Ok(state) = rx.try_recv() {
+                    latest.insert(state.kind.clone(), state);
+                }
+
+                for state in latest.into_values() {
+                    if let Err(e) = collection
+                        .replace_one(
+                            doc! { "_id": &state.kind },
+                            &state,
+                            ReplaceOptions::builder().upsert(true).build(),
+                        )
+                        .await
+                    {
+                        error!(kind = %state.kind, "Failed to persist coordinator state: {}", e);
+                    }
+                }
+            }
+
+            Ok(())
+        }
     }
 }
@@ -7,13 +7,16 @@
 #![deny(missing_docs)]
 
 use eyre::Result;
+use tokio::sync::mpsc::unbounded_channel;
 use tracing::level_filters::LevelFilter;
 
 use crate::{app::App, config::Config, db::DB};
 
 pub mod app;
+pub mod assign;
 pub mod config;
 pub mod db;
+pub mod state;
 pub mod worker;
 
 #[cfg(test)]
@@ -28,14 +31,20 @@ async fn main() -> Result<()> {
 
     let config = Config::from_env()?;
 
-    let app = App::new(config.clone());
+    let (snapshot_tx, snapshot_rx) = unbounded_channel();
+    let app = App::with_snapshots(config.clone(), snapshot_tx);
     let mut db = DB::new(app.clone(), config).await?;
 
+    app.resume(db.load_state().await?).await;
     db.init_tasks().await?;
+    let save_state = db.save_state(snapshot_rx);
+    let reassign_entities = db.reassign_entities();
 
     tokio::select! {
         r = app.serve() => r?,
         r = db.watch_tasks() => r?,
+        r = save_state => r?,
+        r = reassign_entities => r?,
     };
 
     Ok(())
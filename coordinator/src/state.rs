@@ -0,0 +1,111 @@
+//! Coordinator state persisted across restarts.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime},
+};
+
+use mongodb::bson::{DateTime, Uuid};
+use serde::{Deserialize, Serialize};
+use sg_core::load::Load;
+
+/// Snapshot of a worker group, saved on each rebalance and heartbeat.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupState {
+    /// Kind of workers in the group.
+    #[serde(rename = "_id")]
+    pub kind: String,
+    /// Workers of the group when the snapshot is taken.
+    pub workers: Vec<WorkerState>,
+    /// Tasks assigned to workers.
+    pub assignments: Vec<Assignment>,
+    /// Tasks left unassigned because no worker has the tags they require.
+    #[serde(default)]
+    pub unschedulable: Vec<Uuid>,
+}
+
+/// A worker in a [`GroupState`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkerState {
+    /// Worker ID.
+    pub id: Uuid,
+    /// Tags advertised by the worker.
+    #[serde(default)]
+    pub tags: HashSet<String>,
+    /// Last time the worker answered a ping.
+    pub last_heartbeat: DateTime,
+    /// Load reported in the last heartbeat, if any.
+    #[serde(default)]
+    pub load: Option<Load>,
+}
+
+/// A task assigned to a worker in a [`GroupState`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Assignment {
+    /// Task ID.
+    pub task: Uuid,
+    /// Entity of the task.
+    pub entity: Uuid,
+    /// Worker running the task.
+    pub worker: Uuid,
+}
+
+impl GroupState {
+    /// Drop workers that haven't answered a ping within `max_age` before `now`, along with tasks
+    /// assigned to them.
+    #[must_use]
+    pub fn prune(mut self, now: SystemTime, max_age: Duration) -> Self {
+        self.workers.retain(|worker| {
+            now.duration_since(worker.last_heartbeat.to_system_time())
+                .map_or(true, |age| age <= max_age)
+        });
+        let workers = &self.workers;
+        self.assignments
+            .retain(|assignment| workers.iter().any(|worker| worker.id == assignment.worker));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        time::{Duration, SystemTime},
+    };
+
+    use mongodb::bson::{DateTime, Uuid};
+
+    use crate::state::{Assignment, GroupState, WorkerState};
+
+    #[test]
+    fn must_prune_stale_workers() {
+        let now = SystemTime::now();
+        let alive = WorkerState {
+            id: Uuid::new(),
+            tags: HashSet::new(),
+            last_heartbeat: DateTime::from(now - Duration::from_secs(5)),
+            load: None,
+        };
+        let stale = WorkerState {
+            id: Uuid::new(),
+            tags: HashSet::new(),
+            last_heartbeat: DateTime::from(now - Duration::from_secs(60)),
+            load: None,
+        };
+        let assignment = |worker: &WorkerState| Assignment {
+            task: Uuid::new(),
+            entity: Uuid::new(),
+            worker: worker.id,
+        };
+        let state = GroupState {
+            kind: String::from("test"),
+            assignments: vec![assignment(&alive), assignment(&stale)],
+            workers: vec![alive.clone(), stale],
+            unschedulable: vec![],
+        };
+
+        let pruned = state.clone().prune(now, Duration::from_secs(20));
+        assert_eq!(pruned.workers, [alive]);
+        assert_eq!(pruned.assignments, state.assignments[..1]);
+    }
+}
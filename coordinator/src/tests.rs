@@ -2,27 +2,51 @@ use std::{
     collections::{HashMap, HashSet},
     fmt::Display,
     net::UdpSocket,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
     time::Duration,
 };
 
 use educe::Educe;
 use eyre::Result;
-use mongodb::{bson::doc, Client, Collection};
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    Client,
+    Collection,
+};
 use sg_core::{
+    load::Load,
     models::Task,
-    protocol::{WorkerRpc, WorkerRpcExt},
+    protocol::{Drain, Heartbeat, WorkerRpc, WorkerRpcExt},
     utils::ScopedJoinHandle,
 };
 use tarpc::context::Context;
 use tokio::{
-    sync::oneshot::{channel, Sender},
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver},
+        oneshot::{channel, Sender},
+    },
     task::JoinHandle,
     time::{sleep, timeout},
 };
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::StatusCode,
+    Error as WsError,
+};
 use uuid::Uuid;
 
-use crate::{config::Config, db::DB, App};
+use crate::{
+    config::Config,
+    db::{Change, DB},
+    state::GroupState,
+    App,
+};
 
 #[derive(Clone, Educe)]
 #[educe(Hash, Eq, PartialEq)]
@@ -34,6 +58,9 @@ struct DummyWorker {
     kind: String,
     #[educe(Hash(ignore), Eq(ignore), PartialEq(ignore))]
     tasks: Arc<Mutex<HashMap<Uuid, Task>>>,
+    /// Number of times the coordinator asked for all tasks.
+    #[educe(Hash(ignore), Eq(ignore), PartialEq(ignore))]
+    listed: Arc<AtomicUsize>,
 }
 
 impl DummyWorker {
@@ -43,6 +70,7 @@ impl DummyWorker {
             id: Uuid::new_v4(),
             kind: kind.to_string(),
             tasks: Default::default(),
+            listed: Default::default(),
         }
     }
 
@@ -53,11 +81,15 @@ impl DummyWorker {
 
 #[tarpc::server]
 impl WorkerRpc for DummyWorker {
-    async fn ping(self, _: Context, id: u64) -> u64 {
-        id
+    async fn ping(self, _: Context, id: u64) -> Heartbeat {
+        let load = Load {
+            tasks: self.tasks.lock().unwrap().len(),
+            ..Load::default()
+        };
+        Heartbeat { id, load }
     }
 
-    async fn add_task(self, _: Context, task: Task) -> bool {
+    async fn add_task(self, _: Context, task: Task, _version: u64) -> bool {
         self.tasks
             .lock()
             .unwrap()
@@ -65,11 +97,12 @@ impl WorkerRpc for DummyWorker {
             .is_none()
     }
 
-    async fn remove_task(self, _: Context, id: Uuid) -> bool {
+    async fn remove_task(self, _: Context, id: Uuid, _version: u64) -> bool {
         self.tasks.lock().unwrap().remove(&id).is_some()
     }
 
     async fn tasks(self, _: Context) -> Vec<Task> {
+        self.listed.fetch_add(1, Ordering::SeqCst);
         self.tasks.lock().unwrap().values().cloned().collect()
     }
 }
@@ -222,6 +255,12 @@ impl Tester {
                 entity: Uuid::new_v4().into(),
                 kind: kind.clone(),
                 params: Default::default(),
+                created_at: None,
+                updated_at: None,
+                deleted_at: None,
+                enabled: true,
+                schedule: None,
+                required_tags: Default::default(),
             };
 
             self.tasks
@@ -301,6 +340,12 @@ async fn must_consistent_after_repeated_join() {
             entity: Default::default(),
             kind: String::from("test"),
             params: Default::default(),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            enabled: true,
+            schedule: None,
+            required_tags: Default::default(),
         })
         .await;
 
@@ -310,6 +355,7 @@ async fn must_consistent_after_repeated_join() {
         id: Default::default(),
         kind: String::from("test"),
         tasks: Arc::new(Mutex::new(Default::default())),
+        listed: Default::default(),
     };
     // gets a task, and quits immediately before next ping.
     assert!(
@@ -339,6 +385,511 @@ async fn must_consistent_after_repeated_join() {
         .await;
 }
 
+#[tokio::test]
+async fn must_serve_over_ipv6() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("[::1]:{}", port).parse().unwrap(),
+        ping_interval: Duration::from_secs(9999),
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    server
+        .add_task(Task {
+            id: Default::default(),
+            entity: Default::default(),
+            kind: String::from("test"),
+            params: Default::default(),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            enabled: true,
+            schedule: None,
+            required_tags: Default::default(),
+        })
+        .await;
+
+    let client = DummyWorker {
+        ws: format!("ws://[::1]:{}", port),
+        id: Default::default(),
+        kind: String::from("test"),
+        tasks: Arc::new(Mutex::new(Default::default())),
+        listed: Default::default(),
+    };
+    assert!(
+        timeout(Duration::from_millis(300), client.clone().join_remote())
+            .await
+            .is_err(),
+        "unable to join remote"
+    );
+    assert!(!client.tasks.lock().unwrap().is_empty(), "no task received");
+}
+
+#[tokio::test]
+async fn must_reject_legacy_worker() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        ping_interval: Duration::from_secs(9999),
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    // A worker of the first protocol version doesn't send the protocol header.
+    let mut req = format!("ws://127.0.0.1:{}", port)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut()
+        .insert("Sg-Worker-Kind", "test".parse().unwrap());
+    req.headers_mut()
+        .insert("Sg-Worker-ID", Uuid::new_v4().to_string().parse().unwrap());
+
+    let Err(WsError::Http(resp)) = tokio_tungstenite::connect_async(req).await else {
+        panic!("legacy worker is accepted");
+    };
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(server.worker_groups.lock().await.is_empty());
+}
+
+#[tokio::test]
+async fn must_reassign_on_drain() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        // Make sure tasks are reassigned by draining, not by a failed ping.
+        ping_interval: Duration::from_secs(9999),
+        ..Default::default()
+    });
+    tokio::spawn(server.clone().serve());
+    sleep(Duration::from_millis(100)).await;
+
+    for _ in 0..10 {
+        server
+            .add_task(Task {
+                id: Uuid::new_v4().into(),
+                entity: Uuid::new_v4().into(),
+                kind: String::from("test"),
+                params: Default::default(),
+                created_at: None,
+                updated_at: None,
+                deleted_at: None,
+                enabled: true,
+                schedule: None,
+                required_tags: Default::default(),
+            })
+            .await;
+    }
+
+    let ws = format!("ws://127.0.0.1:{}", port);
+    let draining = DummyWorker::new(&ws, "test");
+    let staying = DummyWorker::new(&ws, "test");
+
+    let drain = Drain::new();
+    let draining_handle = {
+        let worker = draining.clone();
+        tokio::spawn(worker.clone().join_with_drain(
+            worker.ws,
+            worker.id,
+            worker.kind,
+            drain.clone(),
+        ))
+    };
+    let _staying_handle = {
+        let worker = staying.clone();
+        ScopedJoinHandle(tokio::spawn(async move {
+            worker.join_remote().await.unwrap();
+        }))
+    };
+    sleep(Duration::from_millis(300)).await;
+
+    let total = draining.tasks.lock().unwrap().len() + staying.tasks.lock().unwrap().len();
+    assert_eq!(total, 10, "tasks are not distributed");
+
+    assert!(
+        drain.drain(Duration::from_secs(1)).await,
+        "drain is not acknowledged"
+    );
+    timeout(Duration::from_secs(1), draining_handle)
+        .await
+        .expect("worker doesn't leave after drain")
+        .unwrap()
+        .unwrap();
+
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(
+        staying.tasks.lock().unwrap().len(),
+        10,
+        "tasks are not reassigned"
+    );
+}
+
+#[tokio::test]
+async fn must_resume_on_reconnect() {
+    let port = free_port();
+    let server = App::new(Config {
+        bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+        // Make sure the worker is only gone because of the lost connection.
+        ping_interval: Duration::from_secs(9999),
+        resume_grace: Duration::from_secs(5),
+        ..Default::default()
+    });
+    let _server = ScopedJoinHandle(tokio::spawn(server.clone().serve()));
+
+    // Forward connections to the coordinator, so that they can be cut.
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_port = proxy.local_addr().unwrap().port();
+    let connections = Arc::new(Mutex::new(vec![]));
+    let _proxy = {
+        let connections = connections.clone();
+        ScopedJoinHandle(tokio::spawn(async move {
+            loop {
+                let (mut inbound, _) = proxy.accept().await.unwrap();
+                let mut outbound = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                let forward = tokio::spawn(async move {
+                    drop(copy_bidirectional(&mut inbound, &mut outbound).await);
+                });
+                connections.lock().unwrap().push(ScopedJoinHandle(forward));
+            }
+        }))
+    };
+    sleep(Duration::from_millis(100)).await;
+
+    for _ in 0..10 {
+        server
+            .add_task(Task {
+                id: Uuid::new_v4().into(),
+                entity: Uuid::new_v4().into(),
+                kind: String::from("test"),
+                params: Default::default(),
+                created_at: None,
+                updated_at: None,
+                deleted_at: None,
+                enabled: true,
+                schedule: None,
+                required_tags: Default::default(),
+            })
+            .await;
+    }
+
+    let worker = DummyWorker::new(format!("ws://127.0.0.1:{}", proxy_port), "test");
+    let _worker = ScopedJoinHandle(tokio::spawn(worker.clone().join_with_resume(
+        worker.ws.clone(),
+        worker.id,
+        worker.kind.clone(),
+        HashSet::new(),
+        1,
+        Drain::new(),
+        Duration::from_millis(100),
+    )));
+    sleep(Duration::from_millis(300)).await;
+
+    let assigned = |worker: &DummyWorker| -> HashSet<Uuid> {
+        worker.tasks.lock().unwrap().keys().copied().collect()
+    };
+    let before = assigned(&worker);
+    assert_eq!(before.len(), 10, "tasks are not assigned");
+
+    // Cut the connection, and let the worker reconnect.
+    connections.lock().unwrap().clear();
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(connections.lock().unwrap().len(), 1, "worker doesn't reconnect");
+
+    // The worker resumes with its tasks, without being asked for all of them.
+    let members = server.worker_groups.lock().await["test"]
+        .with(|group| group.worker_len())
+        .await;
+    assert_eq!(members, 1, "worker is not back in the group");
+    assert_eq!(assigned(&worker), before);
+    assert_eq!(worker.listed.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn must_reassign_entity() {
+    let mut tester = Tester::new().await;
+    tester.increase_workers("test", 3).await;
+
+    let entity = Uuid::new_v4();
+    let task = Task {
+        id: Uuid::new_v4().into(),
+        entity: entity.into(),
+        kind: String::from("test"),
+        params: Default::default(),
+        created_at: None,
+        updated_at: None,
+        deleted_at: None,
+        enabled: true,
+        schedule: None,
+        required_tags: Default::default(),
+    };
+    let task_id: Uuid = task.id.into();
+    tester
+        .tasks
+        .entry(String::from("test"))
+        .or_default()
+        .insert(task_id);
+    tester.server.add_task(task).await;
+    sleep(Duration::from_millis(150)).await;
+    tester.validate().await;
+
+    let owner = |tester: &Tester| {
+        tester.clients["test"]
+            .keys()
+            .find(|worker| worker.tasks.lock().unwrap().contains_key(&task_id))
+            .map(|worker| worker.id)
+            .unwrap()
+    };
+
+    // Pin to a specific worker.
+    let current = owner(&tester);
+    let target = tester.clients["test"]
+        .keys()
+        .map(|worker| worker.id)
+        .find(|id| *id != current)
+        .unwrap();
+    let owners = tester.server.reassign_entity(entity, Some(target)).await;
+    assert_eq!(owners, HashMap::from([(task_id, target)]));
+    sleep(Duration::from_millis(150)).await;
+    tester.validate().await;
+    assert_eq!(owner(&tester), target);
+
+    // Let coordinator pick another one.
+    let owners = tester.server.reassign_entity(entity, None).await;
+    assert_ne!(owners[&task_id], target);
+    sleep(Duration::from_millis(150)).await;
+    tester.validate().await;
+    assert_eq!(owner(&tester), owners[&task_id]);
+
+    // Unknown worker.
+    assert!(tester
+        .server
+        .reassign_entity(entity, Some(Uuid::new_v4()))
+        .await
+        .is_empty());
+
+    tester.finish().await;
+}
+
+#[tokio::test]
+async fn must_resume() {
+    let serve = |config: Config, state: Option<GroupState>| async move {
+        let port = config.bind.port();
+        let (tx, rx) = unbounded_channel();
+        let app = App::with_snapshots(config, tx);
+        if let Some(state) = state {
+            app.resume(vec![state]).await;
+        }
+        let handle = ScopedJoinHandle(tokio::spawn(app.clone().serve()));
+        sleep(Duration::from_millis(100)).await;
+        (app, rx, format!("ws://127.0.0.1:{}", port), handle)
+    };
+    let config = || Config {
+        bind: format!("127.0.0.1:{}", free_port()).parse().unwrap(),
+        ping_interval: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let join = |worker: &DummyWorker, ws: &str| {
+        let worker = DummyWorker {
+            ws: ws.to_string(),
+            tasks: Default::default(),
+            ..worker.clone()
+        };
+        let handle = ScopedJoinHandle(tokio::spawn(worker.clone().join_remote()));
+        (worker, handle)
+    };
+    let assigned = |worker: &DummyWorker| -> HashSet<Uuid> {
+        worker.tasks.lock().unwrap().keys().copied().collect()
+    };
+    let tasks: Vec<_> = (0..20)
+        .map(|_| Task {
+            id: Uuid::new_v4().into(),
+            entity: Uuid::new_v4().into(),
+            kind: String::from("test"),
+            params: Default::default(),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            enabled: true,
+            schedule: None,
+            required_tags: Default::default(),
+        })
+        .collect();
+
+    // Snapshots are taken on rebalance.
+    let (app, mut rx, ws, _server) = serve(config(), None).await;
+    let workers = [DummyWorker::new(&ws, "test"), DummyWorker::new(&ws, "test")];
+    let _handles: Vec<_> = workers.iter().map(|worker| join(worker, &ws)).collect();
+    for task in &tasks {
+        app.add_task(task.clone()).await;
+    }
+    sleep(Duration::from_millis(300)).await;
+    let mut state = None;
+    while let Ok(snapshot) = rx.try_recv() {
+        state = Some(snapshot);
+    }
+    let state = state.unwrap();
+    assert_eq!(state.workers.len(), 2);
+    assert_eq!(state.assignments.len(), 20);
+    let owned = |worker: &DummyWorker| -> HashSet<Uuid> {
+        state
+            .assignments
+            .iter()
+            .filter(|assignment| Uuid::from(assignment.worker) == worker.id)
+            .map(|assignment| assignment.task.into())
+            .collect()
+    };
+    assert!(!owned(&workers[0]).is_empty() && !owned(&workers[1]).is_empty());
+
+    // Tasks wait for their workers to reconnect to a restarted coordinator.
+    let (app, _rx, ws, _server) = serve(config(), Some(state.clone())).await;
+    for task in &tasks {
+        app.add_task(task.clone()).await;
+    }
+    let (first, _first) = join(&workers[0], &ws);
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(assigned(&first), owned(&workers[0]));
+    let (second, _second) = join(&workers[1], &ws);
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(assigned(&first), owned(&workers[0]));
+    assert_eq!(assigned(&second), owned(&workers[1]));
+
+    // Until the grace period is over.
+    let (app, _rx, ws, _server) = serve(config(), Some(state.clone())).await;
+    for task in &tasks {
+        app.add_task(task.clone()).await;
+    }
+    let (first, _first) = join(&workers[0], &ws);
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(assigned(&first), owned(&workers[0]));
+    sleep(Duration::from_secs(1)).await;
+    assert_eq!(assigned(&first).len(), 20);
+}
+
+#[tokio::test]
+async fn must_place_by_tags() {
+    let port = free_port();
+    let (tx, mut rx) = unbounded_channel();
+    let app = App::with_snapshots(
+        Config {
+            bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+            ping_interval: Duration::from_millis(100),
+            ..Default::default()
+        },
+        tx,
+    );
+    let _server = ScopedJoinHandle(tokio::spawn(app.clone().serve()));
+    sleep(Duration::from_millis(100)).await;
+
+    let ws = format!("ws://127.0.0.1:{}", port);
+    let tagged = DummyWorker::new(&ws, "test");
+    let untagged = DummyWorker::new(&ws, "test");
+    let _handles = [
+        ScopedJoinHandle(tokio::spawn(tagged.clone().join_with_tags(
+            ws.clone(),
+            tagged.id,
+            "test",
+            HashSet::from([String::from("jp"), String::from("residential")]),
+            Drain::new(),
+        ))),
+        ScopedJoinHandle(tokio::spawn(untagged.clone().join_remote())),
+    ];
+    sleep(Duration::from_millis(100)).await;
+
+    let task = |tags: &[&str]| Task {
+        id: Uuid::new_v4().into(),
+        entity: Uuid::new_v4().into(),
+        kind: String::from("test"),
+        params: Default::default(),
+        created_at: None,
+        updated_at: None,
+        deleted_at: None,
+        enabled: true,
+        schedule: None,
+        required_tags: tags.iter().map(ToString::to_string).collect(),
+    };
+    let anywhere: Vec<_> = (0..10).map(|_| task(&[])).collect();
+    let jp: Vec<_> = (0..5).map(|_| task(&["jp"])).collect();
+    let us = task(&["us"]);
+    for task in anywhere.iter().chain(&jp).chain([&us]) {
+        app.add_task(task.clone()).await;
+    }
+    sleep(Duration::from_millis(300)).await;
+
+    let assigned = |worker: &DummyWorker| -> HashSet<Uuid> {
+        worker.tasks.lock().unwrap().keys().copied().collect()
+    };
+    let ids = |tasks: &[Task]| -> HashSet<Uuid> {
+        tasks.iter().map(|task| task.id.into()).collect()
+    };
+    // Tasks requiring tags are only placed on workers with all of them.
+    assert!(assigned(&tagged).is_superset(&ids(&jp)));
+    assert!(assigned(&untagged).is_subset(&ids(&anywhere)));
+    // and left unassigned if there's none.
+    let all = &assigned(&tagged) | &assigned(&untagged);
+    assert_eq!(all, &ids(&anywhere) | &ids(&jp));
+
+    let mut state = None;
+    while let Ok(snapshot) = rx.try_recv() {
+        state = Some(snapshot);
+    }
+    assert_eq!(state.unwrap().unschedulable, [us.id]);
+
+    // Nor pinned to workers without them.
+    assert!(app
+        .reassign_entity(jp[0].entity.into(), Some(untagged.id))
+        .await
+        .is_empty());
+}
+
+#[tokio::test]
+async fn must_report_load() {
+    let port = free_port();
+    let (tx, mut rx) = unbounded_channel();
+    let app = App::with_snapshots(
+        Config {
+            bind: format!("127.0.0.1:{}", port).parse().unwrap(),
+            ping_interval: Duration::from_millis(100),
+            ..Default::default()
+        },
+        tx,
+    );
+    let _server = ScopedJoinHandle(tokio::spawn(app.clone().serve()));
+    sleep(Duration::from_millis(100)).await;
+
+    let ws = format!("ws://127.0.0.1:{}", port);
+    let worker = DummyWorker::new(&ws, "test");
+    let _handle = ScopedJoinHandle(tokio::spawn(worker.clone().join_remote()));
+    sleep(Duration::from_millis(100)).await;
+
+    for _ in 0..3 {
+        app.add_task(Task {
+            id: Uuid::new_v4().into(),
+            entity: Uuid::new_v4().into(),
+            kind: String::from("test"),
+            params: Default::default(),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            enabled: true,
+            schedule: None,
+            required_tags: Default::default(),
+        })
+        .await;
+    }
+    sleep(Duration::from_millis(300)).await;
+
+    // Members carry the load of their last heartbeat.
+    let mut state = None;
+    while let Ok(snapshot) = rx.try_recv() {
+        state = Some(snapshot);
+    }
+    let workers = state.unwrap().workers;
+    assert_eq!(workers.len(), 1);
+    assert_eq!(workers[0].load.map(|load| load.tasks), Some(3));
+}
+
 #[tokio::test]
 async fn must_db() {
     let client = Client::with_uri_str("mongodb://localhost:27017/")
@@ -364,6 +915,12 @@ async fn must_db() {
             entity: Uuid::new_v4().into(),
             kind: String::from("test"),
             params: Default::default(),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            enabled: true,
+            schedule: None,
+            required_tags: Default::default(),
         })
         .collect();
     collection.insert_many(&tasks, None).await.unwrap();
@@ -386,6 +943,12 @@ async fn must_db() {
         entity: Uuid::new_v4().into(),
         kind: String::from("test"),
         params: Default::default(),
+        created_at: None,
+        updated_at: None,
+        deleted_at: None,
+        enabled: true,
+        schedule: None,
+        required_tags: Default::default(),
     };
 
     // Insert a new task.
@@ -430,6 +993,141 @@ async fn must_db() {
     assert_task_ids(&app, &tasks).await;
 }
 
+#[tokio::test]
+async fn must_watch_entities() {
+    let client = Client::with_uri_str("mongodb://localhost:27017/")
+        .await
+        .unwrap();
+    let db = client.database("test");
+    let collection: Collection<Document> = db.collection("coordinator_entities");
+    let config = Config {
+        mongo_uri: String::from("mongodb://localhost:27017/"),
+        mongo_db: String::from("test"),
+        mongo_collection: String::from("coordinator"),
+        entities_collection: String::from("coordinator_entities"),
+        ..Default::default()
+    };
+    let entity = |id: Uuid, name: &str| {
+        doc! {
+            "id": mongodb::bson::Uuid::from(id),
+            "meta": {
+                "name": { "name": { "en": name }, "default_language": "en" },
+                "group": null,
+            },
+            "tasks": [],
+        }
+    };
+
+    // Clear test collection before test.
+    collection.drop(None).await.unwrap();
+
+    let existing = Uuid::new_v4();
+    collection
+        .insert_one(entity(existing, "existing"), None)
+        .await
+        .unwrap();
+
+    let app = App::new(config.clone());
+    let db = DB::new(app, config).await.unwrap();
+    let (tx, mut rx) = unbounded_channel();
+    tokio::spawn(db.watch_entities(tx));
+
+    // Existing entities come first.
+    let change = recv_change(&mut rx).await;
+    assert!(matches!(change, Change::Added(e) if Uuid::from(e.id) == existing));
+
+    let added = Uuid::new_v4();
+    collection
+        .insert_one(entity(added, "added"), None)
+        .await
+        .unwrap();
+    let change = recv_change(&mut rx).await;
+    assert!(matches!(change, Change::Added(e) if Uuid::from(e.id) == added));
+
+    collection
+        .update_one(
+            doc! { "id": mongodb::bson::Uuid::from(added) },
+            doc! { "$set": { "meta.name.name.en": "updated" } },
+            None,
+        )
+        .await
+        .unwrap();
+    let change = recv_change(&mut rx).await;
+    assert!(
+        matches!(change, Change::Updated(e) if e.meta.name.name.values().any(|n| n == "updated"))
+    );
+
+    collection
+        .delete_one(doc! { "id": mongodb::bson::Uuid::from(existing) }, None)
+        .await
+        .unwrap();
+    assert_eq!(recv_change(&mut rx).await, Change::Removed(existing));
+}
+
+#[tokio::test]
+async fn must_clear_applied_reassignments() {
+    let client = Client::with_uri_str("mongodb://localhost:27017/")
+        .await
+        .unwrap();
+    let db = client.database("test");
+    let collection: Collection<Document> = db.collection("coordinator_reassign");
+    let config = Config {
+        mongo_uri: String::from("mongodb://localhost:27017/"),
+        mongo_db: String::from("test"),
+        mongo_collection: String::from("coordinator"),
+        entities_collection: String::from("coordinator_reassign"),
+        ..Default::default()
+    };
+    let id = mongodb::bson::Uuid::from(Uuid::new_v4());
+    let reassignment = || doc! { "target": null, "at": DateTime::now() };
+    let entities = &collection;
+    let pending = move || async move {
+        let entity = entities.find_one(doc! { "id": id }, None).await.unwrap();
+        entity.unwrap().contains_key("reassignment")
+    };
+
+    // Clear test collection before test.
+    collection.drop(None).await.unwrap();
+
+    // Requested while the coordinator is down
+    collection
+        .insert_one(
+            doc! {
+                "id": id,
+                "meta": {
+                    "name": { "name": { "en": "entity" }, "default_language": "en" },
+                    "group": null,
+                },
+                "tasks": [],
+                "reassignment": reassignment(),
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+    let app = App::new(config.clone());
+    let db = DB::new(app, config).await.unwrap();
+    tokio::spawn(db.reassign_entities());
+
+    sleep(Duration::from_millis(500)).await;
+    assert!(!pending().await);
+
+    collection
+        .update_one(doc! { "id": id }, doc! { "$set": { "reassignment": reassignment() } }, None)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(500)).await;
+    assert!(!pending().await);
+}
+
+async fn recv_change<T>(rx: &mut UnboundedReceiver<Change<T>>) -> Change<T> {
+    timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("Change must be received")
+        .unwrap()
+}
+
 async fn assert_task_ids(app: &App, expected: &[Task]) {
     app.worker_groups.lock().await["test"]
         .with(|group| {
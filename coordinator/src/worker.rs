@@ -1,16 +1,26 @@
 //! Worker node and worker group.
-#[cfg(debug_assertions)]
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::{
     collections::{HashMap, HashSet},
     fmt::{Debug, Formatter},
-    sync::{Arc, Weak},
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc,
+        RwLock,
+        Weak,
+    },
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
 };
 
-use consistent_hash_ring::Ring;
-use futures_util::{Sink, Stream};
+use consistent_hash_ring::{Ring, RingBuilder};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use mongodb::bson::DateTime;
 use sg_core::{
     adapter::WsTransport,
+    load::Load,
     models::Task,
     protocol::WorkerRpcClient,
     utils::ScopedJoinHandle,
@@ -20,12 +30,19 @@ use tarpc::{
     client::{Config as ClientConfig, RpcError},
     context::Context,
 };
-use tokio::sync::{Mutex, Notify};
+use tokio::{
+    sync::{mpsc::UnboundedSender, Mutex, Notify},
+    time::Sleep,
+};
 use tokio_tungstenite::tungstenite::{Error as WsError, Message};
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use crate::config::Config;
+use crate::{
+    assign::{assign_with_vnodes, weighted_vnodes, DEFAULT_VNODES},
+    config::Config,
+    state::{Assignment, GroupState, WorkerState},
+};
 
 /// Worker group for homogeneous workers.
 #[derive(Debug)]
@@ -44,8 +61,17 @@ impl WorkerGroup {
     /// Create a new worker group.
     #[must_use]
     pub fn new() -> Self {
+        Self::with_vnodes(DEFAULT_VNODES)
+    }
+
+    /// Create a new worker group with `vnodes` virtual nodes per unit of worker weight in its ring.
+    #[must_use]
+    pub fn with_vnodes(vnodes: usize) -> Self {
         let balance_notify = Arc::new(Notify::new());
-        let inner = Arc::new(Mutex::new(WorkerGroupImpl::new(balance_notify.clone())));
+        let inner = Arc::new(Mutex::new(WorkerGroupImpl::with_vnodes(
+            balance_notify.clone(),
+            vnodes,
+        )));
 
         let task = {
             let inner = inner.clone();
@@ -81,6 +107,33 @@ impl WorkerGroup {
         drop(lock);
         output
     }
+
+    /// Add a worker reconnecting after it has seen assignment changes up to `version`.
+    ///
+    /// If its tasks are still kept and it has seen all changes sent to it, it resumes with them,
+    /// and only changes since then are sent on the next balance. Otherwise, the tasks running on
+    /// it are asked for and reconciled with the assignment.
+    pub async fn rejoin(&self, worker: Arc<Worker>, version: u64) {
+        let kept = self
+            .with(|group| group.take_departed(worker.id, version))
+            .await;
+        let tasks = if let Some(tasks) = kept {
+            info!(worker_id = %worker.id, version, "Worker resumed");
+            tasks
+        } else {
+            info!(worker_id = %worker.id, version, "Worker can't resume, reconcile its tasks");
+            match worker.client.tasks(Context::current()).await {
+                Ok(tasks) => tasks.into_iter().map(|task| task.id.into()).collect(),
+                Err(e) => {
+                    warn!(worker_id = %worker.id, "Failed to get tasks of worker: {}", e);
+                    HashSet::new()
+                }
+            }
+        };
+
+        *worker.tasks.lock().await = tasks.clone();
+        self.with(|group| group.resume_worker(worker, &tasks)).await;
+    }
 }
 
 /// Weak reference to a worker group.
@@ -107,6 +160,18 @@ pub(crate) struct BoundTask {
     task: Task,
     /// The worker that is currently executing the task.
     pub(crate) worker: Option<Uuid>,
+    /// The worker the task is pinned to, overriding the ring until that worker leaves.
+    pinned: Option<Uuid>,
+}
+
+/// Tasks of a disconnected worker, kept for it to resume.
+#[derive(Debug)]
+struct Departed {
+    /// Tasks are reassigned after this.
+    until: Instant,
+    /// Version of the last assignment change sent to the worker.
+    version: u64,
+    tasks: HashSet<Uuid>,
 }
 
 /// Worker group implementation.
@@ -114,7 +179,17 @@ pub struct WorkerGroupImpl {
     pub(crate) workers: HashMap<Uuid, Arc<Worker>>,
     pub(crate) tasks: HashMap<Uuid, BoundTask>,
     ring: Ring</* worker */ Uuid>,
+    /// Virtual nodes per unit of worker weight in the ring.
+    vnodes: usize,
     balance_notify: Arc<Notify>,
+    /// Kind of the group and where its snapshots are sent to be persisted.
+    snapshots: Option<(String, UnboundedSender<GroupState>)>,
+    /// State loaded on startup, honored until the deadline.
+    resumed: Option<(Instant, GroupState)>,
+    /// Version of the last assignment change sent to any worker.
+    version: u64,
+    /// Disconnected workers that may resume, keyed by id.
+    departed: HashMap<Uuid, Departed>,
 
     #[cfg(debug_assertions)]
     poison: AtomicBool,
@@ -160,11 +235,22 @@ impl WorkerGroupImpl {
     /// Create a new worker group implementation.
     #[must_use]
     pub fn new(balance_notify: Arc<Notify>) -> Self {
+        Self::with_vnodes(balance_notify, DEFAULT_VNODES)
+    }
+
+    /// Create a new worker group implementation with `vnodes` virtual nodes per worker in its ring.
+    #[must_use]
+    pub fn with_vnodes(balance_notify: Arc<Notify>, vnodes: usize) -> Self {
         Self {
             workers: HashMap::new(),
             tasks: HashMap::new(),
-            ring: Ring::default(),
+            ring: RingBuilder::default().vnodes(vnodes).build(),
+            vnodes,
             balance_notify,
+            snapshots: None,
+            resumed: None,
+            version: 0,
+            departed: HashMap::new(),
 
             #[cfg(debug_assertions)]
             poison: AtomicBool::new(false),
@@ -186,7 +272,13 @@ impl WorkerGroupImpl {
     /// Add a new worker to the group.
     pub fn add_worker(&mut self, worker: Arc<Worker>) {
         debug!(worker_id = %worker.id, "Add worker to group");
-        let id = worker.id;
+        let (id, weight) = (worker.id, worker.weight);
+        let Some(vnodes) = weighted_vnodes(self.vnodes, weight) else {
+            error!(worker_id = %id, weight, "Worker weight too large, refuse to add it");
+            return;
+        };
+        // Tasks kept for it no longer hold once it's back, they are either resumed or reassigned.
+        self.departed.remove(&id);
         if self.workers.insert(id, worker).is_some() {
             warn!(worker_id = %id, "Worker already exists in group. It might be crashed and rejoined the coordinator before a ping was sent.");
 
@@ -198,7 +290,7 @@ impl WorkerGroupImpl {
             });
         } else {
             // Add the worker to the ring.
-            self.ring.insert(id);
+            self.ring.insert_weight(id, vnodes);
         }
 
         self.balance_notify.notify_one();
@@ -210,14 +302,88 @@ impl WorkerGroupImpl {
         self.ring.remove(&id);
         self.workers.remove(&id);
 
+        // Pins to this worker no longer hold.
+        for bound_task in self.tasks.values_mut() {
+            if bound_task.pinned == Some(id) {
+                bound_task.pinned = None;
+            }
+        }
+
         self.balance_notify.notify_one();
     }
 
+    /// Remove a disconnected worker from the group, keeping `tasks` running on it for `grace`.
+    ///
+    /// Until then, the tasks are left unassigned for the worker to resume with on reconnection.
+    pub fn depart_worker(&mut self, id: Uuid, version: u64, tasks: HashSet<Uuid>, grace: Duration) {
+        if grace.is_zero() {
+            self.remove_worker(id);
+            return;
+        }
+
+        debug!(worker_id = %id, version, "Keep tasks of departed worker");
+        for bound_task in self.tasks.values_mut() {
+            if bound_task.worker == Some(id) {
+                bound_task.worker = None;
+            }
+        }
+        self.departed.insert(
+            id,
+            Departed {
+                until: Instant::now() + grace,
+                version,
+                tasks,
+            },
+        );
+        self.remove_worker(id);
+
+        // Reassign the tasks once the grace period is over.
+        let balance_notify = self.balance_notify.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            balance_notify.notify_one();
+        });
+    }
+
+    /// Take the tasks kept for departed worker `id`, if it has seen all changes to them, i.e. up
+    /// to `version`.
+    fn take_departed(&mut self, id: Uuid, version: u64) -> Option<HashSet<Uuid>> {
+        self.departed
+            .remove(&id)
+            .filter(|departed| departed.until > Instant::now() && departed.version == version)
+            .map(|departed| departed.tasks)
+    }
+
+    /// Add a worker still running `tasks` to the group.
+    ///
+    /// Tasks not assigned to other workers are bound to it again. The others are removed from it
+    /// on the next balance.
+    pub fn resume_worker(&mut self, worker: Arc<Worker>, tasks: &HashSet<Uuid>) {
+        let id = worker.id;
+        self.add_worker(worker);
+
+        for task_id in tasks {
+            if let Some(bound_task) = self.tasks.get_mut(task_id) {
+                if bound_task.worker.is_none() {
+                    bound_task.worker = Some(id);
+                }
+            }
+        }
+    }
+
     /// Add a task to the group.
     pub fn add_task(&mut self, task: Task) {
         let id = task.id;
+        // Change streams may replay a task that's already loaded, keep its assignment.
+        if matches!(self.tasks.get(&id.into()), Some(bound) if bound.task == task) {
+            return;
+        }
         debug!(task_id = %id, "Add task to group");
-        let bound_task = BoundTask { task, worker: None };
+        let bound_task = BoundTask {
+            task,
+            worker: None,
+            pinned: None,
+        };
         self.tasks.insert(id.into(), bound_task);
 
         self.balance_notify.notify_one();
@@ -231,6 +397,191 @@ impl WorkerGroupImpl {
         self.balance_notify.notify_one();
     }
 
+    /// Pin tasks of `entity` to `target`, or to the next worker on the ring other than the current
+    /// one if `None`.
+    ///
+    /// Tasks stay pinned until the worker leaves. Tasks are only pinned to workers with the tags
+    /// they require. Return the new owner of each pinned task, which is empty if `target` is not in
+    /// this group or no task of `entity` is in this group.
+    pub fn reassign_entity(&mut self, entity: Uuid, target: Option<Uuid>) -> HashMap<Uuid, Uuid> {
+        if matches!(target, Some(target) if !self.workers.contains_key(&target)) {
+            return HashMap::new();
+        }
+
+        let mut owners = HashMap::new();
+        for (task_id, bound_task) in &mut self.tasks {
+            if Uuid::from(bound_task.task.entity) != entity {
+                continue;
+            }
+
+            let eligible = |worker: &Uuid| bound_task.task.runs_on(&self.workers[worker].tags);
+            let owner = match target {
+                Some(target) => Some(target).filter(eligible),
+                None => self
+                    .ring
+                    .replicas(task_id)
+                    .find(|worker| Some(**worker) != bound_task.worker && eligible(worker))
+                    .copied(),
+            };
+            if let Some(owner) = owner {
+                debug!(%task_id, worker_id = %owner, "Pin task to worker");
+                bound_task.pinned = Some(owner);
+                owners.insert(*task_id, owner);
+            }
+        }
+
+        if !owners.is_empty() {
+            self.balance_notify.notify_one();
+        }
+        owners
+    }
+
+    /// Owner of each task on the ring, among workers with the tags it requires.
+    ///
+    /// Tasks no worker is eligible for are left out.
+    fn ring_owners(&self) -> HashMap<Uuid, Uuid> {
+        // Tasks are assigned among the workers eligible for them, which are the same for tasks
+        // requiring the same tags.
+        let mut by_workers: HashMap<Vec<(Uuid, u32)>, Vec<Uuid>> = HashMap::new();
+        for (task_id, bound_task) in &self.tasks {
+            let mut workers: Vec<_> = self
+                .workers
+                .values()
+                .filter(|worker| bound_task.task.runs_on(&worker.tags))
+                .map(|worker| (worker.id, worker.weight))
+                .collect();
+            workers.sort_unstable();
+            by_workers.entry(workers).or_default().push(*task_id);
+        }
+
+        by_workers
+            .into_iter()
+            .flat_map(|(workers, tasks)| assign_with_vnodes(&tasks, &workers, self.vnodes))
+            .flat_map(|(worker, tasks)| tasks.into_iter().map(move |task| (task, worker)))
+            .collect()
+    }
+
+    /// Send a snapshot of the group to `tx` on each rebalance and heartbeat.
+    pub fn persist_to(&mut self, kind: String, tx: UnboundedSender<GroupState>) {
+        self.snapshots = Some((kind, tx));
+    }
+
+    /// Honor assignments in `state` for `grace`, so that a restarted coordinator doesn't reshuffle
+    /// all tasks.
+    ///
+    /// Until then, tasks whose workers haven't reconnected yet are left unassigned instead of being
+    /// moved to other workers.
+    pub fn resume(&mut self, state: GroupState, grace: Duration) {
+        debug!(
+            workers = state.workers.len(),
+            tasks = state.assignments.len(),
+            "Resume group"
+        );
+        self.resumed = Some((Instant::now() + grace, state));
+
+        // Reconcile once the grace period is over.
+        let balance_notify = self.balance_notify.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            balance_notify.notify_one();
+        });
+    }
+
+    /// Map from task to its worker before the restart, if still in the grace period.
+    fn resumed_owners(&self) -> HashMap<Uuid, Uuid> {
+        self.resumed
+            .iter()
+            .flat_map(|(_, state)| &state.assignments)
+            .map(|assignment| (assignment.task.into(), assignment.worker.into()))
+            .collect()
+    }
+
+    /// Whether the task is left unassigned until its worker before the restart reconnects.
+    fn awaits_worker(&self, resumed: &HashMap<Uuid, Uuid>, task_id: &Uuid) -> bool {
+        let pinned = self
+            .tasks
+            .get(task_id)
+            .is_some_and(|bound_task| bound_task.pinned.is_some());
+        let reconnected = |worker: &Uuid| self.workers.contains_key(worker);
+        let departed = self
+            .departed
+            .values()
+            .any(|departed| departed.tasks.contains(task_id));
+        !pinned && (departed || resumed.get(task_id).is_some_and(|worker| !reconnected(worker)))
+    }
+
+    /// Whether no worker in the group has the tags the task requires.
+    fn unschedulable(&self, task: &Task) -> bool {
+        !self.workers.values().any(|worker| task.runs_on(&worker.tags))
+    }
+
+    /// Workers in the group, with the load they reported in their last heartbeat.
+    #[must_use]
+    pub fn members(&self) -> Vec<WorkerState> {
+        self.workers
+            .values()
+            .map(|worker| WorkerState {
+                id: worker.id.into(),
+                tags: worker.tags.clone(),
+                last_heartbeat: worker.last_heartbeat(),
+                load: worker.load(),
+            })
+            .collect()
+    }
+
+    /// Send a snapshot of the group to be persisted, if enabled.
+    pub fn persist(&self) {
+        let Some((kind, tx)) = &self.snapshots else {
+            return;
+        };
+
+        let mut workers = self.members();
+        let mut assignments: Vec<_> = self
+            .tasks
+            .iter()
+            .filter_map(|(task_id, bound_task)| {
+                Some(Assignment {
+                    task: (*task_id).into(),
+                    entity: bound_task.task.entity,
+                    worker: bound_task.worker?.into(),
+                })
+            })
+            .collect();
+
+        // Keep assignments still waiting for their workers, in case of another restart.
+        if let Some((_, state)) = &self.resumed {
+            let resumed = self.resumed_owners();
+            assignments.extend(state.assignments.iter().filter(|assignment| {
+                let task_id = assignment.task.into();
+                self.tasks.contains_key(&task_id) && self.awaits_worker(&resumed, &task_id)
+            }));
+            let waited_for = state.workers.iter().filter(|worker| {
+                assignments
+                    .iter()
+                    .any(|assignment| assignment.worker == worker.id)
+                    && !self.workers.contains_key(&worker.id.into())
+            });
+            workers.extend(waited_for.cloned());
+        }
+
+        let unschedulable = self
+            .tasks
+            .iter()
+            .filter(|(_, bound_task)| {
+                bound_task.worker.is_none() && self.unschedulable(&bound_task.task)
+            })
+            .map(|(task_id, _)| (*task_id).into())
+            .collect();
+
+        // It's fine if the coordinator is shutting down.
+        drop(tx.send(GroupState {
+            kind: kind.clone(),
+            workers,
+            assignments,
+            unschedulable,
+        }));
+    }
+
     /// Balance the group.
     ///
     /// Workers not responding or inconsistent will be removed. Return `false`
@@ -239,6 +590,7 @@ impl WorkerGroupImpl {
     pub async fn balance(&mut self) -> bool {
         self.balance_impl()
             .await
+            .tap_ok(|()| self.persist())
             .tap_err(|bad_worker| {
                 warn!(worker_id=%bad_worker, "Balance: remove bad worker");
                 self.remove_worker(*bad_worker);
@@ -256,10 +608,23 @@ impl WorkerGroupImpl {
     async fn balance_impl(&mut self) -> Result<(), Uuid> {
         // TODO instrument this future
 
-        // Remove gone tasks.
+        if matches!(&self.resumed, Some((until, _)) if *until <= Instant::now()) {
+            info!("Grace period after restart is over, reconcile");
+            self.resumed = None;
+        }
+        let now = Instant::now();
+        self.departed.retain(|_, departed| departed.until > now);
+
+        // Remove gone tasks, and tasks bound to other workers, e.g. while a resumed worker was
+        // disconnected.
         for worker in self.workers.values_mut() {
             // Note that we collect tasks_gone first to avoid holding the lock across
             // awaits.
+            let owned = |task: &Uuid| {
+                self.tasks
+                    .get(task)
+                    .is_some_and(|bound_task| bound_task.worker == Some(worker.id))
+            };
 
             // Do RPC to remove tasks from remote worker.
             let tasks_gone: Vec<_> = worker
@@ -267,13 +632,18 @@ impl WorkerGroupImpl {
                 .lock()
                 .await
                 .iter()
-                .filter(|task| !self.tasks.contains_key(task))
+                .filter(|task| !owned(task))
                 .copied()
                 .collect();
             for task in tasks_gone {
                 // This task is gone, we remove it from the worker.
                 debug!(task_id=%task, worker_id=%worker.id, "Task is gone, remove from worker");
-                let resp = worker.client.remove_task(Context::current(), task).await;
+                self.version += 1;
+                let resp = worker
+                    .client
+                    .remove_task(Context::current(), task, self.version)
+                    .await;
+                worker.last_version.store(self.version, Ordering::Relaxed);
                 check_resp(
                     resp,
                     task,
@@ -284,11 +654,7 @@ impl WorkerGroupImpl {
             }
 
             // Remove tasks from local map.
-            worker
-                .tasks
-                .lock()
-                .await
-                .retain(|task| self.tasks.contains_key(task));
+            worker.tasks.lock().await.retain(owned);
         }
 
         if self.ring.is_empty() {
@@ -300,15 +666,38 @@ impl WorkerGroupImpl {
             }
         } else {
             // Migrate tasks to new workers.
+            let owners = self.ring_owners();
+            let resumed = self.resumed_owners();
+            let awaiting: HashSet<_> = self
+                .tasks
+                .keys()
+                .filter(|task_id| self.awaits_worker(&resumed, task_id))
+                .copied()
+                .collect();
             for (task_id, bound_task) in &mut self.tasks {
-                // Calculate expected worker using the ring.
-                let expected_worker_id = self.ring.get(&task_id);
+                if awaiting.contains(task_id) {
+                    // Its worker before the restart may be reconnecting.
+                    continue;
+                }
+
+                // Calculate expected worker using the ring, unless it's pinned or resumed. Only
+                // workers with the tags the task requires are eligible.
+                let eligible = |worker: &&Uuid| {
+                    self.workers
+                        .get(worker)
+                        .is_some_and(|worker| bound_task.task.runs_on(&worker.tags))
+                };
+                let expected_worker_id = bound_task
+                    .pinned
+                    .as_ref()
+                    .filter(eligible)
+                    .or_else(|| resumed.get(task_id).filter(eligible))
+                    .or_else(|| owners.get(task_id))
+                    .copied();
                 // Currently assigned worker.
                 let bound_worker_id = &mut bound_task.worker;
 
-                debug!(%task_id, worker_id=%expected_worker_id, "Migrating task");
-
-                if *bound_worker_id != Some(*expected_worker_id) {
+                if *bound_worker_id != expected_worker_id {
                     // If task is not assigned to the expected worker ...
 
                     // If the task has already assigned to a worker, remove it.
@@ -316,10 +705,14 @@ impl WorkerGroupImpl {
                         bound_worker_id.and_then(|id| self.workers.get_mut(&id))
                     {
                         // Do RPC to remove tasks from remote worker.
+                        self.version += 1;
                         let resp = old_worker
                             .client
-                            .remove_task(Context::current(), *task_id)
+                            .remove_task(Context::current(), *task_id, self.version)
                             .await;
+                        old_worker
+                            .last_version
+                            .store(self.version, Ordering::Relaxed);
                         check_resp(
                             resp,
                             *task_id,
@@ -331,6 +724,13 @@ impl WorkerGroupImpl {
                         // Remove tasks from local map.
                         old_worker.tasks.lock().await.remove(task_id);
                     }
+                    *bound_worker_id = None;
+
+                    let Some(expected_worker_id) = &expected_worker_id else {
+                        warn!(%task_id, "No worker has the tags required by the task");
+                        continue;
+                    };
+                    debug!(%task_id, worker_id=%expected_worker_id, "Migrating task");
 
                     // Assign the task to the expected worker.
                     let expected_worker = self
@@ -338,10 +738,14 @@ impl WorkerGroupImpl {
                         .get_mut(expected_worker_id)
                         .expect("Migration target worker must exist");
                     // Do RPC to add tasks to remote worker.
+                    self.version += 1;
                     let resp = expected_worker
                         .client
-                        .add_task(Context::current(), bound_task.task.clone())
+                        .add_task(Context::current(), bound_task.task.clone(), self.version)
                         .await;
+                    expected_worker
+                        .last_version
+                        .store(self.version, Ordering::Relaxed);
                     check_resp(
                         resp,
                         *task_id,
@@ -387,12 +791,15 @@ impl WorkerGroupImpl {
 
         // Worker-task and task-worker map must have the same tasks.
         let count_unallocated_task = !self.ring.is_empty();
+        let resumed = self.resumed_owners();
         assert_eq!(
             tasks,
             self.tasks
                 .iter()
-                .filter_map(|(id, BoundTask { task: _, worker })| (worker.is_some()
-                    || count_unallocated_task)
+                .filter_map(|(id, BoundTask { task, worker, .. })| (worker.is_some()
+                    || count_unallocated_task
+                        && !self.awaits_worker(&resumed, id)
+                        && !self.unschedulable(task))
                     .then_some(id))
                 .copied()
                 .collect(),
@@ -454,6 +861,10 @@ impl WorkerGroupImpl {
 pub struct Worker {
     /// Worker ID.
     id: Uuid,
+    /// Tags advertised by the worker on join.
+    tags: HashSet<String>,
+    /// Weight advertised by the worker on join, scaling its share of the ring.
+    weight: u32,
     /// Reference to the worker group.
     parent: WeakWorkerGroup,
     /// RPC client to the worker.
@@ -463,11 +874,24 @@ pub struct Worker {
     watchdog_job: ScopedJoinHandle<()>,
     /// Tasks assigned to the worker.
     tasks: Mutex<HashSet<Uuid>>,
+    /// Unix time in milliseconds the worker last answered a ping.
+    last_heartbeat: AtomicI64,
+    /// Load reported in the last heartbeat.
+    load: RwLock<Option<Load>>,
+    /// Version of the last assignment change sent to the worker.
+    last_version: AtomicU64,
 }
 
 impl Worker {
     /// Create a new worker from given stream and worker group.
-    pub fn new<S>(id: Uuid, stream: S, parent: WeakWorkerGroup, config: &Config) -> Arc<Self>
+    pub fn new<S>(
+        id: Uuid,
+        tags: HashSet<String>,
+        weight: u32,
+        stream: S,
+        parent: WeakWorkerGroup,
+        config: &Config,
+    ) -> Arc<Self>
     where
         S: Stream<Item = Result<Message, WsError>>
             + Sink<Message, Error = WsError>
@@ -475,25 +899,59 @@ impl Worker {
             + Send
             + 'static,
     {
+        let closed = Arc::new(Notify::new());
+        let hung_up = Arc::new(AtomicBool::new(false));
+        let stream = NotifyOnClose {
+            inner: stream,
+            id,
+            closed: closed.clone(),
+            hung_up: hung_up.clone(),
+            send_timeout: config.send_timeout,
+            stalled: None,
+        };
+        // Requests wait for room once the buffer is full, instead of piling up in memory.
+        let mut client_config = ClientConfig::default();
+        client_config.max_in_flight_requests = config.send_buffer.max(1);
+        client_config.pending_request_buffer = config.send_buffer.max(1);
+
         Arc::new_cyclic(|this: &Weak<Self>| {
             let this = this.clone();
-            let ping_interval = config.ping_interval;
+            let config = config.clone();
             let watchdog_job = tokio::spawn(async move {
-                let mut check_interval = tokio::time::interval(ping_interval);
+                // The first ping is sent immediately.
+                let mut delay = Duration::ZERO;
                 loop {
-                    check_interval.tick().await;
+                    tokio::select! {
+                        () = tokio::time::sleep(delay) => delay = config.ping_delay(),
+                        () = closed.notified() => {
+                            // Don't wait for the next ping to reassign its tasks. A worker that
+                            // hung up, e.g. draining, won't come back, while one that dropped
+                            // unexpectedly may reconnect and resume.
+                            if let Some(this) = this.upgrade() {
+                                if hung_up.load(Ordering::SeqCst) {
+                                    info!(worker_id = %this.id, "Worker left");
+                                    this.remove_self().await;
+                                } else {
+                                    info!(worker_id = %this.id, "Worker disconnected");
+                                    this.depart_self(config.resume_grace).await;
+                                }
+                            }
+                            break;
+                        }
+                    }
 
                     if let Some(this) = this.upgrade() {
                         let tag = rand::random();
                         let resp = this.client.ping(tarpc::context::current(), tag).await;
 
-                        if !matches!(resp, Ok(_tag)) {
+                        let Ok(heartbeat) = resp else {
                             // ping failed, remove node from worker group.
                             error!(worker_id = %this.id, "Ping failed");
                             this.remove_self().await;
 
                             break;
-                        }
+                        };
+                        this.heartbeat(heartbeat.load).await;
                     } else {
                         // self is dropped, so we can stop the watchdog.
                         break;
@@ -503,19 +961,211 @@ impl Worker {
 
             Self {
                 id,
+                tags,
+                weight,
                 parent,
-                client: WorkerRpcClient::new(ClientConfig::default(), WsTransport::new(stream))
-                    .spawn(),
+                client: WorkerRpcClient::new(client_config, WsTransport::new(stream)).spawn(),
                 watchdog_job: ScopedJoinHandle(watchdog_job),
                 tasks: Default::default(),
+                last_heartbeat: AtomicI64::new(DateTime::now().timestamp_millis()),
+                load: RwLock::new(None),
+                last_version: AtomicU64::new(0),
             }
         })
     }
 
+    /// Last time the worker answered a ping, or when it joined if it hasn't been pinged yet.
+    pub fn last_heartbeat(&self) -> DateTime {
+        DateTime::from_millis(self.last_heartbeat.load(Ordering::Relaxed))
+    }
+
+    /// Load reported in the last heartbeat, or `None` if it hasn't been pinged yet.
+    pub fn load(&self) -> Option<Load> {
+        *self.load.read().expect("Load lock poisoned")
+    }
+
+    /// Record that the worker answered a ping with its load, and persist it with the group.
+    async fn heartbeat(&self, load: Load) {
+        self.last_heartbeat
+            .store(DateTime::now().timestamp_millis(), Ordering::Relaxed);
+        *self.load.write().expect("Load lock poisoned") = Some(load);
+        if let Some(parent) = self.parent.upgrade() {
+            parent.with(|parent| parent.persist()).await;
+        }
+    }
+
     /// Remove self from worker group.
     pub async fn remove_self(&self) {
         if let Some(parent) = self.parent.upgrade() {
             parent.with(|parent| parent.remove_worker(self.id)).await;
         }
     }
+
+    /// Remove self from worker group after disconnected, keeping its tasks for `grace` in case it
+    /// reconnects.
+    pub async fn depart_self(&self, grace: Duration) {
+        if let Some(parent) = self.parent.upgrade() {
+            let tasks = self.tasks.lock().await.clone();
+            let version = self.last_version.load(Ordering::Relaxed);
+            parent
+                .with(|parent| parent.depart_worker(self.id, version, tasks, grace))
+                .await;
+        }
+    }
+}
+
+/// Websocket stream that notifies when the connection is closed, or stuck on sending.
+struct NotifyOnClose<S> {
+    inner: S,
+    /// Worker on the other end.
+    id: Uuid,
+    closed: Arc<Notify>,
+    /// Set once the worker starts the close handshake.
+    hung_up: Arc<AtomicBool>,
+    /// Time a send may be blocked for before the connection is considered stuck.
+    send_timeout: Duration,
+    /// Fires once the pending send is blocked for `send_timeout`.
+    stalled: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> NotifyOnClose<S> {
+    /// Fail a send that's been blocked for `send_timeout`, and notify that the connection is
+    /// closed so that the worker is removed.
+    fn check_stalled(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+        poll: Poll<Result<(), WsError>>,
+    ) -> Poll<Result<(), WsError>> {
+        if poll.is_ready() {
+            self.stalled = None;
+            return poll;
+        }
+
+        let send_timeout = self.send_timeout;
+        let stalled = self
+            .stalled
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(send_timeout)));
+        futures_util::ready!(stalled.as_mut().poll(cx));
+
+        warn!(worker_id = %self.id, ?send_timeout, "Worker stuck, send blocked for too long");
+        self.stalled = None;
+        self.closed.notify_one();
+        Poll::Ready(Err(WsError::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "send to worker blocked for too long",
+        ))))
+    }
+}
+
+impl<S> Stream for NotifyOnClose<S>
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let item = futures_util::ready!(self.inner.poll_next_unpin(cx));
+        if matches!(item, Some(Ok(Message::Close(_)))) {
+            self.hung_up.store(true, Ordering::SeqCst);
+        }
+        if matches!(item, None | Some(Err(_))) {
+            self.closed.notify_one();
+        }
+        Poll::Ready(item)
+    }
+}
+
+impl<S> Sink<Message> for NotifyOnClose<S>
+where
+    S: Sink<Message, Error = WsError> + Unpin,
+{
+    type Error = WsError;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        let poll = self.inner.poll_ready_unpin(cx);
+        self.check_stalled(cx, poll)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        let poll = self.inner.poll_flush_unpin(cx);
+        self.check_stalled(cx, poll)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::Arc,
+        task::{Context as TaskContext, Poll},
+        time::{Duration, Instant},
+    };
+
+    use futures_util::{Sink, SinkExt};
+    use tokio::sync::Notify;
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+    use uuid::Uuid;
+
+    use crate::worker::NotifyOnClose;
+
+    /// Sink of a worker that never drains its connection.
+    struct Stuck;
+
+    impl Sink<Message> for Stuck {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Pending
+        }
+
+        fn start_send(self: Pin<&mut Self>, _: Message) -> Result<(), WsError> {
+            unreachable!("Never ready to send")
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn must_detect_stuck_worker() {
+        let closed = Arc::new(Notify::new());
+        let mut stream = NotifyOnClose {
+            inner: Stuck,
+            id: Uuid::new_v4(),
+            closed: closed.clone(),
+            hung_up: Arc::default(),
+            send_timeout: Duration::from_millis(100),
+            stalled: None,
+        };
+
+        // The send waits, then fails once blocked for too long
+        let start = Instant::now();
+        assert!(stream.send(Message::Ping(vec![])).await.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        tokio::time::timeout(Duration::from_secs(1), closed.notified())
+            .await
+            .unwrap();
+    }
 }
@@ -30,17 +30,16 @@ where
     type Item = Result<Item, TransportError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Poll::Ready(match ready!(self.0.poll_next_unpin(cx)) {
-            Some(Ok(e)) => {
-                if let Message::Binary(data) = e {
-                    Some(Ok(serde_json::from_slice(&data)?))
-                } else {
-                    return Poll::Pending;
-                }
-            }
-            Some(Err(e)) => Some(Err(e.into())),
-            None => None,
-        })
+        loop {
+            return Poll::Ready(match ready!(self.0.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(data))) => Some(Ok(serde_json::from_slice(&data)?)),
+                // Control frames are handled by tungstenite. Keep polling so that the stream
+                // ends after a close handshake.
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => Some(Err(e.into())),
+                None => None,
+            });
+        }
     }
 }
 
@@ -0,0 +1,181 @@
+//! Registry of event kinds and the fields their events must carry.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display, Formatter},
+};
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::models::Event;
+
+/// JSON type of an event field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    /// A string.
+    String,
+    /// A number, integer or not.
+    Number,
+    /// `true` or `false`.
+    Bool,
+    /// An array of any values.
+    Array,
+    /// An object of any fields.
+    Object,
+}
+
+impl FieldType {
+    /// Whether `value` is of this type. `null` is of no type.
+    #[must_use]
+    pub const fn matches(self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (Self::String, Value::String(_))
+                | (Self::Number, Value::Number(_))
+                | (Self::Bool, Value::Bool(_))
+                | (Self::Array, Value::Array(_))
+                | (Self::Object, Value::Object(_))
+        )
+    }
+}
+
+impl Display for FieldType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Bool => "bool",
+            Self::Array => "array",
+            Self::Object => "object",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Fields an event of some kind must carry, along with their types. Other fields are allowed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventSchema {
+    required: BTreeMap<String, FieldType>,
+}
+
+impl EventSchema {
+    /// Create a schema requiring no field.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require a field of type `ty`.
+    #[must_use]
+    pub fn field(mut self, name: impl Into<String>, ty: FieldType) -> Self {
+        self.required.insert(name.into(), ty);
+        self
+    }
+
+    /// Required fields and their types, sorted by name.
+    pub fn required(&self) -> impl Iterator<Item = (&str, FieldType)> {
+        self.required.iter().map(|(name, ty)| (name.as_str(), *ty))
+    }
+}
+
+/// Registered event schemas, keyed by event kind.
+#[derive(Debug, Clone)]
+pub struct EventSchemas {
+    kinds: HashMap<String, EventSchema>,
+}
+
+impl EventSchemas {
+    /// Create an empty registry.
+    #[must_use]
+    pub fn empty() -> Self {
+        Self {
+            kinds: HashMap::new(),
+        }
+    }
+
+    /// Register the schema of events of `kind`, replacing the previous one.
+    #[must_use]
+    pub fn register(mut self, kind: impl Into<String>, schema: EventSchema) -> Self {
+        self.kinds.insert(kind.into(), schema);
+        self
+    }
+
+    /// Schema of events of `kind`, if registered.
+    #[must_use]
+    pub fn get(&self, kind: &str) -> Option<&EventSchema> {
+        self.kinds.get(kind)
+    }
+
+    /// Check `event` against the schema of its kind. Events of unregistered kinds always pass.
+    ///
+    /// # Errors
+    /// Returns an error if the event misses a required field or has one of the wrong type.
+    pub fn validate(&self, event: &Event) -> Result<()> {
+        match self.get(&event.kind) {
+            Some(schema) => event.validate_against(schema),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for EventSchemas {
+    /// Schemas of events emitted by built-in workers.
+    fn default() -> Self {
+        Self::empty()
+            .register(
+                "twitter",
+                EventSchema::new()
+                    .field("id", FieldType::Number)
+                    .field("text", FieldType::String)
+                    .field("photos", FieldType::Array)
+                    .field("link", FieldType::String)
+                    .field("is_rt", FieldType::Bool),
+            )
+            .register(
+                "bililive",
+                EventSchema::new()
+                    .field("title", FieldType::String)
+                    .field("link", FieldType::String),
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::Uuid;
+    use serde_json::json;
+
+    use crate::{
+        event_schema::{EventSchema, EventSchemas, FieldType},
+        models::Event,
+    };
+
+    #[test]
+    fn must_validate() {
+        let schemas = EventSchemas::empty().register(
+            "a",
+            EventSchema::new()
+                .field("text", FieldType::String)
+                .field("count", FieldType::Number),
+        );
+        let event = |kind, fields| Event::from_serializable(kind, Uuid::new(), fields).unwrap();
+
+        schemas
+            .validate(&event("a", json!({ "text": "hi", "count": 1, "extra": null })))
+            .unwrap();
+        // Missing field
+        assert!(schemas.validate(&event("a", json!({ "text": "hi" }))).is_err());
+        // Wrong type
+        assert!(schemas
+            .validate(&event("a", json!({ "text": "hi", "count": "1" })))
+            .is_err());
+        assert!(schemas
+            .validate(&event("a", json!({ "text": null, "count": 1 })))
+            .is_err());
+        // Unregistered kind
+        schemas.validate(&event("b", json!({}))).unwrap();
+    }
+}
@@ -0,0 +1,690 @@
+//! Delivery of events to users on IM platforms.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Debug, Formatter},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use eyre::{Report, Result};
+use futures_util::{future, TryStreamExt};
+use isolanguage_1::LanguageCode;
+use mongodb::{
+    bson::{doc, DateTime, Uuid},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, time::Instant};
+use tracing::{error, info, warn};
+
+use crate::models::{DeadLetter, Event, User};
+
+/// A backend delivering events to users on one IM platform.
+#[async_trait]
+pub trait ImBackend: Send + Sync {
+    /// The platform this backend delivers to, i.e. [`User::im`] of its users.
+    fn platform(&self) -> &str;
+
+    /// Deliver `event` to `user`, rendering text and the entity name in `lang`.
+    ///
+    /// Names missing in `lang` should fall back to the default language of the entity, see
+    /// [`Event::name_in`].
+    ///
+    /// # Errors
+    /// Returns an error if the event can't be delivered.
+    async fn deliver(&self, user: &User, event: &Event, lang: LanguageCode) -> Result<()>;
+}
+
+/// How failed deliveries are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Number of attempts before giving up, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled after each retry.
+    #[serde(with = "humantime_serde")]
+    pub backoff: Duration,
+    /// Upper bound of the delay between retries.
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before the `retry`-th retry, counting from 0.
+    #[must_use]
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How fast events are delivered to a single user, to avoid being throttled by the platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeliveryLimit {
+    /// Deliveries per minute to a user. `0` disables the limit.
+    pub per_minute: u32,
+    /// Deliveries to a user that can be made at once.
+    pub burst: u32,
+    /// Maximum number of deliveries waiting for a user, above which the oldest one is dropped.
+    pub queue_len: usize,
+}
+
+impl DeliveryLimit {
+    /// Size of the token bucket. At least one delivery must be possible.
+    fn capacity(self) -> f64 {
+        f64::from(self.burst.max(1))
+    }
+
+    /// Tokens refilled per second.
+    fn rate(self) -> f64 {
+        f64::from(self.per_minute) / 60.
+    }
+}
+
+impl Default for DeliveryLimit {
+    fn default() -> Self {
+        Self {
+            per_minute: 20,
+            burst: 5,
+            queue_len: 100,
+        }
+    }
+}
+
+/// A delivery waiting for its turn.
+struct Pending {
+    user: User,
+    event: Event,
+}
+
+/// Outcome of [`ImRegistry::dispatch`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Dispatched {
+    /// Deliveries made, after retries if any.
+    pub delivered: usize,
+    /// Deliveries queued behind the delivery limit, to be made later.
+    pub queued: usize,
+}
+
+/// Deliveries waiting for a user, and the token bucket they're spaced out by.
+struct UserQueue {
+    pending: VecDeque<Pending>,
+    tokens: f64,
+    last: Instant,
+    /// Wakes the drain task up on new deliveries.
+    notify: Arc<Notify>,
+}
+
+/// What the drain task of a queue does next.
+enum Step {
+    Deliver(Box<Pending>),
+    Wait(Duration, Arc<Notify>),
+    Done,
+}
+
+impl UserQueue {
+    fn new(limit: DeliveryLimit) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            tokens: limit.capacity(),
+            last: Instant::now(),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    fn step(&mut self, limit: DeliveryLimit, now: Instant) -> Step {
+        let capacity = limit.capacity();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = elapsed.mul_add(limit.rate(), self.tokens).min(capacity);
+        self.last = now;
+
+        if self.pending.is_empty() {
+            // Kept until the bucket is full, so the limit holds across bursts
+            return if self.tokens >= capacity {
+                Step::Done
+            } else {
+                let wait = (capacity - self.tokens) / limit.rate();
+                Step::Wait(Duration::from_secs_f64(wait), self.notify.clone())
+            };
+        }
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            Step::Deliver(Box::new(
+                self.pending.pop_front().expect("Queue must not be empty"),
+            ))
+        } else {
+            let wait = (1. - self.tokens) / limit.rate();
+            Step::Wait(Duration::from_secs_f64(wait), self.notify.clone())
+        }
+    }
+}
+
+/// Registered IM backends, keyed by platform.
+#[derive(Clone, Default)]
+pub struct ImRegistry {
+    backends: HashMap<String, Arc<dyn ImBackend>>,
+    retry: RetryPolicy,
+    /// Where undeliverable events go. They're dropped if unset.
+    dead_letters: Option<Collection<DeadLetter>>,
+    /// Unlimited if unset.
+    limit: Option<DeliveryLimit>,
+    /// Queued deliveries, keyed by user id.
+    queues: Arc<Mutex<HashMap<Uuid, UserQueue>>>,
+}
+
+impl ImRegistry {
+    /// Create an empty registry.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a backend. A backend of the same platform is replaced.
+    #[must_use]
+    pub fn register(mut self, backend: impl ImBackend + 'static) -> Self {
+        self.backends
+            .insert(backend.platform().to_owned(), Arc::new(backend));
+        self
+    }
+
+    /// Retry failed deliveries with `retry`.
+    #[must_use]
+    pub const fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Space out deliveries to each user by `limit`.
+    #[must_use]
+    pub const fn with_limit(mut self, limit: DeliveryLimit) -> Self {
+        self.limit = if limit.per_minute == 0 {
+            None
+        } else {
+            Some(limit)
+        };
+        self
+    }
+
+    /// Keep events that can't be delivered after all retries in `collection`.
+    #[must_use]
+    pub fn with_dead_letters(mut self, collection: Collection<DeadLetter>) -> Self {
+        self.dead_letters = Some(collection);
+        self
+    }
+
+    /// Get the backend of `platform`.
+    #[must_use]
+    pub fn get(&self, platform: &str) -> Option<&Arc<dyn ImBackend>> {
+        self.backends.get(platform)
+    }
+
+    /// Platforms with a registered backend.
+    pub fn platforms(&self) -> impl Iterator<Item = &str> {
+        self.backends.keys().map(String::as_str)
+    }
+
+    /// Deliver `event` to each of `users` with the backend of their platform, and return how many
+    /// deliveries succeeded or are queued.
+    ///
+    /// Events are delivered in the preferred language of each user. Users who are muted or whose
+    /// platform has no backend are skipped. With a delivery limit, deliveries are queued and made
+    /// in the background, spaced out per user, and the oldest waiting ones are dropped once too
+    /// many are queued. Failed deliveries are retried, and kept as dead letters once retries are
+    /// exhausted.
+    pub async fn dispatch(&self, users: &[User], event: &Event) -> Dispatched {
+        let now = DateTime::now();
+        let mut queued = 0;
+        let mut deliveries = vec![];
+        for user in users {
+            match self.get(&user.im) {
+                _ if user.is_muted_at(now) => {
+                    info!(user = %user.id, event = %event.id, "User muted, skipped");
+                }
+                Some(backend) => match self.limit {
+                    Some(limit) => {
+                        self.enqueue(limit, user, event);
+                        queued += 1;
+                    }
+                    None => deliveries.push(self.deliver_or_bury(backend.as_ref(), user, event)),
+                },
+                None => {
+                    warn!(user = %user.id, im = %user.im, "No backend for platform, skipped");
+                }
+            }
+        }
+
+        let delivered = future::join_all(deliveries)
+            .await
+            .into_iter()
+            .filter(|delivered| *delivered)
+            .count();
+        Dispatched { delivered, queued }
+    }
+
+    /// Deliver dead letters marked for replay, and return how many are delivered.
+    ///
+    /// Delivered letters are removed. The others are updated with the new error and unmarked.
+    ///
+    /// # Errors
+    /// Returns an error on database error.
+    pub async fn replay(&self) -> Result<usize> {
+        let dead_letters = match &self.dead_letters {
+            Some(dead_letters) => dead_letters,
+            None => return Ok(0),
+        };
+
+        let letters: Vec<_> = dead_letters
+            .find(doc! { "replay": true }, None)
+            .await?
+            .try_collect()
+            .await?;
+        let mut delivered = 0;
+
+        for letter in letters {
+            let backend = match self.get(&letter.user.im) {
+                Some(backend) => backend,
+                None => {
+                    warn!(
+                        id = %letter.id, im = %letter.user.im,
+                        "No backend for platform, skipped"
+                    );
+                    continue;
+                }
+            };
+
+            match self.deliver(backend.as_ref(), &letter.user, &letter.event).await {
+                Ok(()) => {
+                    dead_letters
+                        .delete_one(doc! { "id": letter.id }, None)
+                        .await?;
+                    delivered += 1;
+                }
+                Err((attempts, e)) => {
+                    let update = doc! {
+                        "$set": {
+                            "error": format!("{:#}", e),
+                            "failed_at": DateTime::now(),
+                            "replay": false,
+                        },
+                        "$inc": { "attempts": i64::from(attempts) },
+                    };
+                    dead_letters
+                        .update_one(doc! { "id": letter.id }, update, None)
+                        .await?;
+                }
+            }
+        }
+
+        info!(delivered, "Dead letters replayed");
+        Ok(delivered)
+    }
+
+    /// Queue a delivery to `user`, made by the drain task of its queue.
+    fn enqueue(&self, limit: DeliveryLimit, user: &User, event: &Event) {
+        let spawn = {
+            let mut queues = self.queues.lock().expect("Delivery queue lock poisoned");
+            let spawn = !queues.contains_key(&user.id);
+            let queue = queues
+                .entry(user.id)
+                .or_insert_with(|| UserQueue::new(limit));
+
+            if queue.pending.len() >= limit.queue_len {
+                if let Some(oldest) = queue.pending.pop_front() {
+                    warn!(
+                        user = %user.id, event = %oldest.event.id,
+                        "Delivery queue full, oldest event dropped"
+                    );
+                }
+            }
+            queue.pending.push_back(Pending {
+                user: user.clone(),
+                event: event.clone(),
+            });
+            queue.notify.notify_one();
+            spawn
+        };
+
+        if spawn {
+            tokio::spawn(self.clone().drain(limit, user.id));
+        }
+    }
+
+    /// Deliver queued events of user `id` as the limit allows, until the queue is drained.
+    async fn drain(self, limit: DeliveryLimit, id: Uuid) {
+        loop {
+            let step = {
+                let mut queues = self.queues.lock().expect("Delivery queue lock poisoned");
+                let queue = queues.get_mut(&id).expect("Queue is only removed by its drain task");
+                let step = queue.step(limit, Instant::now());
+                if matches!(step, Step::Done) {
+                    queues.remove(&id);
+                }
+                step
+            };
+
+            match step {
+                Step::Deliver(pending) => {
+                    let Pending { user, event } = *pending;
+                    if let Some(backend) = self.get(&user.im) {
+                        self.deliver_or_bury(backend.as_ref(), &user, &event).await;
+                    }
+                }
+                Step::Wait(wait, notify) => {
+                    // Woken up by a new delivery, or once the bucket is refilled
+                    drop(tokio::time::timeout(wait, notify.notified()).await);
+                }
+                Step::Done => return,
+            }
+        }
+    }
+
+    /// Deliver with retries, and keep the event as a dead letter if it fails.
+    async fn deliver_or_bury(&self, backend: &dyn ImBackend, user: &User, event: &Event) -> bool {
+        match self.deliver(backend, user, event).await {
+            Ok(()) => true,
+            Err((attempts, e)) => {
+                self.bury(user, event, attempts, &e).await;
+                false
+            }
+        }
+    }
+
+    /// Deliver with retries. Returns the number of attempts and the last error on failure.
+    async fn deliver(
+        &self,
+        backend: &dyn ImBackend,
+        user: &User,
+        event: &Event,
+    ) -> Result<(), (u32, Report)> {
+        let mut attempt = 1;
+        loop {
+            match backend.deliver(user, event, user.preferred_language).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= self.retry.attempts => {
+                    error!(user = %user.id, im = %user.im, attempt, "Failed to deliver: {:#}", e);
+                    return Err((attempt, e));
+                }
+                Err(e) => {
+                    warn!(
+                        user = %user.id, im = %user.im, attempt,
+                        "Failed to deliver, retrying: {:#}", e
+                    );
+                    tokio::time::sleep(self.retry.delay(attempt - 1)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Keep an undeliverable event as a dead letter.
+    async fn bury(&self, user: &User, event: &Event, attempts: u32, error: &Report) {
+        let dead_letters = match &self.dead_letters {
+            Some(dead_letters) => dead_letters,
+            None => return,
+        };
+        let letter = DeadLetter {
+            id: Uuid::new(),
+            user: user.clone(),
+            event: event.clone(),
+            error: format!("{:#}", error),
+            attempts,
+            failed_at: DateTime::now(),
+            replay: false,
+        };
+
+        if let Err(e) = dead_letters.insert_one(&letter, None).await {
+            error!(user = %user.id, "Failed to keep dead letter: {:#}", e);
+        }
+    }
+}
+
+impl Debug for ImRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImRegistry")
+            .field("backends", &self.backends.keys().collect::<Vec<_>>())
+            .field("retry", &self.retry)
+            .field("limit", &self.limit)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use async_trait::async_trait;
+    use eyre::{bail, Result};
+    use isolanguage_1::LanguageCode;
+    use mongodb::bson::{DateTime, Uuid};
+    use serde_json::json;
+
+    use crate::{
+        im::{DeliveryLimit, ImBackend, ImRegistry, RetryPolicy},
+        models::{Event, EventFilter, Meta, Name, User},
+    };
+
+    struct MockBackend {
+        platform: &'static str,
+        delivered: Arc<Mutex<Vec<Uuid>>>,
+        /// Entity names rendered in deliveries.
+        rendered: Arc<Mutex<Vec<String>>>,
+        /// Number of failures before a `flaky` user is reached.
+        failures: Mutex<u32>,
+    }
+
+    #[async_trait]
+    impl ImBackend for MockBackend {
+        fn platform(&self) -> &str {
+            self.platform
+        }
+
+        async fn deliver(&self, user: &User, event: &Event, lang: LanguageCode) -> Result<()> {
+            match user.name.as_str() {
+                "blocked" => bail!("blocked by user"),
+                "flaky" => {
+                    let mut failures = self.failures.lock().unwrap();
+                    if *failures > 0 {
+                        *failures -= 1;
+                        bail!("network error");
+                    }
+                }
+                _ => {}
+            }
+            self.delivered.lock().unwrap().push(user.id);
+            if let Some(name) = event.name_in(lang) {
+                self.rendered.lock().unwrap().push(name.to_owned());
+            }
+            Ok(())
+        }
+    }
+
+    fn user(im: &str, name: &str) -> User {
+        User {
+            id: Uuid::new(),
+            im: im.to_owned(),
+            im_payload: String::new(),
+            name: name.to_owned(),
+            avatar: None,
+            event_filter: EventFilter {
+                entities: Default::default(),
+                groups: Default::default(),
+                kinds: Default::default(),
+            },
+            muted_until: None,
+            preferred_language: LanguageCode::En,
+        }
+    }
+
+    fn mock_registry(failures: u32, attempts: u32) -> (ImRegistry, Arc<Mutex<Vec<Uuid>>>) {
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let registry = ImRegistry::new()
+            .register(MockBackend {
+                platform: "tg",
+                delivered: delivered.clone(),
+                rendered: Arc::default(),
+                failures: Mutex::new(failures),
+            })
+            .with_retry(RetryPolicy {
+                attempts,
+                backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(2),
+            });
+        (registry, delivered)
+    }
+
+    /// Wait until `n` deliveries are made, or panic after a second.
+    async fn wait_delivered(delivered: &Mutex<Vec<Uuid>>, n: usize) {
+        let made = async {
+            while delivered.lock().unwrap().len() < n {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), made)
+            .await
+            .expect("Deliveries not made in time");
+    }
+
+    #[tokio::test]
+    async fn must_dispatch() {
+        let (registry, delivered) = mock_registry(0, 1);
+        let event = Event::from_serializable("a", Uuid::new(), json!({})).unwrap();
+
+        // Unknown platforms are skipped, and failures don't affect others
+        let users = [user("tg", "a"), user("discord", "b"), user("tg", "blocked")];
+        assert_eq!(registry.dispatch(&users, &event).await.delivered, 1);
+        assert_eq!(*delivered.lock().unwrap(), [users[0].id]);
+        assert!(registry.get("discord").is_none());
+    }
+
+    #[tokio::test]
+    async fn must_skip_muted() {
+        let (registry, delivered) = mock_registry(0, 1);
+        let event = Event::from_serializable("a", Uuid::new(), json!({})).unwrap();
+        let now = DateTime::now().timestamp_millis();
+        let muted = |offset: i64| User {
+            muted_until: Some(DateTime::from_millis(now + offset)),
+            ..user("tg", "a")
+        };
+
+        // Expired mutes are ignored
+        let users = [muted(60_000), muted(-60_000)];
+        assert_eq!(registry.dispatch(&users, &event).await.delivered, 1);
+        assert_eq!(*delivered.lock().unwrap(), [users[1].id]);
+    }
+
+    #[tokio::test]
+    async fn must_deliver_in_preferred_language() {
+        let rendered = Arc::new(Mutex::new(vec![]));
+        let registry = ImRegistry::new().register(MockBackend {
+            platform: "tg",
+            delivered: Arc::default(),
+            rendered: rendered.clone(),
+            failures: Mutex::new(0),
+        });
+        let meta = Meta {
+            name: Name {
+                name: HashMap::from([
+                    (LanguageCode::En, "Suisei".to_owned()),
+                    (LanguageCode::Ja, "星街すいせい".to_owned()),
+                ]),
+                default_language: LanguageCode::Ja,
+            },
+            group: None,
+            debut: None,
+            links: HashMap::new(),
+        };
+        let event =
+            Event::from_serializable_localized("a", Uuid::new(), &meta, json!({})).unwrap();
+        let in_lang = |lang| User {
+            preferred_language: lang,
+            ..user("tg", "a")
+        };
+
+        // Names missing in the preferred language fall back to the default one
+        let users = [in_lang(LanguageCode::En)];
+        assert_eq!(registry.dispatch(&users, &event).await.delivered, 1);
+        let users = [in_lang(LanguageCode::Zh)];
+        assert_eq!(registry.dispatch(&users, &event).await.delivered, 1);
+        assert_eq!(*rendered.lock().unwrap(), ["Suisei", "星街すいせい"]);
+    }
+
+    #[tokio::test]
+    async fn must_retry() {
+        let event = Event::from_serializable("a", Uuid::new(), json!({})).unwrap();
+        let users = [user("tg", "flaky")];
+
+        let (registry, _) = mock_registry(2, 3);
+        assert_eq!(registry.dispatch(&users, &event).await.delivered, 1);
+
+        let (registry, _) = mock_registry(3, 3);
+        assert_eq!(registry.dispatch(&users, &event).await.delivered, 0);
+    }
+
+    #[tokio::test]
+    async fn must_limit_per_user() {
+        let event = Event::from_serializable("a", Uuid::new(), json!({})).unwrap();
+        let a = user("tg", "a");
+        let b = user("tg", "b");
+
+        // 100ms per delivery after the first
+        let (registry, delivered) = mock_registry(0, 1);
+        let registry = registry.with_limit(DeliveryLimit {
+            per_minute: 600,
+            burst: 1,
+            queue_len: 10,
+        });
+        let start = Instant::now();
+        let users = [a.clone(), b.clone(), a.clone(), a.clone()];
+        let dispatched = registry.dispatch(&users, &event).await;
+        // Returned without waiting for the queue
+        assert_eq!(dispatched.queued, 4);
+        assert!(start.elapsed() < Duration::from_millis(50));
+        wait_delivered(&delivered, 4).await;
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        // Other users are not held up
+        assert_eq!(delivered.lock().unwrap()[..2], [a.id, b.id]);
+
+        // The oldest waiting delivery is dropped
+        let (registry, delivered) = mock_registry(0, 1);
+        let registry = registry.with_limit(DeliveryLimit {
+            per_minute: 60_000,
+            burst: 1,
+            queue_len: 2,
+        });
+        let users = [a.clone(), a.clone(), a.clone()];
+        assert_eq!(registry.dispatch(&users, &event).await.queued, 3);
+        wait_delivered(&delivered, 2).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(delivered.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn must_back_off() {
+        let retry = RetryPolicy {
+            attempts: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+        };
+        let delays: Vec<_> = (0..4).map(|retry_| retry.delay(retry_).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5]);
+        assert_eq!(retry.delay(u32::MAX), Duration::from_secs(5));
+    }
+}
@@ -6,8 +6,15 @@ pub use async_trait;
 
 pub mod adapter;
 pub mod error;
+pub mod event_schema;
+pub mod im;
+pub mod load;
+pub mod message;
 pub mod models;
 #[cfg(feature = "mq")]
 pub mod mq;
 pub mod protocol;
+pub mod schedule;
+pub mod task_kind;
 pub mod utils;
+pub mod webhook;
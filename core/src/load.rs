@@ -0,0 +1,179 @@
+//! Load of workers, reported to the coordinator in heartbeats.
+
+use std::{
+    collections::HashSet,
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::Task;
+
+/// Clock ticks per second in `/proc`, fixed by the kernel ABI regardless of `CONFIG_HZ`.
+const USER_HZ: u64 = 100;
+
+/// Lightweight load metrics of a worker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Load {
+    /// Number of entities the worker has tasks of.
+    pub entities: usize,
+    /// Number of tasks on the worker.
+    pub tasks: usize,
+    /// CPU time used since the last heartbeat, as a fraction of one core. `None` if unavailable.
+    pub cpu: Option<f64>,
+    /// Resident memory in bytes. `None` if unavailable.
+    pub memory: Option<u64>,
+    /// Fraction of task runs that failed since the last heartbeat. `0` if nothing ran.
+    pub error_rate: f64,
+}
+
+/// Measures the [`Load`] of a worker between heartbeats.
+///
+/// CPU and memory are read from `/proc`, so they're only available on Linux.
+#[derive(Debug, Default)]
+pub struct LoadMeter {
+    runs: AtomicU64,
+    errors: AtomicU64,
+    /// When the last sample is taken, and CPU time used by then.
+    last_sample: Mutex<Option<(Instant, Duration)>>,
+}
+
+impl LoadMeter {
+    /// Create a meter with nothing recorded.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a run of a task, failed or not.
+    pub fn record<T, E>(&self, result: &Result<T, E>) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Load since the last sample, with `tasks` running on the worker. Runs recorded so far are
+    /// reset.
+    pub fn sample<'a>(&self, tasks: impl IntoIterator<Item = &'a Task>) -> Load {
+        let (tasks, entities) = tasks
+            .into_iter()
+            .fold((0, HashSet::new()), |(count, mut entities), task| {
+                entities.insert(task.entity);
+                (count + 1, entities)
+            });
+
+        let runs = self.runs.swap(0, Ordering::Relaxed);
+        let errors = self.errors.swap(0, Ordering::Relaxed);
+        #[allow(clippy::cast_precision_loss)]
+        let error_rate = if runs == 0 {
+            0.0
+        } else {
+            errors as f64 / runs as f64
+        };
+
+        Load {
+            entities: entities.len(),
+            tasks,
+            cpu: self.sample_cpu(),
+            memory: fs::read_to_string("/proc/self/status")
+                .ok()
+                .and_then(|status| parse_rss(&status)),
+            error_rate,
+        }
+    }
+
+    fn sample_cpu(&self) -> Option<f64> {
+        let used = parse_cpu_time(&fs::read_to_string("/proc/self/stat").ok()?)?;
+        let now = Instant::now();
+        let last = self
+            .last_sample
+            .lock()
+            .expect("Load meter lock poisoned")
+            .replace((now, used));
+
+        // Nothing to compare with on the first sample.
+        let (then, used_then) = last?;
+        let elapsed = now.duration_since(then).as_secs_f64();
+        (elapsed > 0.0).then(|| used.saturating_sub(used_then).as_secs_f64() / elapsed)
+    }
+}
+
+/// CPU time used by the process, from the content of `/proc/self/stat`.
+fn parse_cpu_time(stat: &str) -> Option<Duration> {
+    // The command name may contain spaces, so fields are counted after it. `utime` and `stime`
+    // are the 14th and 15th fields, and the state after the name is the 3rd.
+    let (_, fields) = stat.rsplit_once(')')?;
+    let mut fields = fields.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(Duration::from_millis((utime + stime) * 1000 / USER_HZ))
+}
+
+/// Resident memory of the process in bytes, from the content of `/proc/self/status`.
+fn parse_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line
+        .trim_start_matches("VmRSS:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mongodb::bson::Uuid;
+
+    use crate::{
+        load::{parse_cpu_time, parse_rss, LoadMeter},
+        models::Task,
+    };
+
+    #[test]
+    fn must_parse_proc() {
+        let stat = "1234 (tokio (worker)) S 1 1234 1234 0 -1 4194560 5713 0 0 0 150 50 0 0 20 0 9";
+        assert_eq!(parse_cpu_time(stat), Some(Duration::from_secs(2)));
+        assert_eq!(parse_cpu_time("1234 (truncated"), None);
+
+        let status = "Name:\tworker\nVmPeak:\t  20000 kB\nVmRSS:\t   1536 kB\nThreads:\t4\n";
+        assert_eq!(parse_rss(status), Some(1536 * 1024));
+        assert_eq!(parse_rss("Name:\tworker\n"), None);
+    }
+
+    #[test]
+    fn must_sample_load() {
+        let meter = LoadMeter::new();
+        let entity = Uuid::new();
+        let tasks = [
+            Task::new_twitter("a", entity),
+            Task::new_bilibili("b", entity),
+            Task::new_twitter("c", Uuid::new()),
+        ];
+
+        meter.record(&Ok::<_, ()>(()));
+        meter.record(&Err::<(), _>(()));
+        meter.record(&Ok::<_, ()>(()));
+        meter.record(&Ok::<_, ()>(()));
+        let load = meter.sample(&tasks);
+        assert_eq!((load.tasks, load.entities), (3, 2));
+        assert!((load.error_rate - 0.25).abs() < f64::EPSILON);
+
+        // Runs are counted since the last sample
+        let load = meter.sample(&tasks[..1]);
+        assert_eq!((load.tasks, load.entities), (1, 1));
+        assert!(load.error_rate.abs() < f64::EPSILON);
+        if cfg!(target_os = "linux") {
+            assert!(load.cpu.is_some() && load.memory.is_some());
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! Messages exchanged between workers and the coordinator.
+//!
+//! Workers connect to the coordinator over websocket, announcing themselves with the
+//! `Sg-Worker-ID`, `Sg-Worker-Kind`, optional `Sg-Worker-Tags`, optional
+//! [`WEIGHT_HEADER`](crate::protocol::WEIGHT_HEADER), [`PROTOCOL_HEADER`] and, when reconnecting,
+//! [`VERSION_HEADER`](crate::protocol::VERSION_HEADER) handshake headers.
+//!
+//! After the handshake, the coordinator sends requests and the worker answers them, each as a
+//! JSON binary frame wrapping a [`ProtocolMessage`] in a tarpc envelope. A worker drains by
+//! starting a websocket close handshake, there's no message for it.
+
+use eyre::Result;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    models::Task,
+    protocol::{Heartbeat, WorkerRpcRequest, WorkerRpcResponse},
+};
+
+/// Version of the worker-coordinator protocol. Bumped on incompatible changes to
+/// [`ProtocolMessage`].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Header carrying the [`PROTOCOL_VERSION`] a worker speaks. Workers without it are rejected.
+pub const PROTOCOL_HEADER: &str = "Sg-Protocol-Version";
+
+/// A message of the worker-coordinator protocol.
+///
+/// Serialized as a JSON object whose `type` field names the variant, e.g.
+/// `{"type": "abandon_task", "id": "...", "version": 3}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProtocolMessage {
+    /// Coordinator to worker: ping, answered by a [`Heartbeat`](Self::Heartbeat).
+    Ping {
+        /// Id of the ping, echoed in the heartbeat.
+        id: u64,
+    },
+    /// Worker to coordinator: answer to a [`Ping`](Self::Ping), carrying the load of the worker.
+    Heartbeat(Heartbeat),
+    /// Coordinator to worker: take over a task as of assignment `version`. Answered by an
+    /// [`Ack`](Self::Ack).
+    TakeTask {
+        /// Task to take.
+        task: Task,
+        /// Assignment version of this change.
+        version: u64,
+    },
+    /// Coordinator to worker: abandon a task as of assignment `version`. Answered by an
+    /// [`Ack`](Self::Ack).
+    AbandonTask {
+        /// Id of the task to abandon.
+        id: Uuid,
+        /// Assignment version of this change.
+        version: u64,
+    },
+    /// Worker to coordinator: answer to a [`TakeTask`](Self::TakeTask) or
+    /// [`AbandonTask`](Self::AbandonTask).
+    Ack {
+        /// Kind of the acknowledged request.
+        request: AckedRequest,
+        /// `false` if the task was already taken, or not found when abandoning.
+        accepted: bool,
+    },
+    /// Coordinator to worker: list the tasks running on the worker. Answered by an
+    /// [`AssignmentSnapshot`](Self::AssignmentSnapshot).
+    ListTasks,
+    /// Worker to coordinator: tasks running on the worker.
+    AssignmentSnapshot {
+        /// Running tasks.
+        tasks: Vec<Task>,
+    },
+}
+
+/// Request acknowledged by a [`ProtocolMessage::Ack`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckedRequest {
+    /// [`ProtocolMessage::TakeTask`].
+    TakeTask,
+    /// [`ProtocolMessage::AbandonTask`].
+    AbandonTask,
+}
+
+/// A message was received in the wrong direction.
+#[derive(Debug, Error)]
+#[error("Unexpected {0} message")]
+pub struct UnexpectedMessage(&'static str);
+
+impl ProtocolMessage {
+    /// Name of the variant, as in the `type` field.
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Ping { .. } => "ping",
+            Self::Heartbeat(_) => "heartbeat",
+            Self::TakeTask { .. } => "take_task",
+            Self::AbandonTask { .. } => "abandon_task",
+            Self::Ack { .. } => "ack",
+            Self::ListTasks => "list_tasks",
+            Self::AssignmentSnapshot { .. } => "assignment_snapshot",
+        }
+    }
+
+    /// Encode the message as JSON.
+    ///
+    /// # Errors
+    /// Returns an error if the message can't be serialized.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Decode a message from JSON.
+    ///
+    /// # Errors
+    /// Returns an error if `data` isn't a valid message.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+impl From<&WorkerRpcRequest> for ProtocolMessage {
+    fn from(req: &WorkerRpcRequest) -> Self {
+        match req {
+            WorkerRpcRequest::Ping { id } => Self::Ping { id: *id },
+            WorkerRpcRequest::AddTask { task, version } => Self::TakeTask {
+                task: task.clone(),
+                version: *version,
+            },
+            WorkerRpcRequest::RemoveTask { id, version } => Self::AbandonTask {
+                id: *id,
+                version: *version,
+            },
+            WorkerRpcRequest::Tasks {} => Self::ListTasks,
+        }
+    }
+}
+
+impl TryFrom<ProtocolMessage> for WorkerRpcRequest {
+    type Error = UnexpectedMessage;
+
+    fn try_from(msg: ProtocolMessage) -> Result<Self, Self::Error> {
+        match msg {
+            ProtocolMessage::Ping { id } => Ok(Self::Ping { id }),
+            ProtocolMessage::TakeTask { task, version } => Ok(Self::AddTask { task, version }),
+            ProtocolMessage::AbandonTask { id, version } => Ok(Self::RemoveTask { id, version }),
+            ProtocolMessage::ListTasks => Ok(Self::Tasks {}),
+            msg => Err(UnexpectedMessage(msg.name())),
+        }
+    }
+}
+
+impl From<&WorkerRpcResponse> for ProtocolMessage {
+    fn from(resp: &WorkerRpcResponse) -> Self {
+        match resp {
+            WorkerRpcResponse::Ping(heartbeat) => Self::Heartbeat(*heartbeat),
+            WorkerRpcResponse::AddTask(accepted) => Self::Ack {
+                request: AckedRequest::TakeTask,
+                accepted: *accepted,
+            },
+            WorkerRpcResponse::RemoveTask(accepted) => Self::Ack {
+                request: AckedRequest::AbandonTask,
+                accepted: *accepted,
+            },
+            WorkerRpcResponse::Tasks(tasks) => Self::AssignmentSnapshot {
+                tasks: tasks.clone(),
+            },
+        }
+    }
+}
+
+impl TryFrom<ProtocolMessage> for WorkerRpcResponse {
+    type Error = UnexpectedMessage;
+
+    fn try_from(msg: ProtocolMessage) -> Result<Self, Self::Error> {
+        match msg {
+            ProtocolMessage::Heartbeat(heartbeat) => Ok(Self::Ping(heartbeat)),
+            ProtocolMessage::Ack {
+                request: AckedRequest::TakeTask,
+                accepted,
+            } => Ok(Self::AddTask(accepted)),
+            ProtocolMessage::Ack {
+                request: AckedRequest::AbandonTask,
+                accepted,
+            } => Ok(Self::RemoveTask(accepted)),
+            ProtocolMessage::AssignmentSnapshot { tasks } => Ok(Self::Tasks(tasks)),
+            msg => Err(UnexpectedMessage(msg.name())),
+        }
+    }
+}
+
+// RPC requests and responses go on the wire as protocol messages.
+
+impl Serialize for WorkerRpcRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ProtocolMessage::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for WorkerRpcRequest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::try_from(ProtocolMessage::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for WorkerRpcResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ProtocolMessage::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for WorkerRpcResponse {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::try_from(ProtocolMessage::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use crate::{
+        load::Load,
+        message::{AckedRequest, ProtocolMessage},
+        models::Task,
+        protocol::{Heartbeat, WorkerRpcRequest, WorkerRpcResponse},
+    };
+
+    #[test]
+    fn must_round_trip() {
+        let task = Task::new_twitter("42", mongodb::bson::Uuid::new());
+        let messages = [
+            ProtocolMessage::Ping { id: 1 },
+            ProtocolMessage::Heartbeat(Heartbeat {
+                id: 1,
+                load: Load {
+                    entities: 1,
+                    tasks: 2,
+                    cpu: Some(0.5),
+                    memory: None,
+                    error_rate: 0.,
+                },
+            }),
+            ProtocolMessage::TakeTask {
+                task: task.clone(),
+                version: 2,
+            },
+            ProtocolMessage::AbandonTask {
+                id: Uuid::from_u128(1),
+                version: 3,
+            },
+            ProtocolMessage::Ack {
+                request: AckedRequest::TakeTask,
+                accepted: true,
+            },
+            ProtocolMessage::Ack {
+                request: AckedRequest::AbandonTask,
+                accepted: false,
+            },
+            ProtocolMessage::ListTasks,
+            ProtocolMessage::AssignmentSnapshot {
+                tasks: vec![task],
+            },
+        ];
+
+        for msg in messages {
+            let encoded = msg.encode().unwrap();
+            let value: serde_json::Value = serde_json::from_slice(&encoded).unwrap();
+            assert_eq!(value["type"], msg.name());
+            assert_eq!(ProtocolMessage::decode(&encoded).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn must_carry_rpc() {
+        let req = serde_json::to_value(WorkerRpcRequest::RemoveTask {
+            id: Uuid::from_u128(1),
+            version: 3,
+        })
+        .unwrap();
+        assert_eq!(
+            req,
+            json!({ "type": "abandon_task", "id": Uuid::from_u128(1), "version": 3 })
+        );
+        assert!(matches!(
+            serde_json::from_value(req.clone()).unwrap(),
+            WorkerRpcRequest::RemoveTask { version: 3, .. }
+        ));
+        // Requests are not responses.
+        assert!(serde_json::from_value::<WorkerRpcResponse>(req).is_err());
+
+        let resp = serde_json::to_vec(&WorkerRpcResponse::RemoveTask(false)).unwrap();
+        assert!(matches!(
+            serde_json::from_slice(&resp).unwrap(),
+            WorkerRpcResponse::RemoveTask(false)
+        ));
+    }
+}
@@ -1,17 +1,29 @@
 //! Models for the entity collection.
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    mem,
     ops::{Deref, DerefMut},
 };
 
-use eyre::{bail, Result, WrapErr};
+use eyre::{bail, eyre, Result, WrapErr};
 use isolanguage_1::LanguageCode;
-use mongodb::bson::{oid::ObjectId, Uuid};
+use itertools::Itertools;
+use mongodb::bson::{oid::ObjectId, DateTime, Uuid};
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 use url::Url;
 
-use crate::utils::map;
+use crate::{
+    event_schema::EventSchema,
+    schedule::Schedule,
+    utils::{map, uuid_v5},
+};
+
+/// Namespace of entity ids derived from external ids. See [`Entity::id_of_external`].
+pub const EXTERNAL_ID_NAMESPACE: Uuid = Uuid::from_bytes([
+    0x5f, 0x1d, 0x3b, 0x0e, 0x8c, 0x27, 0x4a, 0x61, 0x9e, 0x42, 0x6d, 0xb3, 0x70, 0x15, 0xc8, 0xa4,
+]);
 
 /// Entity for a vtuber.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -22,6 +34,156 @@ pub struct Entity {
     pub meta: Meta,
     /// Tasks to be scheduled.
     pub tasks: Vec<Uuid>,
+    /// Time the entity is created. `None` if it's created before timestamps are recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime>,
+    /// Time the entity or its task list is last updated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime>,
+    /// Time the entity is soft-deleted. `None` if it's alive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime>,
+    /// Id of the vtuber in the source it's imported from, which `id` is derived from. See
+    /// [`Entity::id_of_external`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    /// Reassignment of its tasks requested by an admin, cleared by the coordinator once applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reassignment: Option<Reassignment>,
+}
+
+impl Entity {
+    /// Create a builder with a fresh id.
+    #[must_use]
+    pub fn builder() -> EntityBuilder {
+        EntityBuilder::default()
+    }
+
+    /// Id of the entity with `external_id`, which is the same on every import.
+    ///
+    /// It's a version 5 UUID of `external_id` in [`EXTERNAL_ID_NAMESPACE`].
+    #[must_use]
+    pub fn id_of_external(external_id: &str) -> Uuid {
+        uuid_v5(EXTERNAL_ID_NAMESPACE, external_id)
+    }
+}
+
+/// Builder for [`Entity`]. See [`Entity::builder`].
+#[derive(Debug, Clone, Default)]
+pub struct EntityBuilder {
+    id: Option<Uuid>,
+    name: HashMap<LanguageCode, String>,
+    default_language: Option<LanguageCode>,
+    group: Option<Uuid>,
+    debut: Option<DateTime>,
+    links: HashMap<String, String>,
+    tasks: Vec<Uuid>,
+    external_id: Option<String>,
+}
+
+impl EntityBuilder {
+    /// Use a given id instead of a fresh one.
+    #[must_use]
+    pub const fn id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Set the name in `lang`.
+    #[must_use]
+    pub fn name(mut self, lang: LanguageCode, name: impl Into<String>) -> Self {
+        self.name.insert(lang, name.into());
+        self
+    }
+
+    /// Set the preferred language of the name. Defaults to the only language if there's one name.
+    #[must_use]
+    pub const fn default_language(mut self, lang: LanguageCode) -> Self {
+        self.default_language = Some(lang);
+        self
+    }
+
+    /// Set the group of the entity.
+    #[must_use]
+    pub const fn group(mut self, group: Uuid) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    /// Set the debut date of the vtuber.
+    #[must_use]
+    pub const fn debut(mut self, debut: DateTime) -> Self {
+        self.debut = Some(debut);
+        self
+    }
+
+    /// Set the link to the vtuber's page on `platform`.
+    #[must_use]
+    pub fn link(mut self, platform: impl Into<String>, url: impl Into<String>) -> Self {
+        self.links.insert(platform.into(), url.into());
+        self
+    }
+
+    /// Overwrite names, preferred language, group, debut and links with `meta`.
+    #[must_use]
+    pub fn meta(mut self, meta: Meta) -> Self {
+        self.name = meta.name.name;
+        self.default_language = Some(meta.name.default_language);
+        self.group = meta.group;
+        self.debut = meta.debut;
+        self.links = meta.links;
+        self
+    }
+
+    /// Set the external id of the entity, and derive its id from it unless given by
+    /// [`id`](Self::id).
+    #[must_use]
+    pub fn external_id(mut self, external_id: impl Into<String>) -> Self {
+        self.external_id = Some(external_id.into());
+        self
+    }
+
+    /// Add a task to the entity.
+    #[must_use]
+    pub fn task(mut self, task: Uuid) -> Self {
+        self.tasks.push(task);
+        self
+    }
+
+    /// Build the entity.
+    ///
+    /// # Errors
+    /// Returns an error if the preferred language can't be decided, or the meta is invalid. See
+    /// [`Meta::validate`].
+    pub fn build(self) -> Result<Entity> {
+        let default_language = match (self.default_language, self.name.len()) {
+            (Some(lang), _) => lang,
+            (None, 1) => *self.name.keys().next().expect("There's exactly one name"),
+            (None, _) => bail!("default language must be set unless there's exactly one name"),
+        };
+        let meta = Meta {
+            name: Name {
+                name: self.name,
+                default_language,
+            },
+            group: self.group,
+            debut: self.debut,
+            links: self.links,
+        };
+        meta.validate()?;
+
+        let derived = self.external_id.as_deref().map(Entity::id_of_external);
+        Ok(Entity {
+            id: self.id.or(derived).unwrap_or_default(),
+            meta,
+            tasks: self.tasks,
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            external_id: self.external_id,
+            reassignment: None,
+        })
+    }
 }
 
 /// Meta of the vtuber.
@@ -31,6 +193,33 @@ pub struct Meta {
     pub name: Name,
     /// Affiliation of the vtuber.
     pub group: Option<Uuid>,
+    /// Time the vtuber debuted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debut: Option<DateTime>,
+    /// Links to the vtuber's pages, keyed by platform, e.g. `youtube`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub links: HashMap<String, String>,
+}
+
+impl Meta {
+    /// Check that the meta is consistent.
+    ///
+    /// # Errors
+    /// Returns an error if the name is invalid (see [`Name::validate`]), a platform name can't be
+    /// used as a field name, or a link isn't an http(s) url.
+    pub fn validate(&self) -> Result<()> {
+        self.name.validate()?;
+        for (platform, link) in &self.links {
+            if platform.is_empty() || platform.contains('.') || platform.starts_with('$') {
+                bail!("invalid platform `{platform}`");
+            }
+            let url = Url::parse(link).wrap_err_with(|| format!("invalid link on `{platform}`"))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                bail!("link on `{platform}` must be http(s), got `{link}`");
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Name of a vtuber/group.
@@ -42,6 +231,40 @@ pub struct Name {
     pub default_language: LanguageCode,
 }
 
+impl Name {
+    /// Check that the name is consistent.
+    ///
+    /// # Errors
+    /// Returns an error if there's no name in `default_language`.
+    pub fn validate(&self) -> Result<()> {
+        if !self.name.contains_key(&self.default_language) {
+            bail!(
+                "default language `{}` has no corresponding name",
+                self.default_language.code()
+            );
+        }
+        Ok(())
+    }
+
+    /// Name in `lang`, or in the default language if there's none.
+    #[must_use]
+    pub fn localized(&self, lang: LanguageCode) -> &str {
+        self.name
+            .get(&lang)
+            .or_else(|| self.name.get(&self.default_language))
+            .map_or("", String::as_str)
+    }
+}
+
+/// Request to move tasks of an entity to another worker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reassignment {
+    /// Worker to pin the tasks to, or `None` for the next-best worker other than the current one.
+    pub target: Option<Uuid>,
+    /// Time the reassignment is requested.
+    pub at: DateTime,
+}
+
 /// A group/organization of vtubers.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Group {
@@ -62,9 +285,50 @@ pub struct Task {
     pub kind: String,
     /// Parameters of the task.
     pub params: Map<String, Value>,
+    /// Time the task is created. `None` if it's created before timestamps are recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime>,
+    /// Time the task is last updated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime>,
+    /// Time the task is soft-deleted along with its entity. Deleted tasks are not scheduled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime>,
+    /// Whether the task is scheduled. Disabled tasks keep their params but are not run.
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// When the task runs. Workers fall back to their own cadence if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    /// Tags a worker must have to run the task, e.g. a region. Runs on any worker if empty.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub required_tags: HashSet<String>,
+}
+
+/// Tasks stored before they could be disabled are enabled.
+const fn enabled_by_default() -> bool {
+    true
 }
 
 impl Task {
+    /// Whether the task should be scheduled, i.e. it's enabled and not soft-deleted.
+    #[must_use]
+    pub const fn is_active(&self) -> bool {
+        self.enabled && self.deleted_at.is_none()
+    }
+
+    /// Whether a worker with `tags` can run the task, i.e. it has all tags the task requires.
+    #[must_use]
+    pub fn runs_on(&self, tags: &HashSet<String>) -> bool {
+        self.required_tags.is_subset(tags)
+    }
+
+    /// Create a builder with a fresh id.
+    #[must_use]
+    pub fn builder() -> TaskBuilder {
+        TaskBuilder::default()
+    }
+
     /// Create a new youtube task with `channel_id` and `parent`,
     ///
     /// # Params
@@ -77,6 +341,12 @@ impl Task {
             entity: parent,
             kind: "youtube".to_string(),
             params: map!("channel_id", channel_id),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            enabled: true,
+            schedule: None,
+            required_tags: HashSet::new(),
         }
     }
 
@@ -91,6 +361,12 @@ impl Task {
             entity: parent,
             kind: "bililive".to_string(),
             params: map!("uid", uid),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            enabled: true,
+            schedule: None,
+            required_tags: HashSet::new(),
         }
     }
 
@@ -105,10 +381,88 @@ impl Task {
             entity: parent,
             kind: "twitter".to_string(),
             params: map!("id", id),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            enabled: true,
+            schedule: None,
+            required_tags: HashSet::new(),
         }
     }
 }
 
+/// Builder for [`Task`]. See [`Task::builder`].
+#[derive(Debug, Clone, Default)]
+pub struct TaskBuilder {
+    id: Option<Uuid>,
+    entity: Option<Uuid>,
+    kind: Option<String>,
+    params: Map<String, Value>,
+    required_tags: HashSet<String>,
+}
+
+impl TaskBuilder {
+    /// Use a given id instead of a fresh one.
+    #[must_use]
+    pub const fn id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Set the parent entity of the task.
+    #[must_use]
+    pub const fn entity(mut self, entity: Uuid) -> Self {
+        self.entity = Some(entity);
+        self
+    }
+
+    /// Set the kind of the task.
+    #[must_use]
+    pub fn kind(mut self, kind: impl Into<String>) -> Self {
+        self.kind = Some(kind.into());
+        self
+    }
+
+    /// Set a parameter of the task.
+    #[must_use]
+    pub fn param(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.params.insert(key.into(), value.into());
+        self
+    }
+
+    /// Only run the task on workers with `tag`.
+    #[must_use]
+    pub fn required_tag(mut self, tag: impl Into<String>) -> Self {
+        self.required_tags.insert(tag.into());
+        self
+    }
+
+    /// Build the task.
+    ///
+    /// # Errors
+    /// Returns an error if the entity or kind is not set.
+    pub fn build(self) -> Result<Task> {
+        let entity = self.entity.ok_or_else(|| eyre!("task entity must be set"))?;
+        let kind = match self.kind {
+            Some(kind) if !kind.is_empty() => kind,
+            _ => bail!("task kind must be set"),
+        };
+
+        Ok(Task {
+            id: self.id.unwrap_or_default(),
+            entity,
+            kind,
+            params: self.params,
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            enabled: true,
+            schedule: None,
+            required_tags: self.required_tags,
+        })
+    }
+}
+
 /// Event pushed by workers (or addons) to the message queue and received by IM
 /// agents.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -121,6 +475,14 @@ pub struct Event {
     pub entity: Uuid,
     /// Fields of the event.
     pub fields: Map<String, Value>,
+    /// Names of the entity at the time the event is emitted, for consumers that can't look it up.
+    ///
+    /// Empty unless the event is created by [`Event::from_serializable_localized`].
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub names: HashMap<LanguageCode, String>,
+    /// Preferred language of the names. Set along with `names`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_language: Option<LanguageCode>,
 }
 
 impl Event {
@@ -148,6 +510,8 @@ impl Event {
             kind: kind.to_string(),
             entity: entity.into(),
             fields,
+            names: HashMap::new(),
+            default_language: None,
         })
     }
 
@@ -162,6 +526,90 @@ impl Event {
     ) -> Result<Self> {
         Self::from_serializable_with_id(Uuid::new(), kind, entity, fields)
     }
+
+    /// Create a new event with its fields set by a serializable object, embedding names of the
+    /// entity in `meta`.
+    ///
+    /// # Errors
+    /// Returns an error if the fields cannot be serialized into a map.
+    pub fn from_serializable_localized(
+        kind: &str,
+        entity: impl Into<Uuid>,
+        meta: &Meta,
+        fields: impl Serialize,
+    ) -> Result<Self> {
+        Ok(Self {
+            names: meta.name.name.clone(),
+            default_language: Some(meta.name.default_language),
+            ..Self::from_serializable(kind, entity, fields)?
+        })
+    }
+
+    /// Embedded name of the entity in `lang`, or in its default language if there's none.
+    #[must_use]
+    pub fn name_in(&self, lang: LanguageCode) -> Option<&str> {
+        self.names
+            .get(&lang)
+            .or_else(|| self.names.get(&self.default_language?))
+            .map(String::as_str)
+    }
+
+    /// Hash of the content of the event, i.e. its kind, entity and fields.
+    ///
+    /// Events carrying the same content have the same hash within a process, regardless of their
+    /// ids and the order of their fields.
+    #[must_use]
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.kind.hash(&mut hasher);
+        self.entity.hash(&mut hasher);
+        hash_map(&self.fields, &mut hasher);
+        hasher.finish()
+    }
+
+    /// Check that the event carries the fields required by `schema`, of the right types.
+    ///
+    /// # Errors
+    /// Returns an error if a required field is missing or of the wrong type.
+    pub fn validate_against(&self, schema: &EventSchema) -> Result<()> {
+        for (name, ty) in schema.required() {
+            match self.fields.get(name) {
+                None => bail!("event of kind `{}` misses field `{name}`", self.kind),
+                Some(value) if !ty.matches(value) => bail!(
+                    "field `{name}` of event of kind `{}` must be {ty}, got {value}",
+                    self.kind
+                ),
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hash_map(map: &Map<String, Value>, hasher: &mut impl Hasher) {
+    map.len().hash(hasher);
+    for (k, v) in map.iter().sorted_by_key(|(k, _)| *k) {
+        k.hash(hasher);
+        hash_value(v, hasher);
+    }
+}
+
+fn hash_value(value: &Value, hasher: &mut impl Hasher) {
+    mem::discriminant(value).hash(hasher);
+    match value {
+        Value::Null => {}
+        Value::Bool(b) => b.hash(hasher),
+        // Numbers are hashed by their textual form, which is lossless.
+        Value::Number(n) => n.to_string().hash(hasher),
+        Value::String(s) => s.hash(hasher),
+        Value::Array(values) => {
+            values.len().hash(hasher);
+            for v in values {
+                hash_value(v, hasher);
+            }
+        }
+        Value::Object(map) => hash_map(map, hasher),
+    }
 }
 
 /// IM subscriber.
@@ -180,6 +628,44 @@ pub struct User {
     pub avatar: Option<Url>,
     /// The events that the user is subscribed to.
     pub event_filter: EventFilter,
+    /// Time until which no event is delivered to the user. Ignored once passed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub muted_until: Option<DateTime>,
+    /// Language events are delivered in. Names missing in it fall back to their default language.
+    #[serde(default = "default_preferred_language")]
+    pub preferred_language: LanguageCode,
+}
+
+const fn default_preferred_language() -> LanguageCode {
+    LanguageCode::En
+}
+
+impl User {
+    /// Whether events are not delivered to the user at `now`.
+    #[must_use]
+    pub fn is_muted_at(&self, now: DateTime) -> bool {
+        self.muted_until.is_some_and(|until| until > now)
+    }
+}
+
+/// An event failed to be delivered to a user, kept for inspection or replay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// The unique identifier of the dead letter.
+    pub id: Uuid,
+    /// The user the event failed to be delivered to.
+    pub user: User,
+    /// The undelivered event.
+    pub event: Event,
+    /// Error of the last attempt.
+    pub error: String,
+    /// Number of failed attempts.
+    pub attempts: u32,
+    /// Time of the last attempt.
+    pub failed_at: DateTime,
+    /// Whether an admin asked to deliver it again.
+    #[serde(default)]
+    pub replay: bool,
 }
 
 /// Filter for events.
@@ -187,10 +673,54 @@ pub struct User {
 pub struct EventFilter {
     /// Event must be related to these entities.
     pub entities: HashSet<Uuid>,
-    /// Event must be in these kinds.
+    /// Or related to entities in these groups.
+    #[serde(default)]
+    pub groups: HashSet<Uuid>,
+    /// Event must be in these kinds, or in their namespaces. See [`kind_matches`].
     pub kinds: HashSet<String>,
 }
 
+impl EventFilter {
+    /// Check if the event passes this filter.
+    ///
+    /// `entity_meta` is the meta of the entity the event is related to.
+    #[must_use]
+    pub fn matches(&self, event: &Event, entity_meta: &Meta) -> bool {
+        let entity_matches = self.entities.contains(&event.entity)
+            || entity_meta.group.is_some_and(|group| self.groups.contains(&group));
+        entity_matches && self.matches_kind(&event.kind)
+    }
+
+    /// Check if events of `kind` are subscribed by this filter. See [`kind_matches`].
+    #[must_use]
+    pub fn matches_kind(&self, kind: &str) -> bool {
+        self.kinds
+            .iter()
+            .any(|filter_kind| kind_matches(filter_kind, kind))
+    }
+}
+
+/// Check if `event_kind` is subscribed by `filter_kind`.
+///
+/// Kinds are dotted namespaces, so `youtube.live` subscribes to `youtube.live` itself and
+/// `youtube.live.start`, but not `youtube.liveness`.
+#[must_use]
+pub fn kind_matches(filter_kind: &str, event_kind: &str) -> bool {
+    event_kind
+        .strip_prefix(filter_kind)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// All filter kinds subscribing to `event_kind`, from the outermost namespace to itself.
+///
+/// `youtube.live.start` yields `youtube`, `youtube.live` and `youtube.live.start`.
+pub fn kind_prefixes(event_kind: &str) -> impl Iterator<Item = &str> {
+    event_kind
+        .match_indices('.')
+        .map(|(i, _)| &event_kind[..i])
+        .chain(std::iter::once(event_kind))
+}
+
 /// Wrapper for model providing `MongoDB` `ObjectId`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InDB<T> {
@@ -226,3 +756,277 @@ impl<T> DerefMut for InDB<T> {
         &mut self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use isolanguage_1::LanguageCode;
+    use mongodb::bson::{DateTime, Uuid};
+    use serde_json::{json, Map};
+
+    use crate::models::{kind_matches, kind_prefixes, Entity, Event, EventFilter, Meta, Name, Task};
+
+    #[test]
+    fn must_match_event_filter() {
+        let entity = Uuid::new();
+        let group = Uuid::new();
+        let event = Event {
+            id: Uuid::new(),
+            kind: "twitter/new_tweet".to_owned(),
+            entity,
+            fields: Map::new(),
+            names: HashMap::new(),
+            default_language: None,
+        };
+        let meta = |group| Meta {
+            name: Name {
+                name: HashMap::from([(LanguageCode::En, "Suisei".to_owned())]),
+                default_language: LanguageCode::En,
+            },
+            group,
+            debut: None,
+            links: HashMap::new(),
+        };
+        let filter = |entities: &[Uuid], groups: &[Uuid], kind: &str| EventFilter {
+            entities: entities.iter().copied().collect(),
+            groups: groups.iter().copied().collect(),
+            kinds: HashSet::from([kind.to_owned()]),
+        };
+
+        // By entity
+        assert!(filter(&[entity], &[], "twitter/new_tweet").matches(&event, &meta(None)));
+        // By group
+        assert!(filter(&[], &[group], "twitter/new_tweet").matches(&event, &meta(Some(group))));
+        assert!(!filter(&[], &[group], "twitter/new_tweet").matches(&event, &meta(None)));
+        assert!(!filter(&[], &[Uuid::new()], "twitter/new_tweet")
+            .matches(&event, &meta(Some(group))));
+        // Kind must match either way
+        assert!(!filter(&[entity], &[group], "bililive/live_start")
+            .matches(&event, &meta(Some(group))));
+    }
+
+    #[test]
+    fn must_match_kind() {
+        assert!(kind_matches("youtube.live", "youtube.live"));
+        assert!(kind_matches("youtube.live", "youtube.live.start"));
+        assert!(kind_matches("youtube", "youtube.live.end"));
+        assert!(!kind_matches("youtube.live", "youtube.liveness"));
+        assert!(!kind_matches("youtube.live.start", "youtube.live"));
+        assert!(!kind_matches("", "youtube"));
+
+        let prefixes: Vec<_> = kind_prefixes("youtube.live.start").collect();
+        assert_eq!(prefixes, ["youtube", "youtube.live", "youtube.live.start"]);
+        assert!(prefixes.iter().all(|prefix| kind_matches(prefix, "youtube.live.start")));
+        assert_eq!(kind_prefixes("twitter").collect::<Vec<_>>(), ["twitter"]);
+    }
+
+    #[test]
+    fn must_hash_content() {
+        let entity = Uuid::new();
+        let event = |entity, fields| Event::from_serializable("twitter", entity, fields).unwrap();
+
+        let a = event(entity, json!({"id": 1, "text": "hello", "media": [{"url": "a"}]}));
+        let b = event(entity, json!({"media": [{"url": "a"}], "text": "hello", "id": 1}));
+        assert_ne!(a.id, b.id);
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        assert_ne!(
+            a.content_hash(),
+            event(entity, json!({"id": 2, "text": "hello", "media": [{"url": "a"}]})).content_hash()
+        );
+        assert_ne!(
+            a.content_hash(),
+            event(Uuid::new(), json!({"id": 1, "text": "hello", "media": [{"url": "a"}]}))
+                .content_hash()
+        );
+    }
+
+    #[test]
+    fn must_build_entity() {
+        let group = Uuid::new();
+        let entity = Entity::builder()
+            .name(LanguageCode::Ja, "星街すいせい")
+            .name(LanguageCode::En, "Suisei")
+            .default_language(LanguageCode::En)
+            .group(group)
+            .build()
+            .unwrap();
+        assert_eq!(entity.meta.name.name.len(), 2);
+        assert_eq!(entity.meta.name.default_language, LanguageCode::En);
+        assert_eq!(entity.meta.group, Some(group));
+        assert!(entity.tasks.is_empty());
+        assert_ne!(
+            entity.id,
+            Entity::builder()
+                .name(LanguageCode::En, "Suisei")
+                .build()
+                .unwrap()
+                .id
+        );
+
+        // Only name is the default
+        let entity = Entity::builder()
+            .name(LanguageCode::Ja, "星街すいせい")
+            .build()
+            .unwrap();
+        assert_eq!(entity.meta.name.default_language, LanguageCode::Ja);
+
+        // Ambiguous or missing default
+        assert!(Entity::builder()
+            .name(LanguageCode::Ja, "星街すいせい")
+            .name(LanguageCode::En, "Suisei")
+            .build()
+            .is_err());
+        assert!(Entity::builder()
+            .name(LanguageCode::Ja, "星街すいせい")
+            .default_language(LanguageCode::En)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn must_derive_id_from_external_id() {
+        let build = |external_id: &str| {
+            Entity::builder()
+                .name(LanguageCode::Ja, "星街すいせい")
+                .external_id(external_id)
+                .build()
+                .unwrap()
+        };
+        let entity = build("vtbs.moe:1234");
+        assert_eq!(entity.external_id.as_deref(), Some("vtbs.moe:1234"));
+        // The same on every import
+        assert_eq!(entity.id, build("vtbs.moe:1234").id);
+        assert_eq!(entity.id, Entity::id_of_external("vtbs.moe:1234"));
+        assert_ne!(entity.id, build("vtbs.moe:1235").id);
+    }
+
+    #[test]
+    fn must_validate_links() {
+        let entity = Entity::builder()
+            .name(LanguageCode::Ja, "星街すいせい")
+            .debut(DateTime::from_millis(1_521_072_000_000))
+            .link("youtube", "https://www.youtube.com/@HoshimachiSuisei")
+            .build()
+            .unwrap();
+        assert_eq!(entity.meta.links.len(), 1);
+
+        for (platform, url) in [
+            ("youtube", "not a url"),
+            ("youtube", "javascript:alert(1)"),
+            ("you.tube", "https://www.youtube.com"),
+            ("$where", "https://www.youtube.com"),
+            ("", "https://www.youtube.com"),
+        ] {
+            assert!(
+                Entity::builder()
+                    .name(LanguageCode::Ja, "星街すいせい")
+                    .link(platform, url)
+                    .build()
+                    .is_err(),
+                "{platform}: {url}"
+            );
+        }
+    }
+
+    #[test]
+    fn must_default_meta() {
+        let meta = Entity::builder()
+            .name(LanguageCode::Ja, "星街すいせい")
+            .build()
+            .unwrap()
+            .meta;
+        let mut doc = mongodb::bson::to_document(&meta).unwrap();
+        // Stored before debut and links are added
+        assert!(!doc.contains_key("debut") && !doc.contains_key("links"));
+        doc.insert("group", mongodb::bson::Bson::Null);
+        assert_eq!(mongodb::bson::from_document::<Meta>(doc).unwrap(), meta);
+    }
+
+    #[test]
+    fn must_build_task() {
+        let entity = Uuid::new();
+        let task = Task::builder()
+            .entity(entity)
+            .kind("twitter")
+            .param("id", "123")
+            .build()
+            .unwrap();
+        assert_eq!(
+            task,
+            Task {
+                id: task.id,
+                ..Task::new_twitter("123", entity)
+            }
+        );
+
+        assert!(Task::builder().kind("twitter").build().is_err());
+        assert!(Task::builder().entity(entity).build().is_err());
+    }
+
+    #[test]
+    fn must_match_tags() {
+        let tags = |tags: &[&str]| tags.iter().map(ToString::to_string).collect();
+        let task = Task::new_twitter("123", Uuid::new());
+        assert!(task.runs_on(&tags(&[])));
+        // Stored before tags are added
+        assert!(!mongodb::bson::to_document(&task)
+            .unwrap()
+            .contains_key("required_tags"));
+
+        let task = Task::builder()
+            .entity(Uuid::new())
+            .kind("bililive")
+            .required_tag("jp")
+            .build()
+            .unwrap();
+        assert!(!task.runs_on(&tags(&[])));
+        assert!(!task.runs_on(&tags(&["us"])));
+        assert!(task.runs_on(&tags(&["jp", "residential"])));
+    }
+
+    #[test]
+    fn must_default_enabled() {
+        let mut task = Task::new_twitter("123", Uuid::new());
+        let mut doc = mongodb::bson::to_document(&task).unwrap();
+        // Stored before tasks could be disabled
+        doc.remove("enabled");
+        assert_eq!(mongodb::bson::from_document::<Task>(doc).unwrap(), task);
+        assert!(task.is_active());
+
+        task.enabled = false;
+        assert!(!task.is_active());
+    }
+
+    #[test]
+    fn must_embed_names() {
+        let meta = Meta {
+            name: Name {
+                name: HashMap::from([
+                    (LanguageCode::En, "Suisei".to_owned()),
+                    (LanguageCode::Ja, "星街すいせい".to_owned()),
+                ]),
+                default_language: LanguageCode::Ja,
+            },
+            group: None,
+            debut: None,
+            links: HashMap::new(),
+        };
+        let event =
+            Event::from_serializable_localized("twitter", Uuid::new(), &meta, json!({})).unwrap();
+        assert_eq!(event.names, meta.name.name);
+        assert_eq!(event.name_in(LanguageCode::En), Some("Suisei"));
+        // Falls back to the default language
+        assert_eq!(event.name_in(LanguageCode::Zh), Some("星街すいせい"));
+        assert_eq!(meta.name.localized(LanguageCode::Zh), "星街すいせい");
+
+        // Names are omitted when absent, and default to empty
+        let event = Event::from_serializable("twitter", Uuid::new(), json!({})).unwrap();
+        assert_eq!(event.name_in(LanguageCode::En), None);
+        let value = serde_json::to_value(&event).unwrap();
+        assert!(value.get("names").is_none());
+        assert!(value.get("default_language").is_none());
+        assert_eq!(serde_json::from_value::<Event>(value).unwrap(), event);
+    }
+}
@@ -1,17 +1,24 @@
 //! Message queue for workers.
+//!
+//! Workers, middlewares and agents only depend on [`MessageQueue`], so the backing queue can be
+//! swapped without touching them. [`RabbitMQ`] is the default backend, and [`mock::MockMQ`] keeps
+//! events in memory for tests.
 
 use std::{
+    collections::{HashSet, VecDeque},
     convert::Infallible,
     fmt::{Display, Formatter},
     iter,
     ops::{Deref, DerefMut},
     pin::Pin,
     str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
     vec,
 };
 
 use async_trait::async_trait;
-use eyre::Result;
+use eyre::{Result, WrapErr};
 use futures_util::{future, stream, Stream, StreamExt};
 use itertools::Itertools;
 use lapin::{
@@ -33,7 +40,7 @@ use lapin::{
 use tap::TapFallible;
 use tracing::{debug, error, info};
 
-use crate::models::Event;
+use crate::{event_schema::EventSchemas, models::Event};
 
 /// Interface of a message queue.
 #[async_trait]
@@ -249,6 +256,126 @@ impl IntoIterator for Middlewares {
     }
 }
 
+/// Drops events whose content has been seen within a time window.
+///
+/// See [`Event::content_hash`] for what is considered the same content.
+#[derive(Debug)]
+pub struct Deduplicator {
+    window: Duration,
+    capacity: usize,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    hashes: HashSet<u64>,
+    /// Hashes in the order they are first seen.
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl Deduplicator {
+    /// Remember events for `window`, up to `capacity` of them. A zero `window` or `capacity`
+    /// disables deduplication.
+    #[must_use]
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            seen: Mutex::default(),
+        }
+    }
+
+    /// Check whether `event` should be delivered, i.e. no event with the same content has been
+    /// seen within the window. The event is remembered if so.
+    pub fn check(&self, event: &Event) -> bool {
+        self.check_at(event.content_hash(), Instant::now())
+    }
+
+    fn check_at(&self, hash: u64, now: Instant) -> bool {
+        if self.window.is_zero() || self.capacity == 0 {
+            return true;
+        }
+
+        let mut seen = self.seen.lock().expect("Deduplicator lock poisoned");
+        while let Some(&(oldest, at)) = seen.order.front() {
+            if now.saturating_duration_since(at) < self.window && seen.order.len() < self.capacity
+            {
+                break;
+            }
+            seen.order.pop_front();
+            seen.hashes.remove(&oldest);
+        }
+
+        if !seen.hashes.insert(hash) {
+            return false;
+        }
+        seen.order.push_back((hash, now));
+        true
+    }
+}
+
+/// A message queue that drops duplicated events before publishing them.
+pub struct Deduplicated<Q> {
+    inner: Q,
+    dedup: Deduplicator,
+}
+
+impl<Q> Deduplicated<Q> {
+    /// Wrap a message queue with a deduplicator.
+    pub const fn new(inner: Q, dedup: Deduplicator) -> Self {
+        Self { inner, dedup }
+    }
+}
+
+#[async_trait]
+impl<Q: MessageQueue> MessageQueue for Deduplicated<Q> {
+    async fn publish(&self, event: Event, middlewares: Middlewares) -> Result<()> {
+        if !self.dedup.check(&event) {
+            debug!(event_id = %event.id, kind = %event.kind, "Dropping duplicated event");
+            return Ok(());
+        }
+        self.inner.publish(event, middlewares).await
+    }
+
+    async fn consume(
+        &self,
+        middleware: Option<&str>,
+    ) -> Pin<Box<dyn Stream<Item = Result<(Middlewares, Event)>> + Send>> {
+        self.inner.consume(middleware).await
+    }
+}
+
+/// A message queue that rejects events not matching the schema of their kind before publishing
+/// them, so that malformed events never reach agents.
+pub struct Validated<Q> {
+    inner: Q,
+    schemas: EventSchemas,
+}
+
+impl<Q> Validated<Q> {
+    /// Wrap a message queue with a schema registry.
+    pub const fn new(inner: Q, schemas: EventSchemas) -> Self {
+        Self { inner, schemas }
+    }
+}
+
+#[async_trait]
+impl<Q: MessageQueue> MessageQueue for Validated<Q> {
+    async fn publish(&self, event: Event, middlewares: Middlewares) -> Result<()> {
+        self.schemas
+            .validate(&event)
+            .wrap_err_with(|| format!("Refusing to publish malformed event {}", event.id))?;
+        self.inner.publish(event, middlewares).await
+    }
+
+    async fn consume(
+        &self,
+        middleware: Option<&str>,
+    ) -> Pin<Box<dyn Stream<Item = Result<(Middlewares, Event)>> + Send>> {
+        self.inner.consume(middleware).await
+    }
+}
+
 /// Mock implementations.
 #[cfg(any(test, feature = "mock"))]
 pub mod mock {
@@ -318,18 +445,20 @@ pub mod mock {
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     use futures_util::StreamExt;
     use mongodb::bson::Uuid;
     use serde_json::json;
     use tokio::time::timeout;
 
-    #[cfg(feature = "mock")]
-    use crate::mq::mock::MockMQ;
     use crate::{
+        event_schema::{EventSchema, EventSchemas, FieldType},
         models::Event,
-        mq::{MessageQueue, Middlewares, RabbitMQ},
+        mq::{
+            mock::MockMQ, Deduplicated, Deduplicator, MessageQueue, Middlewares, RabbitMQ,
+            Validated,
+        },
     };
 
     #[tokio::test]
@@ -423,4 +552,68 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn must_dedup() {
+        let dedup = Deduplicator::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+
+        assert!(dedup.check_at(1, now));
+        assert!(!dedup.check_at(1, now + Duration::from_secs(5)));
+        // Expired
+        assert!(dedup.check_at(1, now + Duration::from_secs(10)));
+
+        // Evicted when full
+        assert!(dedup.check_at(2, now + Duration::from_secs(11)));
+        assert!(dedup.check_at(3, now + Duration::from_secs(12)));
+        assert!(dedup.check_at(1, now + Duration::from_secs(13)));
+
+        // Disabled
+        let dedup = Deduplicator::new(Duration::ZERO, 2);
+        assert!(dedup.check_at(1, now));
+        assert!(dedup.check_at(1, now));
+    }
+
+    #[tokio::test]
+    async fn must_drop_duplicated() {
+        let mq = Deduplicated::new(
+            MockMQ::default(),
+            Deduplicator::new(Duration::from_secs(60), 16),
+        );
+        let mut consumer = mq.consume(None).await;
+
+        let entity = Uuid::new();
+        let event = |text| Event::from_serializable("a", entity, json!({ "text": text })).unwrap();
+        for text in ["a", "a", "b"] {
+            mq.publish(event(text), Middlewares::default())
+                .await
+                .unwrap();
+        }
+
+        let (_, e) = consumer.next().await.unwrap().unwrap();
+        assert_eq!(e.fields["text"], "a");
+        let (_, e) = consumer.next().await.unwrap().unwrap();
+        assert_eq!(e.fields["text"], "b");
+    }
+
+    #[tokio::test]
+    async fn must_reject_malformed() {
+        let schemas =
+            EventSchemas::empty().register("a", EventSchema::new().field("text", FieldType::String));
+        let mq = Validated::new(MockMQ::default(), schemas);
+        let mut consumer = mq.consume(None).await;
+
+        let event = |fields| Event::from_serializable("a", Uuid::new(), fields).unwrap();
+        let error = mq
+            .publish(event(json!({ "text": 1 })), Middlewares::default())
+            .await
+            .unwrap_err();
+        assert!(error.root_cause().to_string().contains("`text`"), "{error:#}");
+        mq.publish(event(json!({ "text": "a" })), Middlewares::default())
+            .await
+            .unwrap();
+
+        let (_, e) = consumer.next().await.unwrap().unwrap();
+        assert_eq!(e.fields["text"], "a");
+    }
 }
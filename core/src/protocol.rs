@@ -1,28 +1,186 @@
 //! RPC protocol.
 
-use std::{fmt::Display, future::Future, pin::Pin};
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use eyre::Result;
+use futures_util::{task::AtomicWaker, Sink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 use tarpc::server::{BaseChannel, Channel, Serve};
-use tokio_tungstenite::tungstenite::client::IntoClientRequest;
-use tracing::{debug, info};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    handshake::client::Request,
+    Error as WsError,
+    Message,
+};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
-use crate::{adapter::WsTransport, models::Task};
+use crate::{
+    adapter::WsTransport,
+    load::Load,
+    message::{PROTOCOL_HEADER, PROTOCOL_VERSION},
+    models::Task,
+};
+
+/// Header carrying the last assignment version a reconnecting worker has seen, so that the
+/// coordinator can resume its assignment instead of rebuilding it.
+pub const VERSION_HEADER: &str = "Sg-Worker-Version";
+
+/// Header carrying the weight of a worker, i.e. its capacity relative to other workers of its
+/// kind. Assumed to be `1` if missing.
+pub const WEIGHT_HEADER: &str = "Sg-Worker-Weight";
+
+/// Maximum weight a worker may advertise with [`WEIGHT_HEADER`].
+pub const MAX_WEIGHT: u32 = 100;
 
 /// RPC protocol for worker-coordinator communication.
-#[tarpc::service]
+///
+/// Each change to the assignment of a worker carries a version, increasing monotonically on the
+/// coordinator.
+///
+/// Requests and responses are (de)serialized as
+/// [`ProtocolMessage`](crate::message::ProtocolMessage)s.
+#[tarpc::service(derive_serde = false)]
 pub trait WorkerRpc {
-    /// Ping the worker.
-    async fn ping(id: u64) -> u64;
-    /// Add a task to the worker. Return `false` if the task already exists.
-    async fn add_task(task: Task) -> bool;
-    /// Remove a task from the worker. Return `false` if the task was not found.
-    async fn remove_task(id: Uuid) -> bool;
+    /// Ping the worker, which answers with its load.
+    async fn ping(id: u64) -> Heartbeat;
+    /// Add a task to the worker as of assignment `version`. Return `false` if the task already
+    /// exists.
+    async fn add_task(task: Task, version: u64) -> bool;
+    /// Remove a task from the worker as of assignment `version`. Return `false` if the task was
+    /// not found.
+    async fn remove_task(id: Uuid, version: u64) -> bool;
     /// Get the list of tasks running on the worker.
     async fn tasks() -> Vec<Task>;
 }
 
+/// Answer of a worker to a ping.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// Id of the ping.
+    pub id: u64,
+    /// Load of the worker, e.g. sampled by [`LoadMeter`](crate::load::LoadMeter).
+    pub load: Load,
+}
+
+/// Handle to gracefully leave a coordinator joined with [`WorkerRpcExt::join_with_drain`].
+#[derive(Debug, Clone)]
+pub struct Drain(Arc<DrainState>);
+
+#[derive(Debug)]
+struct DrainState {
+    /// Whether a drain is requested.
+    requested: AtomicBool,
+    /// Waker of the connection, to start the close handshake once requested.
+    waker: AtomicWaker,
+    /// Set when the connection is closed.
+    closed: watch::Sender<bool>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drain {
+    /// Create a new drain handle.
+    #[must_use]
+    pub fn new() -> Self {
+        Self(Arc::new(DrainState {
+            requested: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+            closed: watch::channel(false).0,
+        }))
+    }
+
+    /// Leave the coordinator so that tasks on this worker are reassigned immediately, instead of
+    /// after the coordinator notices a missing ping.
+    ///
+    /// Wait until the coordinator acknowledges by completing the close handshake, or `timeout`
+    /// elapses. Return `false` on timeout.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let mut closed = self.0.closed.subscribe();
+
+        self.0.requested.store(true, Ordering::SeqCst);
+        self.0.waker.wake();
+
+        tokio::time::timeout(timeout, async {
+            while !*closed.borrow_and_update() {
+                if closed.changed().await.is_err() {
+                    break;
+                }
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+/// Websocket stream that starts a close handshake once a drain is requested.
+struct Drainable<S> {
+    inner: S,
+    drain: Drain,
+    closing: bool,
+}
+
+impl<S> Stream for Drainable<S>
+where
+    S: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Unpin,
+{
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.drain.0.waker.register(cx.waker());
+
+        if !self.closing && self.drain.0.requested.load(Ordering::SeqCst) {
+            debug!("Draining, closing connection to coordinator");
+            if let Err(e) = futures_util::ready!(self.inner.poll_close_unpin(cx)) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            self.closing = true;
+        }
+
+        // Keep reading until the coordinator replies the close frame and the stream ends.
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+impl<S> Sink<Message> for Drainable<S>
+where
+    S: Sink<Message, Error = WsError> + Unpin,
+{
+    type Error = WsError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
 /// Extension trait for `WorkerRpc`.
 pub trait WorkerRpcExt {
     /// Join a coordinator.
@@ -32,6 +190,50 @@ pub trait WorkerRpcExt {
         id: Uuid,
         ty: impl Display + Send + 'static,
     ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Join a coordinator, and leave it gracefully when `drain` is requested.
+    ///
+    /// The returned future completes once the connection is closed.
+    fn join_with_drain(
+        self,
+        addr: impl IntoClientRequest + Unpin + Send + 'static,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        drain: Drain,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Join a coordinator advertising `tags`, so that only tasks requiring a subset of them are
+    /// assigned to this worker. See [`join_with_drain`](Self::join_with_drain).
+    fn join_with_tags(
+        self,
+        addr: impl IntoClientRequest + Unpin + Send + 'static,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        tags: HashSet<String>,
+        drain: Drain,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    /// Join a coordinator like [`join_with_tags`](Self::join_with_tags), and reconnect after
+    /// `reconnect_delay` whenever the connection is lost, until `drain` is requested.
+    ///
+    /// The worker is given about `weight` times as many tasks as a worker of weight 1.
+    ///
+    /// On reconnection, the last assignment version seen is sent in [`VERSION_HEADER`], so that
+    /// only changes since then are sent if the coordinator still keeps the assignment. Otherwise,
+    /// the coordinator reconciles with all the tasks running on the worker.
+    ///
+    /// The returned future fails if the first connection can't be made.
+    #[allow(clippy::too_many_arguments)]
+    fn join_with_resume(
+        self,
+        addr: impl IntoClientRequest + Unpin + Send + 'static,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        tags: HashSet<String>,
+        weight: u32,
+        drain: Drain,
+        reconnect_delay: Duration,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
 }
 
 impl<T> WorkerRpcExt for T
@@ -48,21 +250,173 @@ where
         id: Uuid,
         ty: impl Display + Send + 'static,
     ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
-        Box::pin(async move {
-            let mut req = addr.into_client_request()?;
+        self.join_with_drain(addr, id, ty, Drain::new())
+    }
 
+    fn join_with_drain(
+        self,
+        addr: impl IntoClientRequest + Unpin + Send + 'static,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        drain: Drain,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        self.join_with_tags(addr, id, ty, HashSet::new(), drain)
+    }
+
+    fn join_with_tags(
+        self,
+        addr: impl IntoClientRequest + Unpin + Send + 'static,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        tags: HashSet<String>,
+        drain: Drain,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        Box::pin(join_impl(self, addr, id, ty, tags, 1, drain, None))
+    }
+
+    fn join_with_resume(
+        self,
+        addr: impl IntoClientRequest + Unpin + Send + 'static,
+        id: Uuid,
+        ty: impl Display + Send + 'static,
+        tags: HashSet<String>,
+        weight: u32,
+        drain: Drain,
+        reconnect_delay: Duration,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        Box::pin(join_impl(self, addr, id, ty, tags, weight, drain, Some(reconnect_delay)))
+    }
+}
+
+/// Join a coordinator, and reconnect after `reconnect_delay` if given until a drain is requested.
+#[allow(clippy::too_many_arguments)]
+async fn join_impl<T>(
+    worker: T,
+    addr: impl IntoClientRequest + Unpin + Send + 'static,
+    id: Uuid,
+    ty: impl Display + Send + 'static,
+    tags: HashSet<String>,
+    weight: u32,
+    drain: Drain,
+    reconnect_delay: Option<Duration>,
+) -> Result<()>
+where
+    T: WorkerRpc + Clone + Send,
+    ServeWorkerRpc<T>: Serve<WorkerRpcRequest, Resp = WorkerRpcResponse, Fut = WorkerRpcResponseFut<T>>
+        + Send
+        + 'static,
+    WorkerRpcResponseFut<T>: Send + 'static,
+{
+    let mut req = addr.into_client_request()?;
+
+    req.headers_mut()
+        .insert("Sg-Worker-Kind", ty.to_string().parse()?);
+    req.headers_mut()
+        .insert("Sg-Worker-ID", id.to_string().parse()?);
+    req.headers_mut()
+        .insert(PROTOCOL_HEADER, PROTOCOL_VERSION.to_string().parse()?);
+    if !tags.is_empty() {
+        let tags = tags.into_iter().collect::<Vec<_>>().join(",");
+        req.headers_mut().insert("Sg-Worker-Tags", tags.parse()?);
+    }
+    if weight != 1 {
+        req.headers_mut()
+            .insert(WEIGHT_HEADER, weight.to_string().parse()?);
+    }
+
+    // Version of the last assignment change applied.
+    let version = Arc::new(AtomicU64::new(0));
+    let mut connected = false;
+    loop {
+        let mut req = clone_request(&req);
+        if connected {
+            let last = version.load(Ordering::SeqCst);
             req.headers_mut()
-                .insert("Sg-Worker-Kind", ty.to_string().parse()?);
-            req.headers_mut()
-                .insert("Sg-Worker-ID", id.to_string().parse()?);
+                .insert(VERSION_HEADER, last.to_string().parse()?);
+        }
+
+        debug!("Connecting to coordinator");
+        match tokio_tungstenite::connect_async(req).await {
+            Ok((stream, _)) => {
+                let stream = Drainable {
+                    inner: stream,
+                    drain: drain.clone(),
+                    closing: false,
+                };
+                let channel = BaseChannel::with_defaults(WsTransport::new(stream));
 
-            debug!("Connecting to coordinator");
-            let (stream, _) = tokio_tungstenite::connect_async(req).await?;
-            let channel = BaseChannel::with_defaults(WsTransport::new(stream));
+                info!("Coordinator connected, ready to receive tasks.");
+                let serve = RecordVersion {
+                    serve: worker.clone().serve(),
+                    version: version.clone(),
+                };
+                channel.execute(serve).await;
+                connected = true;
+            }
+            // Keep trying, the coordinator may be restarting.
+            Err(e) if connected => warn!("Failed to reconnect to coordinator: {}", e),
+            Err(e) => return Err(e.into()),
+        }
 
-            info!("Coordinator connected, ready to receive tasks.");
-            channel.execute(self.serve()).await;
-            Ok(())
+        let reconnect_delay = match reconnect_delay {
+            Some(delay) if !drain.0.requested.load(Ordering::SeqCst) => delay,
+            _ => break,
+        };
+        info!(?reconnect_delay, "Disconnected from coordinator, reconnecting");
+        tokio::time::sleep(reconnect_delay).await;
+        if drain.0.requested.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+
+    drain.0.closed.send_replace(true);
+    Ok(())
+}
+
+/// Serves requests with `serve`, recording in `version` the version of each assignment change
+/// once applied.
+#[derive(Clone)]
+struct RecordVersion<S> {
+    serve: S,
+    version: Arc<AtomicU64>,
+}
+
+impl<S> Serve<WorkerRpcRequest> for RecordVersion<S>
+where
+    S: Serve<WorkerRpcRequest, Resp = WorkerRpcResponse>,
+    S::Fut: Send + 'static,
+{
+    type Resp = WorkerRpcResponse;
+    type Fut = Pin<Box<dyn Future<Output = WorkerRpcResponse> + Send>>;
+
+    fn method(&self, req: &WorkerRpcRequest) -> Option<&'static str> {
+        self.serve.method(req)
+    }
+
+    fn serve(self, ctx: tarpc::context::Context, req: WorkerRpcRequest) -> Self::Fut {
+        let changed = match &req {
+            WorkerRpcRequest::AddTask { version, .. }
+            | WorkerRpcRequest::RemoveTask { version, .. } => Some(*version),
+            _ => None,
+        };
+        let resp = self.serve.serve(ctx, req);
+        let version = self.version;
+        Box::pin(async move {
+            let resp = resp.await;
+            if let Some(changed) = changed {
+                version.store(changed, Ordering::SeqCst);
+            }
+            resp
         })
     }
 }
+
+/// Copy of a handshake request, to connect again with.
+fn clone_request(req: &Request) -> Request {
+    let mut cloned = Request::new(());
+    *cloned.method_mut() = req.method().clone();
+    *cloned.uri_mut() = req.uri().clone();
+    *cloned.version_mut() = req.version();
+    *cloned.headers_mut() = req.headers().clone();
+    cloned
+}
@@ -0,0 +1,292 @@
+//! When tasks run.
+
+use std::time::{Duration, SystemTime};
+
+use eyre::{bail, eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+/// Cadence of a task, honored by workers that poll instead of being pushed events.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Schedule {
+    /// Run repeatedly with this much time in between, e.g. `{"interval": "5m"}`.
+    Interval(#[serde(with = "humantime_serde")] Duration),
+    /// Run at times matching a cron expression in UTC, e.g. `{"cron": "*/5 * * * *"}`. See
+    /// [`Cron`].
+    Cron(String),
+}
+
+impl Schedule {
+    /// Check that the schedule can be run.
+    ///
+    /// # Errors
+    /// Returns an error if the interval is zero or the cron expression is invalid.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Self::Interval(interval) if interval.is_zero() => bail!("interval must be positive"),
+            Self::Interval(_) => Ok(()),
+            Self::Cron(expr) => Cron::parse(expr).map(|_| ()),
+        }
+    }
+
+    /// The first time the task should run after `after`.
+    ///
+    /// `None` if the schedule is invalid or never fires, e.g. on February 30th.
+    #[must_use]
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        match self {
+            Self::Interval(interval) => Some(after + *interval),
+            Self::Cron(expr) => Cron::parse(expr).ok()?.next_after(after),
+        }
+    }
+
+    /// Time to wait from now until the next run, or `fallback` if it never comes.
+    #[must_use]
+    pub fn delay_or(&self, fallback: Duration) -> Duration {
+        let now = SystemTime::now();
+        self.next_after(now)
+            .and_then(|next| next.duration_since(now).ok())
+            .unwrap_or(fallback)
+    }
+}
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+/// Years searched for a matching time before giving up.
+const MAX_YEARS: i64 = 5;
+
+/// A parsed cron expression of five fields: minute, hour, day of month, month and day of week.
+///
+/// Each field is `*`, a number, a range `a-b`, or a comma-separated list of them, optionally
+/// stepped by `/n`. Days of week count from 0 (Sunday) to 7 (Sunday again). Names like `MON`
+/// aren't supported. Like in classic cron, a day matches either day field if both are restricted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month field is `*`.
+    any_day: bool,
+    /// Whether the day of week field is `*`.
+    any_weekday: bool,
+}
+
+impl Cron {
+    /// Parse a cron expression.
+    ///
+    /// # Errors
+    /// Returns an error if there aren't five fields or any field is malformed or out of range.
+    pub fn parse(expr: &str) -> Result<Self> {
+        let fields: Vec<_> = expr.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            bail!("cron expression must have 5 fields, got {}", fields.len());
+        };
+
+        let weekday_set = parse_field(weekdays, 0, 7).wrap_err("invalid day of week")?;
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59).wrap_err("invalid minute")?,
+            hours: parse_field(hours, 0, 23).wrap_err("invalid hour")?,
+            days: parse_field(days, 1, 31).wrap_err("invalid day of month")?,
+            months: parse_field(months, 1, 12).wrap_err("invalid month")?,
+            // 7 is Sunday as well
+            weekdays: (weekday_set | weekday_set >> 7) & 0x7f,
+            any_day: days.starts_with('*'),
+            any_weekday: weekdays.starts_with('*'),
+        })
+    }
+
+    /// The first matching minute strictly after `after`.
+    #[must_use]
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let after = after.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs();
+        let mut t = (after / MINUTE + 1) * MINUTE;
+        let (last_year, _, _) = civil_from_days(days_of(t));
+        let last_year = last_year + MAX_YEARS;
+
+        loop {
+            let days = days_of(t);
+            let (year, month, day) = civil_from_days(days);
+            if year > last_year {
+                return None;
+            }
+
+            if !bit(self.months, month) {
+                let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                t = secs_of(days_from_civil(year, month, 1));
+            } else if !self.matches_day(day, (days + 4).rem_euclid(7)) {
+                t = secs_of(days + 1);
+            } else if !bit(self.hours, t % DAY / HOUR) {
+                t = (t / HOUR + 1) * HOUR;
+            } else if !bit(self.minutes, t % HOUR / MINUTE) {
+                t += MINUTE;
+            } else {
+                return Some(SystemTime::UNIX_EPOCH + Duration::from_secs(t));
+            }
+        }
+    }
+
+    /// Whether the day matches, given day of month and day of week (0 is Sunday).
+    fn matches_day(&self, day: u64, weekday: i64) -> bool {
+        #[allow(clippy::cast_sign_loss)]
+        let weekday = bit(self.weekdays, weekday as u64);
+        let day = bit(self.days, day);
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            (true, false) => weekday,
+            _ => day,
+        }
+    }
+}
+
+/// Parse a cron field into a bit set of allowed values.
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64> {
+    let parse = |s: &str| -> Result<u64> {
+        let n = s.parse().map_err(|_| eyre!("`{s}` is not a number"))?;
+        if !(min..=max).contains(&n) {
+            bail!("{n} is out of range {min}-{max}");
+        }
+        Ok(n)
+    };
+
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, parse_step(step)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            // `a/n` runs from `a` to the end
+            None if step > 1 => (parse(range)?, max),
+            None => {
+                let n = parse(range)?;
+                (n, n)
+            }
+        };
+        if start > end {
+            bail!("range {start}-{end} is empty");
+        }
+        for n in (start..=end).step_by(step) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+fn parse_step(step: &str) -> Result<usize> {
+    match step.parse() {
+        Ok(0) | Err(_) => bail!("step `{step}` must be a positive number"),
+        Ok(step) => Ok(step),
+    }
+}
+
+const fn bit(set: u64, n: u64) -> bool {
+    set & (1 << n) != 0
+}
+
+#[allow(clippy::cast_possible_wrap)]
+const fn days_of(secs: u64) -> i64 {
+    (secs / DAY) as i64
+}
+
+#[allow(clippy::cast_sign_loss)]
+const fn secs_of(days: i64) -> u64 {
+    days as u64 * DAY
+}
+
+/// Convert days since Unix epoch to a proleptic Gregorian `(year, month, day)`.
+///
+/// See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+#[allow(clippy::cast_sign_loss, clippy::cast_possible_wrap)]
+const fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = (z - era * 146_097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe as i64 + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// Convert a proleptic Gregorian date to days since Unix epoch. Inverse of [`civil_from_days`].
+#[allow(clippy::cast_sign_loss, clippy::cast_possible_wrap)]
+const fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = (year - era * 400) as u64;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe as i64 - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::schedule::{civil_from_days, days_from_civil, Cron, Schedule};
+
+    /// Unix time of 2023-01-01T00:00:00Z, a Sunday.
+    const NEW_YEAR: u64 = 1_672_531_200;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(NEW_YEAR + secs)
+    }
+
+    fn next(expr: &str, after: u64) -> Option<u64> {
+        let next = Cron::parse(expr).unwrap().next_after(at(after))?;
+        Some(next.duration_since(at(0)).unwrap().as_secs())
+    }
+
+    #[test]
+    fn must_convert_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19358), (2023, 1, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        for days in [-1000, 0, 11016, 19358, 50000] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn must_parse_cron() {
+        for expr in ["* * * * *", "*/5 1-3,7 1 */2 0-7", "5/15 * * * 1"] {
+            Schedule::Cron(expr.to_string()).validate().unwrap();
+        }
+        for expr in ["* * * *", "60 * * * *", "* * 0 * *", "*/0 * * * *", "5-1 * * * *", "a * * * *"]
+        {
+            assert!(Cron::parse(expr).is_err(), "{expr}");
+        }
+        assert!(Schedule::Interval(Duration::ZERO).validate().is_err());
+    }
+
+    #[test]
+    fn must_find_next_run() {
+        // The next minute, never the current one
+        assert_eq!(next("* * * * *", 0), Some(60));
+        assert_eq!(next("*/15 * * * *", 60), Some(15 * 60));
+        assert_eq!(next("30 2 * * *", 3 * 3600), Some(86400 + 2 * 3600 + 30 * 60));
+        // Next Monday
+        assert_eq!(next("0 0 * * 1", 0), Some(86400));
+        // Sunday as 7
+        assert_eq!(next("0 0 * * 7", 0), Some(7 * 86400));
+        // Either day field matches if both are restricted
+        assert_eq!(next("0 0 15 * 1", 0), Some(86400));
+        // March 1st
+        assert_eq!(next("0 0 1 3 *", 0), Some((31 + 28) * 86400));
+        // Never
+        assert_eq!(next("0 0 30 2 *", 0), None);
+
+        let interval = Schedule::Interval(Duration::from_secs(90));
+        assert_eq!(interval.next_after(at(0)), Some(at(90)));
+    }
+}
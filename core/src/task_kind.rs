@@ -0,0 +1,159 @@
+//! Registry of task kinds and their parameters.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Formatter},
+};
+
+use eyre::{bail, Result, WrapErr};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{Map, Value};
+
+use crate::models::Task;
+
+/// Typed parameters of a task kind.
+pub trait TaskParams: DeserializeOwned {
+    /// Kind of the task, i.e. [`Task::kind`].
+    const KIND: &'static str;
+
+    /// Check that `params` are valid for this kind.
+    ///
+    /// By default, `params` must deserialize into `Self`.
+    ///
+    /// # Errors
+    /// Returns an error if `params` are invalid.
+    fn validate(params: &Map<String, Value>) -> Result<()> {
+        Self::deserialize(Value::Object(params.clone()))
+            .map(|_| ())
+            .wrap_err_with(|| format!("invalid params for task kind `{}`", Self::KIND))
+    }
+}
+
+/// Parameters of a youtube task.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct YoutubeParams {
+    /// Channel id of the youtube channel.
+    pub channel_id: String,
+}
+
+impl TaskParams for YoutubeParams {
+    const KIND: &'static str = "youtube";
+}
+
+/// Parameters of a bilibili live task.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BililiveParams {
+    /// Uid of the bilibili account.
+    pub uid: String,
+}
+
+impl TaskParams for BililiveParams {
+    const KIND: &'static str = "bililive";
+}
+
+/// Parameters of a twitter task.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TwitterParams {
+    /// Id of the twitter account.
+    pub id: String,
+}
+
+impl TaskParams for TwitterParams {
+    const KIND: &'static str = "twitter";
+}
+
+/// Validates params of a task kind. See [`TaskParams::validate`].
+type Validator = fn(&Map<String, Value>) -> Result<()>;
+
+/// Registered task kinds, keyed by kind name.
+#[derive(Clone)]
+pub struct TaskKind {
+    kinds: HashMap<&'static str, Validator>,
+}
+
+impl TaskKind {
+    /// Create an empty registry.
+    #[must_use]
+    pub fn empty() -> Self {
+        Self {
+            kinds: HashMap::new(),
+        }
+    }
+
+    /// Register a task kind with its params.
+    #[must_use]
+    pub fn register<P: TaskParams>(mut self) -> Self {
+        self.kinds.insert(P::KIND, P::validate);
+        self
+    }
+
+    /// Whether `kind` is registered.
+    #[must_use]
+    pub fn contains(&self, kind: &str) -> bool {
+        self.kinds.contains_key(kind)
+    }
+
+    /// Check that the kind of `task` is registered and its params are valid.
+    ///
+    /// # Errors
+    /// Returns an error if the kind is unknown or params are invalid.
+    pub fn validate(&self, task: &Task) -> Result<()> {
+        match self.kinds.get(task.kind.as_str()) {
+            Some(validate) => validate(&task.params),
+            None => bail!("unknown task kind `{}`", task.kind),
+        }
+    }
+}
+
+impl Debug for TaskKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.kinds.keys()).finish()
+    }
+}
+
+impl Default for TaskKind {
+    /// All built-in task kinds.
+    fn default() -> Self {
+        Self::empty()
+            .register::<YoutubeParams>()
+            .register::<BililiveParams>()
+            .register::<TwitterParams>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::Uuid;
+
+    use crate::{models::Task, task_kind::TaskKind};
+
+    #[test]
+    fn must_validate() {
+        let kinds = TaskKind::default();
+        let entity = Uuid::new();
+
+        kinds.validate(&Task::new_twitter("123", entity)).unwrap();
+        kinds.validate(&Task::new_bilibili("123", entity)).unwrap();
+        kinds.validate(&Task::new_youtube("UC123", entity)).unwrap();
+
+        // Typo in params
+        let mut task = Task::new_twitter("123", entity);
+        task.params.insert(String::from("idd"), task.params["id"].clone());
+        task.params.remove("id");
+        assert!(kinds.validate(&task).is_err());
+
+        // Wrong type
+        let mut task = Task::new_twitter("123", entity);
+        task.params.insert(String::from("id"), 123.into());
+        assert!(kinds.validate(&task).is_err());
+
+        // Unknown kind
+        let mut task = Task::new_twitter("123", entity);
+        task.kind = String::from("twitch");
+        assert!(kinds.validate(&task).is_err());
+        assert!(!TaskKind::empty().contains("twitter"));
+    }
+}
@@ -1,12 +1,28 @@
 //! Utility structs and functions.
 
-use std::ops::{Deref, DerefMut};
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
 
 #[cfg(any(feature = "core_derive", test))]
 pub use core_derive::Config;
 #[cfg(any(feature = "figment", test))]
 pub use figment_ext::*;
-use tokio::task::JoinHandle;
+use mongodb::{
+    bson::Uuid,
+    options::{Acknowledgment, ClientOptions, ReadPreference, SelectionCriteria},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::models::Task;
 
 /// A wrapper that holds a join handle and abort the task if dropped.
 #[derive(Debug)]
@@ -32,6 +48,194 @@ impl<T> Drop for ScopedJoinHandle<T> {
     }
 }
 
+/// Task param overriding the limit of [`EntityLimiter`] for the entity of the task.
+pub const ENTITY_CONCURRENCY_PARAM: &str = "entity_concurrency";
+
+/// Bounds how many tasks of one entity run at once. Tasks of different entities don't wait for
+/// each other.
+#[derive(Debug, Clone, Default)]
+pub struct EntityLimiter {
+    /// Default limit per entity. `0` is unlimited.
+    limit: usize,
+    /// Semaphores of entities with tasks running or waiting.
+    entities: Arc<Mutex<HashMap<Uuid, Arc<Semaphore>>>>,
+}
+
+impl EntityLimiter {
+    /// Allow `limit` tasks of each entity to run at once, unless overridden by the
+    /// [`ENTITY_CONCURRENCY_PARAM`] of a task. `0` is unlimited.
+    #[must_use]
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            entities: Arc::default(),
+        }
+    }
+
+    /// Limit of the entity of `task`.
+    #[must_use]
+    pub fn limit_of(&self, task: &Task) -> usize {
+        task.params
+            .get(ENTITY_CONCURRENCY_PARAM)
+            .and_then(Value::as_u64)
+            .and_then(|limit| usize::try_from(limit).ok())
+            .unwrap_or(self.limit)
+    }
+
+    /// Wait until `task` may run, i.e. until the returned permit is dropped.
+    ///
+    /// If tasks of an entity have different limits, the one of the first task to acquire wins,
+    /// until no task of the entity is running or waiting.
+    pub async fn acquire(&self, task: &Task) -> EntityPermit {
+        let limit = self.limit_of(task);
+        if limit == 0 {
+            return EntityPermit { _permit: None };
+        }
+
+        let semaphore = self
+            .entities
+            .lock()
+            .expect("Entity limiter lock poisoned")
+            .entry(task.entity)
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone();
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore is never closed");
+
+        EntityPermit {
+            _permit: Some(Permit {
+                limiter: self.clone(),
+                entity: task.entity,
+                semaphore,
+                permit: Some(permit),
+            }),
+        }
+    }
+}
+
+/// Permit of a task to run, given by [`EntityLimiter::acquire`].
+#[derive(Debug)]
+#[must_use]
+pub struct EntityPermit {
+    // Only held to be released on drop
+    _permit: Option<Permit>,
+}
+
+#[derive(Debug)]
+struct Permit {
+    limiter: EntityLimiter,
+    entity: Uuid,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        let mut entities = self
+            .limiter
+            .entities
+            .lock()
+            .expect("Entity limiter lock poisoned");
+        // Held by the map and this permit only, so no one else is running or waiting
+        if Arc::strong_count(&self.semaphore) == 2 {
+            entities.remove(&self.entity);
+        }
+    }
+}
+
+/// Write acknowledgment required from a `MongoDB` deployment, e.g. `majority` or a number of nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum WriteAck {
+    /// Acknowledged by this many nodes.
+    Nodes(u32),
+    /// `majority`, or a custom write concern defined by the replica set.
+    Named(String),
+}
+
+impl From<WriteAck> for Acknowledgment {
+    fn from(ack: WriteAck) -> Self {
+        match ack {
+            WriteAck::Nodes(n) => n.into(),
+            WriteAck::Named(name) => name.into(),
+        }
+    }
+}
+
+/// Members of a `MongoDB` replica set reads are routed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReadMode {
+    /// Only the primary.
+    Primary,
+    /// The primary, or a secondary if it's unavailable.
+    PrimaryPreferred,
+    /// Only secondaries.
+    Secondary,
+    /// A secondary, or the primary if none is available.
+    SecondaryPreferred,
+    /// The member with the least latency.
+    Nearest,
+}
+
+impl From<ReadMode> for ReadPreference {
+    fn from(mode: ReadMode) -> Self {
+        let options = Default::default();
+        match mode {
+            ReadMode::Primary => Self::Primary,
+            ReadMode::PrimaryPreferred => Self::PrimaryPreferred { options },
+            ReadMode::Secondary => Self::Secondary { options },
+            ReadMode::SecondaryPreferred => Self::SecondaryPreferred { options },
+            ReadMode::Nearest => Self::Nearest { options },
+        }
+    }
+}
+
+/// Parse `MongoDB` client options from `uri`, overridden by those given.
+///
+/// Options left `None` keep what's in `uri`, or the driver defaults.
+///
+/// # Errors
+/// Returns an error if `uri` is invalid.
+pub async fn mongo_options(
+    uri: &str,
+    max_pool_size: Option<u32>,
+    write_concern: Option<WriteAck>,
+    read_preference: Option<ReadMode>,
+) -> mongodb::error::Result<ClientOptions> {
+    let mut options = ClientOptions::parse(uri).await?;
+    if let Some(size) = max_pool_size {
+        options.max_pool_size = Some(size);
+    }
+    if let Some(ack) = write_concern {
+        // Keep the timeout and journal settings in `uri`
+        let mut concern = options.write_concern.take().unwrap_or_default();
+        concern.w = Some(ack.into());
+        options.write_concern = Some(concern);
+    }
+    if let Some(mode) = read_preference {
+        options.selection_criteria = Some(SelectionCriteria::ReadPreference(mode.into()));
+    }
+    Ok(options)
+}
+
+/// Connect to `MongoDB` at `uri`, with options overridden as in [`mongo_options`].
+///
+/// # Errors
+/// Returns an error if `uri` or any option is invalid.
+pub async fn connect_mongo(
+    uri: &str,
+    max_pool_size: Option<u32>,
+    write_concern: Option<WriteAck>,
+    read_preference: Option<ReadMode>,
+) -> mongodb::error::Result<Client> {
+    Client::with_options(mongo_options(uri, max_pool_size, write_concern, read_preference).await?)
+}
+
 /// A macro to quickly create a single `kv` [`map`].
 ///
 /// [`map`]: serde_json::Map
@@ -169,6 +373,12 @@ mod figment_ext {
     }
 }
 
+/// Name-based UUID (version 5) of `name` in `namespace`, as specified in RFC 4122.
+#[must_use]
+pub fn uuid_v5(namespace: Uuid, name: &str) -> Uuid {
+    Uuid::from_uuid_0_8(uuid::Uuid::new_v5(&namespace.to_uuid_0_8(), name.as_bytes()))
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
@@ -178,7 +388,29 @@ mod tests {
     use serde::Deserialize;
     use tokio::{task::yield_now, time::sleep};
 
-    use crate::utils::{FigmentExt, ScopedJoinHandle};
+    use mongodb::{
+        bson::Uuid,
+        options::{Acknowledgment, ReadPreference, SelectionCriteria},
+    };
+
+    use crate::{
+        models::Task,
+        utils::{
+            mongo_options, uuid_v5, EntityLimiter, FigmentExt, ReadMode, ScopedJoinHandle,
+            WriteAck, ENTITY_CONCURRENCY_PARAM,
+        },
+    };
+
+    #[test]
+    fn must_derive_uuid_v5() {
+        // Namespace for DNS names in RFC 4122
+        let dns = Uuid::parse_str("6ba7b810-9dad-11d1-80b4-00c04fd430c8").unwrap();
+        assert_eq!(
+            uuid_v5(dns, "www.example.com").to_string(),
+            "2ed6657d-e927-568b-95e1-2665a8aea6a2"
+        );
+        assert_ne!(uuid_v5(dns, "www.example.org"), uuid_v5(dns, "www.example.com"));
+    }
 
     #[tokio::test]
     async fn must_abort_on_drop() {
@@ -203,6 +435,64 @@ mod tests {
         assert!(tx.is_closed());
     }
 
+    #[tokio::test]
+    async fn must_limit_per_entity() {
+        let limiter = EntityLimiter::new(1);
+        let entity = mongodb::bson::Uuid::new();
+        let (a, b) = (Task::new_twitter("a", entity), Task::new_twitter("b", entity));
+        let other = Task::new_twitter("c", mongodb::bson::Uuid::new());
+        let blocked = |task| tokio::time::timeout(Duration::from_millis(50), limiter.acquire(task));
+
+        let permit = limiter.acquire(&a).await;
+        assert!(blocked(&b).await.is_err());
+        // Other entities don't wait
+        drop(limiter.acquire(&other).await);
+        drop(permit);
+        drop(blocked(&b).await.unwrap());
+        assert!(limiter.entities.lock().unwrap().is_empty());
+
+        // Overridden by task params
+        let mut a = a;
+        a.params.insert(ENTITY_CONCURRENCY_PARAM.to_owned(), 2.into());
+        let permit = limiter.acquire(&a).await;
+        assert!(blocked(&a).await.is_ok());
+        drop(permit);
+
+        // Unlimited
+        let limiter = EntityLimiter::new(0);
+        let _permits = [limiter.acquire(&b).await, limiter.acquire(&b).await];
+        assert!(limiter.entities.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn must_override_mongo_options() {
+        let uri = "mongodb://localhost:27017/?maxPoolSize=5&w=1&wtimeoutMS=100";
+
+        let options = mongo_options(uri, None, None, None).await.unwrap();
+        assert_eq!(options.max_pool_size, Some(5));
+        assert_eq!(options.selection_criteria, None);
+        let default_concern = options.write_concern.unwrap();
+
+        let options = mongo_options(
+            uri,
+            Some(50),
+            Some(WriteAck::Named(String::from("majority"))),
+            Some(ReadMode::SecondaryPreferred),
+        )
+        .await
+        .unwrap();
+        assert_eq!(options.max_pool_size, Some(50));
+        let concern = options.write_concern.unwrap();
+        assert_eq!(concern.w, Some(Acknowledgment::Majority));
+        assert_eq!(concern.w_timeout, default_concern.w_timeout);
+        assert!(matches!(
+            options.selection_criteria,
+            Some(SelectionCriteria::ReadPreference(
+                ReadPreference::SecondaryPreferred { .. }
+            ))
+        ));
+    }
+
     #[derive(Deserialize, Config)]
     #[config(core = "crate")]
     struct ConfigWithNoDefaults {
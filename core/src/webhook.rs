@@ -0,0 +1,242 @@
+//! Delivery of events to HTTP endpoints, as if it's an IM platform.
+//!
+//! A webhook is a [`User`](crate::models::User) of platform [`PLATFORM`], whose `im_payload` is a
+//! [`WebhookTarget`] in JSON. Events are POSTed to it by [`WebhookBackend`], which is registered to
+//! an [`ImRegistry`](crate::im::ImRegistry) like other backends, so that failed deliveries are
+//! retried and kept as dead letters the same way.
+
+use eyre::{bail, Result, WrapErr};
+#[cfg(any(feature = "webhook", test))]
+pub use backend::*;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Platform of webhooks, i.e. their [`User::im`](crate::models::User::im).
+pub const PLATFORM: &str = "webhook";
+
+/// Header carrying the signature of the body, if the webhook has a secret.
+///
+/// The signature is `sha256=` followed by the hex-encoded HMAC-SHA256 of the body, keyed by the
+/// secret.
+pub const SIGNATURE_HEADER: &str = "X-Stargazer-Signature";
+
+/// Where events are delivered to a webhook.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookTarget {
+    /// Endpoint events are POSTed to.
+    pub callback_url: Url,
+    /// Secret the body is signed with. See [`SIGNATURE_HEADER`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl WebhookTarget {
+    /// Parse the target from the `im_payload` of a webhook.
+    ///
+    /// # Errors
+    /// Returns an error if the payload isn't a valid target.
+    pub fn from_payload(payload: &str) -> Result<Self> {
+        let target: Self = serde_json::from_str(payload).wrap_err("invalid webhook payload")?;
+        target.validate()?;
+        Ok(target)
+    }
+
+    /// Serialize the target into the `im_payload` of a webhook.
+    #[must_use]
+    pub fn to_payload(&self) -> String {
+        serde_json::to_string(self).expect("Target must be serializable")
+    }
+
+    /// Check that events can be delivered to the target.
+    ///
+    /// # Errors
+    /// Returns an error if the callback url isn't http(s), or the secret is empty.
+    pub fn validate(&self) -> Result<()> {
+        if !matches!(self.callback_url.scheme(), "http" | "https") {
+            bail!("callback url must be http(s), got `{}`", self.callback_url);
+        }
+        if self.secret.as_deref() == Some("") {
+            bail!("secret must not be empty");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "webhook", test))]
+mod backend {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use eyre::{bail, Result};
+    use hmac::{Hmac, Mac};
+    use isolanguage_1::LanguageCode;
+    use reqwest::header::{CONTENT_LANGUAGE, CONTENT_TYPE};
+    use sha2::Sha256;
+
+    use crate::{
+        im::ImBackend,
+        models::{Event, User},
+        webhook::{WebhookTarget, PLATFORM, SIGNATURE_HEADER},
+    };
+
+    /// Sign `body` with `secret`, as sent in [`SIGNATURE_HEADER`].
+    #[must_use]
+    pub fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    /// Backend POSTing events to webhooks as JSON.
+    ///
+    /// The preferred language of the webhook is sent in `Content-Language`, for receivers to pick
+    /// from the names embedded in the event. Deliveries fail on non-2xx responses, so that they're
+    /// retried by the registry.
+    #[derive(Debug, Clone)]
+    pub struct WebhookBackend {
+        client: reqwest::Client,
+    }
+
+    impl WebhookBackend {
+        /// Create a backend giving up on a delivery if it takes longer than `timeout`.
+        ///
+        /// # Errors
+        /// Returns an error if the HTTP client can't be initialized.
+        pub fn new(timeout: Duration) -> Result<Self> {
+            Ok(Self {
+                client: reqwest::Client::builder().timeout(timeout).build()?,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl ImBackend for WebhookBackend {
+        fn platform(&self) -> &str {
+            PLATFORM
+        }
+
+        async fn deliver(&self, user: &User, event: &Event, lang: LanguageCode) -> Result<()> {
+            let target = WebhookTarget::from_payload(&user.im_payload)?;
+            let body = serde_json::to_vec(event)?;
+
+            let mut req = self
+                .client
+                .post(target.callback_url)
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_LANGUAGE, lang.code());
+            if let Some(secret) = &target.secret {
+                req = req.header(SIGNATURE_HEADER, sign(secret, &body));
+            }
+
+            let status = req.body(body).send().await?.status();
+            if !status.is_success() {
+                bail!("webhook responded with {status}");
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use isolanguage_1::LanguageCode;
+    use mongodb::bson::Uuid;
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{
+        im::ImBackend,
+        models::{Event, EventFilter, User},
+        webhook::{sign, WebhookBackend, WebhookTarget, PLATFORM, SIGNATURE_HEADER},
+    };
+
+    #[test]
+    fn must_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn must_validate_target() {
+        let target = |url: &str, secret: Option<&str>| {
+            WebhookTarget {
+                callback_url: url.parse().unwrap(),
+                secret: secret.map(ToOwned::to_owned),
+            }
+            .to_payload()
+        };
+
+        let payload = target("https://example.com/hook", Some("secret"));
+        let parsed = WebhookTarget::from_payload(&payload).unwrap();
+        assert_eq!(parsed.secret.as_deref(), Some("secret"));
+
+        assert!(WebhookTarget::from_payload("https://example.com/hook").is_err());
+        assert!(WebhookTarget::from_payload(&target("ftp://example.com", None)).is_err());
+        assert!(WebhookTarget::from_payload(&target("https://example.com", Some(""))).is_err());
+    }
+
+    /// Serve one request with `status`, and return the raw request.
+    async fn serve_once(listener: TcpListener, status: &'static str) -> String {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; 64 * 1024];
+        let mut len = 0;
+        // Read until the whole body arrives
+        while !String::from_utf8_lossy(&buf[..len]).ends_with('}') {
+            len += socket.read(&mut buf[len..]).await.unwrap();
+        }
+        let res = format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+        socket.write_all(res.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn must_deliver() {
+        let backend = WebhookBackend::new(Duration::from_secs(5)).unwrap();
+        let event = Event::from_serializable("test", Uuid::new(), json!({ "a": 1 })).unwrap();
+        let body = serde_json::to_string(&event).unwrap();
+
+        for (status, secret, ok) in [("200 OK", Some("secret"), true), ("503 Oops", None, false)] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target = WebhookTarget {
+                callback_url: format!("http://{}/hook", listener.local_addr().unwrap())
+                    .parse()
+                    .unwrap(),
+                secret: secret.map(ToOwned::to_owned),
+            };
+            let user = User {
+                id: Uuid::new(),
+                im: PLATFORM.to_owned(),
+                im_payload: target.to_payload(),
+                name: String::from("integrator"),
+                avatar: None,
+                event_filter: EventFilter {
+                    entities: Default::default(),
+                    groups: Default::default(),
+                    kinds: Default::default(),
+                },
+                muted_until: None,
+                preferred_language: LanguageCode::Ja,
+            };
+
+            let server = tokio::spawn(serve_once(listener, status));
+            let delivered = backend.deliver(&user, &event, user.preferred_language).await;
+            assert_eq!(delivered.is_ok(), ok, "{status}");
+
+            let req = server.await.unwrap().to_lowercase();
+            assert!(req.starts_with("post /hook"));
+            assert!(req.contains("content-language: ja"));
+            assert!(req.ends_with(&body.to_lowercase()));
+            let signature = format!("{}: {}", SIGNATURE_HEADER, sign("secret", body.as_bytes()));
+            assert_eq!(req.contains(&signature.to_lowercase()), secret.is_some());
+        }
+    }
+}
@@ -120,6 +120,8 @@ mod tests {
             .as_object()
             .unwrap()
             .clone(),
+            names: Default::default(),
+            default_language: None,
         };
         let translator = MockTranslator;
         let translated = translator.translate_event(e).await.unwrap();
@@ -139,6 +141,8 @@ mod tests {
                 .as_object()
                 .unwrap()
                 .clone(),
+                names: Default::default(),
+                default_language: None,
             }
         );
     }
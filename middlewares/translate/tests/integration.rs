@@ -29,6 +29,8 @@ async fn must_translate_and_put_back() {
         .as_object()
         .unwrap()
         .clone(),
+        names: Default::default(),
+        default_language: None,
     };
     let translated = Event {
         id: Uuid::nil().into(),
@@ -44,6 +46,8 @@ async fn must_translate_and_put_back() {
         .as_object()
         .unwrap()
         .clone(),
+        names: Default::default(),
+        default_language: None,
     };
 
     let mut program = Command::cargo_bin("translate")
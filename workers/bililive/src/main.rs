@@ -1,7 +1,13 @@
 #![allow(clippy::module_name_repetitions)]
 
 use eyre::{Result, WrapErr};
-use sg_core::{mq::RabbitMQ, protocol::WorkerRpcExt, utils::FigmentExt};
+use sg_core::{
+    event_schema::EventSchemas,
+    mq::{Deduplicated, Deduplicator, RabbitMQ, Validated},
+    protocol::{Drain, WorkerRpcExt},
+    utils::FigmentExt,
+};
+use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 
 use crate::{config::Config, worker::BililiveWorker};
@@ -23,11 +29,35 @@ async fn main() -> Result<()> {
     let mq = RabbitMQ::new(&config.amqp_url, &config.amqp_exchange)
         .await
         .wrap_err("Failed to connect to AMQP")?;
-
-    BililiveWorker::new(mq)
-        .join(config.coordinator_url, config.id, "bililive")
-        .await
-        .wrap_err("Failed to start worker")?;
+    let mq = Deduplicated::new(
+        mq,
+        Deduplicator::new(config.dedup_window, config.dedup_capacity),
+    );
+    // Validated first, so that malformed events are not remembered by the deduplicator
+    let mq = Validated::new(mq, EventSchemas::default());
+
+    let drain = Drain::new();
+    let worker = BililiveWorker::new(mq, config.entity_concurrency);
+    let mut worker = tokio::spawn(worker.join_with_resume(
+        config.coordinator_url,
+        config.id,
+        "bililive",
+        config.tags,
+        config.weight,
+        drain.clone(),
+        config.reconnect_delay,
+    ));
+
+    tokio::select! {
+        r = &mut worker => r?.wrap_err("Failed to start worker")?,
+        r = tokio::signal::ctrl_c() => {
+            r?;
+            info!("Shutdown signal received, draining");
+            if !drain.drain(config.drain_timeout).await {
+                warn!("Coordinator did not acknowledge drain in time");
+            }
+        }
+    }
 
     Ok(())
 }
@@ -6,10 +6,11 @@ use futures_util::StreamExt;
 use parking_lot::Mutex;
 use serde::Deserialize;
 use sg_core::{
+    load::LoadMeter,
     models::{Event, Task},
     mq::{MessageQueue, Middlewares},
-    protocol::WorkerRpc,
-    utils::ScopedJoinHandle,
+    protocol::{Heartbeat, WorkerRpc},
+    utils::{EntityLimiter, ScopedJoinHandle},
 };
 use tap::TapOptional;
 use tarpc::context::Context;
@@ -22,17 +23,21 @@ use crate::bililive::LiveRoom;
 #[derive(Clone)]
 pub struct BililiveWorker {
     mq: Arc<dyn MessageQueue>,
+    limiter: EntityLimiter,
+    load: Arc<LoadMeter>,
 
     #[allow(clippy::type_complexity)]
     tasks: Arc<Mutex<HashMap<Uuid, (Task, ScopedJoinHandle<()>)>>>,
 }
 
 impl BililiveWorker {
-    /// Creates a new worker.
+    /// Creates a new worker, connecting at most `entity_concurrency` tasks of one entity at once.
     #[must_use]
-    pub fn new(mq: impl MessageQueue + 'static) -> Self {
+    pub fn new(mq: impl MessageQueue + 'static, entity_concurrency: usize) -> Self {
         Self {
             mq: Arc::new(mq),
+            limiter: EntityLimiter::new(entity_concurrency),
+            load: Arc::new(LoadMeter::new()),
             tasks: Arc::new(Mutex::new(HashMap::new())),
         }
     }
@@ -40,11 +45,12 @@ impl BililiveWorker {
 
 #[tarpc::server]
 impl WorkerRpc for BililiveWorker {
-    async fn ping(self, _: Context, id: u64) -> u64 {
-        id
+    async fn ping(self, _: Context, id: u64) -> Heartbeat {
+        let load = self.load.sample(self.tasks.lock().values().map(|(task, _)| task));
+        Heartbeat { id, load }
     }
 
-    async fn add_task(self, _: Context, task: Task) -> bool {
+    async fn add_task(self, _: Context, task: Task, _version: u64) -> bool {
         let mut tasks = self.tasks.lock();
         if tasks.contains_key(&task.id.into()) {
             // If the task is already running, do nothing.
@@ -66,10 +72,13 @@ impl WorkerRpc for BililiveWorker {
             }
         };
 
+        let this_task = task.clone();
         let fut = async move {
             loop {
                 info!(?uid, "Spawning bililive task");
-                if let Err(error) = bililive_task(uid, task.entity.into(), &*self.mq).await {
+                let result = bililive_task(uid, &this_task, &self.limiter, &*self.mq).await;
+                self.load.record(&result);
+                if let Err(error) = result {
                     error!(?error, "Bililive task failed");
 
                     // Sleep to avoid looping if the task always fails.
@@ -84,7 +93,7 @@ impl WorkerRpc for BililiveWorker {
         true
     }
 
-    async fn remove_task(self, _: Context, id: Uuid) -> bool {
+    async fn remove_task(self, _: Context, id: Uuid, _version: u64) -> bool {
         self.tasks
             .lock()
             .remove(&id)
@@ -107,7 +116,16 @@ struct Command {
     cmd: String,
 }
 
-async fn bililive_task(uid: u64, entity_id: Uuid, mq: impl MessageQueue) -> Result<()> {
+async fn bililive_task(
+    uid: u64,
+    task: &Task,
+    limiter: &EntityLimiter,
+    mq: impl MessageQueue,
+) -> Result<()> {
+    let entity_id: Uuid = task.entity.into();
+
+    // Only connecting and handling events are bounded, not waiting for them.
+    let permit = limiter.acquire(task).await;
     let config = bililive::ConfigBuilder::new()
         .fetch_conf()
         .await
@@ -120,6 +138,7 @@ async fn bililive_task(uid: u64, entity_id: Uuid, mq: impl MessageQueue) -> Resu
     let mut stream = bililive::connect::tokio::connect_with_retry(config, RetryConfig::default())
         .await
         .wrap_err("Unable to connect to bilibili live server")?;
+    drop(permit);
 
     while let Some(msg) = stream.next().await {
         match msg {
@@ -131,6 +150,7 @@ async fn bililive_task(uid: u64, entity_id: Uuid, mq: impl MessageQueue) -> Resu
                     })
                 {
                     info!(uid = uid, "Live started");
+                    let _permit = limiter.acquire(task).await;
 
                     match LiveRoom::new(room_id).await {
                         Ok(room) => {
@@ -1,6 +1,6 @@
 //! Twitter worker config.
 
-use std::time::Duration;
+use std::{collections::HashSet, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use sg_core::utils::Config;
@@ -27,11 +27,38 @@ pub struct Config {
     #[serde(with = "humantime_serde")]
     #[config(default_str = "60s")]
     pub poll_interval: Duration,
+    /// Maximum time to wait for the coordinator to take over tasks on shutdown.
+    #[serde(with = "humantime_serde")]
+    #[config(default_str = "10s")]
+    pub drain_timeout: Duration,
+    /// Delay before reconnecting to the coordinator once the connection is lost.
+    #[serde(with = "humantime_serde")]
+    #[config(default_str = "1s")]
+    pub reconnect_delay: Duration,
+    /// Time window in which events with the same content are dropped. `0s` disables it.
+    #[serde(with = "humantime_serde")]
+    #[config(default_str = "0s")]
+    pub dedup_window: Duration,
+    /// Maximum number of events remembered for deduplication.
+    #[config(default = "1024")]
+    pub dedup_capacity: usize,
+    /// Maximum number of tasks of one entity polling at once, overridable by the
+    /// `entity_concurrency` param of a task. `0` is unlimited.
+    #[config(default = "0")]
+    pub entity_concurrency: usize,
+    /// Tags advertised to the coordinator, e.g. `[jp]`. Tasks requiring tags are only assigned to
+    /// workers with all of them.
+    #[config(default = "[]")]
+    pub tags: HashSet<String>,
+    /// Capacity relative to other workers of the kind, e.g. `2` to be given about twice as many
+    /// tasks as a worker of weight `1`.
+    #[config(default = "1")]
+    pub weight: u32,
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{collections::HashSet, time::Duration};
 
     use figment::Jail;
     use sg_core::utils::FigmentExt;
@@ -52,6 +79,13 @@ mod tests {
                     coordinator_url: String::from("ws://127.0.0.1:7000"),
                     twitter_token: String::new(),
                     poll_interval: Duration::from_secs(60),
+                    drain_timeout: Duration::from_secs(10),
+                    reconnect_delay: Duration::from_secs(1),
+                    dedup_window: Duration::ZERO,
+                    dedup_capacity: 1024,
+                    entity_concurrency: 0,
+                    tags: HashSet::new(),
+                    weight: 1,
                 }
             );
             Ok(())
@@ -68,6 +102,13 @@ mod tests {
             jail.set_env("WORKER_COORDINATOR_URL", "ws://localhost:8080");
             jail.set_env("WORKER_TWITTER_TOKEN", "blabla");
             jail.set_env("WORKER_POLL_INTERVAL", "30s");
+            jail.set_env("WORKER_DRAIN_TIMEOUT", "5s");
+            jail.set_env("WORKER_RECONNECT_DELAY", "3s");
+            jail.set_env("WORKER_DEDUP_WINDOW", "1m");
+            jail.set_env("WORKER_DEDUP_CAPACITY", "64");
+            jail.set_env("WORKER_ENTITY_CONCURRENCY", "2");
+            jail.set_env("WORKER_TAGS", "[jp, residential]");
+            jail.set_env("WORKER_WEIGHT", "2");
             assert_eq!(
                 Config::from_env("WORKER_").unwrap(),
                 Config {
@@ -77,6 +118,13 @@ mod tests {
                     coordinator_url: String::from("ws://localhost:8080"),
                     twitter_token: String::from("blabla"),
                     poll_interval: Duration::from_secs(30),
+                    drain_timeout: Duration::from_secs(5),
+                    reconnect_delay: Duration::from_secs(3),
+                    dedup_window: Duration::from_secs(60),
+                    dedup_capacity: 64,
+                    entity_concurrency: 2,
+                    tags: HashSet::from([String::from("jp"), String::from("residential")]),
+                    weight: 2,
                 }
             );
             Ok(())
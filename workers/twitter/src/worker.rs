@@ -8,14 +8,16 @@ use futures_util::StreamExt;
 use parking_lot::Mutex;
 use serde_json::Value;
 use sg_core::{
+    load::LoadMeter,
     models::{Event, Task},
     mq::MessageQueue,
-    protocol::WorkerRpc,
-    utils::ScopedJoinHandle,
+    protocol::{Heartbeat, WorkerRpc},
+    schedule::Schedule,
+    utils::{EntityLimiter, ScopedJoinHandle},
 };
 use tap::TapOptional;
 use tarpc::context::Context;
-use tokio::time::{interval, sleep};
+use tokio::time::sleep;
 use tracing::{error, info};
 use uuid::Uuid;
 
@@ -30,6 +32,8 @@ pub struct TwitterWorker {
     token: Arc<Token>,
     mq: Arc<dyn MessageQueue>,
     interval: Duration,
+    limiter: EntityLimiter,
+    load: Arc<LoadMeter>,
 
     #[allow(clippy::type_complexity)]
     tasks: Arc<Mutex<HashMap<Uuid, (Task, ScopedJoinHandle<()>)>>>,
@@ -43,6 +47,8 @@ impl TwitterWorker {
             token: Arc::new(Token::Bearer(config.twitter_token)),
             mq: Arc::new(mq),
             interval: config.poll_interval,
+            limiter: EntityLimiter::new(config.entity_concurrency),
+            load: Arc::new(LoadMeter::new()),
             tasks: Arc::new(Mutex::new(HashMap::new())),
         }
     }
@@ -50,11 +56,12 @@ impl TwitterWorker {
 
 #[tarpc::server]
 impl WorkerRpc for TwitterWorker {
-    async fn ping(self, _: Context, id: u64) -> u64 {
-        id
+    async fn ping(self, _: Context, id: u64) -> Heartbeat {
+        let load = self.load.sample(self.tasks.lock().values().map(|(task, _)| task));
+        Heartbeat { id, load }
     }
 
-    async fn add_task(self, _: Context, task: Task) -> bool {
+    async fn add_task(self, _: Context, task: Task, _version: u64) -> bool {
         let mut tasks = self.tasks.lock();
         if tasks.contains_key(&task.id.into()) {
             // If the task is already running, do nothing.
@@ -80,6 +87,8 @@ impl WorkerRpc for TwitterWorker {
         // Prepare the worker future.
         let token = self.token.clone();
         let poll_interval = self.interval;
+        let schedule = task.schedule.clone();
+        let this_task = task.clone();
 
         let fut = async move {
             loop {
@@ -87,16 +96,18 @@ impl WorkerRpc for TwitterWorker {
                 if let Err(error) = twitter_task(
                     id.clone(),
                     &token,
-                    task.entity.into(),
+                    &this_task,
+                    &self.limiter,
+                    &self.load,
                     &*self.mq,
-                    poll_interval,
+                    || next_poll(schedule.as_ref(), poll_interval),
                 )
                 .await
                 {
                     error!(?error, "Failed to fetch timeline");
 
                     // Sleep to avoid looping if the task always fails.
-                    sleep(poll_interval).await;
+                    sleep(next_poll(schedule.as_ref(), poll_interval)).await;
                 }
             }
         };
@@ -107,7 +118,7 @@ impl WorkerRpc for TwitterWorker {
         true
     }
 
-    async fn remove_task(self, _: Context, id: Uuid) -> bool {
+    async fn remove_task(self, _: Context, id: Uuid, _version: u64) -> bool {
         self.tasks
             .lock()
             .remove(&id)
@@ -125,21 +136,40 @@ impl WorkerRpc for TwitterWorker {
     }
 }
 
+/// Time until the next poll, by the schedule of the task or `poll_interval` if it has none.
+fn next_poll(schedule: Option<&Schedule>, poll_interval: Duration) -> Duration {
+    schedule.map_or(poll_interval, |schedule| schedule.delay_or(poll_interval))
+}
+
 // Fetch the timeline for the given user and send the tweets to the message
-// queue.
+// queue, waiting for `next_poll` in between. Polls are bounded by `limiter`, and
+// recorded in `load`.
 async fn twitter_task(
     user_id: UserID,
     token: &Token,
-    entity_id: Uuid,
+    task: &Task,
+    limiter: &EntityLimiter,
+    load: &LoadMeter,
     mq: impl MessageQueue,
-    poll_interval: Duration,
+    next_poll: impl Fn() -> Duration,
 ) -> Result<()> {
-    let mut ticker = interval(poll_interval);
+    let entity_id: Uuid = task.entity.into();
 
     // Construct a stream of tweets.
-    let mut stream = TimelineStream::new(user_timeline(user_id, false, true, token)).await?;
-    while let Some(resp) = stream.next().await {
+    let mut stream = {
+        let _permit = limiter.acquire(task).await;
+        let stream = TimelineStream::new(user_timeline(user_id, false, true, token)).await;
+        load.record(&stream);
+        stream?
+    };
+    loop {
+        let permit = limiter.acquire(task).await;
+        let Some(resp) = stream.next().await else {
+            break;
+        };
+
         // Parse income tweets.
+        load.record(&resp);
         for raw_tweet in resp?.response {
             let tweet_id = raw_tweet.id;
             let tweet = Tweet::from(raw_tweet);
@@ -150,9 +180,10 @@ async fn twitter_task(
                 error!(?error, %tweet_id, "Failed to publish tweet");
             }
         }
+        drop(permit);
 
-        // Tick.
-        ticker.tick().await;
+        // Wait for the next run.
+        sleep(next_poll()).await;
     }
 
     Ok(())